@@ -0,0 +1,143 @@
+// parng/asynchronous.rs
+//
+// Copyright (c) 2016 Mozilla Foundation
+
+//! An asynchronous API that decodes an image as it arrives from a `tokio_io::AsyncRead` stream.
+//!
+//! This API is only available if the `async` feature is enabled. It's built on
+//! `ImageLoader::try_add_data_from_slice()`, so it never blocks the task that polls it, either on
+//! I/O or on the predictor thread.
+
+use PngError;
+use futures::{Async, Poll, Stream};
+use futures::task::AtomicTask;
+use imageloader::{DataProvider, ImageLoader, LoadProgress};
+use metadata::Metadata;
+use std::io::{self, ErrorKind};
+use std::sync::Arc;
+use tokio_io::AsyncRead;
+
+/// The number of bytes to read from the stream at a time.
+const READ_SIZE: usize = 65536;
+
+/// An event in the loading of an image. See `ImageStream`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum LoadEvent {
+    /// The metadata has been read. A data provider must be attached with
+    /// `ImageStream::set_data_provider()` before the stream is polled again.
+    NeedDataProvider,
+    /// More of the image stream has been read and decoded. Holds the total number of bytes read
+    /// so far.
+    DataAdded(u64),
+    /// The image has been fully decoded, and the data provider's `finished()` method has been
+    /// called. This is the last event.
+    Finished,
+}
+
+/// A stream of events that decodes an image from an asynchronous reader as it is polled.
+///
+/// The image is decoded into a data provider, exactly as with `ImageLoader`. Everything that the
+/// stream has read is kept until the image is finished, since image data is decompressed straight
+/// out of it.
+pub struct ImageStream<R> where R: AsyncRead {
+    reader: R,
+    image: ImageLoader,
+    /// Everything read from `reader` so far.
+    data: Vec<u8>,
+    /// Scratch space that each read from `reader` goes into before being appended to `data`.
+    read_buffer: Vec<u8>,
+    /// True if data has been added since the last `LoadEvent::DataAdded` event.
+    data_added: bool,
+    /// True once the image has been fully entropy decoded.
+    entropy_decoded: bool,
+    finished: bool,
+    /// The task to notify when the predictor thread finishes a batch of scanlines.
+    task: Arc<AtomicTask>,
+}
+
+impl<R> ImageStream<R> where R: AsyncRead {
+    /// Creates a stream that decodes an image from `reader` using `image`, which must not have
+    /// been given any data yet.
+    pub fn new(reader: R, mut image: ImageLoader) -> ImageStream<R> {
+        let task = Arc::new(AtomicTask::new());
+        let task_to_notify = task.clone();
+        image.set_wake_up_callback(Box::new(move || task_to_notify.notify()));
+        ImageStream {
+            reader: reader,
+            image: image,
+            data: Vec::with_capacity(READ_SIZE),
+            read_buffer: vec![0; READ_SIZE],
+            data_added: false,
+            entropy_decoded: false,
+            finished: false,
+            task: task,
+        }
+    }
+
+    /// Returns the metadata of the image, if it has been read.
+    pub fn metadata(&self) -> &Option<Metadata> {
+        self.image.metadata()
+    }
+
+    /// Attaches a data provider to the image loader. This must be done once the stream yields
+    /// `LoadEvent::NeedDataProvider`, if not before.
+    pub fn set_data_provider(&mut self, data_provider: Box<DataProvider + Send>) {
+        self.image.set_data_provider(data_provider)
+    }
+}
+
+impl<R> Stream for ImageStream<R> where R: AsyncRead {
+    type Item = LoadEvent;
+    type Error = PngError;
+
+    fn poll(&mut self) -> Poll<Option<LoadEvent>, PngError> {
+        if self.finished {
+            return Ok(Async::Ready(None))
+        }
+
+        // Register before checking on the predictor thread, so that we hear about any batch of
+        // scanlines that it finishes after we look.
+        self.task.register();
+
+        loop {
+            if self.entropy_decoded {
+                if !try!(self.image.try_wait_until_finished()) {
+                    return Ok(Async::NotReady)
+                }
+                self.finished = true;
+                return Ok(Async::Ready(Some(LoadEvent::Finished)))
+            }
+
+            match try!(self.image.try_add_data_from_slice(&self.data)) {
+                LoadProgress::Finished => {
+                    self.entropy_decoded = true;
+                    continue
+                }
+                LoadProgress::NeedDataProviderAndMoreData => {
+                    return Ok(Async::Ready(Some(LoadEvent::NeedDataProvider)))
+                }
+                LoadProgress::NeedMoreData | LoadProgress::WaitingForPrediction
+                        if self.data_added => {
+                    self.data_added = false;
+                    return Ok(Async::Ready(Some(LoadEvent::DataAdded(self.data.len() as u64))))
+                }
+                LoadProgress::WaitingForPrediction => return Ok(Async::NotReady),
+                LoadProgress::NeedMoreData => {}
+                LoadProgress::Yielded => panic!("Only methods with a work budget yield!"),
+            }
+
+            match self.reader.poll_read(&mut self.read_buffer) {
+                Ok(Async::Ready(0)) => {
+                    return Err(PngError::Io(io::Error::new(ErrorKind::UnexpectedEof,
+                                                           "PNG image stream ended prematurely")))
+                }
+                Ok(Async::Ready(bytes_read)) => {
+                    self.data.extend_from_slice(&self.read_buffer[0..bytes_read]);
+                    self.data_added = true
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(error) => return Err(PngError::Io(error)),
+            }
+        }
+    }
+}
@@ -5,15 +5,70 @@
 
 #[cfg(feature = "bench")]
 mod bench {
+    extern crate parng;
     extern crate stb_image;
     extern crate time;
 
+    use self::parng::imageloader::{ImageLoader, LoadProgress, RowDataProvider};
     use std::env;
+    use std::fs::File;
+    use std::io::Read;
 
     const RUNS: u32 = 10;
 
+    /// The worker counts to time `parng` with if none are given on the command line.
+    static DEFAULT_WORKER_COUNTS: [u32; 3] = [1, 2, 4];
+
+    struct Rows {
+        pixels: Vec<u8>,
+        row_length: usize,
+    }
+
+    fn fetch_row(rows: &mut Rows, y: u32) -> &mut [u8] {
+        let start = y as usize * rows.row_length;
+        &mut rows.pixels[start..(start + rows.row_length)]
+    }
+
+    fn row_complete(_: &mut Rows, _: u32) {}
+
+    /// Decodes the image with `parng`, using the given number of worker threads, and returns the
+    /// elapsed time in nanoseconds.
+    fn load_with_parng(data: &[u8], worker_count: u32) -> u64 {
+        let before = time::precise_time_ns();
+        let mut image_loader = ImageLoader::with_worker_count(worker_count);
+        loop {
+            match image_loader.add_data_from_slice(data).unwrap() {
+                LoadProgress::NeedDataProviderAndMoreData => {
+                    let rows = {
+                        let dimensions = image_loader.metadata().as_ref().unwrap().dimensions;
+                        let row_length = dimensions.width as usize * 4;
+                        Rows {
+                            pixels: vec![0; row_length * dimensions.height as usize],
+                            row_length: row_length,
+                        }
+                    };
+                    let data_provider =
+                        RowDataProvider::new(image_loader.metadata().as_ref().unwrap(),
+                                             rows,
+                                             fetch_row,
+                                             row_complete);
+                    image_loader.set_data_provider(Box::new(data_provider))
+                }
+                LoadProgress::Finished => break,
+                _ => panic!("Image ended prematurely!"),
+            }
+        }
+        image_loader.wait_until_finished().unwrap();
+        time::precise_time_ns() - before
+    }
+
     pub fn go() {
-        let input_path = env::args().skip(1).next().unwrap();
+        let mut args = env::args().skip(1);
+        let input_path = args.next().unwrap();
+        let mut worker_counts: Vec<u32> = args.map(|arg| arg.parse().unwrap()).collect();
+        if worker_counts.is_empty() {
+            worker_counts.extend_from_slice(&DEFAULT_WORKER_COUNTS)
+        }
 
         let mut total_elapsed_time = 0;
         for _ in 0..RUNS {
@@ -26,6 +81,23 @@ mod bench {
 
         total_elapsed_time /= RUNS as u64;
         println!("Mean elapsed time: {}ms", total_elapsed_time as f32 / 1_000_000.0);
+
+        // Compare `parng` with each worker count against the first, a single thread by default.
+        let mut data = vec![];
+        File::open(&input_path).unwrap().read_to_end(&mut data).unwrap();
+        let mut baseline_time = None;
+        for &worker_count in &worker_counts {
+            let mut total_elapsed_time = 0;
+            for _ in 0..RUNS {
+                total_elapsed_time += load_with_parng(&data, worker_count)
+            }
+            total_elapsed_time /= RUNS as u64;
+            let baseline_time = *baseline_time.get_or_insert(total_elapsed_time);
+            println!("parng, {} worker(s): mean elapsed time {}ms, speedup {:.2}x",
+                     worker_count,
+                     total_elapsed_time as f32 / 1_000_000.0,
+                     baseline_time as f32 / total_elapsed_time as f32);
+        }
     }
 }
 
@@ -38,4 +110,3 @@ fn main() {
 fn main() {
     println!("Compile with the `bench` feature to use the benchmarking tool.");
 }
-
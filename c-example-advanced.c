@@ -157,6 +157,8 @@ void finished(void *user_data) {
     pthread_mutex_unlock(&decoded_image->finished_mutex);
 }
 
+static void cancelled(void *user_data) {}
+
 int main(int argc, const char **argv) {
     if (argc < 3) {
         fprintf(stderr, "usage: c-example input.png output.tga\n");
@@ -224,6 +226,7 @@ int main(int argc, const char **argv) {
         fetch_scanlines_for_rgba_conversion,
         rgba_conversion_complete_for_scanline,
         finished,
+        cancelled,
         &decoded_image
     };
     parng_image_loader_set_data_provider(image_loader, &data_provider);
@@ -7,20 +7,32 @@
 #![allow(non_camel_case_types)]
 
 use PngError;
-use imageloader::{self, DataProvider, ImageLoader, InterlacingInfo, LevelOfDetail, LoadProgress};
+use imageloader::{self, DataProvider, DecoderPool, ImageLoader, InterlacingInfo, LevelOfDetail};
+use imageloader::{CancelHandle, LoadProgress, Progress, Region};
 use imageloader::{ScanlinesForPrediction, ScanlinesForRgbaConversion};
-use libc::{self, FILE, SEEK_CUR, SEEK_END, SEEK_SET, c_long, c_void, size_t, uintptr_t};
+use libc::{self, FILE, SEEK_CUR, SEEK_END, SEEK_SET, c_char, c_long, c_void, size_t};
+use libc::uintptr_t;
 use metadata::{ColorType, InterlaceMethod, Metadata};
-use simple::Image;
-use std::io::{self, Cursor, Error, ErrorKind, Read, Seek, SeekFrom};
+use simple::{self, Image, Scale};
+use std::ffi::CStr;
+#[cfg(unix)]
+use std::ffi::OsStr;
+use std::io::{self, Error, ErrorKind, Read, Seek, SeekFrom};
 use std::mem;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
 use std::ptr;
 use std::slice;
 
+/// See `imageloader::CancelHandle`.
+pub type parng_cancel_handle = CancelHandle;
 /// See `metadata::ColorType`.
 pub type parng_color_type = u32;
 /// See `metadata::CompressionMethod`.
 pub type parng_compression_method = u32;
+/// See `imageloader::DecoderPool`.
+pub type parng_decoder_pool = DecoderPool;
 /// See `PngError`.
 pub type parng_error = u32;
 /// See `metadata::FilterMethod`.
@@ -35,6 +47,8 @@ pub type parng_io_error = u32;
 pub type parng_level_of_detail = i32;
 /// See `imageloader::LoadProgress`.
 pub type parng_load_progress = u32;
+/// See `simple::Scale`.
+pub type parng_scale = u32;
 /// See `std::io::SeekFrom`.
 pub type parng_seek_from = u32;
 
@@ -56,6 +70,9 @@ pub const PARNG_ERROR_INVALID_METADATA: u32 = 2;
 pub const PARNG_ERROR_INVALID_SCANLINE_PREDICTOR: u32 = 3;
 pub const PARNG_ERROR_ENTROPY_DECODING_ERROR: u32 = 4;
 pub const PARNG_ERROR_NO_DATA_PROVIDER: u32 = 5;
+pub const PARNG_ERROR_INVALID_ARGUMENT: u32 = 6;
+pub const PARNG_ERROR_INVALID_BUFFER: u32 = 7;
+pub const PARNG_ERROR_CANCELLED: u32 = 8;
 
 pub const PARNG_FILTER_METHOD_ADAPTIVE: u32 = 0;
 
@@ -71,6 +88,10 @@ pub const PARNG_LEVEL_OF_DETAIL_ADAM7_4: i32 = 4;
 pub const PARNG_LEVEL_OF_DETAIL_ADAM7_5: i32 = 5;
 pub const PARNG_LEVEL_OF_DETAIL_ADAM7_6: i32 = 6;
 
+pub const PARNG_SCALE_HALF: u32 = 2;
+pub const PARNG_SCALE_QUARTER: u32 = 4;
+pub const PARNG_SCALE_EIGHTH: u32 = 8;
+
 pub const PARNG_SEEK_FROM_START: u32 = 0;
 pub const PARNG_SEEK_FROM_CURRENT: u32 = 1;
 pub const PARNG_SEEK_FROM_END: u32 = 2;
@@ -220,6 +241,7 @@ pub struct parng_data_provider {
                                                          lod: parng_level_of_detail,
                                                          user_data: *mut c_void),
     finished: extern "C" fn(user_data: *mut c_void),
+    cancelled: extern "C" fn(user_data: *mut c_void),
     user_data: *mut c_void,
 }
 
@@ -303,6 +325,10 @@ impl DataProvider for parng_data_provider {
     fn finished(&mut self) {
         (self.finished)(self.user_data)
     }
+
+    fn cancelled(&mut self) {
+        (self.cancelled)(self.user_data)
+    }
 }
 
 #[repr(C)]
@@ -322,6 +348,24 @@ pub struct parng_interlacing_info {
     pub offset: u8,
 }
 
+#[repr(C)]
+pub struct parng_region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[repr(C)]
+pub struct parng_progress {
+    pub level_of_detail: parng_level_of_detail,
+    pub level_of_detail_count: u32,
+    pub scanlines_decompressed: [u32; 7],
+    pub scanlines_predicted: [u32; 7],
+    pub bytes_read: u64,
+    pub fraction_complete: f32,
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn parng_image_load(c_image: *mut parng_image, reader: *mut parng_reader)
                                           -> parng_error {
@@ -354,7 +398,76 @@ pub unsafe extern "C" fn parng_image_load_from_memory(c_image: *mut parng_image,
                                                       bytes: *const u8,
                                                       length: size_t)
                                                       -> parng_error {
-    match Image::load(&mut Cursor::new(slice::from_raw_parts(bytes, length))) {
+    let bytes = match c_bytes_to_slice(bytes, length) {
+        Some(bytes) => bytes,
+        None => return PARNG_ERROR_INVALID_ARGUMENT,
+    };
+    match Image::load_from_slice(bytes) {
+        Err(error) => png_error_to_c_error(error),
+        Ok(image) => {
+            *c_image = image_to_c_image(image);
+            PARNG_SUCCESS
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn parng_image_load_from_memory_with_stride(c_image: *mut parng_image,
+                                                                  bytes: *const u8,
+                                                                  length: size_t,
+                                                                  stride: size_t)
+                                                                  -> parng_error {
+    let bytes = match c_bytes_to_slice(bytes, length) {
+        Some(bytes) => bytes,
+        None => return PARNG_ERROR_INVALID_ARGUMENT,
+    };
+    match Image::load_from_slice_with_stride(bytes, stride) {
+        Err(error) => png_error_to_c_error(error),
+        Ok(image) => {
+            *c_image = image_to_c_image(image);
+            PARNG_SUCCESS
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn parng_image_load_region_from_memory(c_image: *mut parng_image,
+                                                             bytes: *const u8,
+                                                             length: size_t,
+                                                             region: *const parng_region)
+                                                             -> parng_error {
+    let bytes = match c_bytes_to_slice(bytes, length) {
+        Some(bytes) => bytes,
+        None => return PARNG_ERROR_INVALID_ARGUMENT,
+    };
+    let region = match c_region_to_region(region) {
+        Some(region) => region,
+        None => return PARNG_ERROR_INVALID_ARGUMENT,
+    };
+    match Image::load_region_from_slice(bytes, region) {
+        Err(error) => png_error_to_c_error(error),
+        Ok(image) => {
+            *c_image = image_to_c_image(image);
+            PARNG_SUCCESS
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn parng_image_load_scaled_from_memory(c_image: *mut parng_image,
+                                                             bytes: *const u8,
+                                                             length: size_t,
+                                                             scale: parng_scale)
+                                                             -> parng_error {
+    let bytes = match c_bytes_to_slice(bytes, length) {
+        Some(bytes) => bytes,
+        None => return PARNG_ERROR_INVALID_ARGUMENT,
+    };
+    let scale = match c_scale_to_scale(scale) {
+        Some(scale) => scale,
+        None => return PARNG_ERROR_INVALID_ARGUMENT,
+    };
+    match Image::load_scaled_from_slice(bytes, scale) {
         Err(error) => png_error_to_c_error(error),
         Ok(image) => {
             *c_image = image_to_c_image(image);
@@ -363,6 +476,79 @@ pub unsafe extern "C" fn parng_image_load_from_memory(c_image: *mut parng_image,
     }
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn parng_image_load_preview_from_memory(c_image: *mut parng_image,
+                                                              bytes: *const u8,
+                                                              length: size_t,
+                                                              lod: parng_level_of_detail)
+                                                              -> parng_error {
+    let bytes = match c_bytes_to_slice(bytes, length) {
+        Some(bytes) => bytes,
+        None => return PARNG_ERROR_INVALID_ARGUMENT,
+    };
+    let lod = match try_c_level_of_detail_to_level_of_detail(lod) {
+        Some(lod) => lod,
+        None => return PARNG_ERROR_INVALID_ARGUMENT,
+    };
+    match Image::load_preview_from_slice(bytes, lod) {
+        Err(error) => png_error_to_c_error(error),
+        Ok(image) => {
+            *c_image = image_to_c_image(image);
+            PARNG_SUCCESS
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn parng_image_load_from_path(c_image: *mut parng_image, path: *const c_char)
+                                                    -> parng_error {
+    if path.is_null() {
+        return PARNG_ERROR_INVALID_ARGUMENT
+    }
+    let path = match c_path_to_path_buf(path) {
+        Some(path) => path,
+        None => return PARNG_ERROR_IO,
+    };
+    match Image::load_from_path(&path) {
+        Err(error) => png_error_to_c_error(error),
+        Ok(image) => {
+            *c_image = image_to_c_image(image);
+            PARNG_SUCCESS
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn parng_image_load_from_memory_into_buffer(bytes: *const u8,
+                                                                  length: size_t,
+                                                                  pixels: *mut u8,
+                                                                  pixels_length: size_t,
+                                                                  stride: size_t,
+                                                                  width: *mut u32,
+                                                                  height: *mut u32)
+                                                                  -> parng_error {
+    let bytes = match c_bytes_to_slice(bytes, length) {
+        Some(bytes) => bytes,
+        None => return PARNG_ERROR_INVALID_ARGUMENT,
+    };
+    if width.is_null() || height.is_null() {
+        return PARNG_ERROR_INVALID_ARGUMENT
+    }
+    let pixels = if pixels.is_null() {
+        &mut []
+    } else {
+        slice::from_raw_parts_mut(pixels, pixels_length)
+    };
+    match simple::load_from_slice_into_buffer(bytes, pixels, stride) {
+        Err(error) => png_error_to_c_error(error),
+        Ok(dimensions) => {
+            *width = dimensions.width;
+            *height = dimensions.height;
+            PARNG_SUCCESS
+        }
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn parng_image_destroy(image: *mut parng_image) {
     drop(Vec::from_raw_parts((*image).pixels,
@@ -370,6 +556,23 @@ pub unsafe extern "C" fn parng_image_destroy(image: *mut parng_image) {
                              (*image).capacity))
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn parng_decoder_pool_create(pool: *mut *mut parng_decoder_pool,
+                                                   thread_count: u32)
+                                                   -> parng_error {
+    if thread_count == 0 {
+        return PARNG_ERROR_INVALID_ARGUMENT
+    }
+    let new_pool = DecoderPool::new(thread_count);
+    *pool = mem::transmute::<Box<DecoderPool>, *mut DecoderPool>(Box::new(new_pool));
+    PARNG_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn parng_decoder_pool_destroy(pool: *mut parng_decoder_pool) {
+    drop(mem::transmute::<*mut parng_decoder_pool, Box<DecoderPool>>(pool))
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn parng_image_loader_create(image_loader: *mut *mut parng_image_loader) {
     let new_image_loader = ImageLoader::new();
@@ -377,6 +580,44 @@ pub unsafe extern "C" fn parng_image_loader_create(image_loader: *mut *mut parng
                                      *mut ImageLoader>(Box::new(new_image_loader));
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn parng_image_loader_create_with_worker_count(
+        image_loader: *mut *mut parng_image_loader,
+        worker_count: u32)
+        -> parng_error {
+    if worker_count == 0 {
+        return PARNG_ERROR_INVALID_ARGUMENT
+    }
+    let new_image_loader = ImageLoader::with_worker_count(worker_count);
+    *image_loader = mem::transmute::<Box<ImageLoader>,
+                                     *mut ImageLoader>(Box::new(new_image_loader));
+    PARNG_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn parng_image_loader_create_inline(
+        image_loader: *mut *mut parng_image_loader)
+        -> parng_error {
+    let new_image_loader = ImageLoader::new_inline();
+    *image_loader = mem::transmute::<Box<ImageLoader>,
+                                     *mut ImageLoader>(Box::new(new_image_loader));
+    PARNG_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn parng_image_loader_create_with_pool(
+        image_loader: *mut *mut parng_image_loader,
+        pool: *mut parng_decoder_pool)
+        -> parng_error {
+    if pool.is_null() {
+        return PARNG_ERROR_INVALID_ARGUMENT
+    }
+    let new_image_loader = ImageLoader::with_pool(&*pool);
+    *image_loader = mem::transmute::<Box<ImageLoader>,
+                                     *mut ImageLoader>(Box::new(new_image_loader));
+    PARNG_SUCCESS
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn parng_image_loader_destroy(image_loader: *mut parng_image_loader) {
     drop(mem::transmute::<*mut parng_image_loader, Box<ImageLoader>>(image_loader))
@@ -396,6 +637,26 @@ pub unsafe extern "C" fn parng_image_loader_add_data(image_loader: *mut parng_im
     }
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn parng_image_loader_add_data_from_memory(
+        image_loader: *mut parng_image_loader,
+        bytes: *const u8,
+        length: size_t,
+        result: *mut parng_load_progress)
+        -> parng_error {
+    let bytes = match c_bytes_to_slice(bytes, length) {
+        Some(bytes) => bytes,
+        None => return PARNG_ERROR_INVALID_ARGUMENT,
+    };
+    match (*image_loader).add_data_from_slice(bytes) {
+        Ok(load_progress) => {
+            *result = load_progress_to_c_result(load_progress);
+            PARNG_SUCCESS
+        }
+        Err(err) => png_error_to_c_error(err)
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn parng_image_loader_wait_until_finished(
         image_loader: *mut parng_image_loader)
@@ -414,6 +675,62 @@ pub unsafe extern "C" fn parng_image_loader_set_data_provider(
     (*image_loader).set_data_provider(Box::new(*data_provider))
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn parng_image_loader_set_region_of_interest(
+        image_loader: *mut parng_image_loader,
+        region: *mut parng_region)
+        -> parng_error {
+    let clipped_region = match c_region_to_region(region) {
+        Some(region) => (*image_loader).try_set_region_of_interest(region),
+        None => None,
+    };
+    match clipped_region {
+        None => PARNG_ERROR_INVALID_ARGUMENT,
+        Some(clipped_region) => {
+            (*region).x = clipped_region.x;
+            (*region).y = clipped_region.y;
+            (*region).width = clipped_region.width;
+            (*region).height = clipped_region.height;
+            PARNG_SUCCESS
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn parng_image_loader_set_last_level_of_detail(
+        image_loader: *mut parng_image_loader,
+        lod: parng_level_of_detail)
+        -> parng_error {
+    match try_c_level_of_detail_to_level_of_detail(lod) {
+        Some(lod) if (*image_loader).try_set_last_level_of_detail(lod) => PARNG_SUCCESS,
+        _ => PARNG_ERROR_INVALID_ARGUMENT,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn parng_image_loader_reset(image_loader: *mut parng_image_loader) {
+    (*image_loader).reset()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn parng_image_loader_create_cancel_handle(
+        image_loader: *mut parng_image_loader,
+        cancel_handle: *mut *mut parng_cancel_handle) {
+    let new_cancel_handle = (*image_loader).cancel_handle();
+    *cancel_handle =
+        mem::transmute::<Box<CancelHandle>, *mut CancelHandle>(Box::new(new_cancel_handle));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn parng_cancel_handle_cancel(cancel_handle: *mut parng_cancel_handle) {
+    (*cancel_handle).cancel()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn parng_cancel_handle_destroy(cancel_handle: *mut parng_cancel_handle) {
+    drop(mem::transmute::<*mut parng_cancel_handle, Box<CancelHandle>>(cancel_handle))
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn parng_image_loader_get_metadata(image_loader: *mut parng_image_loader,
                                                          metadata_result: *mut parng_metadata)
@@ -427,6 +744,12 @@ pub unsafe extern "C" fn parng_image_loader_get_metadata(image_loader: *mut parn
     }
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn parng_image_loader_get_progress(image_loader: *mut parng_image_loader,
+                                                         progress_result: *mut parng_progress) {
+    *progress_result = progress_to_c_progress(&(*image_loader).progress())
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn parng_image_loader_align(address: uintptr_t) -> uintptr_t {
     imageloader::align(address)
@@ -444,6 +767,42 @@ pub unsafe extern "C" fn parng_interlacing_info_init(
     (*interlacing_info).offset = info.offset
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn parng_interlacing_info_init_in_region(
+        interlacing_info: *mut parng_interlacing_info,
+        y: u32,
+        color_depth: u8,
+        lod: parng_level_of_detail,
+        region: *const parng_region)
+        -> u32 {
+    let region = match c_region_to_region(region) {
+        Some(region) => region,
+        None => return 0,
+    };
+    match InterlacingInfo::in_region(y,
+                                     color_depth,
+                                     c_level_of_detail_to_level_of_detail(lod),
+                                     &region) {
+        None => 0,
+        Some(info) => {
+            (*interlacing_info).y = info.y;
+            (*interlacing_info).stride = info.stride;
+            (*interlacing_info).offset = info.offset;
+            1
+        }
+    }
+}
+
+#[cfg(unix)]
+unsafe fn c_path_to_path_buf(path: *const c_char) -> Option<PathBuf> {
+    Some(PathBuf::from(OsStr::from_bytes(CStr::from_ptr(path).to_bytes())))
+}
+
+#[cfg(not(unix))]
+unsafe fn c_path_to_path_buf(path: *const c_char) -> Option<PathBuf> {
+    CStr::from_ptr(path).to_str().ok().map(PathBuf::from)
+}
+
 fn png_error_to_c_error(err: PngError) -> parng_error {
     match err {
         PngError::Io(_) => PARNG_ERROR_IO,
@@ -451,6 +810,8 @@ fn png_error_to_c_error(err: PngError) -> parng_error {
         PngError::InvalidScanlinePredictor(_) => PARNG_ERROR_INVALID_SCANLINE_PREDICTOR,
         PngError::EntropyDecodingError => PARNG_ERROR_ENTROPY_DECODING_ERROR,
         PngError::NoDataProvider => PARNG_ERROR_NO_DATA_PROVIDER,
+        PngError::InvalidBuffer(_) => PARNG_ERROR_INVALID_BUFFER,
+        PngError::Cancelled => PARNG_ERROR_CANCELLED,
     }
 }
 
@@ -461,6 +822,10 @@ fn load_progress_to_c_result(result: LoadProgress) -> parng_load_progress {
         LoadProgress::NeedDataProviderAndMoreData => {
             PARNG_LOAD_PROGRESS_NEED_DATA_PROVIDER_AND_MORE_DATA
         }
+        LoadProgress::WaitingForPrediction => {
+            panic!("Only non-blocking methods wait for prediction!")
+        }
+        LoadProgress::Yielded => panic!("Only methods with a work budget yield!"),
     }
 }
 
@@ -472,12 +837,18 @@ fn level_of_detail_to_c_level_of_detail(lod: LevelOfDetail) -> parng_level_of_de
 }
 
 fn c_level_of_detail_to_level_of_detail(c_lod: parng_level_of_detail) -> LevelOfDetail {
+    try_c_level_of_detail_to_level_of_detail(c_lod).expect("Not a valid level of detail!")
+}
+
+/// Returns the level of detail that `c_lod` names, or `None` if it isn't a valid level of detail.
+fn try_c_level_of_detail_to_level_of_detail(c_lod: parng_level_of_detail)
+                                            -> Option<LevelOfDetail> {
     match c_lod {
-        PARNG_LEVEL_OF_DETAIL_NONE => LevelOfDetail::None,
+        PARNG_LEVEL_OF_DETAIL_NONE => Some(LevelOfDetail::None),
         _ if c_lod >= PARNG_LEVEL_OF_DETAIL_ADAM7_0 && c_lod <= PARNG_LEVEL_OF_DETAIL_ADAM7_6 => {
-            LevelOfDetail::Adam7((c_lod - PARNG_LEVEL_OF_DETAIL_ADAM7_0) as u8)
+            Some(LevelOfDetail::Adam7((c_lod - PARNG_LEVEL_OF_DETAIL_ADAM7_0) as u8))
         }
-        _ => panic!("Not a valid level of detail!"),
+        _ => None,
     }
 }
 
@@ -521,6 +892,29 @@ unsafe fn c_scanlines_for_rgba_conversion_to_scanlines_for_rgba_conversion(
     }
 }
 
+/// Returns the scale that `c_scale` names, or `None` if it isn't a valid scale.
+fn c_scale_to_scale(c_scale: parng_scale) -> Option<Scale> {
+    match c_scale {
+        PARNG_SCALE_HALF => Some(Scale::Half),
+        PARNG_SCALE_QUARTER => Some(Scale::Quarter),
+        PARNG_SCALE_EIGHTH => Some(Scale::Eighth),
+        _ => None,
+    }
+}
+
+/// Returns the region at `c_region`, or `None` if `c_region` is null.
+unsafe fn c_region_to_region(c_region: *const parng_region) -> Option<Region> {
+    if c_region.is_null() {
+        return None
+    }
+    Some(Region {
+        x: (*c_region).x,
+        y: (*c_region).y,
+        width: (*c_region).width,
+        height: (*c_region).height,
+    })
+}
+
 fn metadata_to_c_metadata(metadata: &Metadata) -> parng_metadata {
     parng_metadata {
         width: metadata.dimensions.width,
@@ -532,6 +926,24 @@ fn metadata_to_c_metadata(metadata: &Metadata) -> parng_metadata {
     }
 }
 
+fn progress_to_c_progress(progress: &Progress) -> parng_progress {
+    let mut c_progress = parng_progress {
+        level_of_detail: level_of_detail_to_c_level_of_detail(progress.level_of_detail),
+        level_of_detail_count: progress.scanlines_decompressed.len() as u32,
+        scanlines_decompressed: [0; 7],
+        scanlines_predicted: [0; 7],
+        bytes_read: progress.bytes_read,
+        fraction_complete: progress.fraction_complete,
+    };
+    for (i, &scanline_count) in progress.scanlines_decompressed.iter().enumerate() {
+        c_progress.scanlines_decompressed[i] = scanline_count
+    }
+    for (i, &scanline_count) in progress.scanlines_predicted.iter().enumerate() {
+        c_progress.scanlines_predicted[i] = scanline_count
+    }
+    c_progress
+}
+
 fn color_type_to_c_color_type(color_type: ColorType) -> parng_color_type {
     match color_type {
         ColorType::Grayscale => PARNG_COLOR_TYPE_GRAYSCALE,
@@ -563,3 +975,14 @@ unsafe fn image_to_c_image(mut image: Image) -> parng_image {
     c_image
 }
 
+/// Returns the `length` bytes at `bytes`, or `None` if `bytes` is null and `length` isn't zero.
+unsafe fn c_bytes_to_slice<'a>(bytes: *const u8, length: size_t) -> Option<&'a [u8]> {
+    if !bytes.is_null() {
+        Some(slice::from_raw_parts(bytes, length))
+    } else if length == 0 {
+        Some(&[])
+    } else {
+        None
+    }
+}
+
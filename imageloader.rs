@@ -9,69 +9,212 @@
 //! for complete control over the layout and storage of image data in memory.
 
 use PngError;
-use byteorder::{self, ReadBytesExt};
+use byteorder::{self, BigEndian, ByteOrder, ReadBytesExt};
+#[cfg(all(feature = "zlib", not(feature = "builtin-inflate")))]
 use flate2::{DataError, Decompress, Flush};
+use inflate::{self, ParallelInflater};
+#[cfg(any(feature = "builtin-inflate", not(feature = "zlib")))]
+use inflate::Inflater;
 use libc::c_int;
-use metadata::{ChunkHeader, ColorType, InterlaceMethod, Metadata};
-use prediction::{MainThreadToPredictorThreadComm, MainThreadToPredictorThreadMsg};
-use prediction::{PredictionRequest, PerformRgbaConversionRequest, Predictor};
-use prediction::{PredictorThreadToMainThreadMsg, ScanlineToPredict};
+use metadata::{ChunkHeader, ColorType, Dimensions, InterlaceMethod, Metadata};
+use prediction::{self, MainThreadToPredictorThreadComm, MainThreadToPredictorThreadMsg};
+use prediction::{PooledPredictorThreadMsg, PredictionRequest, Predictor};
+use prediction::{PredictorThreadToMainThreadMsg, RgbaConversionInfo, ScanlineToPredict};
+use std::cell::RefCell;
 use std::cmp;
-use std::io::{Read, Seek, SeekFrom};
+use std::collections::VecDeque;
+use std::io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom};
 use std::mem;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
 
 const BUFFER_SIZE: usize = 16384;
 const PIXELS_PER_PREDICTION_CHUNK: u32 = 1024;
+const SCANLINES_PER_PREDICTION_CHUNK_PER_WORKER: u32 = 4;
+const IDOT_CHUNK_LENGTH: u32 = 28;
 
 /// An object that encapsulates the load process for a single image.
-pub struct ImageLoader {
-    entropy_decoder: Decompress,
+///
+/// `P` is the type of the data provider. Image loaders that call it from a background thread
+/// require a `DataProvider + Send`, which is the default; see `LocalImageLoader` for one that
+/// doesn't.
+pub struct ImageLoader<P = DataProvider + Send> where P: ?Sized {
+    entropy_decoder: EntropyDecoder,
     metadata: Option<Metadata>,
     compressed_data_buffer: Vec<u8>,
     compressed_data_consumed: usize,
+    /// How far into the file `add_data_from_slice()` has gotten.
+    slice_position: u64,
     palette: Vec<u8>,
     transparency: Transparency,
+    /// A block of decompressed image data holding a batch of consecutive scanlines, each preceded
+    /// by its predictor byte. Whole blocks are sent to the predictor thread and recycled via
+    /// `cached_scanline_data_buffers` once they come back.
     scanline_data_buffer: Vec<u8>,
     scanline_data_buffer_size: usize,
     cached_scanline_data_buffers: Vec<Vec<u8>>,
+    /// The offset of the scanline currently being decoded within `scanline_data_buffer`.
+    current_scanline_offset: usize,
 
     /// There will be one entry in this vector per buffered scanline.
     scanline_data_buffer_info: Vec<BufferedScanlineInfo>,
 
+    /// When decompressing in parallel, the compressed data of the segment currently being
+    /// gathered up from the `IDAT` chunks.
+    compressed_image_data: Vec<u8>,
+    /// The number of bytes of compressed image data gathered up so far, in every segment.
+    compressed_image_data_length: usize,
+    /// The offsets in the compressed image data at which the segments yet to be gathered up
+    /// begin, if they were found in advance.
+    split_points: VecDeque<usize>,
+    /// The position in the stream of the `IDAT` chunk that the `iDOT` chunk points to, if any,
+    /// unless it has already been accounted for in `split_points`.
+    idot_restart_position: Option<u64>,
+    /// Decompresses the segments of the image data on threads of their own, if it is split into
+    /// segments that can be decompressed independently.
+    parallel_inflater: Option<ParallelInflater>,
+
+    /// The only part of the image that the data provider is interested in, if any.
+    region_of_interest: Option<Region>,
+    /// The level of detail to stop decoding after, if not the last one in the image.
+    last_level_of_detail: Option<LevelOfDetail>,
+
     current_y: u32,
     current_lod: LevelOfDetail,
-    scanlines_decoded_in_this_lod: u32,
-    last_decoded_lod: LevelOfDetail,
-    rgba_conversion_complete: bool,
+    rgba_conversion_info_sent: bool,
+    /// The number of scanlines decompressed so far, counting those of every level of detail.
+    scanlines_decompressed: u64,
+    /// For each prediction request that the predictor thread hasn't finished with yet, in order,
+    /// the level of detail and Y position just past its last scanline.
+    prediction_request_ends: VecDeque<(LevelOfDetail, u32)>,
+    /// The level of detail and Y position just past the last scanline predicted, if any.
+    prediction_end: Option<(LevelOfDetail, u32)>,
+    /// The number of bytes read from the stream so far.
+    bytes_read: u64,
+    progress_callback: Option<Box<FnMut(&Progress) + Send>>,
 
     decode_state: DecodeState,
 
-    predictor_thread_comm: MainThreadToPredictorThreadComm,
+    predictor_thread_comm: MainThreadToPredictorThreadComm<P>,
     have_data_provider: bool,
+    worker_count: u32,
+
+    /// Set by `CancelHandle::cancel()`. The predictor thread shares this flag.
+    cancelled: Arc<AtomicBool>,
+    /// True once the data provider has been, or is certain to be, told that decoding was
+    /// cancelled, after which every attempt to continue decoding fails.
+    data_provider_cancelled: bool,
 }
 
 impl ImageLoader {
     /// Creates a new image loader ready to decode a PNG image.
+    ///
+    /// Prediction and color conversion happen on a single background thread. To spread prediction
+    /// across more threads, use `ImageLoader::with_worker_count()`.
     pub fn new() -> ImageLoader {
+        ImageLoader::with_worker_count(1)
+    }
+
+    /// Creates a new image loader ready to decode a PNG image, using `worker_count` threads for
+    /// prediction.
+    ///
+    /// When `worker_count` is greater than 1, each scanline is split into columns that the worker
+    /// threads predict in parallel. The background thread is one of the workers, and it also hands
+    /// the results to the data provider. Images narrower than a few hundred pixels may not use
+    /// every worker. A `worker_count` of 1 is equivalent to `ImageLoader::new()`.
+    ///
+    /// In addition, if the encoder split the compressed image data into independently compressed
+    /// segments, either as described by an `iDOT` chunk or with full flushes found in the data
+    /// passed to `ImageLoader::add_data_from_slice()`, each segment is decompressed on a thread of
+    /// its own as soon as it has arrived, up to `worker_count` segments in all. Otherwise the
+    /// image data is decompressed on the calling thread as it arrives, as usual.
+    ///
+    /// Panics if `worker_count` is zero.
+    pub fn with_worker_count(worker_count: u32) -> ImageLoader {
+        assert!(worker_count > 0, "An image loader needs at least one worker thread!");
+        ImageLoader::with_predictor_thread_comm(MainThreadToPredictorThreadComm::new(worker_count),
+                                                worker_count)
+    }
+
+    /// Creates a new image loader ready to decode a PNG image, performing prediction and color
+    /// conversion on the calling thread instead of a background thread.
+    ///
+    /// No threads are spawned. The data provider is called synchronously from within
+    /// `ImageLoader::add_data()`, so each scanline has been fully decoded by the time that method
+    /// returns, and `ImageLoader::wait_until_finished()` never blocks. To use a data provider that
+    /// isn't `Send`, create a `LocalImageLoader` with `ImageLoader::new_local()` instead.
+    pub fn new_inline() -> ImageLoader {
+        ImageLoader::with_predictor_thread_comm(MainThreadToPredictorThreadComm::inline(), 1)
+    }
+
+    /// Creates a new image loader ready to decode a PNG image, using one of the threads in `pool`
+    /// for prediction and color conversion instead of spawning a new thread.
+    ///
+    /// Each image loader created this way is assigned to one of the pool's threads for its entire
+    /// lifetime, and the pool's threads are shared among all such image loaders.
+    pub fn with_pool(pool: &DecoderPool) -> ImageLoader {
+        let session_id = pool.next_session_id.fetch_add(1, Ordering::Relaxed);
+        let sender = pool.senders[session_id % pool.senders.len()].clone();
+        ImageLoader::with_predictor_thread_comm(
+            MainThreadToPredictorThreadComm::with_pooled_thread(sender, session_id),
+            1)
+    }
+}
+
+impl LocalImageLoader {
+    /// Creates a new image loader ready to decode a PNG image, like `ImageLoader::new_inline()`,
+    /// whose data provider need not be `Send`.
+    ///
+    /// Everything happens on the calling thread, so the image loader itself isn't `Send` either.
+    pub fn new_local() -> LocalImageLoader {
+        ImageLoader::with_predictor_thread_comm(MainThreadToPredictorThreadComm::inline(), 1)
+    }
+}
+
+impl<P> ImageLoader<P> where P: ?Sized + DataProvider {
+    fn with_predictor_thread_comm(mut predictor_thread_comm: MainThreadToPredictorThreadComm<P>,
+                                  worker_count: u32)
+                                  -> ImageLoader<P> {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        predictor_thread_comm.send(MainThreadToPredictorThreadMsg::SetCancelledFlag(
+                cancelled.clone()));
         ImageLoader {
-            entropy_decoder: Decompress::new(true),
+            entropy_decoder: new_entropy_decoder(),
             metadata: None,
             compressed_data_buffer: vec![],
             compressed_data_consumed: 0,
+            slice_position: 0,
             palette: vec![],
             transparency: Transparency::None,
             scanline_data_buffer: vec![],
             scanline_data_buffer_size: 0,
             scanline_data_buffer_info: vec![],
             cached_scanline_data_buffers: vec![],
+            current_scanline_offset: 0,
+            compressed_image_data: vec![],
+            compressed_image_data_length: 0,
+            split_points: VecDeque::new(),
+            idot_restart_position: None,
+            parallel_inflater: None,
+            region_of_interest: None,
+            last_level_of_detail: None,
             current_y: 0,
             current_lod: LevelOfDetail::None,
-            scanlines_decoded_in_this_lod: 0,
-            last_decoded_lod: LevelOfDetail::None,
-            rgba_conversion_complete: false,
+            rgba_conversion_info_sent: false,
+            scanlines_decompressed: 0,
+            prediction_request_ends: VecDeque::new(),
+            prediction_end: None,
+            bytes_read: 0,
+            progress_callback: None,
             decode_state: DecodeState::Start,
-            predictor_thread_comm: MainThreadToPredictorThreadComm::new(),
+            predictor_thread_comm: predictor_thread_comm,
             have_data_provider: false,
+            worker_count: worker_count,
+            cancelled: cancelled,
+            data_provider_cancelled: false,
         }
     }
 
@@ -86,13 +229,143 @@ impl ImageLoader {
     /// this method, or this function will fail with a `PngError::NoDataProvider` error.
     ///
     /// Returns a `LoadProgress` value that describes the progress of loading the image.
-    #[inline(never)]
     pub fn add_data<R>(&mut self, reader: &mut R) -> Result<LoadProgress,PngError>
                        where R: Read + Seek {
+        self.decode(reader, None, true, None)
+    }
+
+    /// Decodes image data from the given stream, like `ImageLoader::add_data()`, but stops once
+    /// `budget` has been used up and never blocks waiting for the predictor thread.
+    ///
+    /// When the budget runs out, this method returns `LoadProgress::Yielded`; call it again to
+    /// pick up where it left off. The budget is checked between batches of scanlines, so it may be
+    /// overrun by up to one batch, and at least one batch is decoded each time. If the predictor
+    /// thread falls behind, this method returns `LoadProgress::WaitingForPrediction`, as
+    /// `ImageLoader::try_add_data_from_slice()` does. Once it returns `LoadProgress::Finished`, use
+    /// `ImageLoader::try_wait_until_finished()` to find out when the image is fully decoded.
+    ///
+    /// This is meant for decoding on a UI thread in slices. Reads from `reader` may still block,
+    /// and image loaders with more than one worker thread decompress all the image data at once
+    /// when it has arrived, regardless of the budget.
+    pub fn add_data_with_budget<R>(&mut self, reader: &mut R, budget: Budget)
+                                   -> Result<LoadProgress,PngError>
+                                   where R: Read + Seek {
+        self.decode(reader, None, false, Some(budget))
+    }
+
+    /// Decodes image data from a PNG image stream that is held in memory, such as a file that has
+    /// been read or memory-mapped in its entirety.
+    ///
+    /// `data` must begin at the start of the image stream. If the stream is arriving gradually,
+    /// call this method again with a longer prefix of the same stream each time more of it is
+    /// available; decoding picks up where it left off. Image data is decompressed directly out of
+    /// `data` rather than being copied into an internal buffer first.
+    ///
+    /// Don't mix calls to this method with calls to `ImageLoader::add_data()` for the same image.
+    /// Otherwise, this method behaves like that one.
+    pub fn add_data_from_slice(&mut self, data: &[u8]) -> Result<LoadProgress,PngError> {
+        self.decode_slice(data, true, None)
+    }
+
+    /// Decodes image data from a PNG image stream that is held in memory, like
+    /// `ImageLoader::add_data_from_slice()`, but never blocks waiting for the predictor thread.
+    ///
+    /// If the predictor thread falls behind, this method returns
+    /// `LoadProgress::WaitingForPrediction` instead of waiting for it to catch up. Call it again
+    /// with the same data once it has; `ImageLoader::set_wake_up_callback()` can be used to find
+    /// out when that might be. Once this method returns `LoadProgress::Finished`, use
+    /// `ImageLoader::try_wait_until_finished()` rather than `ImageLoader::wait_until_finished()`.
+    ///
+    /// This is meant for event loops, which mustn't block. Note that image loaders created with
+    /// `ImageLoader::new_inline()` perform prediction within this method, and image loaders with
+    /// more than one worker thread decompress all the image data in parallel within it once it
+    /// has arrived.
+    pub fn try_add_data_from_slice(&mut self, data: &[u8]) -> Result<LoadProgress,PngError> {
+        self.decode_slice(data, false, None)
+    }
+
+    /// Decodes image data from a PNG image stream that is held in memory, like
+    /// `ImageLoader::try_add_data_from_slice()`, but stops once `budget` has been used up. See
+    /// `ImageLoader::add_data_with_budget()`.
+    pub fn add_data_from_slice_with_budget(&mut self, data: &[u8], budget: Budget)
+                                           -> Result<LoadProgress,PngError> {
+        self.decode_slice(data, false, Some(budget))
+    }
+
+    fn decode_slice(&mut self, data: &[u8], blocking: bool, budget: Option<Budget>)
+                    -> Result<LoadProgress,PngError> {
+        let mut cursor = Cursor::new(data);
+        cursor.set_position(self.slice_position);
+        let result = self.decode(&mut cursor, Some(data), blocking, budget);
+        self.slice_position = cursor.position();
+        result
+    }
+
+    /// Decodes image data from `reader`. If `in_memory` is present, it is the entire stream that
+    /// `reader` reads from, and image data is decompressed from it in place. If `blocking` is
+    /// false, returns `LoadProgress::WaitingForPrediction` rather than waiting for the predictor
+    /// thread. If `budget` is present, returns `LoadProgress::Yielded` once it has been used up.
+    fn decode<R>(&mut self,
+                 reader: &mut R,
+                 in_memory: Option<&[u8]>,
+                 blocking: bool,
+                 budget: Option<Budget>)
+                 -> Result<LoadProgress,PngError>
+                 where R: Read + Seek {
+        let result = self.decode_data(reader, in_memory, blocking, budget);
+        if let Ok(position) = reader.seek(SeekFrom::Current(0)) {
+            self.bytes_read = position
+        }
+        result
+    }
+
+    #[inline(never)]
+    fn decode_data<R>(&mut self,
+                      reader: &mut R,
+                      in_memory: Option<&[u8]>,
+                      blocking: bool,
+                      budget: Option<Budget>)
+                      -> Result<LoadProgress,PngError>
+                      where R: Read + Seek {
+        let budget = match budget {
+            None => None,
+            Some(budget) => {
+                Some(BudgetTracker {
+                    budget: budget,
+                    start_position: try!(reader.seek(SeekFrom::Current(0))
+                                               .map_err(PngError::Io)),
+                    start_scanline_count: self.scanlines_decompressed,
+                    start_time: Instant::now(),
+                })
+            }
+        };
+        let mut first_iteration = true;
         loop {
             while let Ok(msg) = self.predictor_thread_comm.receiver.try_recv() {
                 try!(self.handle_predictor_thread_msg(msg));
             }
+            try!(self.check_for_cancellation());
+
+            let position = try!(reader.seek(SeekFrom::Current(0)).map_err(PngError::Io));
+            self.bytes_read = position;
+
+            // If only part of the stream has arrived, don't start reading a header that it cuts off
+            // partway through.
+            if let Some(data) = in_memory {
+                if (data.len() as u64).saturating_sub(position) <
+                        self.decode_state.bytes_needed_to_proceed() as u64 {
+                    return Ok(LoadProgress::NeedMoreData)
+                }
+            }
+
+            if let Some(ref budget) = budget {
+                if !first_iteration && self.decode_state != DecodeState::Finished {
+                    if budget.is_used_up(position, self.scanlines_decompressed) {
+                        return Ok(LoadProgress::Yielded)
+                    }
+                }
+            }
+            first_iteration = false;
 
             match self.decode_state {
                 DecodeState::Start => {
@@ -127,6 +400,8 @@ impl ImageLoader {
                     };
                     if &chunk_header.chunk_type == b"PLTE" {
                         self.decode_state = DecodeState::ReadingPalette(chunk_header.length);
+                    } else if &chunk_header.chunk_type == b"iDOT" {
+                        self.decode_state = DecodeState::ReadingIdot(chunk_header.length);
                     } else {
                         // Skip over this chunk, adding 4 to move past the CRC.
                         try!(reader.seek(SeekFrom::Current((chunk_header.length as i64) + 4))
@@ -139,20 +414,34 @@ impl ImageLoader {
                         Ok(chunk_header) => chunk_header,
                     };
 
+                    // If we've reached the end of image data that we've been gathering up,
+                    // decode it all now.
+                    if &chunk_header.chunk_type != b"IDAT" && self.parallel_inflater.is_some() {
+                        try!(self.decode_compressed_image_data())
+                    }
+
                     if &chunk_header.chunk_type == b"IDAT" {
-                        self.decode_state = DecodeState::DecodingData(chunk_header.length);
-                    } else if &chunk_header.chunk_type == b"IEND" {
-                        if self.metadata
-                               .as_ref()
-                               .expect("No metadata before `IEND`?!")
-                               .color_depth != 32 {
-                            self.send_scanlines_to_predictor_thread_to_convert_to_rgba()
+                        let chunk_position =
+                            try!(reader.seek(SeekFrom::Current(0)).map_err(PngError::Io)) - 8;
+                        if !self.rgba_conversion_info_sent {
+                            self.send_rgba_conversion_info_to_predictor_thread();
+                            if self.worker_count > 1 {
+                                self.plan_parallel_decompression(in_memory, chunk_position)
+                            }
                         }
-
-                        self.predictor_thread_comm
-                            .sender
-                            .send(MainThreadToPredictorThreadMsg::Finished)
-                            .unwrap();
+                        self.decode_state = if self.parallel_inflater.is_some() {
+                            // Start a new segment where the `iDOT` chunk told us that one begins.
+                            if self.idot_restart_position == Some(chunk_position) {
+                                self.finish_compressed_segment(false)
+                            }
+                            DecodeState::GatheringData(chunk_header.length)
+                        } else {
+                            DecodeState::DecodingData(chunk_header.length)
+                        };
+                    } else if &chunk_header.chunk_type == b"iDOT" {
+                        self.decode_state = DecodeState::ReadingIdot(chunk_header.length)
+                    } else if &chunk_header.chunk_type == b"IEND" {
+                        self.predictor_thread_comm.send(MainThreadToPredictorThreadMsg::Finished);
 
                         self.decode_state = DecodeState::Finished
                     } else if &chunk_header.chunk_type == b"tRNS" {
@@ -187,23 +476,56 @@ impl ImageLoader {
                         return Err(PngError::NoDataProvider)
                     }
 
-                    let (width, color_depth) = {
-                        let metadata = self.metadata.as_ref().expect("No metadata?!");
-                        (metadata.dimensions.width, metadata.color_depth)
-                    };
-                    let bytes_per_pixel = (color_depth / 8) as u32;
-                    let stride =
-                        InterlacingInfo::new(0,
-                                             color_depth,
-                                             self.current_lod).scanline_stride(width, color_depth);
+                    // Stop early if the rest of the image data isn't wanted.
+                    if self.finished_region_of_interest() ||
+                            (self.last_level_of_detail.is_some() &&
+                             self.finished_entropy_decoding()) {
+                        self.predictor_thread_comm.send(MainThreadToPredictorThreadMsg::Finished);
+                        self.decode_state = DecodeState::Finished;
+                        continue
+                    }
 
                     // Wait for the predictor thread to catch up if necessary.
                     let scanlines_to_buffer = self.scanlines_to_buffer();
                     while self.scanline_data_buffer_info.len() >= scanlines_to_buffer as usize {
-                        let msg = self.predictor_thread_comm.receiver.recv().unwrap();
+                        let msg = if blocking {
+                            self.predictor_thread_comm.receiver.recv().unwrap()
+                        } else {
+                            match self.predictor_thread_comm.receiver.try_recv() {
+                                Ok(msg) => msg,
+                                Err(_) => return Ok(LoadProgress::WaitingForPrediction),
+                            }
+                        };
                         try!(self.handle_predictor_thread_msg(msg));
                     }
 
+                    // If the whole stream is in memory, decompress straight out of it.
+                    if let Some(data) = in_memory {
+                        let position =
+                            try!(reader.seek(SeekFrom::Current(0)).map_err(PngError::Io));
+                        let start_in = cmp::min(position, data.len() as u64) as usize;
+                        let end_in = cmp::min(start_in + bytes_left_in_chunk as usize,
+                                              data.len());
+                        if start_in == end_in && bytes_left_in_chunk > 0 {
+                            return Ok(LoadProgress::NeedMoreData)
+                        }
+
+                        let bytes_consumed =
+                            try!(self.decompress_scanlines(&data[start_in..end_in]));
+                        try!(reader.seek(SeekFrom::Current(bytes_consumed as i64))
+                                   .map_err(PngError::Io));
+
+                        let bytes_left_in_chunk = bytes_left_in_chunk - bytes_consumed as u32;
+                        self.decode_state = if bytes_left_in_chunk == 0 {
+                            // Skip over the CRC.
+                            try!(reader.seek(SeekFrom::Current(4)).map_err(PngError::Io));
+                            DecodeState::LookingForImageData
+                        } else {
+                            DecodeState::DecodingData(bytes_left_in_chunk)
+                        };
+                        continue
+                    }
+
                     let bytes_read;
                     if self.compressed_data_buffer.len() < BUFFER_SIZE {
                         let original_length = self.compressed_data_buffer.len();
@@ -213,8 +535,8 @@ impl ImageLoader {
                         bytes_read =
                             try!(reader.read(&mut self.compressed_data_buffer[original_length..])
                                        .map_err(PngError::Io));
-                        debug_assert!(self.compressed_data_buffer.len() <= original_length +
-                                      bytes_read);
+                        debug_assert!(original_length + bytes_read <=
+                                      self.compressed_data_buffer.len());
                         self.compressed_data_buffer.truncate(original_length + bytes_read);
                     } else {
                         bytes_read = 0
@@ -223,85 +545,24 @@ impl ImageLoader {
                     let avail_in = self.compressed_data_buffer.len() -
                         self.compressed_data_consumed;
 
-                    // Read the scanline data.
-                    //
-                    // TODO(pcwalton): This may well show up in profiles. Probably we are going
-                    // to want to read multiple scanlines at once. Before we do this, though,
-                    // we are going to have to deal with SSE alignment restrictions.
                     if avail_in == 0 {
                         return Ok(LoadProgress::NeedMoreData)
                     }
 
-                    // Make room for the stride + 32 bytes, which should be enough to
-                    // handle any amount of padding on both ends.
-                    unsafe {
-                        self.scanline_data_buffer
-                            .extend_with_uninitialized(1 + (stride as usize) + 32);
-                    }
-
-                    let offset = aligned_scanline_buffer_offset(&self.scanline_data_buffer);
-                    let original_size = self.scanline_data_buffer_size;
+                    // Decompress, and advance the compressed data offset. The buffer is moved out
+                    // temporarily so that it can be borrowed while the rest of `self` is mutated.
                     let start_in = self.compressed_data_consumed;
-                    let avail_out = 1 + (stride as usize) - original_size;
-                    let start_out = offset + original_size - 1;
-                    debug_assert!(avail_out as usize + original_size + offset <=
-                                  self.scanline_data_buffer.len());
-                    let before_decompression_in = self.entropy_decoder.total_in();
-                    let before_decompression_out = self.entropy_decoder.total_out();
-                    try!(self.entropy_decoder
-                             .decompress(&self.compressed_data_buffer[start_in..(start_in +
-                                                                                 avail_in)],
-                                         &mut self.scanline_data_buffer[start_out..(start_out +
-                                                                                    avail_out)],
-                                         Flush::None)
-                             .map_err(PngError::from));
-
-                    // Advance the compressed data offset.
-                    self.compressed_data_consumed = start_in +
-                        (self.entropy_decoder.total_in() - before_decompression_in) as usize;
+                    let compressed_data_buffer = mem::replace(&mut self.compressed_data_buffer,
+                                                              vec![]);
+                    let bytes_consumed = try!(self.decompress_scanlines(
+                            &compressed_data_buffer[start_in..(start_in + avail_in)]));
+                    self.compressed_data_buffer = compressed_data_buffer;
+                    self.compressed_data_consumed = start_in + bytes_consumed;
                     if self.compressed_data_consumed == self.compressed_data_buffer.len() {
                         self.compressed_data_consumed = 0;
                         self.compressed_data_buffer.truncate(0)
                     }
 
-                    // Advance the decompressed data offset.
-                    self.scanline_data_buffer_size = original_size +
-                        (self.entropy_decoder.total_out() - before_decompression_out) as usize;
-
-                    // Save the buffer and advance the Y position if necessary.
-                    if self.scanline_data_buffer_size == 1 + stride as usize {
-                        let empty_scanline_data_buffer = self.cached_scanline_data_buffers
-                                                             .pop()
-                                                             .unwrap_or(vec![]);
-                        let scanline_data = mem::replace(&mut self.scanline_data_buffer,
-                                                         empty_scanline_data_buffer);
-                        self.scanline_data_buffer_size = 0;
-
-                        self.scanline_data_buffer_info.push(BufferedScanlineInfo {
-                            data: scanline_data,
-                            lod: self.current_lod,
-                            y: self.current_y,
-                        });
-                        self.current_y += 1;
-                        let height = self.metadata
-                                         .as_ref()
-                                         .expect("No metadata?!")
-                                         .dimensions
-                                         .height;
-                        let height_of_lod = InterlacingInfo::height_of_lod(height,
-                                                                           self.current_lod);
-                        if self.current_y == height_of_lod && !self.finished_entropy_decoding() {
-                            self.current_y = 0;
-                            if let LevelOfDetail::Adam7(ref mut current_lod) = self.current_lod {
-                                if *current_lod < 6 {
-                                    *current_lod += 1
-                                }
-                            }
-                        }
-
-                        try!(self.send_scanlines_to_predictor_thread_to_predict_if_necessary());
-                    }
-
                     let bytes_left_in_chunk_after_read = bytes_left_in_chunk - bytes_read as u32;
                     self.decode_state = if bytes_left_in_chunk_after_read == 0 &&
                             self.compressed_data_consumed >= self.compressed_data_buffer.len() {
@@ -310,6 +571,83 @@ impl ImageLoader {
                         DecodeState::LookingForImageData
                     } else {
                         DecodeState::DecodingData(bytes_left_in_chunk_after_read)
+                    };
+                }
+                DecodeState::GatheringData(bytes_left_in_chunk) => {
+                    if !self.have_data_provider {
+                        return Err(PngError::NoDataProvider)
+                    }
+
+                    // Read a buffer's worth at most, so that a corrupt chunk length can't make us
+                    // allocate much more than has arrived, and stop at the end of the segment.
+                    let mut bytes_to_read = cmp::min(bytes_left_in_chunk as usize, BUFFER_SIZE);
+                    if let Some(&split_point) = self.split_points.front() {
+                        bytes_to_read = cmp::min(bytes_to_read,
+                                                 split_point - self.compressed_image_data_length)
+                    }
+                    let bytes_read = try!(Read::take(&mut *reader, bytes_to_read as u64)
+                                              .read_to_end(&mut self.compressed_image_data)
+                                              .map_err(PngError::Io));
+                    self.compressed_image_data_length += bytes_read;
+                    if self.split_points.front() == Some(&self.compressed_image_data_length) {
+                        self.split_points.pop_front();
+                        self.finish_compressed_segment(false)
+                    }
+
+                    // Decode any segments that have been decompressed in the meantime.
+                    try!(self.decode_inflated_segments(false));
+
+                    let bytes_left_in_chunk = bytes_left_in_chunk - bytes_read as u32;
+                    if bytes_left_in_chunk > 0 {
+                        self.decode_state = DecodeState::GatheringData(bytes_left_in_chunk);
+                        if bytes_read == 0 {
+                            return Ok(LoadProgress::NeedMoreData)
+                        }
+                        continue
+                    }
+
+                    // Skip over the CRC.
+                    try!(reader.seek(SeekFrom::Current(4)).map_err(PngError::Io));
+                    self.decode_state = DecodeState::LookingForImageData
+                }
+                DecodeState::ReadingIdot(bytes_left_in_chunk) => {
+                    let chunk_position =
+                        try!(reader.seek(SeekFrom::Current(0)).map_err(PngError::Io)) - 8;
+
+                    // The last field is the offset from the start of this chunk to the `IDAT`
+                    // chunk that begins the second of the two segments that the image data is
+                    // split into. We don't need anything else.
+                    if bytes_left_in_chunk == IDOT_CHUNK_LENGTH {
+                        let mut buffer = [0; IDOT_CHUNK_LENGTH as usize];
+                        match reader.read_exact(&mut buffer[..]) {
+                            Ok(()) => {
+                                let offset = BigEndian::read_u32(&buffer[24..]);
+                                self.idot_restart_position = Some(chunk_position + offset as u64)
+                            }
+                            Err(io_error) => {
+                                if io_error.kind() != ErrorKind::UnexpectedEof {
+                                    return Err(PngError::Io(io_error))
+                                }
+                                try!(reader.seek(SeekFrom::Start(chunk_position + 8))
+                                           .map_err(PngError::Io));
+                                return Ok(LoadProgress::NeedMoreData)
+                            }
+                        }
+                    } else {
+                        try!(reader.seek(SeekFrom::Current(bytes_left_in_chunk as i64))
+                                   .map_err(PngError::Io));
+                    }
+
+                    // Move past the CRC.
+                    try!(reader.seek(SeekFrom::Current(4)).map_err(PngError::Io));
+
+                    // The `iDOT` chunk may precede the palette.
+                    let indexed = self.metadata.as_ref().expect("No metadata?!").color_type ==
+                        ColorType::Indexed;
+                    self.decode_state = if indexed && self.palette.is_empty() {
+                        DecodeState::LookingForPalette
+                    } else {
+                        DecodeState::LookingForImageData
                     }
                 }
                 DecodeState::ReadingTransparency(mut bytes_left_in_chunk) => {
@@ -319,9 +657,12 @@ impl ImageLoader {
                               .as_ref()
                               .expect("No metadata before transparency info?!")
                               .color_type {
+                        // Magic color samples are always stored as 16-bit values. We only
+                        // support 8-bit samples, so we keep the low byte.
                         ColorType::Grayscale => {
-                            match reader.read_u8() {
+                            match reader.read_u16::<BigEndian>() {
                                 Ok(value) => {
+                                    let value = value as u8;
                                     self.transparency =
                                         Transparency::MagicColor(value, value, value)
                                 }
@@ -336,18 +677,20 @@ impl ImageLoader {
                             }
                         }
                         ColorType::Rgb => {
-                            let mut buffer = [0, 0, 0];
-                            match reader.read(&mut buffer[..]) {
-                                Ok(3) => {
+                            let mut buffer = [0; 6];
+                            match reader.read_exact(&mut buffer[..]) {
+                                Ok(()) => {
                                     self.transparency =
-                                        Transparency::MagicColor(buffer[0], buffer[1], buffer[2])
+                                        Transparency::MagicColor(buffer[1], buffer[3], buffer[5])
                                 }
-                                Ok(_) => {
+                                Err(io_error) => {
+                                    if io_error.kind() != ErrorKind::UnexpectedEof {
+                                        return Err(PngError::Io(io_error))
+                                    }
                                     try!(reader.seek(SeekFrom::Start(initial_pos))
                                                .map_err(PngError::Io));
                                     return Ok(LoadProgress::NeedMoreData)
                                 }
-                                Err(io_error) => return Err(PngError::Io(io_error)),
                             }
                         }
                         ColorType::Indexed => {
@@ -388,6 +731,209 @@ impl ImageLoader {
         }
     }
 
+    /// Decides, at the start of the image data, whether to decompress it in parallel.
+    ///
+    /// That's only possible if it is split into segments that can be decompressed independently:
+    /// if an `iDOT` chunk says so, or, if the stream is in memory, if full flushes turn up when we
+    /// scan the `IDAT` chunks that have arrived. (Full flushes can't be found in image data that
+    /// hasn't arrived yet.) Otherwise the image data is decompressed as it arrives, as usual.
+    fn plan_parallel_decompression(&mut self, in_memory: Option<&[u8]>, chunk_position: u64) {
+        if let Some(data) = in_memory {
+            let (mut candidates, mut length) = (vec![], 0);
+            let mut position = chunk_position as usize;
+            while position + 8 <= data.len() && &data[(position + 4)..(position + 8)] == b"IDAT" {
+                if self.idot_restart_position == Some(position as u64) {
+                    candidates.push(length)
+                }
+                let chunk_length = match (&data[position..]).read_u32::<BigEndian>() {
+                    Ok(chunk_length) => chunk_length as usize,
+                    Err(_) => break,
+                };
+                let start = position + 8;
+                let end = cmp::min(start + chunk_length, data.len());
+                candidates.extend(inflate::find_full_flush_points(&data[start..end])
+                                      .into_iter()
+                                      .map(|offset| length + offset));
+                length += chunk_length;
+                position = start + chunk_length + 4
+            }
+            self.split_points = inflate::choose_split_points(length,
+                                                             candidates,
+                                                             self.worker_count).into_iter()
+                                                                               .collect();
+            self.idot_restart_position = None;
+            if self.split_points.is_empty() {
+                return
+            }
+        } else if self.idot_restart_position.is_none() {
+            return
+        }
+        self.parallel_inflater = Some(ParallelInflater::new())
+    }
+
+    /// Starts decompressing the compressed image data gathered up so far as a segment of its own,
+    /// which is the `last` one if the image data has ended.
+    fn finish_compressed_segment(&mut self, last: bool) {
+        let segment = mem::replace(&mut self.compressed_image_data, vec![]);
+        if segment.is_empty() && !last {
+            return
+        }
+        self.parallel_inflater
+            .as_mut()
+            .expect("Not decompressing in parallel?!")
+            .add_segment(segment, last)
+    }
+
+    /// Starts decompressing the last segment of the image data gathered up from the `IDAT`
+    /// chunks, and sends the scanlines of every segment not yet decoded off for prediction.
+    fn decode_compressed_image_data(&mut self) -> Result<(), PngError> {
+        self.finish_compressed_segment(true);
+        try!(self.decode_inflated_segments(true));
+        self.parallel_inflater = None;
+        Ok(())
+    }
+
+    /// Sends the scanlines of each segment that has been decompressed off for prediction, in
+    /// order. If `wait` is true, waits for every segment added so far to be decompressed first.
+    fn decode_inflated_segments(&mut self, wait: bool) -> Result<(), PngError> {
+        // The inflater is moved out temporarily so that the rest of `self` can be mutated, and put
+        // back whether or not decoding succeeds.
+        let mut parallel_inflater = self.parallel_inflater
+                                        .take()
+                                        .expect("Not decompressing in parallel?!");
+        let result = self.decode_inflated_segments_from(&mut parallel_inflater, wait);
+        self.parallel_inflater = Some(parallel_inflater);
+        result
+    }
+
+    fn decode_inflated_segments_from(&mut self,
+                                     parallel_inflater: &mut ParallelInflater,
+                                     wait: bool)
+                                     -> Result<(), PngError> {
+        loop {
+            let segment = if wait {
+                parallel_inflater.next_segment()
+            } else {
+                parallel_inflater.next_finished_segment()
+            };
+            let segment = match segment {
+                None => return Ok(()),
+                Some(segment) => try!(segment),
+            };
+            let mut segment_consumed = 0;
+            while segment_consumed < segment.len() {
+                try!(self.check_for_cancellation());
+                let (start_out, end_out) = self.prepare_to_decode_scanlines();
+                let length = cmp::min(end_out - start_out, segment.len() - segment_consumed);
+                self.scanline_data_buffer[start_out..(start_out + length)]
+                    .clone_from_slice(&segment[segment_consumed..(segment_consumed + length)]);
+                segment_consumed += length;
+                try!(self.scanlines_decoded(start_out + length));
+            }
+        }
+    }
+
+    /// Decompresses as many scanlines from `input` as there is room for in the block, and returns
+    /// the number of bytes of `input` consumed.
+    fn decompress_scanlines(&mut self, input: &[u8]) -> Result<usize, PngError> {
+        let (start_out, end_out) = self.prepare_to_decode_scanlines();
+        let before_decompression_in = self.entropy_decoder.total_in();
+        let before_decompression_out = self.entropy_decoder.total_out();
+        try!(entropy_decode(&mut self.entropy_decoder,
+                            input,
+                            &mut self.scanline_data_buffer[start_out..end_out]));
+
+        // Advance the decompressed data offset.
+        let size = start_out +
+            (self.entropy_decoder.total_out() - before_decompression_out) as usize;
+        try!(self.scanlines_decoded(size));
+        Ok((self.entropy_decoder.total_in() - before_decompression_in) as usize)
+    }
+
+    /// Makes sure the block has room for more scanlines, and returns the range of it that the next
+    /// decompressed image data should be written to.
+    fn prepare_to_decode_scanlines(&mut self) -> (usize, usize) {
+        let (width, height, color_depth) = {
+            let metadata = self.metadata.as_ref().expect("No metadata?!");
+            (metadata.dimensions.width, metadata.dimensions.height, metadata.color_depth)
+        };
+        let bytes_per_pixel = (color_depth / 8) as u32;
+        let stride =
+            InterlacingInfo::new(0, color_depth, self.current_lod).scanline_stride(width,
+                                                                                   color_depth);
+        let scanlines_to_buffer = self.scanlines_to_buffer();
+
+        // Make sure the block is big enough to hold a full batch of scanlines of the widest level
+        // of detail, plus 32 bytes of padding, which should be enough to handle the accelerated
+        // prediction routines reading past the end.
+        unsafe {
+            let block_size = scanlines_to_buffer as usize *
+                (1 + (width * bytes_per_pixel) as usize) + 32;
+            self.scanline_data_buffer.extend_with_uninitialized(block_size);
+        }
+
+        // Decode as many scanlines as will fit in the rest of the block. Stop at the end of the
+        // level of detail, since the stride changes there. Once the image is complete, keep
+        // decoding into a single scanline's worth of space so that the rest of the data (such as
+        // the checksum) is consumed.
+        let scanlines_left_in_block =
+            scanlines_to_buffer - self.scanline_data_buffer_info.len() as u32;
+        let scanlines_left_in_lod = InterlacingInfo::height_of_lod(height, self.current_lod)
+                                        .saturating_sub(self.current_y);
+        let scanlines_to_decode =
+            cmp::max(cmp::min(scanlines_left_in_block, scanlines_left_in_lod), 1);
+        let start_out = self.scanline_data_buffer_size;
+        let end_out =
+            self.current_scanline_offset + (1 + stride as usize) * scanlines_to_decode as usize;
+        debug_assert!(end_out <= self.scanline_data_buffer.len());
+        (start_out, end_out)
+    }
+
+    /// Records the scanlines that are complete now that the first `size` bytes of the block have
+    /// been decompressed, and sends them off for prediction if enough have built up.
+    fn scanlines_decoded(&mut self, size: usize) -> Result<(), PngError> {
+        let (width, height, color_depth) = {
+            let metadata = self.metadata.as_ref().expect("No metadata?!");
+            (metadata.dimensions.width, metadata.dimensions.height, metadata.color_depth)
+        };
+        let stride =
+            InterlacingInfo::new(0, color_depth, self.current_lod).scanline_stride(width,
+                                                                                   color_depth);
+        let scanline_size = 1 + stride as usize;
+        self.scanline_data_buffer_size = size;
+
+        // Record each scanline that is now complete, advancing the Y position.
+        let mut scanlines_decoded = false;
+        while !self.finished_entropy_decoding() &&
+                self.scanline_data_buffer_size >= self.current_scanline_offset + scanline_size {
+            self.scanline_data_buffer_info.push(BufferedScanlineInfo {
+                offset: self.current_scanline_offset,
+                lod: self.current_lod,
+                y: self.current_y,
+            });
+            self.current_scanline_offset += scanline_size;
+            self.current_y += 1;
+            self.scanlines_decompressed += 1;
+            if self.current_y == InterlacingInfo::height_of_lod(height, self.current_lod) {
+                if let Some(next_lod) = self.next_level_of_detail() {
+                    self.current_y = 0;
+                    self.current_lod = next_lod
+                }
+            }
+            scanlines_decoded = true
+        }
+
+        // Ignore any data past the end of the image.
+        if self.finished_entropy_decoding() {
+            self.scanline_data_buffer_size = self.current_scanline_offset
+        }
+
+        if scanlines_decoded {
+            try!(self.send_scanlines_to_predictor_thread_to_predict_if_necessary());
+        }
+        Ok(())
+    }
+
     #[inline(never)]
     fn send_scanlines_to_predictor_thread_to_predict_if_necessary(&mut self)
                                                                   -> Result<(), PngError> {
@@ -399,84 +945,86 @@ impl ImageLoader {
         let buffered_scanline_count = self.scanline_data_buffer_info.len() as u32;
         if buffered_scanline_count >= self.scanlines_to_buffer() ||
                 self.finished_entropy_decoding() {
-            let mut request = PredictionRequest {
-                width: dimensions.width,
-                height: dimensions.height,
-                color_depth: color_depth,
-                indexed_color: color_type == ColorType::Indexed,
-                scanlines: Vec::with_capacity(buffered_scanline_count as usize),
+            let request_end = {
+                let last_scanline_info = self.scanline_data_buffer_info
+                                             .last()
+                                             .expect("No scanlines to predict?!");
+                (last_scanline_info.lod, last_scanline_info.y + 1)
             };
+
+            let mut scanlines = Vec::with_capacity(buffered_scanline_count as usize);
             for scanline_info in self.scanline_data_buffer_info.drain(..) {
-                let scanline_buffer_offset = aligned_scanline_buffer_offset(&scanline_info.data);
-                let predictor = scanline_info.data[scanline_buffer_offset - 1];
-                request.scanlines.push(ScanlineToPredict {
+                let predictor = self.scanline_data_buffer[scanline_info.offset];
+                scanlines.push(ScanlineToPredict {
                     predictor: try!(Predictor::from_byte(predictor)),
-                    data: scanline_info.data,
-                    offset: scanline_buffer_offset,
+                    offset: scanline_info.offset + 1,
                     lod: scanline_info.lod,
                     y: scanline_info.y,
                 });
             }
 
-            self.predictor_thread_comm
-                .sender
-                .send(MainThreadToPredictorThreadMsg::Predict(request))
-                .unwrap();
+            let empty_scanline_data_buffer = self.cached_scanline_data_buffers
+                                                 .pop()
+                                                 .unwrap_or(vec![]);
+            let request = PredictionRequest {
+                width: dimensions.width,
+                height: dimensions.height,
+                color_depth: color_depth,
+                indexed_color: color_type == ColorType::Indexed,
+                region_of_interest: self.region_of_interest,
+                data: mem::replace(&mut self.scanline_data_buffer, empty_scanline_data_buffer),
+                scanlines: scanlines,
+            };
+            self.scanline_data_buffer_size = 0;
+            self.current_scanline_offset = 0;
+
+            self.prediction_request_ends.push_back(request_end);
+            self.predictor_thread_comm.send(MainThreadToPredictorThreadMsg::Predict(request));
             self.predictor_thread_comm.scanlines_in_progress += buffered_scanline_count;
+            self.report_progress();
         }
 
         Ok(())
     }
 
-    #[inline(never)]
-    fn send_scanlines_to_predictor_thread_to_convert_to_rgba(&mut self) {
+    fn send_rgba_conversion_info_to_predictor_thread(&mut self) {
         let transparency = mem::replace(&mut self.transparency, Transparency::None);
-        let (dimensions, color_depth, interlaced, indexed) = {
-            let metadata = self.metadata.as_ref().expect("No metadata?!");
-            (metadata.dimensions,
-             metadata.color_depth,
-             metadata.interlace_method != InterlaceMethod::Disabled,
-             metadata.color_type == ColorType::Indexed)
-        };
+        let indexed = self.metadata.as_ref().expect("No metadata?!").color_type ==
+            ColorType::Indexed;
         let rgb_palette = if indexed {
             Some(mem::replace(&mut self.palette, vec![]))
         } else {
             None
         };
         self.predictor_thread_comm
-            .sender
-            .send(MainThreadToPredictorThreadMsg::PerformRgbaConversion(
-                PerformRgbaConversionRequest {
-                    rgb_palette: rgb_palette,
-                    transparency: transparency,
-                    width: dimensions.width,
-                    height: dimensions.height,
-                    color_depth: color_depth,
-                    interlaced: interlaced,
-                })).unwrap();
+            .send(MainThreadToPredictorThreadMsg::SetRgbaConversionInfo(RgbaConversionInfo {
+                rgb_palette: rgb_palette,
+                transparency: transparency,
+            }));
+        self.rgba_conversion_info_sent = true
     }
 
     fn handle_predictor_thread_msg(&mut self, msg: PredictorThreadToMainThreadMsg)
                                    -> Result<(),PngError> {
         match msg {
             PredictorThreadToMainThreadMsg::NoDataProviderError => Err(PngError::NoDataProvider),
-            PredictorThreadToMainThreadMsg::ScanlinePredictionComplete(y, lod, mut buffer) => {
-                buffer.clear();
+            PredictorThreadToMainThreadMsg::PredictionComplete(buffer, scanline_count) => {
                 self.cached_scanline_data_buffers.push(buffer);
-                if lod > self.last_decoded_lod {
-                    self.last_decoded_lod = lod;
-                    self.scanlines_decoded_in_this_lod = 0;
-                }
-                if y >= self.scanlines_decoded_in_this_lod {
-                    debug_assert!(self.last_decoded_lod == lod);
-                    self.scanlines_decoded_in_this_lod = y + 1
-                }
+                self.predictor_thread_comm.scanlines_in_progress -= scanline_count;
+                self.prediction_end = self.prediction_request_ends.pop_front();
+                self.report_progress();
                 Ok(())
             }
-            PredictorThreadToMainThreadMsg::RgbaConversionComplete => {
-                self.rgba_conversion_complete = true;
+            PredictorThreadToMainThreadMsg::PredictionCancelled(buffer, scanline_count) => {
+                self.cached_scanline_data_buffers.push(buffer);
+                self.predictor_thread_comm.scanlines_in_progress -= scanline_count;
+                self.prediction_request_ends.pop_front();
+                self.data_provider_cancelled = true;
                 Ok(())
             }
+            PredictorThreadToMainThreadMsg::ResetComplete => {
+                panic!("Reset complete without a reset?!")
+            }
         }
     }
 
@@ -486,32 +1034,253 @@ impl ImageLoader {
     /// the image may not be fully decoded even when `ImageLoader::add_data()` returns
     /// `LoadProgress::Finished`. Most applications will therefore want to call this function after
     /// receiving that result.
+    ///
+    /// For inline image loaders, created with `ImageLoader::new_inline()` or
+    /// `ImageLoader::new_local()`, this function returns immediately.
     #[inline(never)]
     pub fn wait_until_finished(&mut self) -> Result<(),PngError> {
-        while !self.finished_decoding_altogether() {
+        loop {
+            try!(self.check_for_cancellation());
+            if self.finished_decoding_altogether() {
+                return Ok(())
+            }
             let msg = self.predictor_thread_comm
                           .receiver
                           .recv()
                           .expect("Predictor thread hung up!");
             try!(self.handle_predictor_thread_msg(msg));
         }
-        Ok(())
+    }
+
+    /// Returns true if the image is fully decoded, without blocking.
+    ///
+    /// This is the counterpart of `ImageLoader::wait_until_finished()` for callers of
+    /// `ImageLoader::try_add_data_from_slice()`. If it returns false, call it again later; the
+    /// callback set with `ImageLoader::set_wake_up_callback()` is a good time to.
+    pub fn try_wait_until_finished(&mut self) -> Result<bool,PngError> {
+        while let Ok(msg) = self.predictor_thread_comm.receiver.try_recv() {
+            try!(self.handle_predictor_thread_msg(msg));
+        }
+        try!(self.check_for_cancellation());
+        Ok(self.finished_decoding_altogether())
+    }
+
+    /// Returns this image loader to the state it was in when it was created, ready to decode
+    /// another image, while keeping its predictor thread, decompressor and buffers.
+    ///
+    /// If the current image hasn't been fully decoded, decoding is cancelled first, as though by
+    /// `CancelHandle::cancel()`, and this method waits for the predictor thread to stop working on
+    /// it. The data provider is dropped, and any region of interest or last level of detail is
+    /// cleared, so they must be set again for the next image. The wake-up and progress callbacks
+    /// are kept. Cancel handles taken before the reset have no effect on the next image.
+    pub fn reset(&mut self) {
+        // Have any scanlines still waiting to be predicted returned as they are, then wait for the
+        // predictor thread to catch up.
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.predictor_thread_comm.send(MainThreadToPredictorThreadMsg::Reset);
+        loop {
+            match self.predictor_thread_comm.receiver.recv() {
+                Ok(PredictorThreadToMainThreadMsg::ResetComplete) | Err(_) => break,
+                Ok(msg) => drop(self.handle_predictor_thread_msg(msg)),
+            }
+        }
+        self.predictor_thread_comm.scanlines_in_progress = 0;
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.predictor_thread_comm.send(MainThreadToPredictorThreadMsg::SetCancelledFlag(
+                cancelled.clone()));
+        self.cancelled = cancelled;
+        self.data_provider_cancelled = false;
+
+        reset_entropy_decoder(&mut self.entropy_decoder);
+        self.metadata = None;
+        self.compressed_data_buffer.clear();
+        self.compressed_data_consumed = 0;
+        self.slice_position = 0;
+        self.palette.clear();
+        self.transparency = Transparency::None;
+        self.scanline_data_buffer_size = 0;
+        self.scanline_data_buffer_info.clear();
+        self.current_scanline_offset = 0;
+        self.compressed_image_data.clear();
+        self.compressed_image_data_length = 0;
+        self.split_points.clear();
+        self.parallel_inflater = None;
+        self.idot_restart_position = None;
+        self.region_of_interest = None;
+        self.last_level_of_detail = None;
+        self.current_y = 0;
+        self.current_lod = LevelOfDetail::None;
+        self.rgba_conversion_info_sent = false;
+        self.scanlines_decompressed = 0;
+        self.prediction_request_ends.clear();
+        self.prediction_end = None;
+        self.bytes_read = 0;
+        self.decode_state = DecodeState::Start;
+        self.have_data_provider = false;
+    }
+
+    /// Returns a handle that can be used to cancel decoding from any thread.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle {
+            cancelled: self.cancelled.clone(),
+        }
+    }
+
+    /// Returns a `PngError::Cancelled` error if decoding has been cancelled, first telling the
+    /// predictor thread so if need be.
+    ///
+    /// Once all the image data has been sent off, whether decoding was cancelled is up to the
+    /// predictor thread: if it has already predicted every scanline, the data provider is told
+    /// that the image is finished, even if cancellation was requested.
+    fn check_for_cancellation(&mut self) -> Result<(),PngError> {
+        if !self.data_provider_cancelled && self.decode_state != DecodeState::Finished &&
+                self.cancelled.load(Ordering::SeqCst) {
+            self.predictor_thread_comm.send(MainThreadToPredictorThreadMsg::Cancel);
+            self.data_provider_cancelled = true
+        }
+        if self.data_provider_cancelled {
+            Err(PngError::Cancelled)
+        } else {
+            Ok(())
+        }
     }
 
     fn finished_entropy_decoding(&self) -> bool {
         let height = self.metadata.as_ref().expect("No metadata yet!").dimensions.height;
-        (self.current_lod == LevelOfDetail::None || self.current_lod == LevelOfDetail::Adam7(6)) &&
-            self.current_y >= height
+        (self.current_y >= InterlacingInfo::height_of_lod(height, self.current_lod) &&
+            self.next_level_of_detail().is_none()) || self.finished_region_of_interest()
+    }
+
+    /// Returns true if every scanline that intersects the region of interest has been decoded.
+    /// Scanlines of the last level of detail that lie below the region are the only ones that
+    /// nothing depends on, so this can only happen once that level of detail has been reached.
+    fn finished_region_of_interest(&self) -> bool {
+        match self.region_of_interest {
+            None => false,
+            Some(ref region) => {
+                self.next_level_of_detail().is_none() &&
+                    InterlacingInfo::new(self.current_y, 8, self.current_lod).y >=
+                    region.y + region.height
+            }
+        }
     }
 
     fn finished_decoding_altogether(&self) -> bool {
-        let (height, indexed) = {
-            let metadata = self.metadata.as_ref().expect("No metadata yet!");
-            (metadata.dimensions.height, metadata.color_type == ColorType::Indexed)
+        self.finished_entropy_decoding() && self.predictor_thread_comm.scanlines_in_progress == 0
+    }
+
+    /// Returns the level of detail that follows the current one, skipping over any that contain
+    /// no pixels. (Small interlaced images may have empty Adam7 passes, which are not present in
+    /// the image data at all.) Returns `None` if the current level of detail is the last one, or
+    /// the last one to be decoded.
+    fn next_level_of_detail(&self) -> Option<LevelOfDetail> {
+        let dimensions = self.metadata.as_ref().expect("No metadata yet!").dimensions;
+        let mut lod = self.current_lod;
+        while let LevelOfDetail::Adam7(pass) = lod {
+            if pass >= 6 || self.last_level_of_detail.map_or(false, |last_lod| lod >= last_lod) {
+                break
+            }
+            lod = LevelOfDetail::Adam7(pass + 1);
+            if InterlacingInfo::height_of_lod(dimensions.height, lod) > 0 &&
+                    InterlacingInfo::new(0, 32, lod).scanline_width(dimensions.width, 32) > 0 {
+                return Some(lod)
+            }
+        }
+        None
+    }
+
+    /// Returns a snapshot of how far the loading of the image has got.
+    pub fn progress(&self) -> Progress {
+        let metadata = match self.metadata {
+            None => {
+                return Progress {
+                    level_of_detail: LevelOfDetail::None,
+                    scanlines_decompressed: vec![],
+                    scanlines_predicted: vec![],
+                    bytes_read: self.bytes_read,
+                    fraction_complete: 0.0,
+                }
+            }
+            Some(ref metadata) => metadata,
+        };
+
+        let levels_of_detail = match metadata.interlace_method {
+            InterlaceMethod::Disabled => vec![LevelOfDetail::None],
+            InterlaceMethod::Adam7 => (0..7).map(LevelOfDetail::Adam7).collect(),
+        };
+        let scanlines_decompressed =
+            self.scanline_counts_before(&levels_of_detail,
+                                        Some((self.current_lod, self.current_y)));
+        let scanlines_predicted = self.scanline_counts_before(&levels_of_detail,
+                                                              self.prediction_end);
+
+        // Weight each level of detail by the number of pixels in it.
+        let fraction_complete = if self.finished_decoding_altogether() {
+            1.0
+        } else {
+            let dimensions = metadata.dimensions;
+            let (mut pixels_predicted, mut pixels_wanted) = (0, 0);
+            for (&lod, &scanline_count) in levels_of_detail.iter().zip(&scanlines_predicted) {
+                if self.last_level_of_detail.map_or(false, |last_lod| lod > last_lod) {
+                    continue
+                }
+                let width = InterlacingInfo::new(0, 32, lod).scanline_width(dimensions.width, 32);
+                let height = InterlacingInfo::height_of_lod(dimensions.height, lod);
+                pixels_predicted += width as u64 * scanline_count as u64;
+                pixels_wanted += width as u64 * height as u64;
+            }
+            pixels_predicted as f32 / cmp::max(pixels_wanted, 1) as f32
         };
-        (self.current_lod == LevelOfDetail::None || self.current_lod == LevelOfDetail::Adam7(6)) &&
-            self.scanlines_decoded_in_this_lod >= height / 2 &&
-            (!indexed || self.rgba_conversion_complete)
+
+        Progress {
+            level_of_detail: self.current_lod,
+            scanlines_decompressed: scanlines_decompressed,
+            scanlines_predicted: scanlines_predicted,
+            bytes_read: self.bytes_read,
+            fraction_complete: fraction_complete,
+        }
+    }
+
+    /// Returns the number of scanlines of each of `levels_of_detail` that come before the given
+    /// level of detail and Y position in the image data.
+    fn scanline_counts_before(&self,
+                              levels_of_detail: &[LevelOfDetail],
+                              position: Option<(LevelOfDetail, u32)>)
+                              -> Vec<u32> {
+        let dimensions = self.metadata.as_ref().expect("No metadata yet!").dimensions;
+        levels_of_detail.iter().map(|&lod| {
+            match position {
+                Some((position_lod, y)) if lod == position_lod => y,
+                Some((position_lod, _)) if lod < position_lod => {
+                    // Empty Adam7 passes are skipped over.
+                    if InterlacingInfo::new(0, 32, lod).scanline_width(dimensions.width, 32) > 0 {
+                        InterlacingInfo::height_of_lod(dimensions.height, lod)
+                    } else {
+                        0
+                    }
+                }
+                _ => 0,
+            }
+        }).collect()
+    }
+
+    /// Sets a function to be called with the progress of the loading of the image each time a
+    /// batch of scanlines is decompressed or predicted.
+    ///
+    /// The function is called on the calling thread, from within `ImageLoader::add_data()`,
+    /// `ImageLoader::wait_until_finished()`, and the like.
+    pub fn set_progress_callback(&mut self, callback: Box<FnMut(&Progress) + Send>) {
+        self.progress_callback = Some(callback)
+    }
+
+    fn report_progress(&mut self) {
+        if self.progress_callback.is_some() {
+            let progress = self.progress();
+            if let Some(ref mut progress_callback) = self.progress_callback {
+                progress_callback(&progress)
+            }
+        }
     }
 
     /// Attaches a data provider to this image loader.
@@ -520,12 +1289,92 @@ impl ImageLoader {
     /// `ImageLoader::add_data()` if the metadata is present. The metadata is present if
     /// `ImageLoader::metadata()` returns `Some`.
     #[inline(never)]
-    pub fn set_data_provider(&mut self, data_provider: Box<DataProvider>) {
+    pub fn set_data_provider(&mut self, data_provider: Box<P>) {
         self.have_data_provider = true;
         self.predictor_thread_comm
-            .sender
             .send(MainThreadToPredictorThreadMsg::SetDataProvider(data_provider))
-            .unwrap()
+    }
+
+    /// Sets a function to be called from the predictor thread each time it finishes predicting a
+    /// batch of scanlines.
+    ///
+    /// Callers of `ImageLoader::try_add_data_from_slice()` and
+    /// `ImageLoader::try_wait_until_finished()` can use this to find out when it's worth trying
+    /// again after `LoadProgress::WaitingForPrediction` or a false result, respectively. The
+    /// callback should return quickly, since the predictor thread can't get on with its work
+    /// until it does.
+    pub fn set_wake_up_callback(&mut self, callback: Box<Fn() + Send>) {
+        self.predictor_thread_comm
+            .send(MainThreadToPredictorThreadMsg::SetWakeUpCallback(callback))
+    }
+
+    /// Restricts decoding to a region of the image, which is first clipped to the bounds of the
+    /// image. Returns the clipped region.
+    ///
+    /// Everything above the bottom of the region still has to be decompressed and predicted, since
+    /// later scanlines refer to earlier ones, but decoding stops as soon as the region is complete,
+    /// without reading the rest of the image data. (If segments of the compressed image data are
+    /// being decompressed in parallel, they are all read first regardless.) The data provider is
+    /// asked only for the scanlines that intersect the region, and only for the pixels of those
+    /// scanlines that lie inside it, which are the only ones converted to RGBA. Its storage need
+    /// therefore only be as large as the region; see `InterlacingInfo::in_region()`. `parng` keeps
+    /// its own copy of the reference scanline, so `reference_scanline` is never requested.
+    ///
+    /// This must be called after the metadata has been read and before image data is decoded;
+    /// that is, upon receiving `LoadProgress::NeedDataProviderAndMoreData`. Panics otherwise.
+    pub fn set_region_of_interest(&mut self, region: Region) -> Region {
+        assert!(self.metadata.is_some(), "No metadata yet!");
+        assert!(!self.rgba_conversion_info_sent,
+                "Can't set the region of interest once image data has been decoded!");
+        self.try_set_region_of_interest(region).unwrap()
+    }
+
+    /// Like `set_region_of_interest()`, but returns `None`, leaving the region of interest
+    /// unchanged, instead of panicking if the metadata hasn't been read yet or image data has
+    /// already been decoded.
+    pub fn try_set_region_of_interest(&mut self, region: Region) -> Option<Region> {
+        let dimensions = match self.metadata {
+            Some(ref metadata) if !self.rgba_conversion_info_sent => metadata.dimensions,
+            _ => return None,
+        };
+        let region = region.clip(dimensions);
+        self.region_of_interest = Some(region);
+        Some(region)
+    }
+
+    /// Stops decoding once the given level of detail is complete, without reading the rest of the
+    /// image data. (If segments of the compressed image data are being decompressed in parallel,
+    /// which only image loaders with more than one worker thread do, they are all read and
+    /// decompressed first regardless.)
+    ///
+    /// For Adam7-interlaced images, each pass fills in more of the image, so this yields a
+    /// reduced-resolution preview of it: for instance, `LevelOfDetail::Adam7(0)` alone supplies
+    /// every pixel of an image an eighth of the width and height. Once the pass is complete,
+    /// `ImageLoader::add_data()` returns `LoadProgress::Finished`, and the data provider is told
+    /// that the image is finished. This has no effect on images that aren't interlaced, and
+    /// `LevelOfDetail::None` decodes every level of detail as usual.
+    ///
+    /// This must be called after the metadata has been read and before image data is decoded;
+    /// that is, upon receiving `LoadProgress::NeedDataProviderAndMoreData`. Panics otherwise.
+    pub fn set_last_level_of_detail(&mut self, lod: LevelOfDetail) {
+        assert!(self.metadata.is_some(), "No metadata yet!");
+        assert!(!self.rgba_conversion_info_sent,
+                "Can't set the last level of detail once image data has been decoded!");
+        self.try_set_last_level_of_detail(lod);
+    }
+
+    /// Like `set_last_level_of_detail()`, but returns false, leaving the last level of detail
+    /// unchanged, instead of panicking if the metadata hasn't been read yet or image data has
+    /// already been decoded. Returns true otherwise.
+    pub fn try_set_last_level_of_detail(&mut self, lod: LevelOfDetail) -> bool {
+        if self.metadata.is_none() || self.rgba_conversion_info_sent {
+            return false
+        }
+        self.last_level_of_detail = match lod {
+            LevelOfDetail::None => None,
+            LevelOfDetail::Adam7(_) => Some(lod),
+        };
+        true
     }
 
     /// Returns a reference to the image metadata, which contains image dimensions and color info.
@@ -537,10 +1386,58 @@ impl ImageLoader {
 
     fn scanlines_to_buffer(&self) -> u32 {
         let width = self.metadata.as_ref().expect("No metadata?!").dimensions.width;
-        cmp::max(PIXELS_PER_PREDICTION_CHUNK / width, 1)
+        let scanlines = cmp::max(PIXELS_PER_PREDICTION_CHUNK / width, 1);
+        if self.worker_count == 1 {
+            return scanlines
+        }
+
+        // The workers predict dependent scanlines as a wavefront, which takes as many scanlines as
+        // there are workers to fill up. Make sure each batch is a good deal longer than that.
+        cmp::max(scanlines, self.worker_count * SCANLINES_PER_PREDICTION_CHUNK_PER_WORKER)
     }
 }
 
+/// The entropy decoder in use: `zlib` if the `zlib` feature is enabled, and the built-in inflater
+/// if it isn't or if the `builtin-inflate` feature is also enabled.
+#[cfg(any(feature = "builtin-inflate", not(feature = "zlib")))]
+type EntropyDecoder = Inflater;
+#[cfg(all(feature = "zlib", not(feature = "builtin-inflate")))]
+type EntropyDecoder = Decompress;
+
+#[cfg(any(feature = "builtin-inflate", not(feature = "zlib")))]
+fn new_entropy_decoder() -> EntropyDecoder {
+    Inflater::new()
+}
+
+#[cfg(all(feature = "zlib", not(feature = "builtin-inflate")))]
+fn new_entropy_decoder() -> EntropyDecoder {
+    Decompress::new(true)
+}
+
+#[cfg(any(feature = "builtin-inflate", not(feature = "zlib")))]
+fn reset_entropy_decoder(entropy_decoder: &mut EntropyDecoder) {
+    entropy_decoder.reset()
+}
+
+#[cfg(all(feature = "zlib", not(feature = "builtin-inflate")))]
+fn reset_entropy_decoder(entropy_decoder: &mut EntropyDecoder) {
+    entropy_decoder.reset(true)
+}
+
+#[cfg(any(feature = "builtin-inflate", not(feature = "zlib")))]
+fn entropy_decode(entropy_decoder: &mut EntropyDecoder, input: &[u8], output: &mut [u8])
+                  -> Result<(), PngError> {
+    entropy_decoder.decompress(input, output)
+}
+
+#[cfg(all(feature = "zlib", not(feature = "builtin-inflate")))]
+fn entropy_decode(entropy_decoder: &mut EntropyDecoder, input: &[u8], output: &mut [u8])
+                  -> Result<(), PngError> {
+    try!(entropy_decoder.decompress(input, output, Flush::None).map_err(PngError::from));
+    Ok(())
+}
+
+#[cfg(all(feature = "zlib", not(feature = "builtin-inflate")))]
 impl From<DataError> for PngError {
     fn from(_: DataError) -> PngError {
         PngError::EntropyDecodingError
@@ -551,11 +1448,100 @@ trait FromFlateResult : Sized {
     fn from_flate_result(error: c_int) -> Result<(), Self>;
 }
 
+/// A fixed set of background threads that many image loaders can share.
+///
+/// By default, every `ImageLoader` spawns a thread of its own to perform prediction and color
+/// conversion. When decoding many small images, the cost of spawning and tearing down those
+/// threads can dominate. Instead, create a pool once and pass it to `ImageLoader::with_pool()` for
+/// each image.
+///
+/// To share a pool among several threads, clone it. Clones refer to the same set of threads, which
+/// exit once the pool, all of its clones, and all image loaders created from them have been
+/// dropped.
+#[derive(Clone)]
+pub struct DecoderPool {
+    senders: Vec<Sender<PooledPredictorThreadMsg>>,
+    next_session_id: Arc<AtomicUsize>,
+}
+
+impl DecoderPool {
+    /// Spawns a pool of `thread_count` threads.
+    ///
+    /// Panics if `thread_count` is zero.
+    pub fn new(thread_count: u32) -> DecoderPool {
+        assert!(thread_count > 0, "A decoder pool needs at least one thread!");
+        DecoderPool {
+            senders: (0..thread_count).map(|_| prediction::spawn_pooled_predictor_thread())
+                                      .collect(),
+            next_session_id: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+/// An image loader whose data provider need not be `Send`. See `ImageLoader::new_local()`.
+pub type LocalImageLoader = ImageLoader<DataProvider>;
+
+/// A handle that cancels the decoding of an image, which may be used from any thread. See
+/// `ImageLoader::cancel_handle()`.
+///
+/// To use it from several threads, clone it.
+#[derive(Clone)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    /// Cancels decoding.
+    ///
+    /// The calling thread doesn't wait for decoding to stop. Rather, decompression and prediction
+    /// each stop at the next batch of scanlines, and the data provider's `cancelled()` method is
+    /// called instead of its `finished()` method. From then on, `ImageLoader::add_data()` and
+    /// `ImageLoader::wait_until_finished()` fail with a `PngError::Cancelled` error. If it's too
+    /// late to cancel, because every scanline has already been predicted, the image finishes
+    /// decoding as usual.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst)
+    }
+
+    /// Returns true if `CancelHandle::cancel()` has been called.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// A rectangular region of an image, in pixels.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Region {
+    /// The column of the leftmost pixels in the region.
+    pub x: u32,
+    /// The row of the topmost pixels in the region.
+    pub y: u32,
+    /// The width of the region.
+    pub width: u32,
+    /// The height of the region.
+    pub height: u32,
+}
+
+impl Region {
+    /// Returns the part of this region that lies within an image with the given dimensions. The
+    /// result is empty if none of it does.
+    pub fn clip(&self, dimensions: Dimensions) -> Region {
+        let (x, y) = (cmp::min(self.x, dimensions.width), cmp::min(self.y, dimensions.height));
+        Region {
+            x: x,
+            y: y,
+            width: cmp::min(self.width, dimensions.width - x),
+            height: cmp::min(self.height, dimensions.height - y),
+        }
+    }
+}
+
 /// Describes the progress of loading the image. This is the value returned from
 /// `ImageLoader::add_data()`.
 #[derive(Copy, Clone, PartialEq)]
 pub enum LoadProgress {
-    /// The image has been fully entropy decoded.
+    /// The image has been fully entropy decoded, or, if a region of interest or a last level of
+    /// detail was set, all of the image data that is wanted has been.
     ///
     /// Because the image prediction happens on a background thread, this result does not
     /// necessarily mean that the image has been fully decoded. To wait until the image is truly
@@ -571,6 +1557,66 @@ pub enum LoadProgress {
     /// up. Before calling `ImageLoader::add_data()` again to decode the image data proper, a data
     /// provider must be installed via `ImageLoader::set_data_provider()`.
     NeedDataProviderAndMoreData,
+
+    /// The predictor thread has fallen behind, and no more data can be consumed until it catches
+    /// up. Only the methods that never block, such as `ImageLoader::try_add_data_from_slice()`,
+    /// return this; call the method again later.
+    WaitingForPrediction,
+
+    /// The work budget passed to `ImageLoader::add_data_with_budget()` or
+    /// `ImageLoader::add_data_from_slice_with_budget()` has been used up. Call the method again to
+    /// continue decoding.
+    Yielded,
+}
+
+/// A snapshot of how far the loading of an image has got. See `ImageLoader::progress()`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Progress {
+    /// The level of detail that image data is currently being decompressed for. This is
+    /// `LevelOfDetail::None` if the image isn't interlaced or the metadata hasn't been read yet.
+    pub level_of_detail: LevelOfDetail,
+    /// The number of scanlines of each level of detail that have been decompressed: one entry if
+    /// the image isn't interlaced, or seven, one for each Adam7 pass, if it is. Empty if the
+    /// metadata hasn't been read yet.
+    pub scanlines_decompressed: Vec<u32>,
+    /// The number of scanlines of each level of detail that have been predicted and handed to the
+    /// data provider, laid out like `scanlines_decompressed`.
+    pub scanlines_predicted: Vec<u32>,
+    /// The number of bytes of the image stream read so far.
+    pub bytes_read: u64,
+    /// An estimate of how much of the image has been decoded, from 0.0 to 1.0, based on the number
+    /// of pixels predicted. Levels of detail after the last one to be decoded aren't counted.
+    pub fraction_complete: f32,
+}
+
+/// A limit on the amount of work that a single call to `ImageLoader::add_data_with_budget()` does.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Budget {
+    /// Stop once this many bytes of the stream have been consumed.
+    Bytes(u64),
+    /// Stop once this many scanlines have been decompressed. Each pass of an interlaced image
+    /// counts separately.
+    Scanlines(u64),
+    /// Stop once this much time has elapsed.
+    Time(Duration),
+}
+
+/// Keeps track of how much of a `Budget` has been used up.
+struct BudgetTracker {
+    budget: Budget,
+    start_position: u64,
+    start_scanline_count: u64,
+    start_time: Instant,
+}
+
+impl BudgetTracker {
+    fn is_used_up(&self, position: u64, scanline_count: u64) -> bool {
+        match self.budget {
+            Budget::Bytes(bytes) => position.saturating_sub(self.start_position) >= bytes,
+            Budget::Scanlines(scanlines) => scanline_count - self.start_scanline_count >= scanlines,
+            Budget::Time(duration) => self.start_time.elapsed() >= duration,
+        }
+    }
 }
 
 #[derive(Copy, Clone, PartialEq)]
@@ -580,26 +1626,31 @@ enum DecodeState {
     ReadingPalette(u32),
     LookingForImageData,
     DecodingData(u32),
+    /// Like `DecodingData`, but gathering up the compressed data a segment at a time so that the
+    /// segments can be decompressed in parallel.
+    GatheringData(u32),
+    ReadingIdot(u32),
     ReadingTransparency(u32),
     Finished,
 }
 
-fn aligned_offset_for_slice(slice: &[u8]) -> usize {
-    let address = slice.as_ptr() as usize;
-    let remainder = address % 16;
-    if remainder == 0 {
-        0
-    } else {
-        16 - remainder
-    }
-}
-
-fn aligned_scanline_buffer_offset(buffer: &[u8]) -> usize {
-    let offset = aligned_offset_for_slice(buffer);
-    if offset == 0 {
-        16
-    } else {
-        offset
+impl DecodeState {
+    /// Returns the number of bytes of the stream that must be available to make any progress in
+    /// this state, for states that can't pick up where they left off partway through reading.
+    fn bytes_needed_to_proceed(&self) -> usize {
+        match *self {
+            // The signature and the `IHDR` chunk.
+            DecodeState::Start => 8 + 12 + 13,
+            DecodeState::LookingForPalette | DecodeState::LookingForImageData => 8,
+            DecodeState::ReadingPalette(bytes_left_in_chunk) |
+            DecodeState::ReadingTransparency(bytes_left_in_chunk) => {
+                cmp::min(bytes_left_in_chunk, 1) as usize
+            }
+            DecodeState::DecodingData(_) |
+            DecodeState::GatheringData(_) |
+            DecodeState::ReadingIdot(_) |
+            DecodeState::Finished => 0,
+        }
     }
 }
 
@@ -607,10 +1658,11 @@ fn aligned_scanline_buffer_offset(buffer: &[u8]) -> usize {
 /// trait, you can choose any method you wish to store the image data and it will be transparent to
 /// `parng`.
 ///
-/// Be aware that the data provider will be called on a background thread; i.e. not the thread it
-/// was created on! You must ensure proper synchronization between the main thread and that
-/// background thread if you wish to communicate between them.
-pub trait DataProvider : Send {
+/// Be aware that, unless the image loader was created with `ImageLoader::new_inline()` or
+/// `ImageLoader::new_local()`, the data provider will be called on a background thread; i.e. not
+/// the thread it was created on! You must ensure proper synchronization between the main thread and
+/// that background thread if you wish to communicate between them.
+pub trait DataProvider {
     /// Called when `parng` needs to predict a scanline.
     ///
     /// `parng` requests one or two scanlines using this method: one for writing
@@ -633,17 +1685,22 @@ pub trait DataProvider : Send {
     /// Called when `parng` has finished prediction for a scanline, optionally at a specific level
     /// of detail.
     ///
-    /// If the image is in RGBA or grayscale-alpha format, then the scanline is entirely finished
-    /// at this time. Otherwise, unless the image is in indexed format, the scanline is finished,
-    /// but the alpha values are not yet valid. Finally, if the image is in indexed format, the
-    /// scanline palette values are correct, but the indexed-to-truecolor conversion has not
-    /// occurred yet, so the scanline is not yet suitable for display.
+    /// If the image is in RGBA format, then the scanline is entirely finished at this time.
+    /// Otherwise, the scanline still needs to be converted to RGBA before it is suitable for
+    /// display. If the image is in indexed format, the scanline palette values are correct at this
+    /// point.
     fn prediction_complete_for_scanline(&mut self, scanline: u32, lod: LevelOfDetail);
 
     /// Called when `parng` needs to perform RGBA conversion for a scanline.
     ///
     /// `lod` specifies the level of detail, if the image is interlaced. `indexed` is true if the
-    /// image has indexed color.
+    /// image has indexed color. The returned RGBA scanline, like the current scanline returned
+    /// from `fetch_scanlines_for_prediction()`, must begin at the first pixel of the scanline at
+    /// this level of detail.
+    ///
+    /// For most images, this is called immediately after prediction completes for each scanline.
+    /// For grayscale-alpha images, it is called once the next scanline at the same level of detail
+    /// has been predicted, or once the last scanline at that level of detail has been predicted.
     ///
     /// This method will be called only if the image is not RGBA.
     fn fetch_scanlines_for_rgba_conversion<'a>(&'a mut self,
@@ -660,6 +1717,12 @@ pub trait DataProvider : Send {
 
     /// Called when `parng` has completely finished decoding the image.
     fn finished(&mut self);
+
+    /// Called instead of `finished()` if decoding is cancelled with a `CancelHandle`. No more
+    /// methods are called after this one.
+    ///
+    /// The default implementation does nothing.
+    fn cancelled(&mut self) {}
 }
 
 /// Data providers use this structure to supply scanlines to `parng` in response to prediction
@@ -711,6 +1774,400 @@ pub struct ScanlinesForRgbaConversion<'a> {
     pub indexed_stride: Option<u8>,
 }
 
+/// A pair of full-width scanlines that take turns serving as the reference, for data providers that
+/// don't keep the whole image around to predict from.
+///
+/// Even-numbered scanlines go in one and odd-numbered scanlines in the other, with the pixels
+/// packed tightly. Data providers hand these scanlines out to `parng`, and copy the pixels
+/// elsewhere once they're complete.
+pub struct ScanlinePair {
+    rgba_scanlines: Vec<u8>,
+    indexed_scanlines: Vec<u8>,
+    rgba_scanline_length: usize,
+    indexed_scanline_length: usize,
+}
+
+impl ScanlinePair {
+    /// Creates a pair of scanlines with room for an image `width` pixels wide.
+    pub fn new(width: u32, indexed: bool) -> ScanlinePair {
+        let rgba_scanline_length = align(width as usize * 4);
+        let indexed_scanline_length = align(width as usize);
+        let indexed_scanlines_length = if indexed {
+            indexed_scanline_length * 2
+        } else {
+            0
+        };
+
+        let (mut rgba_scanlines, mut indexed_scanlines) = (vec![], vec![]);
+        unsafe {
+            rgba_scanlines.extend_with_uninitialized(rgba_scanline_length * 2);
+            indexed_scanlines.extend_with_uninitialized(indexed_scanlines_length)
+        }
+        ScanlinePair {
+            rgba_scanlines: rgba_scanlines,
+            indexed_scanlines: indexed_scanlines,
+            rgba_scanline_length: rgba_scanline_length,
+            indexed_scanline_length: indexed_scanline_length,
+        }
+    }
+
+    /// Returns the scanlines to predict the given scanline into, for
+    /// `DataProvider::fetch_scanlines_for_prediction()`.
+    pub fn scanlines_for_prediction(&mut self,
+                                    reference_scanline: Option<u32>,
+                                    current_scanline: u32,
+                                    indexed: bool)
+                                    -> ScanlinesForPrediction {
+        let (scanlines, length) = if indexed {
+            (&mut self.indexed_scanlines, self.indexed_scanline_length)
+        } else {
+            (&mut self.rgba_scanlines, self.rgba_scanline_length)
+        };
+        let (even, odd) = scanlines.split_at_mut(length);
+        let (current, reference) = if current_scanline % 2 == 0 {
+            (even, odd)
+        } else {
+            (odd, even)
+        };
+        ScanlinesForPrediction {
+            reference_scanline: reference_scanline.map(|_| reference),
+            current_scanline: current,
+            stride: if indexed {
+                1
+            } else {
+                4
+            },
+        }
+    }
+
+    /// Returns the scanlines to convert the given scanline to RGBA in, for
+    /// `DataProvider::fetch_scanlines_for_rgba_conversion()`.
+    pub fn scanlines_for_rgba_conversion(&mut self, scanline: u32, indexed: bool)
+                                         -> ScanlinesForRgbaConversion {
+        let rgba_start = self.rgba_scanline_length * (scanline as usize % 2);
+        let indexed_start = self.indexed_scanline_length * (scanline as usize % 2);
+        ScanlinesForRgbaConversion {
+            rgba_scanline: &mut self.rgba_scanlines[rgba_start..],
+            indexed_scanline: if indexed {
+                Some(&self.indexed_scanlines[indexed_start..])
+            } else {
+                None
+            },
+            rgba_stride: 4,
+            indexed_stride: if indexed {
+                Some(1)
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Returns the RGBA pixels of the given scanline.
+    pub fn rgba_scanline(&self, scanline: u32) -> &[u8] {
+        let start = self.rgba_scanline_length * (scanline as usize % 2);
+        &self.rgba_scanlines[start..(start + self.rgba_scanline_length)]
+    }
+}
+
+/// A data provider that decodes into rows of the final image that a closure supplies, so that
+/// applications don't have to deal with interlacing, reference scanlines, or RGBA conversion.
+///
+/// The data provider owns `rows`, whatever storage the rows live in, and passes it to the closures.
+/// `fetch_row` returns row `y` of the final image, with room for 4 bytes per pixel. It may return a
+/// different slice each time, but the contents of each row must be preserved, because the rows of
+/// interlaced images are filled in over several passes. `row_complete` is called once row `y` has
+/// been completely decoded, in big-endian RGBA format. The rows of interlaced images become
+/// complete out of order.
+///
+/// Scanlines are predicted into a `ScanlinePair` and copied into the rows once they're complete,
+/// so the rows needn't be aligned, and `fetch_row` is called just once per scanline.
+///
+/// If decoding stops early (see `ImageLoader::set_last_level_of_detail()`), any rows that aren't
+/// complete are reported once the image is finished, since no more of them will be decoded.
+/// Regions of interest aren't supported.
+pub struct RowDataProvider<T, F, G> where F: for<'a> FnMut(&'a mut T, u32) -> &'a mut [u8],
+                                          G: FnMut(&mut T, u32) {
+    rows: T,
+    fetch_row: F,
+    row_complete: G,
+    width: u32,
+    /// True if scanlines are complete only once they've been converted to RGBA.
+    converted: bool,
+    scanlines: ScanlinePair,
+    /// For each row, the number of scanlines that have yet to be decoded into it.
+    incomplete_scanline_counts: Vec<u8>,
+}
+
+impl<T, F, G> RowDataProvider<T, F, G> where F: for<'a> FnMut(&'a mut T, u32) -> &'a mut [u8],
+                                             G: FnMut(&mut T, u32) {
+    /// Creates a data provider that decodes the image that `metadata` describes into the rows
+    /// that `fetch_row` returns from `rows`, calling `row_complete` as each one is finished.
+    pub fn new(metadata: &Metadata, rows: T, fetch_row: F, row_complete: G)
+               -> RowDataProvider<T, F, G> {
+        let dimensions = metadata.dimensions;
+        let lods = match metadata.interlace_method {
+            InterlaceMethod::Disabled => vec![LevelOfDetail::None],
+            InterlaceMethod::Adam7 => (0..7).map(LevelOfDetail::Adam7).collect(),
+        };
+        let mut incomplete_scanline_counts = vec![0; dimensions.height as usize];
+        for lod in lods {
+            if InterlacingInfo::new(0, 32, lod).scanline_width(dimensions.width, 32) == 0 {
+                continue
+            }
+            for scanline in 0..InterlacingInfo::height_of_lod(dimensions.height, lod) {
+                incomplete_scanline_counts[InterlacingInfo::new(scanline, 32, lod).y as usize] += 1
+            }
+        }
+
+        RowDataProvider {
+            rows: rows,
+            fetch_row: fetch_row,
+            row_complete: row_complete,
+            width: dimensions.width,
+            converted: metadata.color_type != ColorType::RgbAlpha,
+            scanlines: ScanlinePair::new(dimensions.width,
+                                         metadata.color_type == ColorType::Indexed),
+            incomplete_scanline_counts: incomplete_scanline_counts,
+        }
+    }
+
+    /// Copies a scanline that has been completely decoded into its row, and reports the row if
+    /// that completes it.
+    fn scanline_complete(&mut self, scanline: u32, lod: LevelOfDetail) {
+        let info = InterlacingInfo::new(scanline, 32, lod);
+        let pixel_count = info.scanline_width(self.width, 32) as usize;
+        {
+            let row = (self.fetch_row)(&mut self.rows, info.y);
+            assert!(row.len() >= self.width as usize * 4,
+                    "Rows must have room for 4 bytes per pixel!");
+            for (dest, src) in row[(info.offset as usize)..]
+                                   .chunks_mut(info.stride as usize)
+                                   .zip(self.scanlines.rgba_scanline(scanline).chunks(4))
+                                   .take(pixel_count) {
+                dest[0..4].clone_from_slice(src)
+            }
+        }
+
+        let incomplete_scanline_count = &mut self.incomplete_scanline_counts[info.y as usize];
+        *incomplete_scanline_count -= 1;
+        if *incomplete_scanline_count == 0 {
+            (self.row_complete)(&mut self.rows, info.y)
+        }
+    }
+}
+
+impl<T, F, G> DataProvider for RowDataProvider<T, F, G>
+                           where F: for<'a> FnMut(&'a mut T, u32) -> &'a mut [u8],
+                                 G: FnMut(&mut T, u32) {
+    fn fetch_scanlines_for_prediction<'a>(&'a mut self,
+                                          reference_scanline: Option<u32>,
+                                          current_scanline: u32,
+                                          _: LevelOfDetail,
+                                          indexed: bool)
+                                          -> ScanlinesForPrediction<'a> {
+        self.scanlines.scanlines_for_prediction(reference_scanline, current_scanline, indexed)
+    }
+
+    fn prediction_complete_for_scanline(&mut self, scanline: u32, lod: LevelOfDetail) {
+        if !self.converted {
+            self.scanline_complete(scanline, lod)
+        }
+    }
+
+    fn fetch_scanlines_for_rgba_conversion<'a>(&'a mut self,
+                                               scanline: u32,
+                                               _: LevelOfDetail,
+                                               indexed: bool)
+                                               -> ScanlinesForRgbaConversion<'a> {
+        self.scanlines.scanlines_for_rgba_conversion(scanline, indexed)
+    }
+
+    fn rgba_conversion_complete_for_scanline(&mut self, scanline: u32, lod: LevelOfDetail) {
+        self.scanline_complete(scanline, lod)
+    }
+
+    fn finished(&mut self) {
+        for (y, incomplete_scanline_count) in self.incomplete_scanline_counts
+                                                  .iter_mut()
+                                                  .enumerate() {
+            if *incomplete_scanline_count > 0 {
+                *incomplete_scanline_count = 0;
+                (self.row_complete)(&mut self.rows, y as u32)
+            }
+        }
+    }
+}
+
+/// An iterator over the rows of a PNG image, which decodes them from a reader as they're asked for.
+/// See `Rows::new()`.
+///
+/// Each row is yielded in big-endian RGBA format, 32 bits per pixel, packed tightly. Note that
+/// Adam7-interlaced images are buffered in full, and none of their rows are yielded until the
+/// whole image has been decoded.
+pub struct Rows<I> where I: Read + Seek {
+    input: I,
+    image: LocalImageLoader,
+    row_length: usize,
+    /// Blocks of complete rows that have yet to be yielded, in order.
+    complete_rows: Rc<RefCell<VecDeque<Vec<u8>>>>,
+    /// The block of rows currently being yielded, and the offset of the next row in it.
+    rows: Vec<u8>,
+    next_row_offset: usize,
+    finished: bool,
+}
+
+impl<I> Rows<I> where I: Read + Seek {
+    /// Reads the metadata of a PNG image stream from a reader, and returns an iterator that decodes
+    /// the rest of the image a row at a time.
+    ///
+    /// The image is decoded on the calling thread as rows are asked for (see
+    /// `ImageLoader::new_local()`), one batch of scanlines at a time (see
+    /// `ImageLoader::add_data_with_budget()`). For non-interlaced images,
+    /// nothing is kept besides the reference scanline and the rest of the batch that the row
+    /// asked for was decoded in. Adam7-interlaced images, on the other hand, have to be held in
+    /// full, since most of their rows aren't complete until the last pass, so their rows are
+    /// yielded once the whole image has been decoded.
+    ///
+    /// If an error occurs while decoding, it is yielded in place of the next row, and iteration
+    /// ends. Reads that return less data than was asked for are retried, but running out of input
+    /// before the image is finished, which is when the reader returns no data at all, is a
+    /// `PngError::Io` error.
+    pub fn new(mut input: I) -> Result<Rows<I>, PngError> {
+        let mut image = ImageLoader::new_local();
+        // The first call reads the metadata and nothing else.
+        try!(image.add_data(&mut input));
+
+        let metadata = image.metadata().clone().unwrap();
+        let (width, height) = (metadata.dimensions.width, metadata.dimensions.height);
+        let row_length = width as usize * 4;
+        let complete_rows = Rc::new(RefCell::new(VecDeque::new()));
+        let rows_sink = complete_rows.clone();
+        match metadata.interlace_method {
+            InterlaceMethod::Disabled => {
+                // Each row is complete as soon as it's decoded, so one row is all we need.
+                let row = vec![0; row_length];
+                let data_provider =
+                    RowDataProvider::new(&metadata,
+                                         row,
+                                         |row: &mut Vec<u8>, _| &mut row[..],
+                                         move |row, _| {
+                                             rows_sink.borrow_mut().push_back(row.clone())
+                                         });
+                image.set_data_provider(Box::new(data_provider))
+            }
+            InterlaceMethod::Adam7 => {
+                // Keep the whole image, along with the number of complete rows.
+                let rows = (vec![0; row_length * height as usize], 0);
+                let data_provider =
+                    RowDataProvider::new(&metadata,
+                                         rows,
+                                         move |rows: &mut (Vec<u8>, u32), y| {
+                                             let start = row_length * (y as usize);
+                                             &mut rows.0[start..(start + row_length)]
+                                         },
+                                         move |rows, _| {
+                                             rows.1 += 1;
+                                             if rows.1 == height {
+                                                 let image = mem::replace(&mut rows.0, vec![]);
+                                                 rows_sink.borrow_mut().push_back(image)
+                                             }
+                                         });
+                image.set_data_provider(Box::new(data_provider))
+            }
+        }
+
+        Ok(Rows {
+            input: input,
+            image: image,
+            row_length: row_length,
+            complete_rows: complete_rows,
+            rows: vec![],
+            next_row_offset: 0,
+            finished: false,
+        })
+    }
+
+    /// Returns the metadata of the image, which contains its dimensions and color info.
+    pub fn metadata(&self) -> &Metadata {
+        self.image.metadata().as_ref().unwrap()
+    }
+}
+
+impl<I> Iterator for Rows<I> where I: Read + Seek {
+    type Item = Result<Vec<u8>, PngError>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>, PngError>> {
+        loop {
+            if self.next_row_offset < self.rows.len() {
+                // Hand over blocks of a single row as they are.
+                if self.rows.len() == self.row_length {
+                    self.next_row_offset = 0;
+                    return Some(Ok(mem::replace(&mut self.rows, vec![])))
+                }
+                let start = self.next_row_offset;
+                self.next_row_offset += self.row_length;
+                return Some(Ok(self.rows[start..self.next_row_offset].to_vec()))
+            }
+
+            if let Some(rows) = self.complete_rows.borrow_mut().pop_front() {
+                self.rows = rows;
+                self.next_row_offset = 0;
+                continue
+            }
+            if self.finished {
+                return None
+            }
+
+            match self.image.add_data_with_budget(&mut self.input, Budget::Scanlines(1)) {
+                Ok(LoadProgress::Finished) => {
+                    self.finished = true;
+                    if let Err(error) = self.image.wait_until_finished() {
+                        return Some(Err(error))
+                    }
+                }
+                Ok(LoadProgress::Yielded) => {}
+                Ok(LoadProgress::NeedMoreData) => {
+                    // The reader may just have returned less than was asked for, so only give up
+                    // once it has nothing more to return.
+                    match at_end_of_stream(&mut self.input) {
+                        Ok(false) => {}
+                        Ok(true) => {
+                            self.finished = true;
+                            return Some(Err(premature_end_of_stream_error()))
+                        }
+                        Err(error) => {
+                            self.finished = true;
+                            return Some(Err(PngError::Io(error)))
+                        }
+                    }
+                }
+                Ok(_) => {
+                    self.finished = true;
+                    return Some(Err(premature_end_of_stream_error()))
+                }
+                Err(error) => {
+                    self.finished = true;
+                    return Some(Err(error))
+                }
+            }
+        }
+    }
+}
+
+/// Returns true if `input` has no more data, leaving its position unchanged.
+fn at_end_of_stream<I>(input: &mut I) -> io::Result<bool> where I: Read + Seek {
+    let mut byte = [0];
+    if try!(input.read(&mut byte)) == 0 {
+        return Ok(true)
+    }
+    try!(input.seek(SeekFrom::Current(-1)));
+    Ok(false)
+}
+
+fn premature_end_of_stream_error() -> PngError {
+    PngError::Io(io::Error::new(ErrorKind::UnexpectedEof, "PNG image stream ended prematurely"))
+}
+
 pub trait UninitializedExtension {
     unsafe fn extend_with_uninitialized(&mut self, new_len: usize);
 }
@@ -782,6 +2239,38 @@ impl InterlacingInfo {
         }
     }
 
+    /// Creates an `InterlacingInfo` structure that describes the pixel layout of the given
+    /// scanline and level of detail within a buffer holding only the given region of the image.
+    ///
+    /// The row and offset are relative to the top left corner of the region, and the offset is
+    /// that of the first pixel of the scanline that lies inside the region. To find the number of
+    /// such pixels, pass the width of the region to `scanline_width()`. Returns `None` if no pixels
+    /// of the scanline lie inside the region.
+    pub fn in_region(y: u32, color_depth: u8, lod: LevelOfDetail, region: &Region)
+                     -> Option<InterlacingInfo> {
+        let info = InterlacingInfo::new(y, color_depth, lod);
+        if info.y < region.y || info.y >= region.y + region.height {
+            return None
+        }
+
+        let bytes_per_pixel = color_depth / 8;
+        let (x_offset, x_scale_factor) = ((info.offset / bytes_per_pixel) as u32,
+                                          (info.stride / bytes_per_pixel) as u32);
+        let first_x = if region.x <= x_offset {
+            x_offset
+        } else {
+            x_offset + (region.x - x_offset + x_scale_factor - 1) / x_scale_factor * x_scale_factor
+        };
+        if first_x >= region.x + region.width {
+            return None
+        }
+        Some(InterlacingInfo {
+            y: info.y - region.y,
+            stride: info.stride,
+            offset: (first_x - region.x) as u8 * bytes_per_pixel,
+        })
+    }
+
     fn y_offset(lod: LevelOfDetail) -> u8 {
         match lod {
             LevelOfDetail::None | LevelOfDetail::Adam7(0) | LevelOfDetail::Adam7(1) |
@@ -808,6 +2297,9 @@ impl InterlacingInfo {
         let bytes_per_pixel = color_depth / 8;
         let x_offset = self.offset / bytes_per_pixel;
         let x_scale_factor = self.stride / bytes_per_pixel;
+        if image_width <= x_offset as u32 {
+            return 0
+        }
         (image_width - x_offset as u32 + x_scale_factor as u32 - 1) / x_scale_factor as u32
     }
 
@@ -816,17 +2308,24 @@ impl InterlacingInfo {
         self.scanline_width(image_width, color_depth) * bytes_per_pixel as u32
     }
 
-    // This formula is cribbed from `stb_image`.
-    fn height_of_lod(image_height: u32, lod: LevelOfDetail) -> u32 {
+    /// Returns the number of scanlines in the given level of detail of an image with the given
+    /// height.
+    ///
+    /// This formula is cribbed from `stb_image`.
+    pub fn height_of_lod(image_height: u32, lod: LevelOfDetail) -> u32 {
         let y_offset = InterlacingInfo::y_offset(lod);
         let y_scale_factor = InterlacingInfo::y_scale_factor(lod);
+        if image_height <= y_offset as u32 {
+            return 0
+        }
         (image_height - y_offset as u32 + y_scale_factor as u32 - 1) / y_scale_factor as u32
     }
 }
 
 #[derive(Clone)]
 struct BufferedScanlineInfo {
-    data: Vec<u8>,
+    /// The offset of the scanline's predictor byte within the block.
+    offset: usize,
     y: u32,
     lod: LevelOfDetail,
 }
@@ -0,0 +1,1032 @@
+// parng/inflate.rs
+//
+// Copyright (c) 2016 Mozilla Foundation
+
+//! A pure-Rust implementation of `zlib` decompression, tuned for PNG image data.
+//!
+//! The decoder is a resumable state machine, so it can be fed image data in arbitrarily sized
+//! pieces as it arrives. Output is decoded in large runs into an internal window, which doubles as
+//! the 32K history buffer that back-references refer to, and copied out in whatever amounts the
+//! caller asks for. Huffman codes are decoded with a table lookup for codes up to `FAST_BITS` bits
+//! long, which covers nearly all symbols in practice, and the tables are only rebuilt at block
+//! boundaries.
+//!
+//! Some encoders split the compressed data into segments that can be decompressed independently,
+//! by emitting full flushes: an empty stored block after which nothing refers back to earlier
+//! data. A `ParallelInflater` decompresses such segments on separate threads. It is used
+//! regardless of whether the `builtin-inflate` feature is enabled.
+
+use PngError;
+use std::cmp;
+use std::collections::VecDeque;
+#[cfg(any(feature = "builtin-inflate", not(feature = "zlib")))]
+use std::mem;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+/// The maximum distance that a back-reference can reach.
+const HISTORY_SIZE: usize = 32768;
+/// The amount of output that we decode ahead of what the caller has asked for.
+const MAX_PENDING_OUTPUT: usize = 65536;
+/// The longest match that a single symbol can produce.
+const MAX_MATCH_LENGTH: usize = 258;
+/// The size of the internal output window. It must hold the history plus all pending output.
+const WINDOW_SIZE: usize = 2 * HISTORY_SIZE + 2 * MAX_PENDING_OUTPUT;
+
+/// Huffman codes up to this many bits long are decoded with a single table lookup.
+const FAST_BITS: u32 = 10;
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0
+];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13
+];
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15
+];
+
+/// A streaming `zlib` decompressor.
+pub struct Inflater {
+    state: State,
+    final_block: bool,
+
+    /// The position of the next byte to be read from the input passed to `decompress()`.
+    input_pos: usize,
+    bits: u64,
+    bit_count: u32,
+
+    window: Vec<u8>,
+    /// The position of the first decoded byte that hasn't been handed to the caller yet.
+    read_pos: usize,
+    /// The position at which the next decoded byte will be written.
+    write_pos: usize,
+    /// The position of the first decoded byte that hasn't been added to the checksum yet.
+    checksum_pos: usize,
+
+    literal_length_table: Huffman,
+    distance_table: Huffman,
+
+    /// The state of a dynamic Huffman table header being read.
+    literal_length_count: usize,
+    distance_count: usize,
+    code_length_count: usize,
+    code_length_table: Huffman,
+    code_lengths: [u8; 286 + 32],
+    checksum: Adler32,
+    /// The checksum stored in the trailer, once it has been read.
+    stored_checksum: Option<u32>,
+    /// True if this decoder is decompressing one segment of a stream that has been split up. In
+    /// that case the stored checksum covers more than this decoder's output, so it's left to the
+    /// caller to check.
+    segment: bool,
+
+    total_in: u64,
+    total_out: u64,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum State {
+    Header,
+    BlockHeader,
+    Stored(u32),
+    /// Reading the sizes of the Huffman tables of a dynamic block.
+    TableSizes,
+    /// Reading the lengths of the codes used to encode the code lengths. The value is the number
+    /// read so far.
+    CodeLengthCodeLengths(usize),
+    /// Reading the code lengths of the literal/length and distance tables. The value is the number
+    /// read so far.
+    CodeLengths(usize),
+    Compressed,
+    Checksum,
+    Done,
+}
+
+/// Why decoding stopped.
+enum Stop {
+    /// More compressed data is needed to make progress.
+    NeedInput,
+    /// The window is full of output that the caller hasn't asked for yet.
+    OutputFull,
+    /// The compressed data is invalid.
+    Corrupt,
+}
+
+impl Inflater {
+    /// Creates a new decompressor for a `zlib` stream, including the header and Adler-32 trailer.
+    pub fn new() -> Inflater {
+        Inflater::with_window(vec![0; WINDOW_SIZE])
+    }
+
+    /// Returns this decompressor to its initial state, ready for a new stream. The window is kept
+    /// rather than reallocated.
+    #[cfg(any(feature = "builtin-inflate", not(feature = "zlib")))]
+    pub fn reset(&mut self) {
+        let window = mem::replace(&mut self.window, vec![]);
+        *self = Inflater::with_window(window)
+    }
+
+    fn with_window(window: Vec<u8>) -> Inflater {
+        Inflater {
+            state: State::Header,
+            final_block: false,
+            input_pos: 0,
+            bits: 0,
+            bit_count: 0,
+            window: window,
+            read_pos: 0,
+            write_pos: 0,
+            checksum_pos: 0,
+            literal_length_table: Huffman::new(),
+            distance_table: Huffman::new(),
+            literal_length_count: 0,
+            distance_count: 0,
+            code_length_count: 0,
+            code_length_table: Huffman::new(),
+            code_lengths: [0; 286 + 32],
+            checksum: Adler32::new(),
+            stored_checksum: None,
+            segment: false,
+            total_in: 0,
+            total_out: 0,
+        }
+    }
+
+    /// Decompresses as much of `input` as possible into `output`.
+    ///
+    /// Like `zlib`, this consumes only as much input as is needed to fill `output`, apart from at
+    /// most a byte's worth of bits, so the caller can tell from the input left over whether there
+    /// is any more output to come. Any input that isn't consumed should be passed in again on the
+    /// next call. Use `total_in()` and `total_out()` to find out how much was consumed and
+    /// produced. Once the end of the stream has been reached, any further input is consumed and
+    /// ignored.
+    pub fn decompress(&mut self, input: &[u8], output: &mut [u8]) -> Result<(), PngError> {
+        self.input_pos = 0;
+        let mut produced = 0;
+        loop {
+            produced += self.copy_out(&mut output[produced..]);
+            if produced == output.len() || self.state == State::Done {
+                break
+            }
+            let wanted = cmp::min(output.len() - produced, MAX_PENDING_OUTPUT);
+            match self.decode(input, wanted) {
+                Ok(()) | Err(Stop::OutputFull) => {}
+                Err(Stop::NeedInput) => {
+                    if self.write_pos == self.read_pos {
+                        break
+                    }
+                }
+                Err(Stop::Corrupt) => return Err(PngError::EntropyDecodingError),
+            }
+        }
+
+        if self.state == State::Done {
+            self.input_pos = input.len()
+        } else if produced < output.len() {
+            // We ran out of input partway through something that has to be decoded all at once.
+            // That never takes more than 48 bits, so the rest of the input fits in the bit buffer.
+            refill(input, &mut self.input_pos, &mut self.bits, &mut self.bit_count);
+            debug_assert!(self.input_pos == input.len());
+        } else {
+            // Hand back the whole bytes that we read ahead into the bit buffer but didn't use.
+            let unused = cmp::min(self.bit_count / 8, self.input_pos as u32);
+            self.input_pos -= unused as usize;
+            self.bit_count -= unused * 8;
+            self.bits &= (1 << self.bit_count) - 1;
+        }
+        self.total_in += self.input_pos as u64;
+        self.total_out += produced as u64;
+        Ok(())
+    }
+
+    /// Returns the total number of bytes of compressed data consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.total_in
+    }
+
+    /// Returns the total number of bytes of decompressed data produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    fn copy_out(&mut self, output: &mut [u8]) -> usize {
+        let length = cmp::min(output.len(), self.write_pos - self.read_pos);
+        output[0..length].clone_from_slice(&self.window[self.read_pos..(self.read_pos + length)]);
+        self.read_pos += length;
+        length
+    }
+
+    /// Decodes until at least `wanted` bytes of output are pending, the stream ends, or decoding
+    /// can't proceed.
+    fn decode(&mut self, input: &[u8], wanted: usize) -> Result<(), Stop> {
+        let result = self.decode_blocks(input, wanted);
+        self.update_checksum();
+        result
+    }
+
+    fn update_checksum(&mut self) {
+        self.checksum.update(&self.window[self.checksum_pos..self.write_pos]);
+        self.checksum_pos = self.write_pos
+    }
+
+    fn decode_blocks(&mut self, input: &[u8], wanted: usize) -> Result<(), Stop> {
+        loop {
+            if self.write_pos - self.read_pos >= wanted {
+                return Ok(())
+            }
+            match self.state {
+                State::Header => {
+                    let header = try!(self.read_bits(input, 16));
+                    let (cmf, flg) = (header & 0xff, header >> 8);
+                    if cmf & 0xf != 8 || (cmf >> 4) > 7 || ((cmf << 8) | flg) % 31 != 0 ||
+                            flg & 0x20 != 0 {
+                        return Err(Stop::Corrupt)
+                    }
+                    self.state = State::BlockHeader
+                }
+                State::BlockHeader => {
+                    if self.final_block {
+                        self.state = State::Checksum;
+                        continue
+                    }
+                    let saved = self.save();
+                    match self.read_block_header(input) {
+                        Ok(state) => self.state = state,
+                        Err(stop) => {
+                            self.restore(saved);
+                            return Err(stop)
+                        }
+                    }
+                }
+                State::Stored(remaining) => {
+                    if remaining == 0 {
+                        self.state = State::BlockHeader;
+                        continue
+                    }
+                    try!(self.make_room(1));
+                    let limit = wanted - (self.write_pos - self.read_pos);
+                    let remaining = try!(self.copy_stored(input, remaining, limit));
+                    self.state = State::Stored(remaining)
+                }
+                State::TableSizes => {
+                    let sizes = try!(self.read_bits(input, 14));
+                    self.literal_length_count = (sizes & 0x1f) as usize + 257;
+                    self.distance_count = ((sizes >> 5) & 0x1f) as usize + 1;
+                    self.code_length_count = (sizes >> 10) as usize + 4;
+                    if self.literal_length_count > 286 || self.distance_count > 30 {
+                        return Err(Stop::Corrupt)
+                    }
+                    self.code_lengths = [0; 286 + 32];
+                    self.state = State::CodeLengthCodeLengths(0)
+                }
+                State::CodeLengthCodeLengths(count) => {
+                    if count < self.code_length_count {
+                        let length = try!(self.read_bits(input, 3)) as u8;
+                        self.code_lengths[CODE_LENGTH_ORDER[count]] = length;
+                        self.state = State::CodeLengthCodeLengths(count + 1);
+                        continue
+                    }
+                    let mut code_length_code_lengths = [0; 19];
+                    code_length_code_lengths.clone_from_slice(&self.code_lengths[0..19]);
+                    try!(self.code_length_table.build(&code_length_code_lengths));
+                    self.state = State::CodeLengths(0)
+                }
+                State::CodeLengths(count) => {
+                    let total_count = self.literal_length_count + self.distance_count;
+                    if count < total_count {
+                        let saved = self.save();
+                        match self.read_code_lengths(input, count, total_count) {
+                            Ok(count) => self.state = State::CodeLengths(count),
+                            Err(stop) => {
+                                self.restore(saved);
+                                return Err(stop)
+                            }
+                        }
+                        continue
+                    }
+                    let Inflater {
+                        ref mut literal_length_table,
+                        ref mut distance_table,
+                        ref code_lengths,
+                        literal_length_count,
+                        ..
+                    } = *self;
+                    try!(literal_length_table.build(&code_lengths[0..literal_length_count]));
+                    try!(distance_table.build(&code_lengths[literal_length_count..total_count]));
+                    self.state = State::Compressed
+                }
+                State::Compressed => {
+                    if try!(self.decode_symbols(input, wanted)) {
+                        self.state = State::BlockHeader
+                    }
+                }
+                State::Checksum => {
+                    let bits_past_byte = self.bit_count % 8;
+                    self.bits >>= bits_past_byte;
+                    self.bit_count -= bits_past_byte;
+                    // The checksum is stored big-endian, while we read bits least significant
+                    // byte first.
+                    let expected = try!(self.read_bits(input, 32)).swap_bytes();
+                    self.update_checksum();
+                    if !self.segment && expected != self.checksum.value() {
+                        return Err(Stop::Corrupt)
+                    }
+                    self.stored_checksum = Some(expected);
+                    self.state = State::Done
+                }
+                State::Done => return Ok(()),
+            }
+        }
+    }
+
+    fn read_block_header(&mut self, input: &[u8]) -> Result<State, Stop> {
+        let header = try!(self.read_bits(input, 3));
+        let final_block = header & 1 != 0;
+        let state = match header >> 1 {
+            0 => {
+                let bits_past_byte = self.bit_count % 8;
+                self.bits >>= bits_past_byte;
+                self.bit_count -= bits_past_byte;
+                let length = try!(self.read_bits(input, 16));
+                let inverted_length = try!(self.read_bits(input, 16));
+                if length != !inverted_length & 0xffff {
+                    return Err(Stop::Corrupt)
+                }
+                State::Stored(length)
+            }
+            1 => {
+                let mut lengths = [0; 288 + 32];
+                for (index, length) in lengths.iter_mut().enumerate() {
+                    *length = match index {
+                        0..=143 => 8,
+                        144..=255 => 9,
+                        256..=279 => 7,
+                        280..=287 => 8,
+                        _ => 5,
+                    }
+                }
+                try!(self.literal_length_table.build(&lengths[0..288]));
+                try!(self.distance_table.build(&lengths[288..]));
+                State::Compressed
+            }
+            2 => State::TableSizes,
+            _ => return Err(Stop::Corrupt),
+        };
+        self.final_block = final_block;
+        Ok(state)
+    }
+
+    /// Reads one code length, or a run of repeated code lengths, starting with the `count`th.
+    /// Returns the number of code lengths read so far.
+    fn read_code_lengths(&mut self, input: &[u8], count: usize, total_count: usize)
+                         -> Result<usize, Stop> {
+        let symbol = {
+            let Inflater {
+                ref code_length_table,
+                ref mut input_pos,
+                ref mut bits,
+                ref mut bit_count,
+                ..
+            } = *self;
+            try!(decode_huffman(code_length_table, input, input_pos, bits, bit_count))
+        };
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                if count == 0 {
+                    return Err(Stop::Corrupt)
+                }
+                (self.code_lengths[count - 1], 3 + try!(self.read_bits(input, 2)) as usize)
+            }
+            17 => (0, 3 + try!(self.read_bits(input, 3)) as usize),
+            18 => (0, 11 + try!(self.read_bits(input, 7)) as usize),
+            _ => return Err(Stop::Corrupt),
+        };
+        if count + repeat > total_count {
+            return Err(Stop::Corrupt)
+        }
+        for length in &mut self.code_lengths[count..(count + repeat)] {
+            *length = value
+        }
+        Ok(count + repeat)
+    }
+
+    /// Decodes literal/length symbols until the end of the block (returning true), until at least
+    /// `wanted` bytes of output are pending, or until decoding can't proceed.
+    fn decode_symbols(&mut self, input: &[u8], wanted: usize) -> Result<bool, Stop> {
+        while self.write_pos - self.read_pos < wanted {
+            try!(self.make_room(MAX_MATCH_LENGTH));
+
+            // Decode a whole symbol at a time, so that we can back out if the input runs dry.
+            let saved = self.save();
+            match self.decode_symbol(input) {
+                Ok(true) => return Ok(true),
+                Ok(false) => {}
+                Err(stop) => {
+                    self.restore(saved);
+                    return Err(stop)
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Decodes a single literal, match, or end-of-block symbol. Returns true at the end of the
+    /// block.
+    #[inline]
+    fn decode_symbol(&mut self, input: &[u8]) -> Result<bool, Stop> {
+        let symbol = try!(self.decode_literal_length(input));
+        if symbol < 256 {
+            self.window[self.write_pos] = symbol as u8;
+            self.write_pos += 1;
+            return Ok(false)
+        }
+        if symbol == 256 {
+            return Ok(true)
+        }
+
+        let length_index = symbol as usize - 257;
+        if length_index >= LENGTH_BASES.len() {
+            return Err(Stop::Corrupt)
+        }
+        let length = LENGTH_BASES[length_index] as usize +
+            try!(self.read_bits(input, LENGTH_EXTRA_BITS[length_index] as u32)) as usize;
+
+        let distance_index = try!(self.decode_distance(input)) as usize;
+        if distance_index >= DISTANCE_BASES.len() {
+            return Err(Stop::Corrupt)
+        }
+        let distance = DISTANCE_BASES[distance_index] as usize +
+            try!(self.read_bits(input, DISTANCE_EXTRA_BITS[distance_index] as u32)) as usize;
+        if distance > self.write_pos {
+            return Err(Stop::Corrupt)
+        }
+
+        let (write_pos, source) = (self.write_pos, self.write_pos - distance);
+        if distance >= length {
+            let (head, tail) = self.window.split_at_mut(write_pos);
+            tail[0..length].clone_from_slice(&head[source..(source + length)]);
+        } else {
+            // The match overlaps the bytes being written, so copy one byte at a time.
+            for offset in 0..length {
+                self.window[write_pos + offset] = self.window[source + offset]
+            }
+        }
+        self.write_pos += length;
+        Ok(false)
+    }
+
+    #[inline]
+    fn decode_literal_length(&mut self, input: &[u8]) -> Result<u32, Stop> {
+        let Inflater {
+            ref literal_length_table,
+            ref mut input_pos,
+            ref mut bits,
+            ref mut bit_count,
+            ..
+        } = *self;
+        decode_huffman(literal_length_table, input, input_pos, bits, bit_count)
+    }
+
+    #[inline]
+    fn decode_distance(&mut self, input: &[u8]) -> Result<u32, Stop> {
+        let Inflater {
+            ref distance_table,
+            ref mut input_pos,
+            ref mut bits,
+            ref mut bit_count,
+            ..
+        } = *self;
+        decode_huffman(distance_table, input, input_pos, bits, bit_count)
+    }
+
+    /// Copies up to `limit` bytes of a stored block into the window, returning the number of bytes
+    /// remaining in the block.
+    fn copy_stored(&mut self, input: &[u8], mut remaining: u32, limit: usize) -> Result<u32, Stop> {
+        let (start, end) = (self.write_pos, cmp::min(self.window.len(), self.write_pos + limit));
+
+        // Drain whole bytes left over in the bit buffer first.
+        while remaining > 0 && self.bit_count >= 8 && self.write_pos < end {
+            self.window[self.write_pos] = self.bits as u8;
+            self.write_pos += 1;
+            self.bits >>= 8;
+            self.bit_count -= 8;
+            remaining -= 1
+        }
+        if remaining == 0 || self.write_pos == end {
+            return Ok(remaining)
+        }
+
+        let length = cmp::min(cmp::min(remaining as usize, input.len() - self.input_pos),
+                              end - self.write_pos);
+        if length == 0 {
+            return if self.write_pos == start {
+                Err(Stop::NeedInput)
+            } else {
+                Ok(remaining)
+            }
+        }
+        self.window[self.write_pos..(self.write_pos + length)]
+            .clone_from_slice(&input[self.input_pos..(self.input_pos + length)]);
+        self.write_pos += length;
+        self.input_pos += length;
+        Ok(remaining - length as u32)
+    }
+
+    /// Ensures that there is room in the window for at least `length` more bytes of output,
+    /// discarding history that is no longer needed.
+    fn make_room(&mut self, length: usize) -> Result<(), Stop> {
+        if self.write_pos + length <= self.window.len() {
+            return Ok(())
+        }
+        let keep_from = cmp::min(self.read_pos, self.write_pos.saturating_sub(HISTORY_SIZE));
+        if keep_from == 0 {
+            return Err(Stop::OutputFull)
+        }
+        self.update_checksum();
+
+        let kept = self.write_pos - keep_from;
+        if kept <= keep_from {
+            let (head, tail) = self.window.split_at_mut(keep_from);
+            head[0..kept].clone_from_slice(&tail[0..kept]);
+        } else {
+            for index in keep_from..self.write_pos {
+                self.window[index - keep_from] = self.window[index]
+            }
+        }
+        self.read_pos -= keep_from;
+        self.write_pos -= keep_from;
+        self.checksum_pos = self.write_pos;
+        Ok(())
+    }
+
+    fn read_bits(&mut self, input: &[u8], count: u32) -> Result<u32, Stop> {
+        if count == 0 {
+            return Ok(0)
+        }
+        refill(input, &mut self.input_pos, &mut self.bits, &mut self.bit_count);
+        if self.bit_count < count {
+            return Err(Stop::NeedInput)
+        }
+        let value = (self.bits & ((1 << count) - 1)) as u32;
+        self.bits >>= count;
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    fn save(&self) -> (usize, u64, u32) {
+        (self.input_pos, self.bits, self.bit_count)
+    }
+
+    fn restore(&mut self, saved: (usize, u64, u32)) {
+        let (input_pos, bits, bit_count) = saved;
+        self.input_pos = input_pos;
+        self.bits = bits;
+        self.bit_count = bit_count;
+    }
+}
+
+/// Returns the offsets in `data`, part of a `zlib` stream, just past every byte sequence that
+/// looks like the end of a full flush. These are candidates for `choose_split_points()`.
+pub fn find_full_flush_points(data: &[u8]) -> Vec<usize> {
+    data.windows(4)
+        .enumerate()
+        .filter(|&(_, bytes)| bytes == FULL_FLUSH_MARKER)
+        .map(|(offset, _)| offset + 4)
+        .collect()
+}
+
+/// Returns the offsets in a `zlib` stream `length` bytes long at which to split it into at most
+/// `segment_count` segments for a `ParallelInflater`.
+///
+/// Candidates come from `find_full_flush_points()` and from metadata such as Apple's `iDOT`
+/// chunk. Of those, the ones closest to dividing the stream evenly are chosen.
+pub fn choose_split_points(length: usize, mut candidates: Vec<usize>, segment_count: u32)
+                           -> Vec<usize> {
+    // Nothing can be split off before the end of the header or after the start of the trailer.
+    candidates.retain(|&offset| offset > 2 && offset + 4 < length);
+    candidates.sort();
+    candidates.dedup();
+
+    let mut split_points: Vec<usize> = vec![];
+    for segment_index in 1..(segment_count as usize) {
+        let target = length * segment_index / segment_count as usize;
+        let nearest = candidates.iter().cloned().min_by_key(|&offset| {
+            if offset > target {
+                offset - target
+            } else {
+                target - offset
+            }
+        });
+        if let Some(nearest) = nearest {
+            if split_points.last().map_or(true, |&last| nearest > last) {
+                split_points.push(nearest)
+            }
+        }
+    }
+    split_points
+}
+
+/// Decompresses a `zlib` stream that has been split into segments, each on a thread of its own,
+/// starting on each segment as soon as it has arrived.
+///
+/// The split points need only be plausible. If any of them turns out not to be a full flush
+/// point, the rest of the stream is decompressed serially on the calling thread instead.
+pub struct ParallelInflater {
+    /// The results of the segments that haven't been returned yet, in order, each of which
+    /// arrives once its thread has finished. Each thread hands back its segment's compressed data
+    /// along with the result, in case it's needed for decompressing serially.
+    segments: VecDeque<Receiver<(Vec<u8>, Result<InflatedSegment, PngError>)>>,
+    segments_added: usize,
+    /// Whether the last segment has been added.
+    ended: bool,
+    /// Whether a segment failed to decompress on its own, so that the rest of the stream has to be
+    /// decompressed serially once it has all been added.
+    serial: bool,
+    /// The compressed data of the segments returned so far, and, once decompressing serially, of
+    /// every segment added.
+    compressed_data: Vec<u8>,
+    /// The number of bytes of decompressed data returned so far.
+    length: usize,
+    checksum: Adler32,
+}
+
+impl ParallelInflater {
+    pub fn new() -> ParallelInflater {
+        ParallelInflater {
+            segments: VecDeque::new(),
+            segments_added: 0,
+            ended: false,
+            serial: false,
+            compressed_data: vec![],
+            length: 0,
+            checksum: Adler32::new(),
+        }
+    }
+
+    /// Starts decompressing the next segment of the stream, which is the `last` one if it runs to
+    /// the end of the stream.
+    pub fn add_segment(&mut self, data: Vec<u8>, last: bool) {
+        let first = self.segments_added == 0;
+        self.segments_added += 1;
+        self.ended = last;
+        if self.serial {
+            self.compressed_data.extend_from_slice(&data);
+            return
+        }
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let result = inflate_segment(&data, first, last, usize::MAX);
+            drop(sender.send((data, result)))
+        });
+        self.segments.push_back(receiver)
+    }
+
+    /// Waits for the next segment to be decompressed, and returns the decompressed data. Returns
+    /// `None` once every segment added so far has been returned.
+    ///
+    /// If decompressing serially turns out to be necessary, the rest of the stream is returned as
+    /// a single segment once the last segment has been added, and `None` until then.
+    pub fn next_segment(&mut self) -> Option<Result<Vec<u8>, PngError>> {
+        self.next_segment_if(true)
+    }
+
+    /// Like `next_segment()`, but returns `None` instead of waiting if the next segment hasn't
+    /// been decompressed yet.
+    pub fn next_finished_segment(&mut self) -> Option<Result<Vec<u8>, PngError>> {
+        self.next_segment_if(false)
+    }
+
+    fn next_segment_if(&mut self, wait: bool) -> Option<Result<Vec<u8>, PngError>> {
+        if self.serial {
+            if !self.ended {
+                return None
+            }
+            // Every thread has finished by now, so this returns the rest of the stream just once.
+            self.serial = false;
+            return Some(self.inflate_rest_serially())
+        }
+
+        // If a thread hangs up without sending anything, it panicked.
+        let received = match self.segments.front() {
+            None => return None,
+            Some(receiver) if wait => receiver.recv().ok(),
+            Some(receiver) => {
+                match receiver.try_recv() {
+                    Ok(received) => Some(received),
+                    Err(TryRecvError::Empty) => return None,
+                    Err(TryRecvError::Disconnected) => None,
+                }
+            }
+        };
+        self.segments.pop_front();
+        let (compressed_data, result) = match received {
+            Some(received) => received,
+            None => return Some(Err(PngError::EntropyDecodingError)),
+        };
+        self.compressed_data.extend_from_slice(&compressed_data);
+
+        // Every segment that decompresses successfully after the ones before it did matches the
+        // stream decompressed serially, since it can't refer back past its own start.
+        let segment = match result {
+            Ok(segment) => segment,
+            Err(_) => return self.fall_back_to_serial(wait),
+        };
+        self.checksum.combine(&segment.checksum, segment.data.len() as u64);
+        if let Some(stored_checksum) = segment.stored_checksum {
+            if stored_checksum != self.checksum.value() {
+                return self.fall_back_to_serial(wait)
+            }
+        }
+        self.length += segment.data.len();
+        Some(Ok(segment.data))
+    }
+
+    /// Gives up on decompressing the segments separately, gathering up their compressed data so
+    /// that the stream can be decompressed serially once it has all been added.
+    fn fall_back_to_serial(&mut self, wait: bool) -> Option<Result<Vec<u8>, PngError>> {
+        for receiver in self.segments.drain(..) {
+            match receiver.recv() {
+                Ok((compressed_data, _)) => {
+                    self.compressed_data.extend_from_slice(&compressed_data)
+                }
+                Err(_) => return Some(Err(PngError::EntropyDecodingError)),
+            }
+        }
+        self.serial = true;
+        self.next_segment_if(wait)
+    }
+
+    /// Decompresses the whole stream serially, and returns what hasn't been returned already.
+    fn inflate_rest_serially(&mut self) -> Result<Vec<u8>, PngError> {
+        let mut data = try!(inflate(&self.compressed_data));
+        if data.len() < self.length {
+            return Err(PngError::EntropyDecodingError)
+        }
+        let rest = data.split_off(self.length);
+        self.length += rest.len();
+        Ok(rest)
+    }
+}
+
+/// Decompresses the whole `zlib` stream in `data` on the calling thread.
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, PngError> {
+    inflate_at_most(data, usize::MAX)
+}
+
+/// Decompresses the whole `zlib` stream in `data` on the calling thread, like `inflate()`, but
+/// fails once more than `max_length` bytes have been decompressed, so that a small stream can't
+/// make us allocate a huge amount of memory.
+pub fn inflate_at_most(data: &[u8], max_length: usize) -> Result<Vec<u8>, PngError> {
+    let segment = try!(inflate_segment(data, true, true, max_length));
+    if segment.stored_checksum != Some(segment.checksum.value()) {
+        return Err(PngError::EntropyDecodingError)
+    }
+    Ok(segment.data)
+}
+
+/// The last four bytes of the empty stored block that ends a full flush.
+const FULL_FLUSH_MARKER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+struct InflatedSegment {
+    data: Vec<u8>,
+    /// The checksum of `data` alone.
+    checksum: Adler32,
+    /// The checksum of the whole stream, if this is the last segment.
+    stored_checksum: Option<u32>,
+}
+
+/// Decompresses one segment of a split-up `zlib` stream.
+///
+/// Only the first segment begins with the `zlib` header, and only the last ends with the final
+/// block and the trailer. Fails unless every other segment ends exactly at a block boundary,
+/// which is what tells us that the split point was a real flush point. If the flush wasn't a full
+/// flush, the segment that follows it refers back to data before its start, which also fails.
+/// Decompressing more than `max_length` bytes fails too.
+fn inflate_segment(input: &[u8], first: bool, last: bool, max_length: usize)
+                   -> Result<InflatedSegment, PngError> {
+    let mut inflater = Inflater::new();
+    inflater.segment = true;
+    if !first {
+        inflater.state = State::BlockHeader
+    }
+
+    let mut data = vec![];
+    loop {
+        let (start_in, start_out) = (inflater.total_in() as usize, data.len());
+        data.resize(start_out + MAX_PENDING_OUTPUT, 0);
+        try!(inflater.decompress(&input[start_in..], &mut data[start_out..]));
+        data.truncate(inflater.total_out() as usize);
+        if data.len() > max_length {
+            return Err(PngError::EntropyDecodingError)
+        }
+        if data.len() < start_out + MAX_PENDING_OUTPUT {
+            break
+        }
+    }
+
+    let finished = if last {
+        inflater.state == State::Done
+    } else {
+        inflater.state == State::BlockHeader && !inflater.final_block && inflater.bit_count == 0
+    };
+    if !finished {
+        return Err(PngError::EntropyDecodingError)
+    }
+    Ok(InflatedSegment {
+        data: data,
+        checksum: inflater.checksum,
+        stored_checksum: inflater.stored_checksum,
+    })
+}
+
+#[inline]
+fn refill(input: &[u8], input_pos: &mut usize, bits: &mut u64, bit_count: &mut u32) {
+    while *bit_count <= 56 && *input_pos < input.len() {
+        *bits |= (input[*input_pos] as u64) << *bit_count;
+        *bit_count += 8;
+        *input_pos += 1;
+    }
+}
+
+#[inline]
+fn decode_huffman(table: &Huffman,
+                  input: &[u8],
+                  input_pos: &mut usize,
+                  bits: &mut u64,
+                  bit_count: &mut u32)
+                  -> Result<u32, Stop> {
+    if *bit_count < 16 {
+        refill(input, input_pos, bits, bit_count)
+    }
+
+    let entry = table.fast[(*bits & ((1 << FAST_BITS) - 1)) as usize];
+    let (symbol, length) = if entry != 0 {
+        ((entry & 0x1ff) as u32, (entry >> 9) as u32)
+    } else {
+        match table.decode_slowly(*bits) {
+            Some(symbol_and_length) => symbol_and_length,
+            None if *bit_count < 16 => return Err(Stop::NeedInput),
+            None => return Err(Stop::Corrupt),
+        }
+    };
+    if length > *bit_count {
+        return Err(Stop::NeedInput)
+    }
+    *bits >>= length;
+    *bit_count -= length;
+    Ok(symbol)
+}
+
+/// A canonical Huffman decoding table. This is the same layout that `stb_image` uses.
+struct Huffman {
+    /// Maps the next `FAST_BITS` bits of input to `(length << 9) | symbol`, or 0 if the code is
+    /// longer than `FAST_BITS` bits.
+    fast: [u16; 1 << FAST_BITS],
+    first_code: [u16; 16],
+    first_symbol: [u16; 16],
+    /// For each code length, one past the largest code of that length, shifted left so that it
+    /// has 16 bits.
+    max_code: [u32; 17],
+    lengths: [u8; 288],
+    symbols: [u16; 288],
+}
+
+impl Huffman {
+    fn new() -> Huffman {
+        Huffman {
+            fast: [0; 1 << FAST_BITS],
+            first_code: [0; 16],
+            first_symbol: [0; 16],
+            max_code: [0; 17],
+            lengths: [0; 288],
+            symbols: [0; 288],
+        }
+    }
+
+    fn build(&mut self, code_lengths: &[u8]) -> Result<(), Stop> {
+        let mut counts = [0u32; 17];
+        for &length in code_lengths {
+            counts[length as usize] += 1
+        }
+        counts[0] = 0;
+
+        for entry in self.fast.iter_mut() {
+            *entry = 0
+        }
+
+        let mut next_code = [0u32; 16];
+        let (mut code, mut symbol_index) = (0u32, 0u32);
+        for length in 1..16 {
+            next_code[length] = code;
+            self.first_code[length] = code as u16;
+            self.first_symbol[length] = symbol_index as u16;
+            code += counts[length];
+            if counts[length] != 0 && code - 1 >= (1 << length) {
+                return Err(Stop::Corrupt)
+            }
+            self.max_code[length] = code << (16 - length);
+            code <<= 1;
+            symbol_index += counts[length];
+        }
+        self.max_code[16] = 0x10000;
+
+        for (symbol, &length) in code_lengths.iter().enumerate() {
+            let length = length as usize;
+            if length == 0 {
+                continue
+            }
+            let index = (next_code[length] - self.first_code[length] as u32 +
+                         self.first_symbol[length] as u32) as usize;
+            self.lengths[index] = length as u8;
+            self.symbols[index] = symbol as u16;
+            if length as u32 <= FAST_BITS {
+                let mut fast_index = bit_reverse(next_code[length], length as u32) as usize;
+                while fast_index < (1 << FAST_BITS) {
+                    self.fast[fast_index] = ((length << 9) | symbol) as u16;
+                    fast_index += 1 << length
+                }
+            }
+            next_code[length] += 1
+        }
+        Ok(())
+    }
+
+    /// Decodes a code longer than `FAST_BITS` bits. Returns the symbol and the code length.
+    fn decode_slowly(&self, bits: u64) -> Option<(u32, u32)> {
+        let code = bit_reverse(bits as u32 & 0xffff, 16);
+        let mut length = FAST_BITS as usize + 1;
+        while code >= self.max_code[length] {
+            length += 1
+        }
+        if length >= 16 {
+            return None
+        }
+        let index = ((code >> (16 - length)) - self.first_code[length] as u32 +
+                     self.first_symbol[length] as u32) as usize;
+        if index >= self.lengths.len() || self.lengths[index] as usize != length {
+            return None
+        }
+        Some((self.symbols[index] as u32, length as u32))
+    }
+}
+
+fn bit_reverse(mut value: u32, bits: u32) -> u32 {
+    let mut result = 0;
+    for _ in 0..bits {
+        result = (result << 1) | (value & 1);
+        value >>= 1
+    }
+    result
+}
+
+/// A running Adler-32 checksum, as used by `zlib`.
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Adler32 {
+    pub fn new() -> Adler32 {
+        Adler32 {
+            a: 1,
+            b: 0,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        // 5552 is the largest number of bytes that can be summed before `b` could overflow.
+        for chunk in data.chunks(5552) {
+            for &byte in chunk {
+                self.a += byte as u32;
+                self.b += self.a
+            }
+            self.a %= 65521;
+            self.b %= 65521
+        }
+    }
+
+    pub fn value(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+
+    /// Updates this checksum as though the `length` bytes that `other` is the checksum of had been
+    /// passed to `update()`.
+    pub fn combine(&mut self, other: &Adler32, length: u64) {
+        let remainder = (length % 65521) as u32;
+        let b = (remainder * self.a) % 65521 + self.b + other.b + 65521 - remainder;
+        let a = self.a + other.a + 65521 - 1;
+        self.a = a % 65521;
+        self.b = b % 65521
+    }
+}
@@ -5,19 +5,30 @@
 //! A parallel PNG decoder.
 //!
 //! For the simple API, see the `simple` module. For the more complex but more flexible API, see
-//! the `imageloader` module.
+//! the `imageloader` module. With the `async` feature enabled, the `asynchronous` module decodes
+//! images from asynchronous streams.
 
 extern crate byteorder;
+#[cfg(feature = "zlib")]
 extern crate flate2;
+#[cfg(feature = "async")]
+extern crate futures;
 extern crate libc;
+#[cfg(feature = "async")]
+extern crate tokio_io;
 
 use std::io;
 
+#[cfg(feature = "async")]
+pub mod asynchronous;
 pub mod capi;
 pub mod imageloader;
 pub mod metadata;
 pub mod simple;
+mod inflate;
+mod mmap;
 mod prediction;
+mod workers;
 
 #[cfg(test)]
 pub mod test;
@@ -41,5 +52,10 @@ pub enum PngError {
     InvalidScanlinePredictor(u8),
     /// The entropy decoding (`zlib` decompression) failed. This indicates corrupt image data.
     EntropyDecodingError,
+    /// The buffer supplied to decode the image into was too small for it. The string contains
+    /// detailed information about the error.
+    InvalidBuffer(String),
+    /// Decoding was cancelled with a `CancelHandle`.
+    Cancelled,
 }
 
@@ -4,11 +4,16 @@
 
 use PngError;
 use byteorder::{self, BigEndian, ByteOrder, ReadBytesExt};
-use std::io::Read;
+use inflate;
+use std::io::{self, Read};
 
 // 8 for the header; 12 for the chunk info (including CRC); 13 for the header.
 const METADATA_SIZE: usize = 8 + 12 + 13;
 
+/// The most that the contents of a compressed ancillary chunk may decompress to. Chunks that
+/// decompress to more are ignored.
+const MAX_DECOMPRESSED_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
 /// Represents image dimensions in pixels.
 ///
 /// It is possible to convert pairs of type `(T1, T2)`, where `T1` and `T2` are primitive
@@ -233,6 +238,260 @@ impl Metadata {
     }
 }
 
+/// What `ImageInfo::probe()` finds out about a PNG image from the chunks that precede its image
+/// data.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct ImageInfo {
+    /// The metadata from the `IHDR` chunk, including whether the image is interlaced.
+    pub metadata: Metadata,
+    /// The number of entries in the palette, or 0 if there is no `PLTE` chunk.
+    pub palette_size: u32,
+    /// True if there is a `tRNS` chunk, which makes some colors of the image transparent.
+    pub has_transparency: bool,
+    /// The color space of the image, as described by its color space chunks.
+    pub color_space: ColorSpace,
+    /// The text from the `tEXt`, `zTXt` and `iTXt` chunks, in order.
+    pub text: Vec<TextChunk>,
+    /// The contents of the `acTL` chunk, if this is an animated PNG.
+    pub animation: Option<Animation>,
+}
+
+/// The color space information found in a PNG image. Each field is `None` if the corresponding
+/// chunk isn't present.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct ColorSpace {
+    /// The gamma of the image times 100,000, from the `gAMA` chunk.
+    pub gamma: Option<u32>,
+    /// The x and y chromaticities of the white point and then the red, green and blue primaries,
+    /// each times 100,000, from the `cHRM` chunk.
+    pub chromaticities: Option<[u32; 8]>,
+    /// The rendering intent from the `sRGB` chunk, which means that the image is in the sRGB color
+    /// space.
+    pub srgb_rendering_intent: Option<u8>,
+    /// The embedded ICC profile from the `iCCP` chunk.
+    pub icc_profile: Option<IccProfile>,
+}
+
+/// An ICC profile embedded in a PNG image.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct IccProfile {
+    pub name: String,
+    /// The profile itself, decompressed.
+    pub profile: Vec<u8>,
+}
+
+/// A piece of text from a `tEXt`, `zTXt` or `iTXt` chunk.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct TextChunk {
+    /// The keyword that says what the text is, such as `Title` or `Author`.
+    pub keyword: String,
+    /// The text itself, decompressed if necessary.
+    pub text: String,
+    /// The language of the text, for `iTXt` chunks that specify one. Empty otherwise.
+    pub language_tag: String,
+    /// The keyword translated into that language, for `iTXt` chunks that specify one. Empty
+    /// otherwise.
+    pub translated_keyword: String,
+}
+
+/// The animation control information of an animated PNG, from its `acTL` chunk.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Animation {
+    /// The number of frames in the animation.
+    pub frame_count: u32,
+    /// The number of times to play the animation, or 0 to loop forever.
+    pub play_count: u32,
+    /// True if the static image, which decoders that don't support animation display, is also the
+    /// first frame of the animation. Otherwise, it isn't part of the animation at all.
+    pub static_image_is_first_frame: bool,
+}
+
+impl ImageInfo {
+    /// Reads the metadata and the rest of the chunks up to the first `IDAT` chunk from the start
+    /// of a PNG image stream, and reports what they say about the image.
+    ///
+    /// This is much cheaper than creating an `ImageLoader`: no image data is decoded, and no
+    /// threads are spawned. Ancillary chunks that are malformed or that decompress to more than 4MB
+    /// are ignored, and so are any that come after the image data, such as text chunks at the end
+    /// of the stream.
+    pub fn probe<R: ?Sized + Read>(reader: &mut R) -> Result<ImageInfo,PngError> {
+        let mut info = ImageInfo {
+            metadata: try!(Metadata::load(reader)),
+            palette_size: 0,
+            has_transparency: false,
+            color_space: ColorSpace::default(),
+            text: vec![],
+            animation: None,
+        };
+        let mut frame_control_seen = false;
+
+        loop {
+            let chunk_header = try!(ChunkHeader::load(reader));
+            match &chunk_header.chunk_type {
+                b"IDAT" | b"IEND" => break,
+                b"PLTE" => {
+                    try!(skip_chunk_data(reader, chunk_header.length));
+                    info.palette_size = chunk_header.length / 3
+                }
+                b"tRNS" => {
+                    try!(skip_chunk_data(reader, chunk_header.length));
+                    info.has_transparency = true
+                }
+                b"fcTL" => {
+                    try!(skip_chunk_data(reader, chunk_header.length));
+                    frame_control_seen = true
+                }
+                b"gAMA" | b"cHRM" | b"sRGB" | b"iCCP" | b"tEXt" | b"zTXt" | b"iTXt" | b"acTL" => {
+                    let data = try!(read_chunk_data(reader, chunk_header.length));
+                    info.add_chunk(&chunk_header.chunk_type, &data)
+                }
+                _ => try!(skip_chunk_data(reader, chunk_header.length)),
+            }
+        }
+
+        if let Some(ref mut animation) = info.animation {
+            animation.static_image_is_first_frame = frame_control_seen
+        }
+        Ok(info)
+    }
+
+    /// Records whatever `data`, the contents of a chunk of the given type, says about the image.
+    fn add_chunk(&mut self, chunk_type: &[u8; 4], data: &[u8]) {
+        match chunk_type {
+            b"gAMA" if data.len() == 4 => {
+                self.color_space.gamma = Some(BigEndian::read_u32(data))
+            }
+            b"cHRM" if data.len() == 32 => {
+                let mut chromaticities = [0; 8];
+                for (i, chromaticity) in chromaticities.iter_mut().enumerate() {
+                    *chromaticity = BigEndian::read_u32(&data[(i * 4)..])
+                }
+                self.color_space.chromaticities = Some(chromaticities)
+            }
+            b"sRGB" if data.len() == 1 => self.color_space.srgb_rendering_intent = Some(data[0]),
+            b"iCCP" => {
+                // The name is followed by the compression method, which must be 0, and the
+                // compressed profile.
+                if let Some((name, rest)) = split_at_nul(data) {
+                    if let Some(profile) = decompress(rest) {
+                        self.color_space.icc_profile = Some(IccProfile {
+                            name: latin1_to_string(name),
+                            profile: profile,
+                        })
+                    }
+                }
+            }
+            b"tEXt" => {
+                if let Some((keyword, text)) = split_at_nul(data) {
+                    self.text.push(TextChunk {
+                        keyword: latin1_to_string(keyword),
+                        text: latin1_to_string(text),
+                        language_tag: String::new(),
+                        translated_keyword: String::new(),
+                    })
+                }
+            }
+            b"zTXt" => {
+                if let Some((keyword, rest)) = split_at_nul(data) {
+                    if let Some(text) = decompress(rest) {
+                        self.text.push(TextChunk {
+                            keyword: latin1_to_string(keyword),
+                            text: latin1_to_string(&text),
+                            language_tag: String::new(),
+                            translated_keyword: String::new(),
+                        })
+                    }
+                }
+            }
+            b"iTXt" => {
+                // The keyword is followed by a compression flag, the compression method, the
+                // language tag, the translated keyword, and the UTF-8 text.
+                let (keyword, rest) = match split_at_nul(data) {
+                    Some((keyword, rest)) if rest.len() >= 2 => (keyword, rest),
+                    _ => return,
+                };
+                let (compressed, compression_method) = (rest[0] != 0, rest[1]);
+                let (language_tag, rest) = match split_at_nul(&rest[2..]) {
+                    Some(split) => split,
+                    None => return,
+                };
+                let (translated_keyword, text) = match split_at_nul(rest) {
+                    Some(split) => split,
+                    None => return,
+                };
+                let text = if compressed {
+                    match inflate::inflate_at_most(text, MAX_DECOMPRESSED_CHUNK_SIZE) {
+                        Ok(ref text) if compression_method == 0 => {
+                            String::from_utf8_lossy(text).into_owned()
+                        }
+                        _ => return,
+                    }
+                } else {
+                    String::from_utf8_lossy(text).into_owned()
+                };
+                self.text.push(TextChunk {
+                    keyword: latin1_to_string(keyword),
+                    text: text,
+                    language_tag: String::from_utf8_lossy(language_tag).into_owned(),
+                    translated_keyword: String::from_utf8_lossy(translated_keyword).into_owned(),
+                })
+            }
+            b"acTL" if data.len() == 8 => {
+                self.animation = Some(Animation {
+                    frame_count: BigEndian::read_u32(&data[0..4]),
+                    play_count: BigEndian::read_u32(&data[4..8]),
+                    static_image_is_first_frame: false,
+                })
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Reads the contents of a chunk of the given length, and skips over its CRC.
+fn read_chunk_data<R: ?Sized + Read>(reader: &mut R, length: u32) -> Result<Vec<u8>,PngError> {
+    let mut data = vec![];
+    let mut chunk = Read::take(reader, length as u64 + 4);
+    let bytes_read = try!(chunk.read_to_end(&mut data).map_err(PngError::Io));
+    if bytes_read < length as usize + 4 {
+        return Err(format_eof("when reading chunk data"))
+    }
+    data.truncate(length as usize);
+    Ok(data)
+}
+
+/// Skips over the contents and the CRC of a chunk of the given length.
+fn skip_chunk_data<R: ?Sized + Read>(reader: &mut R, length: u32) -> Result<(),PngError> {
+    let mut chunk = Read::take(reader, length as u64 + 4);
+    let bytes_skipped = try!(io::copy(&mut chunk, &mut io::sink()).map_err(PngError::Io));
+    if bytes_skipped < length as u64 + 4 {
+        return Err(format_eof("when reading chunk data"))
+    }
+    Ok(())
+}
+
+/// Splits `data` at the first null byte, which separates the fields of text chunks, leaving out
+/// the null byte itself.
+fn split_at_nul(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    data.iter().position(|&byte| byte == 0).map(|nul| (&data[..nul], &data[(nul + 1)..]))
+}
+
+/// Decompresses data that is preceded by a compression method byte, as in `zTXt` and `iCCP`
+/// chunks. Returns `None` if the compression method is unknown, the data is invalid, or it
+/// decompresses to more than `MAX_DECOMPRESSED_CHUNK_SIZE` bytes.
+fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+    match data.split_first() {
+        Some((&0, compressed_data)) => {
+            inflate::inflate_at_most(compressed_data, MAX_DECOMPRESSED_CHUNK_SIZE).ok()
+        }
+        _ => None,
+    }
+}
+
+fn latin1_to_string(data: &[u8]) -> String {
+    data.iter().map(|&byte| byte as char).collect()
+}
+
 trait MapByteOrderError {
     type OkType;
     fn map_byteorder_error(self, description: &'static str) -> Result<Self::OkType,PngError>;
@@ -0,0 +1,109 @@
+// parng/mmap.rs
+//
+// Copyright (c) 2016 Mozilla Foundation
+
+//! Read-only memory mappings of whole files.
+
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+
+#[cfg(unix)]
+use libc::{self, c_void};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::ptr;
+#[cfg(unix)]
+use std::slice;
+
+/// A file mapped read-only into memory. Dereferences to the contents of the file.
+///
+/// On platforms without `mmap()`, the file is simply read into memory instead.
+pub struct MappedFile {
+    #[cfg(unix)]
+    address: *mut c_void,
+    #[cfg(unix)]
+    length: usize,
+    #[cfg(not(unix))]
+    data: Vec<u8>,
+}
+
+impl MappedFile {
+    #[cfg(unix)]
+    pub fn open<P>(path: P) -> io::Result<MappedFile> where P: AsRef<Path> {
+        let file = try!(File::open(path));
+        let length = try!(file.metadata()).len() as usize;
+
+        // `mmap()` refuses to map empty files.
+        if length == 0 {
+            return Ok(MappedFile {
+                address: ptr::null_mut(),
+                length: 0,
+            })
+        }
+
+        unsafe {
+            let address = libc::mmap(ptr::null_mut(),
+                                     length,
+                                     libc::PROT_READ,
+                                     libc::MAP_PRIVATE,
+                                     file.as_raw_fd(),
+                                     0);
+            if address == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error())
+            }
+
+            // We read the file from front to back exactly once. This is only a hint, so ignore
+            // failure.
+            libc::madvise(address, length, libc::MADV_SEQUENTIAL);
+
+            Ok(MappedFile {
+                address: address,
+                length: length,
+            })
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn open<P>(path: P) -> io::Result<MappedFile> where P: AsRef<Path> {
+        use std::io::Read;
+
+        let mut data = vec![];
+        try!(try!(File::open(path)).read_to_end(&mut data));
+        Ok(MappedFile {
+            data: data,
+        })
+    }
+}
+
+impl Deref for MappedFile {
+    type Target = [u8];
+
+    #[cfg(unix)]
+    fn deref(&self) -> &[u8] {
+        if self.length == 0 {
+            return &[]
+        }
+        unsafe {
+            slice::from_raw_parts(self.address as *const u8, self.length)
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(unix)]
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        if self.length != 0 {
+            unsafe {
+                libc::munmap(self.address, self.length);
+            }
+        }
+    }
+}
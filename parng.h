@@ -27,6 +27,9 @@
 #define PARNG_ERROR_INVALID_SCANLINE_PREDICTOR                  4
 #define PARNG_ERROR_ENTROPY_DECODING_ERROR                      5
 #define PARNG_ERROR_NO_DATA_PROVIDER                            6
+#define PARNG_ERROR_INVALID_ARGUMENT                            7
+#define PARNG_ERROR_INVALID_BUFFER                              8
+#define PARNG_ERROR_CANCELLED                                   9
 
 #define PARNG_FILTER_METHOD_ADAPTIVE                            0
 
@@ -42,10 +45,20 @@
 #define PARNG_LEVEL_OF_DETAIL_ADAM7_5                           5
 #define PARNG_LEVEL_OF_DETAIL_ADAM7_6                           6
 
+#define PARNG_SCALE_HALF                                        2
+#define PARNG_SCALE_QUARTER                                     4
+#define PARNG_SCALE_EIGHTH                                      8
+
 #define PARNG_SEEK_FROM_START                                   0
 #define PARNG_SEEK_FROM_CURRENT                                 1
 #define PARNG_SEEK_FROM_END                                     2
 
+// A handle that cancels the decoding of an image.
+//
+// A handle that cancels the decoding of an image, which may be used from any thread. See
+// `parng_image_loader_create_cancel_handle`.
+typedef struct parng_cancel_handle parng_cancel_handle;
+
 // The color type used in an image.
 //
 // The color type used in an image. These color types directly correspond to the color types
@@ -67,11 +80,22 @@ typedef uint32_t parng_compression_method;
 // you can choose any method you wish to store the image data and it will be transparent to
 // `parng`.
 //
-// Be aware that the data provider will be called on a background thread; i.e. not the thread it
-// was created on! You must ensure proper synchronization between the main thread and that
-// background thread if you wish to communicate between them.
+// Be aware that, unless the image loader was created with `parng_image_loader_create_inline`, the
+// data provider will be called on a background thread; i.e. not the thread it was created on! You
+// must ensure proper synchronization between the main thread and that background thread if you
+// wish to communicate between them.
 typedef struct parng_data_provider parng_data_provider;
 
+// A fixed set of background threads that many image loaders can share.
+//
+// A fixed set of background threads that many image loaders can share.
+//
+// By default, every image loader spawns a thread of its own to perform prediction and color
+// conversion. When decoding many small images, the cost of spawning and tearing down those threads
+// can dominate. Instead, create a pool once and pass it to
+// `parng_image_loader_create_with_pool` for each image.
+typedef struct parng_decoder_pool parng_decoder_pool;
+
 // Errors that can occur while decoding a PNG image.
 typedef uint32_t parng_error;
 
@@ -128,12 +152,24 @@ typedef struct parng_metadata parng_metadata;
 // An object that defines the low-level access to the data stream.
 typedef struct parng_reader parng_reader;
 
+// A snapshot of how far the loading of an image has got.
+typedef struct parng_progress parng_progress;
+
+// A rectangular region of an image, in pixels.
+typedef struct parng_region parng_region;
+
 // Data providers use this to supply scanlines to `parng` in response to prediction requests.
 typedef struct parng_scanlines_for_prediction parng_scanlines_for_prediction;
 
 // Data providers use this to supply scanlines to `parng` in response to RGBA conversion requests.
 typedef struct parng_scanlines_for_rgba_conversion parng_scanlines_for_rgba_conversion;
 
+// A factor to downscale an image by as it is decoded.
+//
+// A factor to downscale an image by as it is decoded. The dimensions of the downscaled image are
+// those of the original image divided by the factor, rounded up.
+typedef uint32_t parng_scale;
+
 // Specifies the position in a stream that a seek offset is relative to.
 typedef uint32_t parng_seek_from;
 
@@ -259,9 +295,10 @@ struct parng_scanlines_for_rgba_conversion {
 // you can choose any method you wish to store the image data and it will be transparent to
 // `parng`.
 //
-// Be aware that the data provider will be called on a background thread; i.e. not the thread it
-// was created on! You must ensure proper synchronization between the main thread and that
-// background thread if you wish to communicate between them.
+// Be aware that, unless the image loader was created with `parng_image_loader_create_inline`, the
+// data provider will be called on a background thread; i.e. not the thread it was created on! You
+// must ensure proper synchronization between the main thread and that background thread if you
+// wish to communicate between them.
 struct parng_data_provider {
     // Called when `parng` needs to predict a scanline.
     //
@@ -291,11 +328,10 @@ struct parng_data_provider {
     // Called when `parng` has finished prediction for a scanline, optionally at a specific level
     // of detail.
     //
-    // If the image is in RGBA or grayscale-alpha format, then the scanline is entirely finished
-    // at this time. Otherwise, unless the image is in indexed format, the scanline is finished,
-    // but the alpha values are not yet valid. Finally, if the image is in indexed format, the
-    // scanline palette values are correct, but the indexed-to-truecolor conversion has not
-    // occurred yet, so the scanline is not yet suitable for display.
+    // If the image is in RGBA format, then the scanline is entirely finished at this time.
+    // Otherwise, the scanline still needs to be converted to RGBA before it is suitable for
+    // display. If the image is in indexed format, the scanline palette values are correct at this
+    // point.
     //
     // `user_data` is the contents of the data provider's `user_data` field.
     void (*prediction_complete_for_scanline)(uint32_t scanline,
@@ -307,7 +343,12 @@ struct parng_data_provider {
     // Called when `parng` needs to perform RGBA conversion for a scanline, optionally at a
     // specific level of detail. `lod` specifies the level of detail, if the image is interlaced.
     // `indexed` will have a nonzero value if the image is indexed. `user_data` is the contents of
-    // the data provider's `user_data` field.
+    // the data provider's `user_data` field. The returned RGBA scanline must begin at the first
+    // pixel of the scanline at this level of detail.
+    //
+    // For most images, this is called immediately after prediction completes for each scanline.
+    // For grayscale-alpha images, it is called once the next scanline at the same level of detail
+    // has been predicted, or once the last scanline at that level of detail has been predicted.
     //
     // This method will be called only if the image is not RGBA.
     void (*fetch_scanlines_for_rgba_conversion)(uint32_t scanline,
@@ -331,6 +372,12 @@ struct parng_data_provider {
     // Called when `parng` has completely finished decoding the image.
     void (*finished)(void *user_data);
 
+    // Called instead of `finished` if decoding is cancelled.
+    //
+    // Called instead of `finished` if decoding is cancelled with `parng_cancel_handle_cancel`. No
+    // more callbacks are called after this one.
+    void (*cancelled)(void *user_data);
+
     // An arbitrary pointer that `parng` passes to the `read` and `seek` functions.
     void *user_data;
 };
@@ -349,7 +396,7 @@ struct parng_image {
     // equal to `4 * width`.
     //
     // Because of SIMD alignment restrictions, `parng` may well choose a value greater than `4 *
-    // width` here.
+    // width` here, unless the image was loaded with `parng_image_load_from_memory_with_stride`.
     size_t stride;
 
     // The number of bytes in the `pixels` allocation.
@@ -405,6 +452,55 @@ struct parng_interlacing_info {
     uint8_t offset;
 };
 
+// A rectangular region of an image, in pixels.
+struct parng_region {
+    // The column of the leftmost pixels in the region.
+    uint32_t x;
+
+    // The row of the topmost pixels in the region.
+    uint32_t y;
+
+    // The width of the region.
+    uint32_t width;
+
+    // The height of the region.
+    uint32_t height;
+};
+
+// A snapshot of how far the loading of an image has got.
+struct parng_progress {
+    // The level of detail that image data is currently being decompressed for.
+    //
+    // The level of detail that image data is currently being decompressed for. This is
+    // `PARNG_LEVEL_OF_DETAIL_NONE` if the image isn't interlaced or the metadata hasn't been read
+    // yet.
+    parng_level_of_detail level_of_detail;
+
+    // The number of entries of `scanlines_decompressed` and `scanlines_predicted` that are used.
+    //
+    // The number of entries of `scanlines_decompressed` and `scanlines_predicted` that are used:
+    // 1 if the image isn't interlaced, 7 if it is, or 0 if the metadata hasn't been read yet.
+    uint32_t level_of_detail_count;
+
+    // The number of scanlines of each level of detail that have been decompressed.
+    uint32_t scanlines_decompressed[7];
+
+    // The number of scanlines of each level of detail that have been predicted.
+    //
+    // The number of scanlines of each level of detail that have been predicted and handed to the
+    // data provider.
+    uint32_t scanlines_predicted[7];
+
+    // The number of bytes of the image stream read so far.
+    uint64_t bytes_read;
+
+    // An estimate of how much of the image has been decoded, from 0.0 to 1.0.
+    //
+    // An estimate of how much of the image has been decoded, from 0.0 to 1.0, based on the number
+    // of pixels predicted. Levels of detail after the last one to be decoded aren't counted.
+    float fraction_complete;
+};
+
 #ifdef __cplusplus
 extern "C" {
 #endif
@@ -426,17 +522,147 @@ parng_error parng_image_load_from_file(parng_image *image, FILE *file);
 //
 // A convenience method that calls `parng_image_load` configured to read from an in-memory buffer.
 // `bytes` points to the PNG image stream in memory, and `length` represents its length in bytes.
+// The image data is decompressed directly out of `bytes` without being copied.
+// `PARNG_ERROR_INVALID_ARGUMENT` is returned if `bytes` is null and `length` isn't zero.
 parng_error parng_image_load_from_memory(parng_image *image, const uint8_t *bytes, size_t length);
 
+// Like `parng_image_load_from_memory`, but with scanlines `stride` bytes apart.
+//
+// Like `parng_image_load_from_memory`, but with scanlines `stride` bytes apart. Any `stride` of at
+// least `4 * width` may be chosen; for instance, `4 * width` for tightly packed pixels.
+// `PARNG_ERROR_INVALID_BUFFER` is returned if `stride` is too small.
+parng_error parng_image_load_from_memory_with_stride(parng_image *image,
+                                                     const uint8_t *bytes,
+                                                     size_t length,
+                                                     size_t stride);
+
+// Like `parng_image_load_from_memory`, but loads only the given region of the image.
+//
+// Like `parng_image_load_from_memory`, but loads only the given region of the image. The region is
+// clipped to the bounds of the image, and the returned image is the size of the clipped region.
+// Decoding stops once the bottom of the region is reached, and pixels to either side of it are
+// never stored or converted to RGBA. See `parng_image_loader_set_region_of_interest`.
+// `PARNG_ERROR_INVALID_ARGUMENT` is returned if `region` is null.
+parng_error parng_image_load_region_from_memory(parng_image *image,
+                                                const uint8_t *bytes,
+                                                size_t length,
+                                                const parng_region *region);
+
+// Like `parng_image_load_from_memory`, but downscales the image by the given factor as it is
+// decoded.
+//
+// Like `parng_image_load_from_memory`, but downscales the image by the given factor as it is
+// decoded. `scale` is one of `PARNG_SCALE_HALF`, `PARNG_SCALE_QUARTER`, or `PARNG_SCALE_EIGHTH`.
+// The full-size image is never held in memory.
+//
+// Each pixel of the returned image is the average of a block of pixels of the original image (a
+// box filter), except for pixels in partial blocks along the right and bottom edges.
+// Adam7-interlaced images are instead downscaled by taking the pixel at the top left of each
+// block, which the first few passes of the image supply, and decoding stops after those passes.
+//
+// `PARNG_ERROR_INVALID_ARGUMENT` is returned if `scale` is not one of the above.
+parng_error parng_image_load_scaled_from_memory(parng_image *image,
+                                                const uint8_t *bytes,
+                                                size_t length,
+                                                parng_scale scale);
+
+// Like `parng_image_load_from_memory`, but loads a reduced-resolution preview of an interlaced
+// image.
+//
+// Like `parng_image_load_from_memory`, but loads a reduced-resolution preview of an interlaced
+// image, decoding only up to the Adam7 pass `lod`. The returned image holds just the pixels that
+// the passes up to and including `lod` supply, packed together, so it is smaller than the full
+// image; the rest of the image data is never read. See
+// `parng_image_loader_set_last_level_of_detail`.
+//
+// Images that aren't interlaced, or a `lod` of `PARNG_LEVEL_OF_DETAIL_NONE`, are loaded in full.
+// `PARNG_ERROR_INVALID_ARGUMENT` is returned if `lod` isn't a valid level of detail.
+parng_error parng_image_load_preview_from_memory(parng_image *image,
+                                                 const uint8_t *bytes,
+                                                 size_t length,
+                                                 parng_level_of_detail lod);
+
+// A convenience method that loads the PNG image in the file at `path` by memory-mapping it.
+//
+// A convenience method that loads the PNG image in the file at `path` by memory-mapping it.
+// The image is decoded straight from the mapping, without any `fread` calls or copies. `path` is
+// a null-terminated path in the platform's encoding. Errors opening or mapping the file are
+// reported as `PARNG_ERROR_IO`, and `PARNG_ERROR_INVALID_ARGUMENT` is returned if `path` is null.
+parng_error parng_image_load_from_path(parng_image *image, const char *path);
+
+// Loads a PNG image held in memory directly into a buffer supplied by the caller.
+//
+// Loads a PNG image held in memory directly into a buffer supplied by the caller, without
+// allocating space for the image. `bytes` points to the PNG image stream in memory, and `length`
+// represents its length in bytes. The image is stored in `pixels` as big-endian RGBA, 32 bits per
+// pixel, with successive scanlines `stride` bytes apart.
+//
+// `stride` must be at least `4 * width`, and `pixels_length` must be at least
+// `stride * (height - 1) + 4 * width`. This is checked before anything is written to `pixels`,
+// and `PARNG_ERROR_INVALID_BUFFER` is returned if the buffer is too small, or if `pixels` is null.
+// On success, the dimensions of the image are stored in `width` and `height`.
+// `PARNG_ERROR_INVALID_ARGUMENT` is returned if `bytes` is null and `length` isn't zero, or if
+// `width` or `height` is null.
+parng_error parng_image_load_from_memory_into_buffer(const uint8_t *bytes,
+                                                     size_t length,
+                                                     uint8_t *pixels,
+                                                     size_t pixels_length,
+                                                     size_t stride,
+                                                     uint32_t *width,
+                                                     uint32_t *height);
+
 // Destroys a `parng_image` object and frees the memory pointed to by `pixels`.
 //
 // Destroys a `parng_image` object and frees the memory pointed to by `pixels`. This function does
 // not attempt to free the `image` pointer itself.
 void parng_image_destroy(parng_image *image);
 
+// Fills the `pool` pointer with a new pool of `thread_count` threads.
+//
+// Fills the `pool` pointer with a new pool of `thread_count` threads. If `thread_count` is zero,
+// `PARNG_ERROR_INVALID_ARGUMENT` is returned and `pool` is left untouched.
+parng_error parng_decoder_pool_create(parng_decoder_pool **pool, uint32_t thread_count);
+
+// Destroys the given `pool`.
+//
+// Destroys the given `pool`. Its threads exit once all image loaders created from it have also
+// been destroyed.
+void parng_decoder_pool_destroy(parng_decoder_pool *pool);
+
 // Fills the `image_loader` pointer with a new image loader ready to decode a PNG image.
 parng_error parng_image_loader_create(parng_image_loader **image_loader);
 
+// Fills the `image_loader` pointer with a new image loader that uses `worker_count` threads for
+// prediction.
+//
+// Fills the `image_loader` pointer with a new image loader that uses `worker_count` threads for
+// prediction.
+//
+// When `worker_count` is greater than 1, each scanline is split into columns that the worker
+// threads predict in parallel. The background thread is one of the workers, and it also hands the
+// results to the data provider. Images narrower than a few hundred pixels may not use every
+// worker. A `worker_count` of 1 is equivalent to `parng_image_loader_create`. If `worker_count`
+// is zero, `PARNG_ERROR_INVALID_ARGUMENT` is returned and `image_loader` is left untouched.
+parng_error parng_image_loader_create_with_worker_count(parng_image_loader **image_loader,
+                                                        uint32_t worker_count);
+
+// Fills the `image_loader` pointer with a new image loader that doesn't use any threads.
+//
+// Fills the `image_loader` pointer with a new image loader that performs prediction and color
+// conversion on the calling thread instead of a background thread. The data provider is called
+// synchronously from within `parng_image_loader_add_data`, and
+// `parng_image_loader_wait_until_finished` never blocks.
+parng_error parng_image_loader_create_inline(parng_image_loader **image_loader);
+
+// Fills the `image_loader` pointer with a new image loader that uses one of the threads in `pool`.
+//
+// Fills the `image_loader` pointer with a new image loader that uses one of the threads in `pool`
+// for prediction and color conversion instead of spawning a new thread. Each image loader created
+// this way is assigned to one of the pool's threads for its entire lifetime.
+// `PARNG_ERROR_INVALID_ARGUMENT` is returned if `pool` is null.
+parng_error parng_image_loader_create_with_pool(parng_image_loader **image_loader,
+                                                parng_decoder_pool *pool);
+
 // Destroys the given `image_loader`, freeing it and all memory associated with it.
 void parng_image_loader_destroy(parng_image_loader *image_loader);
 
@@ -457,6 +683,20 @@ parng_error parng_image_loader_add_data(parng_image_loader *image_loader,
                                         parng_reader *reader,
                                         parng_load_progress *load_progress);
 
+// Decodes image data from a PNG image stream held in memory.
+//
+// Decodes image data from a PNG image stream held in memory, decompressing it in place instead of
+// copying it through a reader. `bytes` must point to the start of the stream, and `length` is the
+// number of bytes of it that are available. If the stream is arriving gradually, call this
+// function again with the same stream and a larger `length` each time more of it is available.
+// Don't mix calls to this function with calls to `parng_image_loader_add_data` for the same image.
+// Otherwise, this behaves like `parng_image_loader_add_data`. `PARNG_ERROR_INVALID_ARGUMENT` is
+// returned if `bytes` is null and `length` isn't zero.
+parng_error parng_image_loader_add_data_from_memory(parng_image_loader *image_loader,
+                                                    const uint8_t *bytes,
+                                                    size_t length,
+                                                    parng_load_progress *load_progress);
+
 // Blocks the current thread until the image is fully decoded.
 //
 // Blocks the current thread until the image is fully decoded.
@@ -477,6 +717,74 @@ parng_error parng_image_loader_wait_until_finished(parng_image_loader *image_loa
 void parng_image_loader_set_data_provider(parng_image_loader *image_loader,
                                           parng_data_provider *data_provider);
 
+// Restricts decoding to a region of the image.
+//
+// Restricts decoding to a region of the image. The region is first clipped to the bounds of the
+// image, and `region` is updated to the clipped region.
+//
+// Everything above the bottom of the region still has to be decompressed and predicted, since
+// later scanlines refer to earlier ones, but decoding stops as soon as the region is complete,
+// without reading the rest of the image data. (If segments of the compressed image data are being
+// decompressed in parallel, they are all read first regardless.) The data provider is asked only
+// for the scanlines that intersect the region, and only for the pixels of those scanlines that lie
+// inside it, which are the only ones converted to RGBA. Its storage need therefore only be as
+// large as the region; see `parng_interlacing_info_init_in_region`. `parng` keeps its own copy of
+// the reference scanline, so no reference scanline is ever requested.
+//
+// This must be called after the metadata has been read and before image data is decoded; that is,
+// upon receiving `PARNG_LOAD_PROGRESS_NEED_DATA_PROVIDER_AND_MORE_DATA`. Otherwise, or if `region`
+// is null, `PARNG_ERROR_INVALID_ARGUMENT` is returned and the region of interest is left unchanged.
+parng_error parng_image_loader_set_region_of_interest(parng_image_loader *image_loader,
+                                                      parng_region *region);
+
+// Stops decoding once the given level of detail is complete.
+//
+// Stops decoding once the given level of detail is complete, without reading the rest of the
+// image data. (If segments of the compressed image data are being decompressed in parallel, which
+// only image loaders with more than one worker thread do, they are all read and decompressed first
+// regardless.) For interlaced images, this yields a reduced-resolution preview: for instance,
+// `PARNG_LEVEL_OF_DETAIL_ADAM7_0` alone supplies every pixel of an image an eighth of the width
+// and height. Once the pass is complete, `parng_image_loader_add_data` reports
+// `PARNG_LOAD_PROGRESS_FINISHED`, and the data provider's `finished` callback is called. This has
+// no effect on images that aren't interlaced, and `PARNG_LEVEL_OF_DETAIL_NONE` decodes every level
+// of detail as usual.
+//
+// This must be called after the metadata has been read and before image data is decoded; that is,
+// upon receiving `PARNG_LOAD_PROGRESS_NEED_DATA_PROVIDER_AND_MORE_DATA`. Otherwise, or if `lod`
+// isn't a valid level of detail, `PARNG_ERROR_INVALID_ARGUMENT` is returned and the last level of
+// detail is left unchanged.
+parng_error parng_image_loader_set_last_level_of_detail(parng_image_loader *image_loader,
+                                                        parng_level_of_detail lod);
+
+// Prepares the image loader to decode another image.
+//
+// Prepares the image loader to decode another image, keeping its predictor thread, decompressor and
+// buffers. If the current image hasn't been fully decoded, decoding is cancelled first, and this
+// function waits for the predictor thread to stop working on it. The data provider is dropped, and
+// any region of interest or last level of detail is cleared. Cancel handles created before the
+// reset have no effect on the next image.
+void parng_image_loader_reset(parng_image_loader *image_loader);
+
+// Fills the `cancel_handle` pointer with a new handle that cancels decoding of this image.
+//
+// Fills the `cancel_handle` pointer with a new handle that cancels decoding of this image. The
+// handle may be used from any thread, and it must be destroyed with `parng_cancel_handle_destroy`.
+void parng_image_loader_create_cancel_handle(parng_image_loader *image_loader,
+                                             parng_cancel_handle **cancel_handle);
+
+// Cancels decoding.
+//
+// Cancels decoding. The calling thread doesn't wait for decoding to stop. Rather, decompression
+// and prediction each stop at the next batch of scanlines, and the data provider's `cancelled`
+// callback is called instead of its `finished` callback. From then on,
+// `parng_image_loader_add_data` and `parng_image_loader_wait_until_finished` fail with
+// `PARNG_ERROR_CANCELLED`. If it's too late to cancel, because every scanline has already been
+// predicted, the image finishes decoding as usual.
+void parng_cancel_handle_cancel(parng_cancel_handle *cancel_handle);
+
+// Destroys the given `cancel_handle`.
+void parng_cancel_handle_destroy(parng_cancel_handle *cancel_handle);
+
 // Fills `metadata_result` with a copy of the image metadata.
 //
 // Fills `metadata_result` with a copy of the image metadata, which contains image dimensions and
@@ -488,6 +796,10 @@ void parng_image_loader_set_data_provider(parng_image_loader *image_loader,
 uint32_t parng_image_loader_get_metadata(parng_image_loader *image_loader,
                                          parng_metadata *metadata_result);
 
+// Fills `progress_result` with a snapshot of how far the loading of the image has got.
+void parng_image_loader_get_progress(parng_image_loader *image_loader,
+                                     parng_progress *progress_result);
+
 // Rounds the given stride in bytes up to the value that provides the best performance.
 //
 // Rounds the given stride in bytes up to the value that provides the best performance.
@@ -509,6 +821,19 @@ void parng_interlacing_info_init(parng_interlacing_info *interlacing_info,
                                  uint8_t color_depth,
                                  parng_level_of_detail lod);
 
+// Like `parng_interlacing_info_init`, but for a buffer holding only the given region of the image.
+//
+// Like `parng_interlacing_info_init`, but for a buffer holding only the given region of the image.
+// The row and offset are relative to the top left corner of the region, and the offset is that of
+// the first pixel of the scanline that lies inside the region. Returns 0, leaving
+// `interlacing_info` untouched, if no pixels of the scanline lie inside the region or `region` is
+// null, and 1 otherwise.
+uint32_t parng_interlacing_info_init_in_region(parng_interlacing_info *interlacing_info,
+                                               uint32_t y,
+                                               uint8_t color_depth,
+                                               parng_level_of_detail lod,
+                                               const parng_region *region);
+
 #ifdef __cplusplus
 }
 #endif
@@ -3,31 +3,40 @@
 // Copyright (c) 2016 Mozilla Foundation
 
 use PngError;
-use imageloader::{DataProvider, InterlacingInfo, LevelOfDetail, ScanlinesForPrediction};
-use imageloader::{ScanlinesForRgbaConversion, Transparency};
-use std::iter;
+use imageloader::{self, DataProvider, InterlacingInfo, LevelOfDetail, ScanlinesForPrediction};
+use imageloader::{Region, ScanlinesForRgbaConversion, Transparency};
+use std::cmp;
+use std::collections::HashMap;
 use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::thread;
+use workers::WorkerPool;
 
-static NO_LEVELS_OF_DETAIL: [LevelOfDetail; 1] = [LevelOfDetail::None];
-static ADAM7_LEVELS_OF_DETAIL: [LevelOfDetail; 7] = [
-    LevelOfDetail::Adam7(0),
-    LevelOfDetail::Adam7(1),
-    LevelOfDetail::Adam7(2),
-    LevelOfDetail::Adam7(3),
-    LevelOfDetail::Adam7(4),
-    LevelOfDetail::Adam7(5),
-    LevelOfDetail::Adam7(6),
-];
-
-pub enum MainThreadToPredictorThreadMsg {
+/// A message to a predictor thread, or to an inline predictor, whose data provider is a `P`.
+pub enum MainThreadToPredictorThreadMsg<P = DataProvider + Send> where P: ?Sized {
     /// Sets a new data provider.
-    SetDataProvider(Box<DataProvider>),
+    SetDataProvider(Box<P>),
+    /// Supplies the palette and transparency information needed for RGBA conversion.
+    ///
+    /// This is sent before any scanlines are predicted. The PNG specification requires `PLTE` and
+    /// `tRNS` chunks to precede the image data, so this information is always available by then.
+    SetRgbaConversionInfo(RgbaConversionInfo),
     /// The image is finished entropy decoding.
     Finished,
     Predict(PredictionRequest),
-    PerformRgbaConversion(PerformRgbaConversionRequest),
+    /// Sets a function to call after replying to each prediction request.
+    SetWakeUpCallback(Box<Fn() + Send>),
+    /// Supplies the flag that a `CancelHandle` sets. Once it's set, prediction requests are
+    /// returned without being predicted, and the data provider is told that decoding was
+    /// cancelled.
+    SetCancelledFlag(Arc<AtomicBool>),
+    /// Decoding has been cancelled.
+    Cancel,
+    /// The image loader is starting on a new image. A data provider that hasn't been told that
+    /// decoding finished is told that it was cancelled. Replied to with `ResetComplete`.
+    Reset,
 }
 
 pub struct PredictionRequest {
@@ -35,232 +44,654 @@ pub struct PredictionRequest {
     pub height: u32,
     pub color_depth: u8,
     pub indexed_color: bool,
+    /// The only part of the image that the data provider is interested in, if any.
+    pub region_of_interest: Option<Region>,
+    /// A block of image data holding all the scanlines to be predicted.
+    pub data: Vec<u8>,
     pub scanlines: Vec<ScanlineToPredict>,
 }
 
-pub struct PerformRgbaConversionRequest {
+pub struct RgbaConversionInfo {
     pub rgb_palette: Option<Vec<u8>>,
     pub transparency: Transparency,
-    pub width: u32,
-    pub height: u32,
-    pub color_depth: u8,
-    pub interlaced: bool,
 }
 
 pub struct ScanlineToPredict {
     pub predictor: Predictor,
-    pub data: Vec<u8>,
+    /// The offset of the scanline's data (not including the predictor byte) within the block.
     pub offset: usize,
     pub lod: LevelOfDetail,
     pub y: u32,
 }
 
 pub enum PredictorThreadToMainThreadMsg {
-    ScanlinePredictionComplete(u32, LevelOfDetail, Vec<u8>),
-    RgbaConversionComplete,
+    /// All the scanlines in a prediction request have been predicted. Returns the block of image
+    /// data for reuse, along with the number of scanlines it held.
+    PredictionComplete(Vec<u8>, u32),
+    /// Decoding was cancelled before the scanlines in a prediction request were predicted, so
+    /// they weren't. Otherwise like `PredictionComplete`.
+    PredictionCancelled(Vec<u8>, u32),
     NoDataProviderError,
+    /// Every message sent before a `Reset` message has been handled.
+    ResetComplete,
 }
 
-pub struct MainThreadToPredictorThreadComm {
-    pub sender: Sender<MainThreadToPredictorThreadMsg>,
+/// Messages sent to a predictor thread that is shared among many image loaders.
+pub enum PooledPredictorThreadMsg<P = DataProvider + Send> where P: ?Sized {
+    /// Starts a new session, which holds the state for one image loader.
+    OpenSession(usize, Sender<PredictorThreadToMainThreadMsg>),
+    /// Forwards a message from an image loader to its session.
+    SessionMsg(usize, MainThreadToPredictorThreadMsg<P>),
+    /// Ends a session. This is sent when an image loader is dropped.
+    CloseSession(usize),
+}
+
+enum PredictorThreadSender<P> where P: ?Sized {
+    Dedicated(Sender<MainThreadToPredictorThreadMsg<P>>),
+    Pooled(usize, Sender<PooledPredictorThreadMsg<P>>),
+    /// There is no predictor thread; messages are handled immediately on the calling thread.
+    Inline(PredictorSession<P>),
+}
+
+/// The image loader's end of its connection to the predictor, whose data provider is a `P`.
+///
+/// Only inline predictors, which run on the calling thread, can have data providers that aren't
+/// `Send`. Predictor threads always have a `DataProvider + Send`.
+pub struct MainThreadToPredictorThreadComm<P = DataProvider + Send> where P: ?Sized {
+    sender: PredictorThreadSender<P>,
     pub receiver: Receiver<PredictorThreadToMainThreadMsg>,
     pub scanlines_in_progress: u32,
 }
 
 impl MainThreadToPredictorThreadComm {
-    pub fn new() -> MainThreadToPredictorThreadComm {
+    /// Spawns a predictor thread dedicated to a single image loader.
+    pub fn new(worker_count: u32) -> MainThreadToPredictorThreadComm {
         let (main_thread_to_predictor_thread_sender, main_thread_to_predictor_thread_receiver) =
             mpsc::channel();
         let (predictor_thread_to_main_thread_sender, predictor_thread_to_main_thread_receiver) =
             mpsc::channel();
         thread::spawn(move || {
             predictor_thread(predictor_thread_to_main_thread_sender,
-                             main_thread_to_predictor_thread_receiver)
+                             main_thread_to_predictor_thread_receiver,
+                             worker_count)
         });
         MainThreadToPredictorThreadComm {
-            sender: main_thread_to_predictor_thread_sender,
+            sender: PredictorThreadSender::Dedicated(main_thread_to_predictor_thread_sender),
+            receiver: predictor_thread_to_main_thread_receiver,
+            scanlines_in_progress: 0,
+        }
+    }
+
+    /// Opens a new session on a shared predictor thread spawned with
+    /// `spawn_pooled_predictor_thread()`.
+    pub fn with_pooled_thread(sender: Sender<PooledPredictorThreadMsg>, session_id: usize)
+                              -> MainThreadToPredictorThreadComm {
+        let (predictor_thread_to_main_thread_sender, predictor_thread_to_main_thread_receiver) =
+            mpsc::channel();
+        sender.send(PooledPredictorThreadMsg::OpenSession(session_id,
+                                                          predictor_thread_to_main_thread_sender))
+              .unwrap();
+        MainThreadToPredictorThreadComm {
+            sender: PredictorThreadSender::Pooled(session_id, sender),
+            receiver: predictor_thread_to_main_thread_receiver,
+            scanlines_in_progress: 0,
+        }
+    }
+}
+
+impl<P> MainThreadToPredictorThreadComm<P> where P: ?Sized + DataProvider {
+    /// Performs prediction and color conversion on the calling thread instead of spawning a
+    /// predictor thread.
+    ///
+    /// Replies are still delivered through `receiver`, but they are all available by the time
+    /// `send()` returns.
+    pub fn inline() -> MainThreadToPredictorThreadComm<P> {
+        let (predictor_thread_to_main_thread_sender, predictor_thread_to_main_thread_receiver) =
+            mpsc::channel();
+        MainThreadToPredictorThreadComm {
+            sender: PredictorThreadSender::Inline(PredictorSession::new(
+                    predictor_thread_to_main_thread_sender,
+                    1)),
             receiver: predictor_thread_to_main_thread_receiver,
             scanlines_in_progress: 0,
         }
     }
+
+    pub fn send(&mut self, msg: MainThreadToPredictorThreadMsg<P>) {
+        match self.sender {
+            PredictorThreadSender::Dedicated(ref sender) => sender.send(msg).unwrap(),
+            PredictorThreadSender::Pooled(session_id, ref sender) => {
+                sender.send(PooledPredictorThreadMsg::SessionMsg(session_id, msg)).unwrap()
+            }
+            PredictorThreadSender::Inline(ref mut session) => session.handle_msg(msg),
+        }
+    }
+}
+
+impl<P> Drop for MainThreadToPredictorThreadComm<P> where P: ?Sized {
+    fn drop(&mut self) {
+        // A dedicated thread exits on its own once its sender is dropped, but a shared one has to
+        // be told to discard the session.
+        if let PredictorThreadSender::Pooled(session_id, ref sender) = self.sender {
+            drop(sender.send(PooledPredictorThreadMsg::CloseSession(session_id)))
+        }
+    }
+}
+
+/// Spawns a predictor thread that can be shared among many image loaders, each of which opens a
+/// session on it with `MainThreadToPredictorThreadComm::with_pooled_thread()`.
+///
+/// The thread exits once every sender for it has been dropped.
+pub fn spawn_pooled_predictor_thread() -> Sender<PooledPredictorThreadMsg> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || pooled_predictor_thread(receiver));
+    sender
+}
+
+/// Returns true if RGBA conversion of each scanline of images with the given color depth must wait
+/// until the next scanline in the same level of detail has been predicted.
+///
+/// This is only the case for grayscale-alpha images. Their luminance and alpha values are
+/// predicted into the first two bytes of each RGBA pixel, and converting a scanline in place would
+/// clobber the values that prediction of the next scanline refers to.
+fn rgba_conversion_lags_prediction(color_depth: u8, indexed: bool) -> bool {
+    !indexed && color_depth == 16
 }
 
 fn predictor_thread(sender: Sender<PredictorThreadToMainThreadMsg>,
-                    receiver: Receiver<MainThreadToPredictorThreadMsg>) {
-    let mut data_provider: Option<Box<DataProvider>> = None;
-    let mut palette: Option<Vec<u8>> = None;
-    let mut blank = vec![];
+                    receiver: Receiver<MainThreadToPredictorThreadMsg>,
+                    worker_count: u32) {
+    let mut session = PredictorSession::new(sender, worker_count);
+    while let Ok(msg) = receiver.recv() {
+        session.handle_msg(msg)
+    }
+}
+
+fn pooled_predictor_thread(receiver: Receiver<PooledPredictorThreadMsg>) {
+    let mut sessions = HashMap::new();
     while let Ok(msg) = receiver.recv() {
         match msg {
-            MainThreadToPredictorThreadMsg::Predict(PredictionRequest {
-                    width,
-                    height,
-                    color_depth,
-                    indexed_color,
-                    scanlines,
-            }) => {
-                let data_provider = match data_provider {
-                    None => {
-                        sender.send(PredictorThreadToMainThreadMsg::NoDataProviderError).unwrap();
-                        continue
-                    }
-                    Some(ref mut data_provider) => data_provider,
-                };
+            PooledPredictorThreadMsg::OpenSession(session_id, sender) => {
+                sessions.insert(session_id, PredictorSession::new(sender, 1));
+            }
+            PooledPredictorThreadMsg::SessionMsg(session_id, msg) => {
+                if let Some(session) = sessions.get_mut(&session_id) {
+                    session.handle_msg(msg)
+                }
+            }
+            PooledPredictorThreadMsg::CloseSession(session_id) => {
+                sessions.remove(&session_id);
+            }
+        }
+    }
+}
+
+/// The state that a predictor thread keeps for a single image loader.
+struct PredictorSession<P> where P: ?Sized {
+    /// Replies to the image loader. They're discarded if it has been dropped, as it may be once
+    /// decoding has been cancelled.
+    sender: Sender<PredictorThreadToMainThreadMsg>,
+    data_provider: Option<Box<P>>,
+    rgba_conversion_info: RgbaConversionInfo,
+    worker_pool: Option<WorkerPool>,
+    blank: Vec<u8>,
+    /// Space to copy scanlines that aren't properly aligned for the accelerated routines into.
+    aligned_scanline: Vec<u8>,
+    /// Space to predict into, and to copy the reference scanline into, when the data provider's
+    /// scanlines aren't properly aligned for the accelerated routines.
+    aligned_dest: Vec<u8>,
+    aligned_prev: Vec<u8>,
+    region_scanlines: RegionScanlines,
+    wake_up_callback: Option<Box<Fn() + Send>>,
+    cancelled: Arc<AtomicBool>,
+}
 
-                if !indexed_color {
-                    palette = None
+impl<P> PredictorSession<P> where P: ?Sized + DataProvider {
+    fn new(sender: Sender<PredictorThreadToMainThreadMsg>, worker_count: u32)
+           -> PredictorSession<P> {
+        PredictorSession {
+            sender: sender,
+            data_provider: None,
+            rgba_conversion_info: RgbaConversionInfo {
+                rgb_palette: None,
+                transparency: Transparency::None,
+            },
+            worker_pool: if worker_count > 1 {
+                Some(WorkerPool::new(worker_count))
+            } else {
+                None
+            },
+            blank: vec![],
+            aligned_scanline: vec![],
+            aligned_dest: vec![],
+            aligned_prev: vec![],
+            region_scanlines: RegionScanlines {
+                current: vec![],
+                previous: vec![],
+            },
+            wake_up_callback: None,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn handle_msg(&mut self, msg: MainThreadToPredictorThreadMsg<P>) {
+        match msg {
+            MainThreadToPredictorThreadMsg::Predict(request) => {
+                if self.cancelled.load(Ordering::SeqCst) {
+                    self.cancel();
+                    let scanline_count = request.scanlines.len() as u32;
+                    drop(self.sender
+                             .send(PredictorThreadToMainThreadMsg::PredictionCancelled(
+                                     request.data,
+                                     scanline_count)));
+                } else {
+                    self.predict(request);
+                }
+                if let Some(ref wake_up_callback) = self.wake_up_callback {
+                    wake_up_callback()
+                }
+            }
+            MainThreadToPredictorThreadMsg::SetDataProvider(new_data_provider) => {
+                self.data_provider = Some(new_data_provider)
+            }
+            MainThreadToPredictorThreadMsg::SetRgbaConversionInfo(new_rgba_conversion_info) => {
+                self.rgba_conversion_info = new_rgba_conversion_info
+            }
+            MainThreadToPredictorThreadMsg::Finished => {
+                if let Some(ref mut data_provider) = mem::replace(&mut self.data_provider, None) {
+                    data_provider.finished()
                 }
+            }
+            MainThreadToPredictorThreadMsg::SetWakeUpCallback(wake_up_callback) => {
+                self.wake_up_callback = Some(wake_up_callback)
+            }
+            MainThreadToPredictorThreadMsg::SetCancelledFlag(cancelled) => {
+                self.cancelled = cancelled
+            }
+            MainThreadToPredictorThreadMsg::Cancel => self.cancel(),
+            MainThreadToPredictorThreadMsg::Reset => {
+                self.cancel();
+                self.rgba_conversion_info = RgbaConversionInfo {
+                    rgb_palette: None,
+                    transparency: Transparency::None,
+                };
+                drop(self.sender.send(PredictorThreadToMainThreadMsg::ResetComplete))
+            }
+        }
+    }
 
-                let dest_width_in_bytes = width as usize * 4;
-
-                for ScanlineToPredict {
-                    mut predictor,
-                    data: src,
-                    offset: scanline_offset,
-                    lod: scanline_lod,
-                    y: scanline_y
-                } in scanlines {
-                    let prev_scanline_y = if scanline_y == 0 {
-                        None
-                    } else {
-                        Some(scanline_y - 1)
-                    };
-
-                    {
-                        let ScanlinesForPrediction {
-                            reference_scanline: mut prev,
-                            current_scanline: dest,
-                            stride,
-                        } = data_provider.fetch_scanlines_for_prediction(prev_scanline_y,
-                                                                         scanline_y,
-                                                                         scanline_lod,
-                                                                         indexed_color);
-                        let mut properly_aligned = true;
-                        let prev = match prev {
-                            Some(ref mut prev) => {
-                                if !slice_is_properly_aligned(prev) {
-                                    properly_aligned = false;
-                                }
-                                &mut prev[..]
-                            }
-                            None => {
-                                blank.extend(iter::repeat(0).take(dest_width_in_bytes as usize));
-                                &mut blank[..]
-                            }
-                        };
-                        if !slice_is_properly_aligned(dest) {
-                            properly_aligned = false;
-                        }
+    /// Tells the data provider, if it hasn't been told already, that decoding has been cancelled,
+    /// and drops it.
+    fn cancel(&mut self) {
+        if let Some(ref mut data_provider) = mem::replace(&mut self.data_provider, None) {
+            data_provider.cancelled()
+        }
+    }
 
-                        if properly_aligned {
-                            predictor.accelerated_predict(&mut dest[..],
-                                                          &src[scanline_offset..],
-                                                          &prev[..],
-                                                          width,
-                                                          color_depth,
-                                                          stride)
-                        } else {
-                            predictor.predict(&mut dest[0..dest_width_in_bytes],
-                                              &src[scanline_offset..],
-                                              &prev[0..dest_width_in_bytes],
-                                              color_depth,
-                                              stride);
-                        }
+    fn predict(&mut self, request: PredictionRequest) {
+        let PredictionRequest {
+            width,
+            height,
+            color_depth,
+            indexed_color,
+            region_of_interest,
+            data,
+            scanlines,
+        } = request;
+        let scanline_count = scanlines.len() as u32;
+        let (sender, rgba_conversion_info) = (&self.sender, &self.rgba_conversion_info);
+        let data_provider = match self.data_provider {
+            None => {
+                drop(sender.send(PredictorThreadToMainThreadMsg::NoDataProviderError));
+                return
+            }
+            Some(ref mut data_provider) => data_provider,
+        };
+
+        let dest_width_in_bytes = width as usize * 4;
+        let pixel_size = if indexed_color {
+            1
+        } else {
+            4
+        };
+        let region_scanline_length = imageloader::align(width as usize * pixel_size);
 
-                        sender.send(PredictorThreadToMainThreadMsg::ScanlinePredictionComplete(
-                                scanline_y,
-                                scanline_lod,
-                                src)).unwrap();
+        if let Some(ref mut worker_pool) = self.worker_pool {
+            let (data, scanlines, mut tiles) =
+                worker_pool.predict(width, color_depth, indexed_color, data, scanlines);
+            for ScanlineToPredict {
+                lod: scanline_lod,
+                y: scanline_y,
+                ..
+            } in scanlines {
+                if let Some(ref region) = region_of_interest {
+                    let scanline = self.region_scanlines.current(region_scanline_length);
+                    tiles.copy_next_scanline(scanline, pixel_size as u8, scanline_y, scanline_lod);
+                    if !copy_scanline_to_region(&mut **data_provider,
+                                                scanline,
+                                                scanline_y,
+                                                scanline_lod,
+                                                region,
+                                                indexed_color) {
+                        continue
                     }
+                } else {
+                    let ScanlinesForPrediction {
+                        current_scanline: dest,
+                        stride,
+                        ..
+                    } = data_provider.fetch_scanlines_for_prediction(None,
+                                                                     scanline_y,
+                                                                     scanline_lod,
+                                                                     indexed_color);
+                    tiles.copy_next_scanline(dest, stride, scanline_y, scanline_lod);
+                }
 
-                    data_provider.prediction_complete_for_scanline(scanline_y, scanline_lod);
+                data_provider.prediction_complete_for_scanline(scanline_y, scanline_lod);
+
+                // The workers keep their own copies of the reference scanlines, so every
+                // scanline can be converted to RGBA right away.
+                if color_depth != 32 {
+                    convert_scanline_to_rgba(&mut **data_provider,
+                                             rgba_conversion_info,
+                                             scanline_y,
+                                             scanline_lod,
+                                             width,
+                                             color_depth,
+                                             &region_of_interest)
                 }
             }
-            MainThreadToPredictorThreadMsg::SetDataProvider(new_data_provider) => {
-                data_provider = Some(new_data_provider)
+            worker_pool.recycle(tiles);
+            drop(sender.send(PredictorThreadToMainThreadMsg::PredictionComplete(data,
+                                                                                scanline_count)));
+            return
+        }
+
+        // Unless the color conversion would destroy data that we need for prediction, convert
+        // each scanline to RGBA while it's still in the cache. Otherwise, convert each scanline
+        // once the next one has been predicted. When decoding a region of interest, we predict
+        // from our own copies of the scanlines, so there's no danger of that.
+        let lag_rgba_conversion = region_of_interest.is_none() &&
+            rgba_conversion_lags_prediction(color_depth, indexed_color);
+        let fuse_rgba_conversion = color_depth != 32 && !lag_rgba_conversion;
+
+        for ScanlineToPredict {
+            predictor,
+            offset: scanline_offset,
+            lod: scanline_lod,
+            y: scanline_y
+        } in scanlines {
+            // Scanlines below the region of interest aren't needed, not even as references.
+            if let Some(ref region) = region_of_interest {
+                if InterlacingInfo::new(scanline_y, 8, scanline_lod).y >=
+                        region.y + region.height {
+                    continue
+                }
             }
-            MainThreadToPredictorThreadMsg::PerformRgbaConversion(PerformRgbaConversionRequest {
-                    rgb_palette,
-                    transparency,
-                    width,
-                    height,
-                    color_depth,
-                    interlaced,
-            }) => {
-                let data_provider = match data_provider {
+
+            let prev_scanline_y = if scanline_y == 0 {
+                None
+            } else {
+                Some(scanline_y - 1)
+            };
+
+            {
+                let ScanlinesForPrediction {
+                    reference_scanline: mut prev,
+                    current_scanline: dest,
+                    stride,
+                } = match region_of_interest {
                     None => {
-                        sender.send(PredictorThreadToMainThreadMsg::NoDataProviderError).unwrap();
-                        continue
+                        data_provider.fetch_scanlines_for_prediction(prev_scanline_y,
+                                                                     scanline_y,
+                                                                     scanline_lod,
+                                                                     indexed_color)
+                    }
+                    Some(_) => {
+                        self.region_scanlines.scanlines_for_prediction(prev_scanline_y,
+                                                                       region_scanline_length,
+                                                                       pixel_size)
                     }
-                    Some(ref mut data_provider) => data_provider,
-                };
-                let levels_of_detail = if !interlaced {
-                    &NO_LEVELS_OF_DETAIL[..]
-                } else {
-                    &ADAM7_LEVELS_OF_DETAIL[..]
                 };
-                let indexed = rgb_palette.is_some();
-
-                for lod in levels_of_detail {
-                    for scanline_y in 0..height {
-                        {
-                            let ScanlinesForRgbaConversion {
-                                rgba_scanline: dest,
-                                indexed_scanline: src,
-                                rgba_stride: dest_stride,
-                                indexed_stride: src_stride,
-                            } = data_provider.fetch_scanlines_for_rgba_conversion(scanline_y,
-                                                                                  *lod,
-                                                                                  indexed);
-                            let scanline_width =
-                                InterlacingInfo::new(scanline_y,
-                                                     color_depth,
-                                                     *lod).scanline_width(width,
-                                                                          color_depth) as usize;
-                            let dest_line_stride = (dest_stride as usize) * scanline_width;
-                            let src_line_stride = src_stride.map(|src_stride| {
-                                (src_stride as usize) * scanline_width
-                            });
-                            match (&rgb_palette, color_depth) {
-                                (&Some(ref rgb_palette), _) => {
-                                    let src_line_stride = src_line_stride.unwrap();
-                                    convert_indexed_to_rgba(&mut dest[0..dest_line_stride],
-                                                            &src.as_ref()
-                                                                .unwrap()[0..src_line_stride],
-                                                            &rgb_palette[..],
-                                                            &transparency,
-                                                            color_depth,
-                                                            dest_stride,
-                                                            src_stride.unwrap())
-                                }
-                                (&None, 24) => {
-                                    convert_rgb_to_rgba(&mut dest[0..dest_line_stride],
-                                                        &transparency)
-                                }
-                                (&None, 16) => {
-                                    convert_grayscale_alpha_to_rgba(&mut dest[0..dest_line_stride])
-                                }
-                                (&None, 8) => {
-                                    convert_8bpp_grayscale_to_rgba(&mut dest[0..dest_line_stride],
-                                                                   &transparency)
-                                }
-                                (&None, _) => panic!("Unsupported color depth!"),
-                            }
+                let prev = match prev {
+                    Some(ref mut prev) => &mut prev[..],
+                    None => {
+                        if self.blank.len() < dest_width_in_bytes {
+                            self.blank.resize(dest_width_in_bytes, 0)
                         }
+                        &mut self.blank[..dest_width_in_bytes]
+                    }
+                };
+
+                // The pixels of this scanline. The data provider need not have supplied any room
+                // past the last one.
+                let scanline_width = InterlacingInfo::new(scanline_y, 32, scanline_lod)
+                                         .scanline_width(width, 32) as usize;
+                let scanline_length = match scanline_width {
+                    0 => 0,
+                    scanline_width => (scanline_width - 1) * (stride as usize) + pixel_size,
+                };
+
+                if indexed_color {
+                    // The accelerated routines always write 32-bit pixels, so they can't be used
+                    // for indexed images.
+                    predictor.predict(&mut dest[0..scanline_length],
+                                      &data[scanline_offset..],
+                                      &prev[0..scanline_length],
+                                      color_depth,
+                                      stride,
+                                      indexed_color);
+                } else {
+                    let src = properly_aligned_scanline(&data[scanline_offset..],
+                                                        width as usize * (color_depth / 8) as usize,
+                                                        &mut self.aligned_scanline);
 
-                        data_provider.rgba_conversion_complete_for_scanline(scanline_y, *lod);
+                    // The accelerated routines process the full width of the image a vector at a
+                    // time, so they need that much room in both scanlines, starting at properly
+                    // aligned addresses.
+                    let aligned_dest_width_in_bytes = imageloader::align(dest_width_in_bytes);
+                    let properly_aligned = dest.len() >= aligned_dest_width_in_bytes &&
+                        prev.len() >= aligned_dest_width_in_bytes &&
+                        address_is_properly_aligned(dest.as_ptr() as usize) &&
+                        address_is_properly_aligned(prev.as_ptr() as usize);
+                    if properly_aligned {
+                        predictor.accelerated_predict(&mut dest[0..aligned_dest_width_in_bytes],
+                                                      src,
+                                                      &prev[0..aligned_dest_width_in_bytes],
+                                                      width,
+                                                      color_depth,
+                                                      stride)
+                    } else {
+                        // Predict into aligned scratch space, and copy the pixels out afterward.
+                        let aligned_prev = aligned_scratch_scanline(aligned_dest_width_in_bytes,
+                                                                    &mut self.aligned_prev);
+                        let prev_length = cmp::min(prev.len(), aligned_dest_width_in_bytes);
+                        aligned_prev[0..prev_length].clone_from_slice(&prev[0..prev_length]);
+                        let aligned_dest = aligned_scratch_scanline(aligned_dest_width_in_bytes,
+                                                                    &mut self.aligned_dest);
+                        predictor.accelerated_predict(aligned_dest,
+                                                      src,
+                                                      aligned_prev,
+                                                      width,
+                                                      color_depth,
+                                                      stride);
+                        for (dest, src) in dest[0..scanline_length]
+                                               .chunks_mut(stride as usize)
+                                               .zip(aligned_dest.chunks(stride as usize)) {
+                            dest[0..4].clone_from_slice(&src[0..4])
+                        }
                     }
                 }
+            }
 
-                sender.send(PredictorThreadToMainThreadMsg::RgbaConversionComplete).unwrap();
+            if let Some(ref region) = region_of_interest {
+                let scanline = self.region_scanlines.current(region_scanline_length);
+                if !copy_scanline_to_region(&mut **data_provider,
+                                            scanline,
+                                            scanline_y,
+                                            scanline_lod,
+                                            region,
+                                            indexed_color) {
+                    continue
+                }
             }
-            MainThreadToPredictorThreadMsg::Finished => {
-                if let Some(ref mut data_provider) = mem::replace(&mut data_provider, None) {
-                    data_provider.finished()
+
+            data_provider.prediction_complete_for_scanline(scanline_y, scanline_lod);
+
+            if fuse_rgba_conversion {
+                convert_scanline_to_rgba(&mut **data_provider,
+                                         rgba_conversion_info,
+                                         scanline_y,
+                                         scanline_lod,
+                                         width,
+                                         color_depth,
+                                         &region_of_interest)
+            } else if lag_rgba_conversion {
+                if let Some(prev_scanline_y) = prev_scanline_y {
+                    convert_scanline_to_rgba(&mut **data_provider,
+                                             rgba_conversion_info,
+                                             prev_scanline_y,
+                                             scanline_lod,
+                                             width,
+                                             color_depth,
+                                             &None)
+                }
+                if scanline_y + 1 == InterlacingInfo::height_of_lod(height, scanline_lod) {
+                    convert_scanline_to_rgba(&mut **data_provider,
+                                             rgba_conversion_info,
+                                             scanline_y,
+                                             scanline_lod,
+                                             width,
+                                             color_depth,
+                                             &None)
                 }
             }
         }
+
+        drop(sender.send(PredictorThreadToMainThreadMsg::PredictionComplete(data, scanline_count)));
+    }
+}
+
+/// The scanlines that are predicted into when decoding a region of interest.
+///
+/// The data provider only has room for the pixels inside the region, so each scanline is predicted
+/// here in its entirety, with the pixels packed tightly, and the region is copied out afterward.
+/// The previous scanline is kept around to serve as the reference.
+struct RegionScanlines {
+    current: Vec<u8>,
+    previous: Vec<u8>,
+}
+
+impl RegionScanlines {
+    /// Makes the current scanline the previous one, and returns the scanlines to predict the next
+    /// one with. Each is `length` bytes long, starting at a properly aligned address.
+    fn scanlines_for_prediction(&mut self,
+                                reference_scanline: Option<u32>,
+                                length: usize,
+                                pixel_size: usize)
+                                -> ScanlinesForPrediction {
+        mem::swap(&mut self.current, &mut self.previous);
+        let previous = aligned_scratch_scanline(length, &mut self.previous);
+        ScanlinesForPrediction {
+            reference_scanline: reference_scanline.map(|_| previous),
+            current_scanline: aligned_scratch_scanline(length, &mut self.current),
+            stride: pixel_size as u8,
+        }
+    }
+
+    /// Returns the current scanline, which is `length` bytes long.
+    fn current(&mut self, length: usize) -> &mut [u8] {
+        aligned_scratch_scanline(length, &mut self.current)
+    }
+}
+
+/// Copies the pixels of a scanline that lie inside the region of interest out of `scanline`, in
+/// which all of the pixels are packed tightly, into the data provider. Returns false, without
+/// calling the data provider, if none of them do.
+fn copy_scanline_to_region<P>(data_provider: &mut P,
+                              scanline: &[u8],
+                              scanline_y: u32,
+                              lod: LevelOfDetail,
+                              region: &Region,
+                              indexed: bool)
+                              -> bool
+                              where P: ?Sized + DataProvider {
+    let color_depth = if indexed {
+        8
+    } else {
+        32
+    };
+    let region_scanline = match InterlacingInfo::in_region(scanline_y, color_depth, lod, region) {
+        None => return false,
+        Some(region_scanline) => region_scanline,
+    };
+
+    // Find the first pixel of the scanline that lies inside the region.
+    let pixel_size = (color_depth / 8) as usize;
+    let image_scanline = InterlacingInfo::new(scanline_y, color_depth, lod);
+    let first_x = region.x as usize + region_scanline.offset as usize / pixel_size;
+    let start = (first_x - image_scanline.offset as usize / pixel_size) /
+        (image_scanline.stride as usize / pixel_size);
+    let length = region_scanline.scanline_width(region.width, color_depth) as usize;
+    let src = &scanline[(start * pixel_size)..((start + length) * pixel_size)];
+
+    let ScanlinesForPrediction {
+        current_scanline: dest,
+        stride,
+        ..
+    } = data_provider.fetch_scanlines_for_prediction(None, scanline_y, lod, indexed);
+    for (dest, src) in dest.chunks_mut(stride as usize).zip(src.chunks(pixel_size)) {
+        dest[0..pixel_size].clone_from_slice(src)
+    }
+    true
+}
+
+fn convert_scanline_to_rgba<P>(data_provider: &mut P,
+                               rgba_conversion_info: &RgbaConversionInfo,
+                               scanline_y: u32,
+                               lod: LevelOfDetail,
+                               width: u32,
+                               color_depth: u8,
+                               region_of_interest: &Option<Region>)
+                               where P: ?Sized + DataProvider {
+    let indexed = rgba_conversion_info.rgb_palette.is_some();
+    let scanline_width = match *region_of_interest {
+        None => InterlacingInfo::new(scanline_y, 32, lod).scanline_width(width, 32) as usize,
+        Some(ref region) => {
+            InterlacingInfo::in_region(scanline_y, 32, lod, region)
+                .map_or(0, |info| info.scanline_width(region.width, 32) as usize)
+        }
+    };
+    if scanline_width > 0 {
+        let ScanlinesForRgbaConversion {
+            rgba_scanline: dest,
+            indexed_scanline: src,
+            rgba_stride: dest_stride,
+            indexed_stride: src_stride,
+        } = data_provider.fetch_scanlines_for_rgba_conversion(scanline_y, lod, indexed);
+        let dest = &mut dest[0..((scanline_width - 1) * (dest_stride as usize) + 4)];
+        let transparency = &rgba_conversion_info.transparency;
+        match (&rgba_conversion_info.rgb_palette, color_depth) {
+            (&Some(ref rgb_palette), _) => {
+                let src = src.expect("No indexed scanline supplied for an indexed image!");
+                let src_stride = src_stride.expect("No indexed stride supplied!");
+                let src = &src[0..((scanline_width - 1) * (src_stride as usize) + 1)];
+                convert_indexed_to_rgba(dest,
+                                        src,
+                                        &rgb_palette[..],
+                                        transparency,
+                                        color_depth,
+                                        dest_stride,
+                                        src_stride)
+            }
+            (&None, 24) => convert_rgb_to_rgba(dest, transparency, dest_stride),
+            (&None, 16) => convert_grayscale_alpha_to_rgba(dest, dest_stride),
+            (&None, 8) => convert_8bpp_grayscale_to_rgba(dest, transparency, dest_stride),
+            (&None, _) => panic!("Unsupported color depth!"),
+        }
     }
+
+    data_provider.rgba_conversion_complete_for_scanline(scanline_y, lod);
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -285,10 +716,41 @@ impl Predictor {
         }
     }
 
-    fn predict(self, dest: &mut [u8], src: &[u8], prev: &[u8], color_depth: u8, stride: u8) {
+    fn predict(self,
+               dest: &mut [u8],
+               src: &[u8],
+               prev: &[u8],
+               color_depth: u8,
+               stride: u8,
+               indexed: bool) {
+        self.predict_tile(dest, src, prev, color_depth, stride, indexed, [0; 4], [0; 4])
+    }
+
+    /// Predicts a horizontal section of a scanline.
+    ///
+    /// `left` is the pixel immediately to the left of the first pixel in `dest`, and `upper_left`
+    /// is the pixel immediately to the left of the first pixel in `prev`. Both should be zero at
+    /// the beginning of a scanline.
+    pub fn predict_tile(self,
+                        dest: &mut [u8],
+                        src: &[u8],
+                        prev: &[u8],
+                        color_depth: u8,
+                        stride: u8,
+                        indexed: bool,
+                        left: [u8; 4],
+                        upper_left: [u8; 4]) {
         let color_depth = (color_depth / 8) as usize;
-        let mut a: [u8; 4] = [0; 4];
-        let mut c: [u8; 4] = [0; 4];
+        // Truecolor pixels are padded out to 32 bits with an opaque alpha value. Indexed pixels are
+        // stored as-is. In either case, we must not touch the bytes past the end of the pixel, as
+        // they may belong to other levels of detail.
+        let pixel_size = if indexed {
+            1
+        } else {
+            4
+        };
+        let mut a: [u8; 4] = left;
+        let mut c: [u8; 4] = upper_left;
         let stride = stride as usize;
 
         // We use iterators here to avoid bounds checks, as this is performance-critical code.
@@ -298,7 +760,7 @@ impl Predictor {
                     for (dest, src) in dest.iter_mut().take(4).zip(src.iter()) {
                         *dest = *src
                     }
-                    for dest in &mut dest[color_depth..] {
+                    for dest in dest.iter_mut().take(pixel_size).skip(color_depth) {
                         *dest = 0xff
                     }
                 }
@@ -311,7 +773,7 @@ impl Predictor {
                         *a = src.wrapping_add(*a);
                         *dest = *a
                     }
-                    for dest in &mut dest[color_depth..] {
+                    for dest in dest.iter_mut().take(pixel_size).skip(color_depth) {
                         *dest = 0xff
                     }
                 }
@@ -324,7 +786,7 @@ impl Predictor {
                                                 .zip(src.iter().zip(b.iter().take(4))) {
                         *dest = src.wrapping_add(*b)
                     }
-                    for dest in &mut dest[color_depth..] {
+                    for dest in dest.iter_mut().take(pixel_size).skip(color_depth) {
                         *dest = 0xff
                     }
                 }
@@ -339,7 +801,7 @@ impl Predictor {
                         *a = src.wrapping_add((((*a as u16) + (*b as u16)) / 2) as u8);
                         *dest = *a
                     }
-                    for dest in &mut dest[color_depth..] {
+                    for dest in dest.iter_mut().take(pixel_size).skip(color_depth) {
                         *dest = 0xff
                     }
                 }
@@ -357,36 +819,39 @@ impl Predictor {
                         *c = *b;
                         *dest = *a;
                     }
-                    for dest in &mut dest[color_depth..] {
+                    for dest in dest.iter_mut().take(pixel_size).skip(color_depth) {
                         *dest = 0xff
                     }
                 }
             }
         }
+    }
 
-        fn paeth(a: u8, b: u8, c: u8) -> u8 {
-            let (a, b, c) = (a as i16, b as i16, c as i16);
-            let p = a + b - c;
-            let pa = (p - a).abs();
-            let pb = (p - b).abs();
-            let pc = (p - c).abs();
-            if pa <= pb && pa <= pc {
-                a as u8
-            } else if pb <= pc {
-                b as u8
-            } else {
-                c as u8
-            }
+    /// Returns the value to add to each sample of the first pixel of a tile so that predicting the
+    /// tile as though it began a scanline gives the same result as predicting it with `a` to the
+    /// left and `c` to the upper left. `b` is the sample above.
+    ///
+    /// The accelerated routines always start from zero pixels, so this allows them to predict
+    /// tiles that don't begin a scanline: nothing past the first pixel of the tile depends on the
+    /// pixels outside it.
+    pub fn tile_start_adjustment(self, a: u8, b: u8, c: u8) -> u8 {
+        match self {
+            Predictor::None | Predictor::Up => 0,
+            Predictor::Left => a,
+            Predictor::Average => (((a as u16 + b as u16) / 2) as u8).wrapping_sub(b / 2),
+            // With zero pixels to the left and upper left, the Paeth predictor picks the pixel
+            // above.
+            Predictor::Paeth => paeth(a, b, c).wrapping_sub(b),
         }
     }
 
-    fn accelerated_predict(self,
-                           dest: &mut [u8],
-                           src: &[u8],
-                           prev: &[u8],
-                           width: u32,
-                           color_depth: u8,
-                           stride: u8) {
+    pub fn accelerated_predict(self,
+                               dest: &mut [u8],
+                               src: &[u8],
+                               prev: &[u8],
+                               width: u32,
+                               color_depth: u8,
+                               stride: u8) {
         debug_assert!(slice_is_properly_aligned(dest));
         debug_assert!(slice_is_properly_aligned(src));
         debug_assert!(slice_is_properly_aligned(prev));
@@ -437,11 +902,26 @@ impl Predictor {
                                                stride as u64)
                 }
             }
-            None => self.predict(dest, src, prev, color_depth, stride),
+            None => self.predict(dest, src, prev, color_depth, stride, false),
         }
     }
 }
 
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let (a, b, c) = (a as i16, b as i16, c as i16);
+    let p = a + b - c;
+    let pa = (p - a).abs();
+    let pb = (p - b).abs();
+    let pc = (p - c).abs();
+    if pa <= pb && pa <= pc {
+        a as u8
+    } else if pb <= pc {
+        b as u8
+    } else {
+        c as u8
+    }
+}
+
 /// TODO(pcwalton): Agner says latency is going down for `vpgatherdd`. I don't have a Skylake to
 /// test on, but maybe it's worth using that instruction on that model and later?
 fn convert_indexed_to_rgba(dest: &mut [u8],
@@ -474,11 +954,11 @@ fn convert_indexed_to_rgba(dest: &mut [u8],
 
 /// TODO(pcwalton): Use SIMD for this.
 #[inline(never)]
-fn convert_rgb_to_rgba(scanline: &mut [u8], transparency: &Transparency) {
+fn convert_rgb_to_rgba(scanline: &mut [u8], transparency: &Transparency, stride: u8) {
     match *transparency {
         Transparency::None => {}
         Transparency::MagicColor(r, g, b) => {
-            for color in scanline.chunks_mut(4) {
+            for color in scanline.chunks_mut(stride as usize) {
                 color[3] = if color[0] == r && color[1] == g && color[2] == b {
                     0
                 } else {
@@ -493,8 +973,8 @@ fn convert_rgb_to_rgba(scanline: &mut [u8], transparency: &Transparency) {
 /// TODO(pcwalton): Use SIMD for this. Greyscale images are pretty rare, so it's not a priority,
 /// but it would be nice.
 #[inline(never)]
-fn convert_grayscale_alpha_to_rgba(scanline: &mut [u8]) {
-    for color in scanline.chunks_mut(4) {
+fn convert_grayscale_alpha_to_rgba(scanline: &mut [u8], stride: u8) {
+    for color in scanline.chunks_mut(stride as usize) {
         let (y, a) = (color[0], color[1]);
         color[1] = y;
         color[2] = y;
@@ -504,19 +984,49 @@ fn convert_grayscale_alpha_to_rgba(scanline: &mut [u8]) {
 
 /// TODO(pcwalton): Use SIMD for this too.
 #[inline(never)]
-fn convert_8bpp_grayscale_to_rgba(scanline: &mut [u8], transparency: &Transparency) {
-    for color in scanline.chunks_mut(4) {
+fn convert_8bpp_grayscale_to_rgba(scanline: &mut [u8], transparency: &Transparency, stride: u8) {
+    for color in scanline.chunks_mut(stride as usize) {
         let y = color[0];
         color[1] = y;
         color[2] = y;
-        color[3] = y;
-        match *transparency {
-            Transparency::MagicColor(r, g, b) if r == y && g == y && b == y => color[4] = 0,
-            _ => {}
+        color[3] = match *transparency {
+            Transparency::MagicColor(r, g, b) if r == y && g == y && b == y => 0,
+            _ => 0xff,
         }
     }
 }
 
+/// Returns the first `length` bytes of `src`, rounded up to a multiple of 16, copying them to a
+/// properly aligned address in `scratch` first if `src` doesn't start at one. Scanlines are packed
+/// tightly within blocks of image data, so most of them aren't aligned.
+///
+/// Like the blocks, the copy is followed by 32 bytes of padding, since the accelerated routines
+/// may read past the end of the scanline.
+fn properly_aligned_scanline<'a>(src: &'a [u8], length: usize, scratch: &'a mut Vec<u8>)
+                                 -> &'a [u8] {
+    let padded_length = (length + 15) & !15;
+    if address_is_properly_aligned(src.as_ptr() as usize) {
+        return &src[0..padded_length]
+    }
+    scratch.resize(padded_length + 32 + 16, 0);
+    let offset = match scratch.as_ptr() as usize & 0xf {
+        0 => 0,
+        remainder => 16 - remainder,
+    };
+    scratch[offset..(offset + length)].clone_from_slice(&src[0..length]);
+    &scratch[offset..(offset + padded_length)]
+}
+
+/// Returns `length` bytes of `scratch`, starting at a properly aligned address.
+pub fn aligned_scratch_scanline(length: usize, scratch: &mut Vec<u8>) -> &mut [u8] {
+    scratch.resize(length + 16, 0);
+    let offset = match scratch.as_ptr() as usize & 0xf {
+        0 => 0,
+        remainder => 16 - remainder,
+    };
+    &mut scratch[offset..(offset + length)]
+}
+
 fn slice_is_properly_aligned(buffer: &[u8]) -> bool {
     address_is_properly_aligned(buffer.as_ptr() as usize) &&
         address_is_properly_aligned(buffer.len())
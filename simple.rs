@@ -6,10 +6,16 @@
 
 use PngError;
 use imageloader::{self, DataProvider, ImageLoader, InterlacingInfo, LevelOfDetail, LoadProgress};
-use imageloader::{ScanlinesForPrediction, ScanlinesForRgbaConversion, UninitializedExtension};
-use metadata::ColorType;
-use std::io::{Read, Seek};
+use imageloader::{Region, ScanlinePair, ScanlinesForPrediction, ScanlinesForRgbaConversion};
+use imageloader::UninitializedExtension;
+use metadata::{ColorType, Dimensions, InterlaceMethod, Metadata};
+use mmap::MappedFile;
+use std::cmp;
+use std::io::{self, ErrorKind, Read, Seek};
 use std::mem;
+use std::path::Path;
+use std::slice;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{self, Receiver, Sender};
 
 struct MemoryDataProvider {
@@ -17,12 +23,17 @@ struct MemoryDataProvider {
     indexed_pixels: Vec<u8>,
     rgba_aligned_stride: usize,
     indexed_aligned_stride: usize,
+    /// The region of the image that the pixels hold, if not all of it.
+    region: Option<Region>,
     data_sender: Sender<Vec<u8>>,
 }
 
 impl MemoryDataProvider {
+    /// Creates a data provider with room for an image `width` by `height` pixels in size, or, if
+    /// `region` is present, for a region of that size.
     #[inline(never)]
-    pub fn new(width: u32, height: u32, indexed: bool) -> (MemoryDataProvider, Receiver<Vec<u8>>) {
+    pub fn new(width: u32, height: u32, indexed: bool, region: Option<Region>)
+               -> (MemoryDataProvider, Receiver<Vec<u8>>) {
         let rgba_aligned_stride = imageloader::align(width as usize * 4);
         let indexed_aligned_stride = imageloader::align(width as usize * 4);
         let (data_sender, data_receiver) = mpsc::channel();
@@ -47,6 +58,7 @@ impl MemoryDataProvider {
             indexed_pixels: indexed_pixels,
             rgba_aligned_stride: rgba_aligned_stride,
             indexed_aligned_stride: indexed_aligned_stride,
+            region: region,
             data_sender: data_sender,
         };
         (data_provider, data_receiver)
@@ -60,45 +72,144 @@ impl DataProvider for MemoryDataProvider {
                                           lod: LevelOfDetail,
                                           indexed: bool)
                                           -> ScanlinesForPrediction {
-        let buffer_color_depth = buffer_color_depth(indexed);
-        let reference_scanline = reference_scanline.map(|reference_scanline| {
-            InterlacingInfo::new(reference_scanline, buffer_color_depth, lod)
-        });
-        let current_scanline = InterlacingInfo::new(current_scanline, buffer_color_depth, lod);
+        if indexed {
+            scanlines_for_prediction(&mut self.indexed_pixels,
+                                     self.indexed_aligned_stride,
+                                     self.indexed_aligned_stride,
+                                     reference_scanline,
+                                     current_scanline,
+                                     lod,
+                                     indexed,
+                                     self.region.as_ref())
+        } else {
+            scanlines_for_prediction(&mut self.rgba_pixels,
+                                     self.rgba_aligned_stride,
+                                     self.rgba_aligned_stride,
+                                     reference_scanline,
+                                     current_scanline,
+                                     lod,
+                                     indexed,
+                                     self.region.as_ref())
+        }
+    }
 
-        let aligned_stride = if indexed {
-            self.indexed_aligned_stride
+    fn prediction_complete_for_scanline(&mut self, _: u32, _: LevelOfDetail) {}
+
+    fn fetch_scanlines_for_rgba_conversion<'a>(&'a mut self,
+                                               scanline: u32,
+                                               lod: LevelOfDetail,
+                                               indexed: bool)
+                                               -> ScanlinesForRgbaConversion<'a> {
+        let rgba_scanline = scanline_interlacing_info(scanline, 32, lod, self.region.as_ref());
+        let indexed_scanline = if indexed {
+            Some(scanline_interlacing_info(scanline, 8, lod, self.region.as_ref()))
         } else {
-            self.rgba_aligned_stride
+            None
         };
+        let rgba_start = self.rgba_aligned_stride * (rgba_scanline.y as usize) +
+            (rgba_scanline.offset as usize);
+        let indexed_aligned_stride = self.indexed_aligned_stride;
+        ScanlinesForRgbaConversion {
+            rgba_scanline: &mut self.rgba_pixels[rgba_start..],
+            indexed_scanline: match indexed_scanline {
+                Some(indexed_scanline) => {
+                    let indexed_start = indexed_aligned_stride * (indexed_scanline.y as usize) +
+                        (indexed_scanline.offset as usize);
+                    Some(&self.indexed_pixels[indexed_start..])
+                }
+                None => None,
+            },
+            rgba_stride: rgba_scanline.stride,
+            indexed_stride: indexed_scanline.map(|indexed_scanline| indexed_scanline.stride),
+        }
+    }
+
+    fn rgba_conversion_complete_for_scanline(&mut self, _: u32, _: LevelOfDetail) {}
+
+    fn finished(&mut self) {
+        self.data_sender.send(mem::replace(&mut self.rgba_pixels, vec![])).unwrap()
+    }
+}
 
-        let split_point = aligned_stride * (current_scanline.y as usize);
-        let dest_pixels = if indexed {
-            &mut self.indexed_pixels
+/// A data provider that decodes into a buffer belonging to the caller of `load_into_buffer()`.
+///
+/// Data providers can't borrow anything, so the buffer is held as a raw pointer. A
+/// `BufferImageLoader` keeps the buffer borrowed until it hears that this object has been dropped.
+struct BufferDataProvider {
+    rgba_pixels: *mut u8,
+    rgba_length: usize,
+    rgba_stride: usize,
+    /// The room the accelerated prediction routines need after the start of each scanline.
+    rgba_scanline_length: usize,
+    indexed_pixels: Vec<u8>,
+    indexed_aligned_stride: usize,
+    dropped_sender: Sender<()>,
+}
+
+unsafe impl Send for BufferDataProvider {}
+
+impl BufferDataProvider {
+    fn new(rgba_pixels: &mut [u8], rgba_stride: usize, width: u32, height: u32, indexed: bool)
+           -> (BufferDataProvider, Receiver<()>) {
+        let indexed_aligned_stride = imageloader::align(width as usize);
+        let indexed_length = if indexed {
+            indexed_aligned_stride * (height as usize)
         } else {
-            &mut self.rgba_pixels
-        };
-        let (head, tail) = dest_pixels.split_at_mut(split_point);
-        let head_length = head.len();
-        let reference_scanline_data = match reference_scanline {
-            None => None,
-            Some(reference_scanline) => {
-                debug_assert!(current_scanline.stride == reference_scanline.stride);
-                let start = (reference_scanline.y as usize) * aligned_stride +
-                    (reference_scanline.offset as usize);
-                let end = start + aligned_stride;
-                let slice = &mut head[start..end];
-                Some(slice)
-            }
+            0
+        };
+
+        let mut indexed_pixels = vec![];
+        unsafe {
+            indexed_pixels.extend_with_uninitialized(indexed_length)
+        }
+
+        let (dropped_sender, dropped_receiver) = mpsc::channel();
+        let data_provider = BufferDataProvider {
+            rgba_pixels: rgba_pixels.as_mut_ptr(),
+            rgba_length: rgba_pixels.len(),
+            rgba_stride: rgba_stride,
+            rgba_scanline_length: imageloader::align(width as usize * 4),
+            indexed_pixels: indexed_pixels,
+            indexed_aligned_stride: indexed_aligned_stride,
+            dropped_sender: dropped_sender,
         };
-        let start = (current_scanline.y as usize) * aligned_stride +
-            (current_scanline.offset as usize) - head_length;
-        let end = start + aligned_stride;
-        let current_scanline_data = &mut tail[start..end];
-        ScanlinesForPrediction {
-            reference_scanline: reference_scanline_data,
-            current_scanline: current_scanline_data,
-            stride: current_scanline.stride,
+        (data_provider, dropped_receiver)
+    }
+
+    fn rgba_pixels(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self.rgba_pixels, self.rgba_length)
+        }
+    }
+}
+
+impl DataProvider for BufferDataProvider {
+    fn fetch_scanlines_for_prediction<'a>(&'a mut self,
+                                          reference_scanline: Option<u32>,
+                                          current_scanline: u32,
+                                          lod: LevelOfDetail,
+                                          indexed: bool)
+                                          -> ScanlinesForPrediction {
+        if indexed {
+            scanlines_for_prediction(&mut self.indexed_pixels,
+                                     self.indexed_aligned_stride,
+                                     self.indexed_aligned_stride,
+                                     reference_scanline,
+                                     current_scanline,
+                                     lod,
+                                     indexed,
+                                     None)
+        } else {
+            let (rgba_stride, rgba_scanline_length) = (self.rgba_stride,
+                                                       self.rgba_scanline_length);
+            scanlines_for_prediction(self.rgba_pixels(),
+                                     rgba_stride,
+                                     rgba_scanline_length,
+                                     reference_scanline,
+                                     current_scanline,
+                                     lod,
+                                     indexed,
+                                     None)
         }
     }
 
@@ -115,14 +226,21 @@ impl DataProvider for MemoryDataProvider {
         } else {
             None
         };
-        let rgba_aligned_stride = self.rgba_aligned_stride;
+        let rgba_start = self.rgba_stride * (rgba_scanline.y as usize) +
+            (rgba_scanline.offset as usize);
+        let rgba_pixels = unsafe {
+            slice::from_raw_parts_mut(self.rgba_pixels, self.rgba_length)
+        };
         let indexed_aligned_stride = self.indexed_aligned_stride;
         ScanlinesForRgbaConversion {
-            rgba_scanline: &mut self.rgba_pixels[(rgba_aligned_stride * scanline as usize)..],
-            indexed_scanline: if indexed_scanline.is_some() {
-                Some(&self.indexed_pixels[(indexed_aligned_stride * scanline as usize)..])
-            } else {
-                None
+            rgba_scanline: &mut rgba_pixels[rgba_start..],
+            indexed_scanline: match indexed_scanline {
+                Some(indexed_scanline) => {
+                    let indexed_start = indexed_aligned_stride * (indexed_scanline.y as usize) +
+                        (indexed_scanline.offset as usize);
+                    Some(&self.indexed_pixels[indexed_start..])
+                }
+                None => None,
             },
             rgba_stride: rgba_scanline.stride,
             indexed_stride: indexed_scanline.map(|indexed_scanline| indexed_scanline.stride),
@@ -131,11 +249,603 @@ impl DataProvider for MemoryDataProvider {
 
     fn rgba_conversion_complete_for_scanline(&mut self, _: u32, _: LevelOfDetail) {}
 
+    fn finished(&mut self) {}
+}
+
+impl Drop for BufferDataProvider {
+    fn drop(&mut self) {
+        let _ = self.dropped_sender.send(());
+    }
+}
+
+/// A data provider that downscales the image as it is decoded, so that the full-size image is never
+/// stored.
+///
+/// Scanlines of non-interlaced images are predicted into a `ScanlinePair`. Once each one has been
+/// converted to RGBA, it is added to a row of sums, and each time a full band of scanlines has been
+/// added up, the sums are averaged into a scanline of the downscaled image. This is a box filter.
+///
+/// The first few passes of Adam7-interlaced images hold exactly the pixels at the top left of every
+/// block, so those passes are instead deinterlaced straight into the downscaled image, and the
+/// image loader is told to stop after the last of them.
+struct ScaledDataProvider {
+    width: u32,
+    height: u32,
+    /// The factors by which the width and height of the image are divided.
+    x_factor: u32,
+    y_factor: u32,
+    interlaced: bool,
+    /// True if the image needs RGBA conversion; i.e. if it isn't already RGBA.
+    converted: bool,
+    rgba_pixels: Vec<u8>,
+    indexed_pixels: Vec<u8>,
+    rgba_aligned_stride: usize,
+    indexed_aligned_stride: usize,
+    scanlines: ScanlinePair,
+    /// For each channel of each pixel of the downscaled scanline being filtered, the sum of the
+    /// corresponding values in the full-size scanlines added up so far.
+    sums: Vec<u32>,
+    data_sender: Sender<Vec<u8>>,
+}
+
+impl ScaledDataProvider {
+    fn new(x_factor: u32, y_factor: u32, metadata: &Metadata)
+           -> (ScaledDataProvider, Receiver<Vec<u8>>) {
+        let dimensions = metadata.dimensions;
+        let indexed = metadata.color_type == ColorType::Indexed;
+        let interlaced = metadata.interlace_method == InterlaceMethod::Adam7;
+        let scaled_dimensions = scaled_dimensions(dimensions, x_factor, y_factor);
+        let rgba_aligned_stride = imageloader::align(scaled_dimensions.width as usize * 4);
+        let indexed_aligned_stride = imageloader::align(scaled_dimensions.width as usize);
+        let (data_sender, data_receiver) = mpsc::channel();
+
+        // Interlaced images are deinterlaced straight into the downscaled image, so they need an
+        // indexed copy of it too.
+        let rgba_length = rgba_aligned_stride * (scaled_dimensions.height as usize);
+        let indexed_length = if indexed && interlaced {
+            indexed_aligned_stride * (scaled_dimensions.height as usize)
+        } else {
+            0
+        };
+
+        let (mut rgba_pixels, mut indexed_pixels) = (vec![], vec![]);
+        unsafe {
+            rgba_pixels.extend_with_uninitialized(rgba_length);
+            indexed_pixels.extend_with_uninitialized(indexed_length)
+        }
+
+        let data_provider = ScaledDataProvider {
+            width: dimensions.width,
+            height: dimensions.height,
+            x_factor: x_factor,
+            y_factor: y_factor,
+            interlaced: interlaced,
+            converted: metadata.color_type != ColorType::RgbAlpha,
+            rgba_pixels: rgba_pixels,
+            indexed_pixels: indexed_pixels,
+            rgba_aligned_stride: rgba_aligned_stride,
+            indexed_aligned_stride: indexed_aligned_stride,
+            scanlines: ScanlinePair::new(dimensions.width, indexed),
+            sums: vec![0; scaled_dimensions.width as usize * 4],
+            data_sender: data_sender,
+        };
+        (data_provider, data_receiver)
+    }
+
+    /// Returns the layout of the given scanline of a sampled level of detail within the downscaled
+    /// image, along with the number of bytes from its first pixel to the end of its last one.
+    fn sampled_scanline_layout(&self, scanline: u32, color_depth: u8, lod: LevelOfDetail)
+                               -> (InterlacingInfo, usize) {
+        let info = InterlacingInfo::new(scanline, color_depth, lod);
+        let pixel_count = info.scanline_width(self.width, color_depth);
+        let info = InterlacingInfo {
+            y: info.y / self.y_factor,
+            stride: info.stride / self.x_factor as u8,
+            offset: info.offset / self.x_factor as u8,
+        };
+
+        // Don't hand out any more room than the pixels need, lest the accelerated prediction
+        // routines write past them into pixels of earlier passes.
+        (info, scanline_length(&info, pixel_count, color_depth))
+    }
+
+    /// Adds a full-size scanline that has been completely decoded into the sums, and, if that
+    /// completes a band, averages the band into a scanline of the downscaled image.
+    fn add_scanline_to_sums(&mut self, scanline: u32) {
+        let (x_factor, y_factor) = (self.x_factor as usize, self.y_factor as usize);
+        {
+            let pixels = &self.scanlines.rgba_scanline(scanline)[0..(self.width as usize * 4)];
+            for (sums, pixels) in self.sums.chunks_mut(4).zip(pixels.chunks(x_factor * 4)) {
+                for pixel in pixels.chunks(4) {
+                    for (sum, value) in sums.iter_mut().zip(pixel.iter()) {
+                        *sum += *value as u32
+                    }
+                }
+            }
+        }
+
+        if (scanline + 1) % self.y_factor != 0 && scanline + 1 != self.height {
+            return
+        }
+
+        let band_height = scanline as usize % y_factor + 1;
+        let start = self.rgba_aligned_stride * (scanline / self.y_factor) as usize;
+        let width = self.width as usize;
+        for (scaled_x, (dest, sums)) in self.rgba_pixels[start..]
+                                            .chunks_mut(4)
+                                            .zip(self.sums.chunks_mut(4))
+                                            .enumerate() {
+            let count = (band_height * cmp::min(x_factor, width - scaled_x * x_factor)) as u32;
+            for (dest, sum) in dest.iter_mut().zip(sums.iter_mut()) {
+                *dest = ((*sum + count / 2) / count) as u8;
+                *sum = 0
+            }
+        }
+    }
+}
+
+impl DataProvider for ScaledDataProvider {
+    fn fetch_scanlines_for_prediction<'a>(&'a mut self,
+                                          reference_scanline: Option<u32>,
+                                          current_scanline: u32,
+                                          lod: LevelOfDetail,
+                                          indexed: bool)
+                                          -> ScanlinesForPrediction {
+        let buffer_color_depth = buffer_color_depth(indexed);
+        if self.interlaced {
+            let (current, length) =
+                self.sampled_scanline_layout(current_scanline, buffer_color_depth, lod);
+            let reference = reference_scanline.map(|reference_scanline| {
+                self.sampled_scanline_layout(reference_scanline, buffer_color_depth, lod).0
+            });
+            let (pixels, stride) = if indexed {
+                (&mut self.indexed_pixels, self.indexed_aligned_stride)
+            } else {
+                (&mut self.rgba_pixels, self.rgba_aligned_stride)
+            };
+            let (head, tail) = pixels.split_at_mut(stride * (current.y as usize));
+            let reference_scanline = match reference {
+                None => None,
+                Some(reference) => {
+                    let start = stride * (reference.y as usize) + (reference.offset as usize);
+                    Some(&mut head[start..(start + length)])
+                }
+            };
+            let start = current.offset as usize;
+            return ScanlinesForPrediction {
+                reference_scanline: reference_scanline,
+                current_scanline: &mut tail[start..(start + length)],
+                stride: current.stride,
+            }
+        }
+
+        self.scanlines.scanlines_for_prediction(reference_scanline, current_scanline, indexed)
+    }
+
+    fn prediction_complete_for_scanline(&mut self, scanline: u32, lod: LevelOfDetail) {
+        if !self.converted && lod == LevelOfDetail::None {
+            self.add_scanline_to_sums(scanline)
+        }
+    }
+
+    fn fetch_scanlines_for_rgba_conversion<'a>(&'a mut self,
+                                               scanline: u32,
+                                               lod: LevelOfDetail,
+                                               indexed: bool)
+                                               -> ScanlinesForRgbaConversion<'a> {
+        if self.interlaced {
+            let rgba_scanline = self.sampled_scanline_layout(scanline, 32, lod).0;
+            let indexed_scanline = if indexed {
+                Some(self.sampled_scanline_layout(scanline, 8, lod).0)
+            } else {
+                None
+            };
+            let rgba_start = self.rgba_aligned_stride * (rgba_scanline.y as usize) +
+                (rgba_scanline.offset as usize);
+            let indexed_aligned_stride = self.indexed_aligned_stride;
+            return ScanlinesForRgbaConversion {
+                rgba_scanline: &mut self.rgba_pixels[rgba_start..],
+                indexed_scanline: match indexed_scanline {
+                    Some(indexed_scanline) => {
+                        let indexed_start = indexed_aligned_stride *
+                            (indexed_scanline.y as usize) + (indexed_scanline.offset as usize);
+                        Some(&self.indexed_pixels[indexed_start..])
+                    }
+                    None => None,
+                },
+                rgba_stride: rgba_scanline.stride,
+                indexed_stride: indexed_scanline.map(|indexed_scanline| indexed_scanline.stride),
+            }
+        }
+
+        self.scanlines.scanlines_for_rgba_conversion(scanline, indexed)
+    }
+
+    fn rgba_conversion_complete_for_scanline(&mut self, scanline: u32, lod: LevelOfDetail) {
+        if lod == LevelOfDetail::None {
+            self.add_scanline_to_sums(scanline)
+        }
+    }
+
     fn finished(&mut self) {
         self.data_sender.send(mem::replace(&mut self.rgba_pixels, vec![])).unwrap()
     }
 }
 
+/// A data provider that decodes into memory and shows Adam7-interlaced images coarsely while they
+/// load, the way browsers do.
+///
+/// As each scanline of a pass is decoded, each of its pixels is replicated over the rest of the
+/// block that it covers, which later passes will fill in: for instance, each pixel of the first
+/// pass covers a block of 8 by 8 pixels. So, once a pass is complete, the image looks like a
+/// coarse version of the whole image, rather than a sparse one with most pixels uninitialized.
+///
+/// A copy of the image is sent to the receiver returned by `BlockFillDataProvider::new()` each
+/// time a pass is complete, and the image itself is sent once it's finished, unless it's
+/// unchanged since the last copy. The last image received is therefore always the finished one.
+/// Images that aren't interlaced are sent only once they're finished.
+///
+/// Regions of interest aren't supported.
+pub struct BlockFillDataProvider {
+    width: u32,
+    height: u32,
+    /// True if scanlines are complete only once they've been converted to RGBA.
+    converted: bool,
+    rgba_pixels: Vec<u8>,
+    indexed_pixels: Vec<u8>,
+    rgba_aligned_stride: usize,
+    indexed_aligned_stride: usize,
+    /// The number of scanlines of each Adam7 pass that are complete.
+    completed_scanlines: [u32; 7],
+    /// True if nothing has changed since the last image was sent.
+    sent: bool,
+    image_sender: Sender<Image>,
+}
+
+impl BlockFillDataProvider {
+    /// Creates a data provider with room for the image that `metadata` describes, along with the
+    /// receiver that copies of the image are sent to as it loads.
+    pub fn new(metadata: &Metadata) -> (BlockFillDataProvider, Receiver<Image>) {
+        let dimensions = metadata.dimensions;
+        let indexed = metadata.color_type == ColorType::Indexed;
+        let rgba_aligned_stride = imageloader::align(dimensions.width as usize * 4);
+        let indexed_aligned_stride = imageloader::align(dimensions.width as usize);
+        let (image_sender, image_receiver) = mpsc::channel();
+
+        let rgba_length = rgba_aligned_stride * (dimensions.height as usize);
+        let indexed_length = if indexed {
+            indexed_aligned_stride * (dimensions.height as usize)
+        } else {
+            0
+        };
+
+        let (mut rgba_pixels, mut indexed_pixels) = (vec![], vec![]);
+        unsafe {
+            rgba_pixels.extend_with_uninitialized(rgba_length);
+            indexed_pixels.extend_with_uninitialized(indexed_length)
+        }
+
+        let data_provider = BlockFillDataProvider {
+            width: dimensions.width,
+            height: dimensions.height,
+            converted: metadata.color_type != ColorType::RgbAlpha,
+            rgba_pixels: rgba_pixels,
+            indexed_pixels: indexed_pixels,
+            rgba_aligned_stride: rgba_aligned_stride,
+            indexed_aligned_stride: indexed_aligned_stride,
+            completed_scanlines: [0; 7],
+            sent: false,
+            image_sender: image_sender,
+        };
+        (data_provider, image_receiver)
+    }
+
+    /// Fills in the blocks that the given scanline covers, and sends a copy of the image if that
+    /// completes a pass.
+    fn scanline_complete(&mut self, scanline: u32, lod: LevelOfDetail) {
+        self.sent = false;
+        let pass = match lod {
+            LevelOfDetail::None => return,
+            LevelOfDetail::Adam7(pass) => pass as usize,
+        };
+
+        self.fill_blocks(scanline, lod);
+        self.completed_scanlines[pass] += 1;
+        if self.completed_scanlines[pass] == InterlacingInfo::height_of_lod(self.height, lod) {
+            let image = Image {
+                width: self.width,
+                height: self.height,
+                stride: self.rgba_aligned_stride,
+                pixels: self.rgba_pixels.clone(),
+            };
+            self.send(image)
+        }
+    }
+
+    /// Replicates each pixel of the given scanline over the rest of the block that it covers.
+    ///
+    /// Blocks are the cells of the grid that the pixels of this pass and all earlier ones lie on,
+    /// so no block holds any pixels of earlier passes other than the one at its top left.
+    fn fill_blocks(&mut self, scanline: u32, lod: LevelOfDetail) {
+        let (block_width, block_height) = preview_factors(lod);
+        let info = InterlacingInfo::new(scanline, 32, lod);
+        let (offset, stride) = (info.offset as usize, info.stride as usize);
+        let (block_length, row_length) = (block_width as usize * 4, self.width as usize * 4);
+        if offset >= row_length {
+            return
+        }
+
+        let block_height = cmp::min(block_height, self.height - info.y) as usize;
+        let aligned_stride = self.rgba_aligned_stride;
+        let start = aligned_stride * (info.y as usize);
+        let (row, rows_below) = self.rgba_pixels[start..].split_at_mut(aligned_stride);
+        let row = &mut row[offset..row_length];
+        for block in row.chunks_mut(stride) {
+            let block_length = cmp::min(block_length, block.len());
+            let (pixel, rest) = block[0..block_length].split_at_mut(4);
+            for dest in rest.chunks_mut(4) {
+                dest.clone_from_slice(pixel)
+            }
+        }
+        for row_below in rows_below.chunks_mut(aligned_stride).take(block_height - 1) {
+            for (dest, src) in row_below[offset..row_length].chunks_mut(stride)
+                                                            .zip(row.chunks(stride)) {
+                let block_length = cmp::min(block_length, src.len());
+                dest[0..block_length].clone_from_slice(&src[0..block_length])
+            }
+        }
+    }
+
+    fn send(&mut self, image: Image) {
+        self.sent = true;
+        drop(self.image_sender.send(image))
+    }
+}
+
+impl DataProvider for BlockFillDataProvider {
+    fn fetch_scanlines_for_prediction<'a>(&'a mut self,
+                                          reference_scanline: Option<u32>,
+                                          current_scanline: u32,
+                                          lod: LevelOfDetail,
+                                          indexed: bool)
+                                          -> ScanlinesForPrediction {
+        if indexed {
+            return scanlines_for_prediction(&mut self.indexed_pixels,
+                                            self.indexed_aligned_stride,
+                                            self.indexed_aligned_stride,
+                                            reference_scanline,
+                                            current_scanline,
+                                            lod,
+                                            indexed,
+                                            None)
+        }
+
+        // Don't hand out any more room than the pixels of interlaced scanlines need, lest the
+        // accelerated prediction routines write past them into blocks that have been filled in.
+        let rgba_scanline_length = match lod {
+            LevelOfDetail::None => self.rgba_aligned_stride,
+            LevelOfDetail::Adam7(_) => {
+                let info = InterlacingInfo::new(current_scanline, 32, lod);
+                scanline_length(&info, info.scanline_width(self.width, 32), 32)
+            }
+        };
+        scanlines_for_prediction(&mut self.rgba_pixels,
+                                 self.rgba_aligned_stride,
+                                 rgba_scanline_length,
+                                 reference_scanline,
+                                 current_scanline,
+                                 lod,
+                                 indexed,
+                                 None)
+    }
+
+    fn prediction_complete_for_scanline(&mut self, scanline: u32, lod: LevelOfDetail) {
+        if !self.converted {
+            self.scanline_complete(scanline, lod)
+        }
+    }
+
+    fn fetch_scanlines_for_rgba_conversion<'a>(&'a mut self,
+                                               scanline: u32,
+                                               lod: LevelOfDetail,
+                                               indexed: bool)
+                                               -> ScanlinesForRgbaConversion<'a> {
+        let rgba_scanline = InterlacingInfo::new(scanline, 32, lod);
+        let indexed_scanline = if indexed {
+            Some(InterlacingInfo::new(scanline, 8, lod))
+        } else {
+            None
+        };
+        let rgba_start = self.rgba_aligned_stride * (rgba_scanline.y as usize) +
+            (rgba_scanline.offset as usize);
+        let indexed_aligned_stride = self.indexed_aligned_stride;
+        ScanlinesForRgbaConversion {
+            rgba_scanline: &mut self.rgba_pixels[rgba_start..],
+            indexed_scanline: match indexed_scanline {
+                Some(indexed_scanline) => {
+                    let indexed_start = indexed_aligned_stride * (indexed_scanline.y as usize) +
+                        (indexed_scanline.offset as usize);
+                    Some(&self.indexed_pixels[indexed_start..])
+                }
+                None => None,
+            },
+            rgba_stride: rgba_scanline.stride,
+            indexed_stride: indexed_scanline.map(|indexed_scanline| indexed_scanline.stride),
+        }
+    }
+
+    fn rgba_conversion_complete_for_scanline(&mut self, scanline: u32, lod: LevelOfDetail) {
+        self.scanline_complete(scanline, lod)
+    }
+
+    fn finished(&mut self) {
+        if !self.sent {
+            let image = Image {
+                width: self.width,
+                height: self.height,
+                stride: self.rgba_aligned_stride,
+                pixels: mem::replace(&mut self.rgba_pixels, vec![]),
+            };
+            self.send(image)
+        }
+    }
+}
+
+/// A data provider that decodes into an image that other threads can read at any time while it
+/// loads, for displaying images progressively.
+///
+/// Scanlines are predicted into a `ScanlinePair`, and, once each one is complete, it is copied into
+/// the shared image, which is locked only while that happens. The rows that change are recorded,
+/// so that, for instance, a UI thread can re-upload just those rows of a texture. See
+/// `SharedImage`.
+///
+/// Regions of interest aren't supported.
+pub struct SharedDataProvider {
+    width: u32,
+    /// True if scanlines are complete only once they've been converted to RGBA.
+    converted: bool,
+    scanlines: ScanlinePair,
+    shared_image: Arc<Mutex<SharedImage>>,
+}
+
+impl SharedDataProvider {
+    /// Creates a data provider for the image that `metadata` describes, along with the shared
+    /// image that it decodes into.
+    pub fn new(metadata: &Metadata) -> (SharedDataProvider, Arc<Mutex<SharedImage>>) {
+        let dimensions = metadata.dimensions;
+        let stride = imageloader::align(dimensions.width as usize * 4);
+        let shared_image = Arc::new(Mutex::new(SharedImage {
+            image: Image {
+                width: dimensions.width,
+                height: dimensions.height,
+                stride: stride,
+                pixels: vec![0; stride * (dimensions.height as usize)],
+            },
+            dirty_rows: vec![],
+            finished: false,
+            cancelled: false,
+        }));
+
+        let data_provider = SharedDataProvider {
+            width: dimensions.width,
+            converted: metadata.color_type != ColorType::RgbAlpha,
+            scanlines: ScanlinePair::new(dimensions.width,
+                                         metadata.color_type == ColorType::Indexed),
+            shared_image: shared_image.clone(),
+        };
+        (data_provider, shared_image)
+    }
+
+    /// Copies a scanline that has been completely decoded into the shared image.
+    fn scanline_complete(&mut self, scanline: u32, lod: LevelOfDetail) {
+        let info = InterlacingInfo::new(scanline, 32, lod);
+        let pixel_count = info.scanline_width(self.width, 32) as usize;
+        let pixels = self.scanlines.rgba_scanline(scanline);
+
+        let mut shared_image = self.shared_image.lock().unwrap();
+        {
+            let image = &mut shared_image.image;
+            let start = image.stride * (info.y as usize) + (info.offset as usize);
+            for (dest, src) in image.pixels[start..]
+                                    .chunks_mut(info.stride as usize)
+                                    .zip(pixels.chunks(4))
+                                    .take(pixel_count) {
+                dest[0..4].clone_from_slice(src)
+            }
+        }
+        shared_image.add_dirty_row(scanline, lod)
+    }
+}
+
+impl DataProvider for SharedDataProvider {
+    fn fetch_scanlines_for_prediction<'a>(&'a mut self,
+                                          reference_scanline: Option<u32>,
+                                          current_scanline: u32,
+                                          _: LevelOfDetail,
+                                          indexed: bool)
+                                          -> ScanlinesForPrediction {
+        self.scanlines.scanlines_for_prediction(reference_scanline, current_scanline, indexed)
+    }
+
+    fn prediction_complete_for_scanline(&mut self, scanline: u32, lod: LevelOfDetail) {
+        if !self.converted {
+            self.scanline_complete(scanline, lod)
+        }
+    }
+
+    fn fetch_scanlines_for_rgba_conversion<'a>(&'a mut self,
+                                               scanline: u32,
+                                               _: LevelOfDetail,
+                                               indexed: bool)
+                                               -> ScanlinesForRgbaConversion<'a> {
+        self.scanlines.scanlines_for_rgba_conversion(scanline, indexed)
+    }
+
+    fn rgba_conversion_complete_for_scanline(&mut self, scanline: u32, lod: LevelOfDetail) {
+        self.scanline_complete(scanline, lod)
+    }
+
+    fn finished(&mut self) {
+        self.shared_image.lock().unwrap().finished = true
+    }
+
+    fn cancelled(&mut self) {
+        self.shared_image.lock().unwrap().cancelled = true
+    }
+}
+
+/// An image that a `SharedDataProvider` decodes into, which other threads may read at any time.
+pub struct SharedImage {
+    /// The image. Pixels that haven't been decoded yet are transparent black.
+    pub image: Image,
+    dirty_rows: Vec<DirtyRows>,
+    /// True once the image has been completely decoded.
+    pub finished: bool,
+    /// True if decoding was cancelled, in which case no more of the image will be decoded.
+    pub cancelled: bool,
+}
+
+impl SharedImage {
+    /// Returns the ranges of rows that have changed since this method was last called, in the
+    /// order in which they changed.
+    ///
+    /// Consecutive rows that change at the same level of detail are merged into one range.
+    pub fn take_dirty_rows(&mut self) -> Vec<DirtyRows> {
+        mem::replace(&mut self.dirty_rows, vec![])
+    }
+
+    fn add_dirty_row(&mut self, scanline: u32, lod: LevelOfDetail) {
+        let y = InterlacingInfo::new(scanline, 32, lod).y;
+        if let Some(last_dirty_rows) = self.dirty_rows.last_mut() {
+            if scanline > 0 && last_dirty_rows.lod == lod &&
+                    last_dirty_rows.end == InterlacingInfo::new(scanline - 1, 32, lod).y + 1 {
+                last_dirty_rows.end = y + 1;
+                return
+            }
+        }
+        self.dirty_rows.push(DirtyRows {
+            lod: lod,
+            start: y,
+            end: y + 1,
+        })
+    }
+}
+
+/// A range of rows of an image that have changed at one level of detail. See
+/// `SharedImage::take_dirty_rows()`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct DirtyRows {
+    /// The level of detail that the rows were decoded at.
+    pub lod: LevelOfDetail,
+    /// The first row that changed.
+    pub start: u32,
+    /// One past the last row that changed.
+    ///
+    /// The scanlines of a level of detail of an interlaced image are several rows apart, so not
+    /// every row in between need have changed.
+    pub end: u32,
+}
+
 /// An in-memory decoded image in big-endian RGBA format, 32 bits per pixel.
 pub struct Image {
     /// The width of the image, in pixels.
@@ -146,7 +856,8 @@ pub struct Image {
     /// equal to `4 * width`.
     ///
     /// Because of SIMD alignment restrictions, `parng` may well choose a value greater than `4 *
-    /// width` here.
+    /// width` here, unless the image was loaded with `Image::load_with_stride()` or
+    /// `Image::load_from_slice_with_stride()`.
     pub stride: usize,
     /// The actual pixels.
     pub pixels: Vec<u8>,
@@ -161,27 +872,230 @@ impl Image {
     /// in-memory representation, or you need to display the image before it's fully loaded,
     /// consider using the `imageloader::ImageLoader` API instead.
     pub fn load<I>(input: &mut I) -> Result<Image, PngError> where I: Read + Seek {
+        Image::load_with(|image| image.add_data(input), None)
+    }
+
+    /// Allocates space for and loads a PNG image stream that is held entirely in memory.
+    ///
+    /// This is like `Image::load()`, but the image data is decompressed directly out of `data`
+    /// instead of being copied out piece by piece through a reader. If `data` ends before the
+    /// image does, a `PngError::Io` error is returned.
+    pub fn load_from_slice(data: &[u8]) -> Result<Image, PngError> {
+        Image::load_with(|image| add_data_from_complete_slice(image, data), None)
+    }
+
+    /// Allocates space for and loads a PNG image from the file at `path`.
+    ///
+    /// The file is memory-mapped and decoded straight from the mapping, which avoids the system
+    /// calls and copies that reading it would involve. Errors opening or mapping the file are
+    /// returned as `PngError::Io`.
+    pub fn load_from_path<P>(path: P) -> Result<Image, PngError> where P: AsRef<Path> {
+        let file = try!(MappedFile::open(path).map_err(PngError::Io));
+        Image::load_from_slice(&file)
+    }
+
+    /// Allocates space for and loads a PNG image stream from a reader into memory, with scanlines
+    /// `stride` bytes apart.
+    ///
+    /// This is like `Image::load()`, but any `stride` of at least `4 * width` may be chosen; for
+    /// instance, `4 * width` for tightly packed pixels. A `PngError::InvalidBuffer` error is
+    /// returned if `stride` is too small. Scanlines that aren't suitably aligned for SIMD are
+    /// predicted in scratch space and copied into place.
+    pub fn load_with_stride<I>(input: &mut I, stride: usize) -> Result<Image, PngError>
+                               where I: Read + Seek {
+        Image::load_with_stride_with(|image| image.add_data(input), stride)
+    }
+
+    /// Allocates space for and loads a PNG image stream that is held entirely in memory, with
+    /// scanlines `stride` bytes apart.
+    ///
+    /// See `Image::load_from_slice()` and `Image::load_with_stride()`.
+    pub fn load_from_slice_with_stride(data: &[u8], stride: usize) -> Result<Image, PngError> {
+        Image::load_with_stride_with(|image| add_data_from_complete_slice(image, data), stride)
+    }
+
+    fn load_with_stride_with<F>(mut add_data: F, stride: usize) -> Result<Image, PngError>
+                                where F: FnMut(&mut ImageLoader)
+                                               -> Result<LoadProgress, PngError> {
         let mut image = ImageLoader::new();
-        loop {
-            match try!(image.add_data(input)) {
-                LoadProgress::NeedDataProviderAndMoreData => break,
-                LoadProgress::NeedMoreData => {}
-                LoadProgress::Finished => panic!("Image ended before metadata was read!"),
+        try!(load_metadata(&mut image, &mut add_data));
+
+        let dimensions = image.metadata().as_ref().unwrap().dimensions;
+        let length = match stride.checked_mul(dimensions.height as usize) {
+            Some(length) => length,
+            None => {
+                return Err(PngError::InvalidBuffer(format!("stride of {} bytes is too large",
+                                                           stride)))
             }
+        };
+        try!(check_buffer_size(dimensions, length, stride));
+
+        let mut pixels = vec![];
+        unsafe {
+            pixels.extend_with_uninitialized(length)
         }
+        try!(finish_loading_into_buffer(image, add_data, &mut pixels, stride));
+        Ok(Image {
+            width: dimensions.width,
+            height: dimensions.height,
+            stride: stride,
+            pixels: pixels,
+        })
+    }
+
+    /// Allocates space for and loads only the given region of a PNG image stream from a reader
+    /// into memory.
+    ///
+    /// The region is clipped to the bounds of the image, and the returned image is the size of the
+    /// clipped region. Decoding stops once the bottom of the region is reached, and pixels to
+    /// either side of it are never stored or converted to RGBA. See
+    /// `ImageLoader::set_region_of_interest()`.
+    pub fn load_region<I>(input: &mut I, region: Region) -> Result<Image, PngError>
+                          where I: Read + Seek {
+        Image::load_with(|image| image.add_data(input), Some(region))
+    }
+
+    /// Allocates space for and loads only the given region of a PNG image stream that is held
+    /// entirely in memory.
+    ///
+    /// See `Image::load_from_slice()` and `Image::load_region()`.
+    pub fn load_region_from_slice(data: &[u8], region: Region) -> Result<Image, PngError> {
+        Image::load_with(|image| add_data_from_complete_slice(image, data), Some(region))
+    }
 
-        let (dimensions, indexed) = {
+    /// Allocates space for and loads a PNG image stream from a reader into memory, downscaling it
+    /// by the given factor as it is decoded.
+    ///
+    /// Each pixel of the returned image is the average of a block of pixels of the original image
+    /// (a box filter), except for pixels in partial blocks along the right and bottom edges. The
+    /// full-size image is never held in memory. See `Scale` for the dimensions of the result.
+    ///
+    /// Adam7-interlaced images are downscaled differently: each pixel of the result is the pixel at
+    /// the top left of its block, which the first few passes of the image supply. (For instance,
+    /// the first pass alone supplies every pixel of an image downscaled by a factor of 8.) Decoding
+    /// stops after those passes, so this is much faster but blurs less than a box filter.
+    pub fn load_scaled<I>(input: &mut I, scale: Scale) -> Result<Image, PngError>
+                          where I: Read + Seek {
+        Image::load_scaled_with(|image| image.add_data(input), scale)
+    }
+
+    /// Allocates space for and loads a PNG image stream that is held entirely in memory,
+    /// downscaling it by the given factor as it is decoded.
+    ///
+    /// See `Image::load_from_slice()` and `Image::load_scaled()`.
+    pub fn load_scaled_from_slice(data: &[u8], scale: Scale) -> Result<Image, PngError> {
+        Image::load_scaled_with(|image| add_data_from_complete_slice(image, data), scale)
+    }
+
+    fn load_scaled_with<F>(mut add_data: F, scale: Scale) -> Result<Image, PngError>
+                           where F: FnMut(&mut ImageLoader) -> Result<LoadProgress, PngError> {
+        let mut image = ImageLoader::new();
+        try!(load_metadata(&mut image, &mut add_data));
+
+        let interlaced = image.metadata().as_ref().unwrap().interlace_method ==
+            InterlaceMethod::Adam7;
+        if interlaced {
+            image.set_last_level_of_detail(scale.last_sampled_lod())
+        }
+        Image::finish_loading_downscaled(image, add_data, scale.factor(), scale.factor())
+    }
+
+    /// Allocates space for and loads a reduced-resolution preview of a PNG image stream from a
+    /// reader into memory, decoding only up to the given Adam7 pass.
+    ///
+    /// The returned image holds just the pixels that the passes up to and including `lod` supply,
+    /// packed together, so it is smaller than the full image: for instance, an eighth of the width
+    /// and height for `LevelOfDetail::Adam7(0)`, or half the height for `LevelOfDetail::Adam7(5)`
+    /// (rounded up). The rest of the image data is never read. See
+    /// `ImageLoader::set_last_level_of_detail()`.
+    ///
+    /// Images that aren't interlaced, or a `lod` of `LevelOfDetail::None`, are loaded in full, as
+    /// by `Image::load()`.
+    pub fn load_preview<I>(input: &mut I, lod: LevelOfDetail) -> Result<Image, PngError>
+                           where I: Read + Seek {
+        Image::load_preview_with(|image| image.add_data(input), lod)
+    }
+
+    /// Allocates space for and loads a reduced-resolution preview of a PNG image stream that is
+    /// held entirely in memory.
+    ///
+    /// See `Image::load_from_slice()` and `Image::load_preview()`.
+    pub fn load_preview_from_slice(data: &[u8], lod: LevelOfDetail) -> Result<Image, PngError> {
+        Image::load_preview_with(|image| add_data_from_complete_slice(image, data), lod)
+    }
+
+    fn load_preview_with<F>(mut add_data: F, lod: LevelOfDetail) -> Result<Image, PngError>
+                            where F: FnMut(&mut ImageLoader)
+                                           -> Result<LoadProgress, PngError> {
+        let mut image = ImageLoader::new();
+        try!(load_metadata(&mut image, &mut add_data));
+
+        let interlaced = image.metadata().as_ref().unwrap().interlace_method ==
+            InterlaceMethod::Adam7;
+        if !interlaced {
+            return Image::finish_loading_with_region(image, add_data, None)
+        }
+        image.set_last_level_of_detail(lod);
+        let (x_factor, y_factor) = preview_factors(lod);
+        Image::finish_loading_downscaled(image, add_data, x_factor, y_factor)
+    }
+
+    fn finish_loading_downscaled<F>(mut image: ImageLoader,
+                                    mut add_data: F,
+                                    x_factor: u32,
+                                    y_factor: u32)
+                                    -> Result<Image, PngError>
+                                    where F: FnMut(&mut ImageLoader)
+                                                   -> Result<LoadProgress, PngError> {
+        let (scaled_dimensions, (data_provider, data_receiver)) = {
+            let metadata = image.metadata().as_ref().unwrap();
+            (scaled_dimensions(metadata.dimensions, x_factor, y_factor),
+             ScaledDataProvider::new(x_factor, y_factor, metadata))
+        };
+        let stride = data_provider.rgba_aligned_stride;
+        image.set_data_provider(Box::new(data_provider));
+        try!(finish_loading(&mut image, &mut add_data));
+
+        let pixels = data_receiver.recv().unwrap();
+        Ok(Image {
+            width: scaled_dimensions.width,
+            height: scaled_dimensions.height,
+            stride: stride,
+            pixels: pixels,
+        })
+    }
+
+    fn load_with<F>(mut add_data: F, region: Option<Region>) -> Result<Image, PngError>
+                    where F: FnMut(&mut ImageLoader) -> Result<LoadProgress, PngError> {
+        let mut image = ImageLoader::new();
+        try!(load_metadata(&mut image, &mut add_data));
+        Image::finish_loading_with_region(image, add_data, region)
+    }
+
+    fn finish_loading_with_region<F>(mut image: ImageLoader,
+                                     mut add_data: F,
+                                     region: Option<Region>)
+                                     -> Result<Image, PngError>
+                                     where F: FnMut(&mut ImageLoader)
+                                                    -> Result<LoadProgress, PngError> {
+        let (mut dimensions, indexed) = {
             let metadata = image.metadata().as_ref().unwrap();
             (metadata.dimensions, metadata.color_type == ColorType::Indexed)
         };
+        let region = region.map(|region| image.set_region_of_interest(region));
+        if let Some(region) = region {
+            dimensions = Dimensions {
+                width: region.width,
+                height: region.height,
+            }
+        }
         let (data_provider, data_receiver) = MemoryDataProvider::new(dimensions.width,
                                                                      dimensions.height,
-                                                                     indexed);
+                                                                     indexed,
+                                                                     region);
         let aligned_stride = data_provider.rgba_aligned_stride;
         image.set_data_provider(Box::new(data_provider));
-
-        while let LoadProgress::NeedMoreData = try!(image.add_data(input)) {}
-        try!(image.wait_until_finished());
+        try!(finish_loading(&mut image, &mut add_data));
 
         let pixels = data_receiver.recv().unwrap();
         Ok(Image {
@@ -193,6 +1107,285 @@ impl Image {
     }
 }
 
+/// A factor to downscale an image by as it is decoded. See `Image::load_scaled()`.
+///
+/// The dimensions of the downscaled image are those of the original image divided by the factor,
+/// rounded up.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Scale {
+    /// Downscale to half the width and height.
+    Half,
+    /// Downscale to a quarter of the width and height.
+    Quarter,
+    /// Downscale to an eighth of the width and height.
+    Eighth,
+}
+
+impl Scale {
+    /// Returns the factor that this scale divides the width and height of the image by.
+    pub fn factor(self) -> u32 {
+        match self {
+            Scale::Half => 2,
+            Scale::Quarter => 4,
+            Scale::Eighth => 8,
+        }
+    }
+
+    /// Returns the dimensions that an image with the given dimensions has when downscaled.
+    pub fn dimensions(self, dimensions: Dimensions) -> Dimensions {
+        scaled_dimensions(dimensions, self.factor(), self.factor())
+    }
+
+    /// Returns the last Adam7 pass needed to supply the pixel at the top left of every block.
+    fn last_sampled_lod(self) -> LevelOfDetail {
+        match self {
+            Scale::Half => LevelOfDetail::Adam7(4),
+            Scale::Quarter => LevelOfDetail::Adam7(2),
+            Scale::Eighth => LevelOfDetail::Adam7(0),
+        }
+    }
+}
+
+/// Returns the dimensions of an image downscaled by the given horizontal and vertical factors,
+/// rounding up.
+fn scaled_dimensions(dimensions: Dimensions, x_factor: u32, y_factor: u32) -> Dimensions {
+    Dimensions {
+        width: (dimensions.width + x_factor - 1) / x_factor,
+        height: (dimensions.height + y_factor - 1) / y_factor,
+    }
+}
+
+/// Returns the horizontal and vertical distances between the pixels that the Adam7 passes up to
+/// and including the given one supply.
+fn preview_factors(lod: LevelOfDetail) -> (u32, u32) {
+    match lod {
+        LevelOfDetail::Adam7(0) => (8, 8),
+        LevelOfDetail::Adam7(1) => (4, 8),
+        LevelOfDetail::Adam7(2) => (4, 4),
+        LevelOfDetail::Adam7(3) => (2, 4),
+        LevelOfDetail::Adam7(4) => (2, 2),
+        LevelOfDetail::Adam7(5) => (1, 2),
+        LevelOfDetail::None | LevelOfDetail::Adam7(_) => (1, 1),
+    }
+}
+
+/// Loads a PNG image stream from a reader directly into `pixels`, a buffer supplied by the caller,
+/// without allocating space for the image.
+///
+/// The image is stored in big-endian, 32 bits per pixel RGBA, with successive scanlines `stride`
+/// bytes apart. `stride` must be at least `4 * width`, and `pixels` must be at least `stride *
+/// (height - 1) + 4 * width` bytes long. This is checked as soon as the image header has been
+/// read, before anything is written to `pixels`, and a `PngError::InvalidBuffer` error is
+/// returned if the buffer is too small. Any padding between scanlines may be overwritten, but
+/// nothing past the end of the last scanline is.
+///
+/// Returns the dimensions of the image. Like `Image::load()`, this function does not return until
+/// the image is fully loaded.
+pub fn load_into_buffer<I>(input: &mut I, pixels: &mut [u8], stride: usize)
+                           -> Result<Dimensions, PngError>
+                           where I: Read + Seek {
+    load_into_buffer_with(|image| image.add_data(input), pixels, stride)
+}
+
+/// Loads a PNG image stream that is held entirely in memory directly into `pixels`, a buffer
+/// supplied by the caller.
+///
+/// This is like `load_into_buffer()`, but the image data is decompressed directly out of `data`,
+/// as `Image::load_from_slice()` does.
+pub fn load_from_slice_into_buffer(data: &[u8], pixels: &mut [u8], stride: usize)
+                                   -> Result<Dimensions, PngError> {
+    load_into_buffer_with(|image| add_data_from_complete_slice(image, data), pixels, stride)
+}
+
+fn load_into_buffer_with<F>(mut add_data: F, pixels: &mut [u8], stride: usize)
+                            -> Result<Dimensions, PngError>
+                            where F: FnMut(&mut ImageLoader) -> Result<LoadProgress, PngError> {
+    let mut image = ImageLoader::new();
+    try!(load_metadata(&mut image, &mut add_data));
+    finish_loading_into_buffer(image, add_data, pixels, stride)
+}
+
+/// Decodes the rest of the image, whose metadata has been read, into `pixels`.
+fn finish_loading_into_buffer<F>(image: ImageLoader,
+                                 mut add_data: F,
+                                 pixels: &mut [u8],
+                                 stride: usize)
+                                 -> Result<Dimensions, PngError>
+                                 where F: FnMut(&mut ImageLoader)
+                                                -> Result<LoadProgress, PngError> {
+    let (dimensions, indexed) = {
+        let metadata = image.metadata().as_ref().unwrap();
+        (metadata.dimensions, metadata.color_type == ColorType::Indexed)
+    };
+    try!(check_buffer_size(dimensions, pixels.len(), stride));
+
+    let (data_provider, dropped_receiver) = BufferDataProvider::new(pixels,
+                                                                   stride,
+                                                                   dimensions.width,
+                                                                   dimensions.height,
+                                                                   indexed);
+    let mut image = BufferImageLoader {
+        image: Some(image),
+        dropped_receiver: dropped_receiver,
+    };
+    image.image().set_data_provider(Box::new(data_provider));
+    try!(finish_loading(image.image(), &mut add_data));
+    Ok(dimensions)
+}
+
+/// An image loader whose data provider writes to a buffer borrowed from the caller.
+///
+/// The data provider writes to the buffer from the predictor thread, so when this is dropped,
+/// whether loading succeeded, failed, or panicked, it drops the image loader and then waits for
+/// the predictor thread to let go of the data provider. Nothing writes to the buffer after that.
+struct BufferImageLoader {
+    image: Option<ImageLoader>,
+    dropped_receiver: Receiver<()>,
+}
+
+impl BufferImageLoader {
+    fn image(&mut self) -> &mut ImageLoader {
+        self.image.as_mut().expect("Image loader already dropped?!")
+    }
+}
+
+impl Drop for BufferImageLoader {
+    fn drop(&mut self) {
+        drop(self.image.take());
+        let _ = self.dropped_receiver.recv();
+    }
+}
+
+fn check_buffer_size(dimensions: Dimensions, length: usize, stride: usize)
+                     -> Result<(), PngError> {
+    let scanline_length = dimensions.width as usize * 4;
+    if stride < scanline_length {
+        return Err(PngError::InvalidBuffer(format!("stride of {} bytes is too small for a \
+                                                    scanline {} pixels wide",
+                                                   stride,
+                                                   dimensions.width)))
+    }
+    let required_length =
+        stride.checked_mul(dimensions.height.saturating_sub(1) as usize)
+              .and_then(|length| length.checked_add(scanline_length));
+    match required_length {
+        Some(required_length) if length >= required_length => Ok(()),
+        _ => {
+            Err(PngError::InvalidBuffer(format!("buffer of {} bytes is too small for a {}x{} \
+                                                 image with a stride of {} bytes",
+                                                length,
+                                                dimensions.width,
+                                                dimensions.height,
+                                                stride)))
+        }
+    }
+}
+
+/// Adds data until the image metadata has been read.
+fn load_metadata<F>(image: &mut ImageLoader, add_data: &mut F) -> Result<(), PngError>
+                    where F: FnMut(&mut ImageLoader) -> Result<LoadProgress, PngError> {
+    loop {
+        match try!(add_data(image)) {
+            LoadProgress::NeedDataProviderAndMoreData => return Ok(()),
+            LoadProgress::NeedMoreData => {}
+            LoadProgress::Finished => panic!("Image ended before metadata was read!"),
+            LoadProgress::WaitingForPrediction => {
+                panic!("Only non-blocking methods wait for prediction!")
+            }
+            LoadProgress::Yielded => panic!("Only methods with a work budget yield!"),
+        }
+    }
+}
+
+/// Adds the rest of the data and waits for the image to be fully decoded.
+fn finish_loading<F>(image: &mut ImageLoader, add_data: &mut F) -> Result<(), PngError>
+                     where F: FnMut(&mut ImageLoader) -> Result<LoadProgress, PngError> {
+    while let LoadProgress::NeedMoreData = try!(add_data(image)) {}
+    image.wait_until_finished()
+}
+
+/// Adds `data`, the whole of a PNG image stream, to `image`. Since no more data can arrive, a
+/// request for more is an error.
+fn add_data_from_complete_slice(image: &mut ImageLoader, data: &[u8])
+                                -> Result<LoadProgress, PngError> {
+    match try!(image.add_data_from_slice(data)) {
+        LoadProgress::NeedMoreData => {
+            Err(PngError::Io(io::Error::new(ErrorKind::UnexpectedEof,
+                                            "PNG image stream ended prematurely")))
+        }
+        load_progress => Ok(load_progress),
+    }
+}
+
+/// Hands out the scanlines that prediction needs from `pixels`, in which scanlines are `stride`
+/// bytes apart and each one has `scanline_length` bytes of room after its first pixel, space
+/// permitting. If `region` is present, `pixels` holds only that region of the image.
+fn scanlines_for_prediction<'a>(pixels: &'a mut [u8],
+                                stride: usize,
+                                scanline_length: usize,
+                                reference_scanline: Option<u32>,
+                                current_scanline: u32,
+                                lod: LevelOfDetail,
+                                indexed: bool,
+                                region: Option<&Region>)
+                                -> ScanlinesForPrediction<'a> {
+    let buffer_color_depth = buffer_color_depth(indexed);
+    let reference_scanline = reference_scanline.map(|reference_scanline| {
+        scanline_interlacing_info(reference_scanline, buffer_color_depth, lod, region)
+    });
+    let current_scanline =
+        scanline_interlacing_info(current_scanline, buffer_color_depth, lod, region);
+
+    let split_point = stride * (current_scanline.y as usize);
+    let (head, tail) = pixels.split_at_mut(split_point);
+    let reference_scanline_data = match reference_scanline {
+        None => None,
+        Some(reference_scanline) => {
+            debug_assert!(current_scanline.stride == reference_scanline.stride);
+            let start = (reference_scanline.y as usize) * stride +
+                (reference_scanline.offset as usize);
+            let end = cmp::min(start + scanline_length, head.len());
+            let slice = &mut head[start..end];
+            Some(slice)
+        }
+    };
+    let start = current_scanline.offset as usize;
+    let end = cmp::min(start + scanline_length, tail.len());
+    let current_scanline_data = &mut tail[start..end];
+    ScanlinesForPrediction {
+        reference_scanline: reference_scanline_data,
+        current_scanline: current_scanline_data,
+        stride: current_scanline.stride,
+    }
+}
+
+/// Returns the layout of the given scanline in a buffer holding either the whole image or, if
+/// `region` is present, only that region of it.
+fn scanline_interlacing_info(scanline: u32,
+                             color_depth: u8,
+                             lod: LevelOfDetail,
+                             region: Option<&Region>)
+                             -> InterlacingInfo {
+    match region {
+        None => InterlacingInfo::new(scanline, color_depth, lod),
+        Some(region) => {
+            InterlacingInfo::in_region(scanline, color_depth, lod, region)
+                .expect("Scanline lies outside the region of interest!")
+        }
+    }
+}
+
+/// Returns the number of bytes from the first pixel of a scanline with the given layout and number
+/// of pixels to the end of its last pixel.
+fn scanline_length(info: &InterlacingInfo, pixel_count: u32, color_depth: u8) -> usize {
+    match pixel_count {
+        0 => 0,
+        pixel_count => {
+            (pixel_count as usize - 1) * (info.stride as usize) + (color_depth / 8) as usize
+        }
+    }
+}
+
 fn buffer_color_depth(indexed: bool) -> u8 {
     if indexed {
         8
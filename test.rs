@@ -5,7 +5,40 @@
 // Any copyright is dedicated to the Public Domain.
 // http://creativecommons.org/publicdomain/zero/1.0/
 
-use std::process::Command;
+use PngError;
+#[cfg(feature = "async")]
+use asynchronous::{ImageStream, LoadEvent};
+use capi::{self, PARNG_ERROR_INVALID_ARGUMENT, PARNG_ERROR_INVALID_BUFFER, PARNG_SUCCESS};
+#[cfg(feature = "zlib")]
+use flate2::Compression;
+#[cfg(feature = "zlib")]
+use flate2::write::ZlibEncoder;
+#[cfg(feature = "async")]
+use futures::task;
+use imageloader::{self, CancelHandle, DataProvider, DecoderPool, ImageLoader, InterlacingInfo};
+use imageloader::LevelOfDetail;
+use imageloader::{Budget, LoadProgress, Region, RowDataProvider, Rows, ScanlinesForPrediction};
+use imageloader::ScanlinesForRgbaConversion;
+use inflate::{self, Inflater, ParallelInflater};
+use metadata::{Animation, ColorSpace, Dimensions, IccProfile, ImageInfo, InterlaceMethod};
+use metadata::TextChunk;
+use simple::{self, BlockFillDataProvider, DirtyRows, Image, Scale, SharedDataProvider};
+use std::cell::Cell;
+use std::cmp;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::process::{self, Command};
+use std::ptr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "async")]
+use tokio_io::AsyncRead;
 
 #[test]
 fn verify_asm() {
@@ -27,3 +60,2717 @@ fn verify_asm() {
                                 .success());
 }
 
+// A minimal PNG encoder, so that tests can construct exactly the images they need.
+
+/// A small deterministic pseudorandom number generator (xorshift32).
+struct Rng(u32);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    fn below(&mut self, limit: u32) -> u32 {
+        self.next() % limit
+    }
+}
+
+/// The x offset, y offset, x spacing, and y spacing of the pixels in each Adam7 pass.
+static ADAM7_PASSES: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4),
+    (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2),
+];
+
+/// An 8-bit-per-sample image to be encoded as a PNG.
+struct TestImage {
+    width: u32,
+    height: u32,
+    /// The PNG color type: 0 (grayscale), 2 (RGB), 3 (indexed), 4 (grayscale-alpha), or 6 (RGBA).
+    color_type: u8,
+    interlaced: bool,
+    /// The samples of each pixel, row by row.
+    pixels: Vec<u8>,
+    /// The RGB palette, for indexed images.
+    palette: Vec<u8>,
+    /// The contents of the `tRNS` chunk, if any.
+    transparency: Option<Vec<u8>>,
+    /// The maximum size of each `IDAT` chunk.
+    idat_size: usize,
+}
+
+impl TestImage {
+    /// Creates an image with a mixture of noise and smooth gradients, so that every predictor
+    /// has something to do.
+    fn new(width: u32, height: u32, color_type: u8, interlaced: bool, seed: u32) -> TestImage {
+        let mut rng = Rng(seed.wrapping_mul(2654435761) | 1);
+        let palette_size = 1 + rng.below(256);
+        let palette = (0..palette_size * 3).map(|_| rng.below(256) as u8).collect();
+        let bytes_per_pixel = bytes_per_pixel(color_type);
+        let mut pixels = Vec::with_capacity((width * height) as usize * bytes_per_pixel);
+        for y in 0..height {
+            for x in 0..width {
+                for sample in 0..bytes_per_pixel as u32 {
+                    pixels.push(if color_type == 3 {
+                        rng.below(palette_size) as u8
+                    } else if rng.below(10) < 3 {
+                        rng.below(256) as u8
+                    } else {
+                        (x * 7 + y * 3 + sample * 50) as u8
+                    })
+                }
+            }
+        }
+        TestImage {
+            width: width,
+            height: height,
+            color_type: color_type,
+            interlaced: interlaced,
+            pixels: pixels,
+            palette: palette,
+            transparency: None,
+            idat_size: 1000,
+        }
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> &[u8] {
+        let bytes_per_pixel = bytes_per_pixel(self.color_type);
+        let start = (y * self.width + x) as usize * bytes_per_pixel;
+        &self.pixels[start..(start + bytes_per_pixel)]
+    }
+
+    /// Makes the color of the pixel at the given position transparent with a `tRNS` chunk.
+    fn with_magic_color(mut self, x: u32, y: u32) -> TestImage {
+        let mut transparency = vec![];
+        for &sample in self.pixel(x, y) {
+            transparency.push(0);
+            transparency.push(sample)
+        }
+        self.transparency = Some(transparency);
+        self
+    }
+
+    /// Gives the first few palette entries of an indexed image alpha values.
+    fn with_palette_alpha(mut self, count: usize) -> TestImage {
+        self.transparency = Some((0..count).map(|index| (index * 37) as u8).collect());
+        self
+    }
+
+    /// Returns the scanlines of the image, or of one Adam7 pass of it, before filtering.
+    fn scanlines(&self, pass: Option<usize>) -> Vec<Vec<u8>> {
+        let (x0, y0, dx, dy) = match pass {
+            None => (0, 0, 1, 1),
+            Some(pass) => ADAM7_PASSES[pass],
+        };
+        let mut scanlines = vec![];
+        let mut y = y0;
+        while y < self.height && x0 < self.width {
+            let mut scanline = vec![];
+            let mut x = x0;
+            while x < self.width {
+                scanline.extend_from_slice(self.pixel(x, y));
+                x += dx
+            }
+            scanlines.push(scanline);
+            y += dy
+        }
+        scanlines
+    }
+
+    /// Returns the zlib stream of filtered scanlines, before compression.
+    fn filtered_data(&self) -> Vec<u8> {
+        let mut rng = Rng(self.width * 31 + self.height + 1);
+        let mut data = vec![];
+        let passes: Vec<Option<usize>> = if self.interlaced {
+            (0..7).map(Some).collect()
+        } else {
+            vec![None]
+        };
+        for pass in passes {
+            let scanlines = self.scanlines(pass);
+            for (index, scanline) in scanlines.iter().enumerate() {
+                let filter = rng.below(5) as u8;
+                data.push(filter);
+                let prev = if index == 0 { None } else { Some(&scanlines[index - 1]) };
+                filter_scanline(&mut data,
+                                scanline,
+                                prev.map(|prev| &prev[..]),
+                                filter,
+                                bytes_per_pixel(self.color_type))
+            }
+        }
+        data
+    }
+
+    /// Encodes the image, storing the filtered scanlines uncompressed.
+    fn encode(&self) -> Vec<u8> {
+        self.encode_with_zlib_stream(&zlib_stored(&self.filtered_data()))
+    }
+
+    fn encode_with_zlib_stream(&self, stream: &[u8]) -> Vec<u8> {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        let mut header = vec![];
+        push_u32(&mut header, self.width);
+        push_u32(&mut header, self.height);
+        header.extend_from_slice(&[8, self.color_type, 0, 0, self.interlaced as u8]);
+        push_chunk(&mut png, b"IHDR", &header);
+        if self.color_type == 3 {
+            push_chunk(&mut png, b"PLTE", &self.palette)
+        }
+        if let Some(ref transparency) = self.transparency {
+            push_chunk(&mut png, b"tRNS", transparency)
+        }
+        for data in stream.chunks(self.idat_size) {
+            push_chunk(&mut png, b"IDAT", data)
+        }
+        push_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    /// Returns the RGBA pixels, tightly packed, that decoding the image should produce.
+    fn expected_rgba(&self) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity((self.width * self.height * 4) as usize);
+        let magic_color: Option<Vec<u8>> = match (self.color_type, &self.transparency) {
+            (0, &Some(ref transparency)) | (2, &Some(ref transparency)) => {
+                Some(transparency.chunks(2).map(|sample| sample[1]).collect())
+            }
+            _ => None,
+        };
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let pixel = self.pixel(x, y);
+                let opaque = Some(pixel) != magic_color.as_ref().map(|color| &color[..]);
+                let alpha = if opaque { 255 } else { 0 };
+                match self.color_type {
+                    0 => rgba.extend_from_slice(&[pixel[0], pixel[0], pixel[0], alpha]),
+                    2 => rgba.extend_from_slice(&[pixel[0], pixel[1], pixel[2], alpha]),
+                    3 => {
+                        let index = pixel[0] as usize;
+                        rgba.extend_from_slice(&self.palette[(index * 3)..(index * 3 + 3)]);
+                        rgba.push(match self.transparency {
+                            Some(ref transparency) if index < transparency.len() => {
+                                transparency[index]
+                            }
+                            _ => 255,
+                        })
+                    }
+                    4 => rgba.extend_from_slice(&[pixel[0], pixel[0], pixel[0], pixel[1]]),
+                    _ => rgba.extend_from_slice(pixel),
+                }
+            }
+        }
+        rgba
+    }
+}
+
+fn bytes_per_pixel(color_type: u8) -> usize {
+    match color_type {
+        0 | 3 => 1,
+        4 => 2,
+        2 => 3,
+        _ => 4,
+    }
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+fn filter_scanline(data: &mut Vec<u8>,
+                   scanline: &[u8],
+                   prev: Option<&[u8]>,
+                   filter: u8,
+                   bytes_per_pixel: usize) {
+    for (index, &value) in scanline.iter().enumerate() {
+        let a = if index >= bytes_per_pixel { scanline[index - bytes_per_pixel] } else { 0 };
+        let b = prev.map(|prev| prev[index]).unwrap_or(0);
+        let c = match prev {
+            Some(prev) if index >= bytes_per_pixel => prev[index - bytes_per_pixel],
+            _ => 0,
+        };
+        let prediction = match filter {
+            0 => 0,
+            1 => a,
+            2 => b,
+            3 => ((a as u16 + b as u16) / 2) as u8,
+            _ => paeth(a, b, c),
+        };
+        data.push(value.wrapping_sub(prediction))
+    }
+}
+
+fn push_u32(data: &mut Vec<u8>, value: u32) {
+    data.extend_from_slice(&[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8,
+                             value as u8])
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 }
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn push_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    push_u32(png, data.len() as u32);
+    let start = png.len();
+    png.extend_from_slice(chunk_type);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    push_u32(png, crc)
+}
+
+/// Wraps `data` in a zlib stream made of stored (uncompressed) DEFLATE blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let mut blocks = data.chunks(65535).peekable();
+    if blocks.peek().is_none() {
+        push_stored_block(&mut stream, &[], true)
+    }
+    while let Some(block) = blocks.next() {
+        push_stored_block(&mut stream, block, blocks.peek().is_none())
+    }
+    push_u32(&mut stream, adler32(data));
+    stream
+}
+
+fn push_stored_block(stream: &mut Vec<u8>, block: &[u8], last: bool) {
+    let length = block.len() as u16;
+    stream.push(last as u8);
+    stream.extend_from_slice(&[length as u8, (length >> 8) as u8]);
+    stream.extend_from_slice(&[!length as u8, (!length >> 8) as u8]);
+    stream.extend_from_slice(block)
+}
+
+/// Like `zlib_stored()`, but splits `data` into `segment_count` segments of (at most) 65535 bytes
+/// each, with a full flush between each one and the next. Returns the stream and the offsets in
+/// it at which each segment after the first begins.
+fn zlib_stored_in_segments(data: &[u8], segment_count: usize) -> (Vec<u8>, Vec<usize>) {
+    let segment_length = (data.len() + segment_count - 1) / segment_count;
+    assert!(segment_length <= 65535);
+    let (mut stream, mut split_points) = (vec![0x78, 0x01], vec![]);
+    let mut segments = data.chunks(segment_length).peekable();
+    while let Some(segment) = segments.next() {
+        let last = segments.peek().is_none();
+        push_stored_block(&mut stream, segment, last);
+        if !last {
+            // The empty stored block that a full flush ends with.
+            push_stored_block(&mut stream, &[], false);
+            split_points.push(stream.len())
+        }
+    }
+    push_u32(&mut stream, adler32(data));
+    (stream, split_points)
+}
+
+/// Returns the pixels of a decoded image, tightly packed.
+fn packed_pixels(image: &Image) -> Vec<u8> {
+    let mut pixels = vec![];
+    for y in 0..(image.height as usize) {
+        let start = y * image.stride;
+        pixels.extend_from_slice(&image.pixels[start..(start + image.width as usize * 4)])
+    }
+    pixels
+}
+
+fn assert_decodes_correctly(image: &TestImage) {
+    let decoded = Image::load(&mut Cursor::new(image.encode())).unwrap();
+    assert_eq!((decoded.width, decoded.height), (image.width, image.height));
+    assert!(packed_pixels(&decoded) == image.expected_rgba(),
+            "{}x{} image of color type {} (interlaced: {}) decoded incorrectly",
+            image.width,
+            image.height,
+            image.color_type,
+            image.interlaced);
+}
+
+#[test]
+fn grayscale_magic_color_is_read_as_a_16_bit_sample() {
+    for &interlaced in &[false, true] {
+        assert_decodes_correctly(&TestImage::new(37, 21, 0, interlaced, 1).with_magic_color(5, 3))
+    }
+}
+
+#[test]
+fn rgb_magic_color_is_read_as_16_bit_samples() {
+    for &interlaced in &[false, true] {
+        assert_decodes_correctly(&TestImage::new(37, 21, 2, interlaced, 2).with_magic_color(5, 3))
+    }
+}
+
+#[test]
+fn indexed_images_with_and_without_palette_alpha() {
+    for &interlaced in &[false, true] {
+        assert_decodes_correctly(&TestImage::new(70, 33, 3, interlaced, 3));
+        assert_decodes_correctly(&TestImage::new(70, 33, 3, interlaced, 4).with_palette_alpha(40))
+    }
+}
+
+#[test]
+fn interlaced_images_of_every_color_type() {
+    // Exercises prediction and RGBA conversion of pixels spaced apart within each scanline, which
+    // must leave the pixels of other levels of detail alone.
+    for &color_type in &[0, 2, 3, 4, 6] {
+        for &(width, height) in &[(17, 9), (64, 40), (301, 23)] {
+            assert_decodes_correctly(&TestImage::new(width, height, color_type, true, width))
+        }
+    }
+}
+
+#[test]
+fn non_interlaced_images_of_every_color_type() {
+    for &color_type in &[0, 2, 3, 4, 6] {
+        for &(width, height) in &[(1, 1), (3, 5), (301, 23)] {
+            assert_decodes_correctly(&TestImage::new(width, height, color_type, false, height))
+        }
+    }
+}
+
+#[test]
+fn interlaced_images_smaller_than_a_pass() {
+    for &color_type in &[0, 3, 6] {
+        for &(width, height) in &[(1, 1), (1, 5), (5, 1), (2, 2), (3, 3), (4, 4), (5, 6)] {
+            assert_decodes_correctly(&TestImage::new(width, height, color_type, true, 5))
+        }
+    }
+}
+
+#[test]
+fn levels_of_detail_past_the_edge_of_small_images_are_empty() {
+    assert_eq!(InterlacingInfo::height_of_lod(1, LevelOfDetail::Adam7(2)), 0);
+    assert_eq!(InterlacingInfo::height_of_lod(2, LevelOfDetail::Adam7(6)), 1);
+    assert_eq!(InterlacingInfo::height_of_lod(4, LevelOfDetail::Adam7(2)), 0);
+    assert_eq!(InterlacingInfo::height_of_lod(5, LevelOfDetail::Adam7(2)), 1);
+    assert_eq!(InterlacingInfo::height_of_lod(1, LevelOfDetail::None), 1);
+
+    let info = InterlacingInfo::new(0, 32, LevelOfDetail::Adam7(1));
+    assert_eq!(info.scanline_width(4, 32), 0);
+    assert_eq!(info.scanline_width(5, 32), 1);
+    let info = InterlacingInfo::new(0, 8, LevelOfDetail::Adam7(5));
+    assert_eq!(info.scanline_width(1, 8), 0);
+    assert_eq!(info.scanline_width(2, 8), 1);
+}
+
+/// A data provider that decodes into buffers with room for the whole image, predicting each
+/// scanline of an interlaced image in place, and sends the pixels, tightly packed, once the image
+/// is finished.
+struct TestDataProvider {
+    width: u32,
+    height: u32,
+    rgba_pixels: Vec<u8>,
+    indexed_pixels: Vec<u8>,
+    rgba_stride: usize,
+    indexed_stride: usize,
+    sender: Sender<Vec<u8>>,
+}
+
+impl TestDataProvider {
+    fn new(width: u32, height: u32) -> (TestDataProvider, Receiver<Vec<u8>>) {
+        // Like `simple::MemoryDataProvider`, leave as much room for indexed pixels as for RGBA
+        // pixels.
+        let (rgba_stride, indexed_stride) = (imageloader::align(width as usize * 4),
+                                             imageloader::align(width as usize * 4));
+        let (sender, receiver) = mpsc::channel();
+        let data_provider = TestDataProvider {
+            width: width,
+            height: height,
+            rgba_pixels: vec![0; rgba_stride * height as usize],
+            indexed_pixels: vec![0; indexed_stride * height as usize],
+            rgba_stride: rgba_stride,
+            indexed_stride: indexed_stride,
+            sender: sender,
+        };
+        (data_provider, receiver)
+    }
+}
+
+impl DataProvider for TestDataProvider {
+    fn fetch_scanlines_for_prediction<'a>(&'a mut self,
+                                          reference_scanline: Option<u32>,
+                                          current_scanline: u32,
+                                          lod: LevelOfDetail,
+                                          indexed: bool)
+                                          -> ScanlinesForPrediction<'a> {
+        let (pixels, stride, color_depth) = if indexed {
+            (&mut self.indexed_pixels, self.indexed_stride, 8)
+        } else {
+            (&mut self.rgba_pixels, self.rgba_stride, 32)
+        };
+        let current = InterlacingInfo::new(current_scanline, color_depth, lod);
+        let current_start = current.y as usize * stride + current.offset as usize;
+        let (before, current_scanline) = pixels.split_at_mut(current_start);
+        let reference_scanline = reference_scanline.map(move |reference_scanline| {
+            let reference = InterlacingInfo::new(reference_scanline, color_depth, lod);
+            &mut before[(reference.y as usize * stride + reference.offset as usize)..]
+        });
+        ScanlinesForPrediction {
+            reference_scanline: reference_scanline,
+            current_scanline: current_scanline,
+            stride: current.stride,
+        }
+    }
+
+    fn prediction_complete_for_scanline(&mut self, _: u32, _: LevelOfDetail) {}
+
+    fn fetch_scanlines_for_rgba_conversion<'a>(&'a mut self,
+                                               scanline: u32,
+                                               lod: LevelOfDetail,
+                                               indexed: bool)
+                                               -> ScanlinesForRgbaConversion<'a> {
+        let rgba = InterlacingInfo::new(scanline, 32, lod);
+        let indexed_info = InterlacingInfo::new(scanline, 8, lod);
+        let indexed_start = indexed_info.y as usize * self.indexed_stride +
+            indexed_info.offset as usize;
+        ScanlinesForRgbaConversion {
+            rgba_scanline: &mut self.rgba_pixels[(rgba.y as usize * self.rgba_stride +
+                                                  rgba.offset as usize)..],
+            indexed_scanline: if indexed {
+                Some(&self.indexed_pixels[indexed_start..])
+            } else {
+                None
+            },
+            rgba_stride: rgba.stride,
+            indexed_stride: if indexed {
+                Some(indexed_info.stride)
+            } else {
+                None
+            },
+        }
+    }
+
+    fn rgba_conversion_complete_for_scanline(&mut self, _: u32, _: LevelOfDetail) {}
+
+    fn finished(&mut self) {
+        let mut pixels = vec![];
+        for y in 0..(self.height as usize) {
+            let start = y * self.rgba_stride;
+            pixels.extend_from_slice(&self.rgba_pixels[start..(start + self.width as usize * 4)])
+        }
+        drop(self.sender.send(pixels))
+    }
+}
+
+/// Something that happened to an `EventDataProvider`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum ProviderEvent {
+    Predicted(u32, LevelOfDetail),
+    Converted(u32, LevelOfDetail),
+    Finished,
+    Cancelled,
+}
+
+/// A `TestDataProvider` that also reports each call of the completion methods as it happens.
+struct EventDataProvider {
+    inner: TestDataProvider,
+    event_sender: Sender<ProviderEvent>,
+}
+
+impl EventDataProvider {
+    fn new(width: u32, height: u32)
+           -> (EventDataProvider, Receiver<Vec<u8>>, Receiver<ProviderEvent>) {
+        let (inner, pixel_receiver) = TestDataProvider::new(width, height);
+        let (event_sender, event_receiver) = mpsc::channel();
+        let data_provider = EventDataProvider {
+            inner: inner,
+            event_sender: event_sender,
+        };
+        (data_provider, pixel_receiver, event_receiver)
+    }
+}
+
+impl DataProvider for EventDataProvider {
+    fn fetch_scanlines_for_prediction<'a>(&'a mut self,
+                                          reference_scanline: Option<u32>,
+                                          current_scanline: u32,
+                                          lod: LevelOfDetail,
+                                          indexed: bool)
+                                          -> ScanlinesForPrediction<'a> {
+        self.inner
+            .fetch_scanlines_for_prediction(reference_scanline, current_scanline, lod, indexed)
+    }
+
+    fn prediction_complete_for_scanline(&mut self, scanline: u32, lod: LevelOfDetail) {
+        let _ = self.event_sender.send(ProviderEvent::Predicted(scanline, lod));
+    }
+
+    fn fetch_scanlines_for_rgba_conversion<'a>(&'a mut self,
+                                               scanline: u32,
+                                               lod: LevelOfDetail,
+                                               indexed: bool)
+                                               -> ScanlinesForRgbaConversion<'a> {
+        self.inner.fetch_scanlines_for_rgba_conversion(scanline, lod, indexed)
+    }
+
+    fn rgba_conversion_complete_for_scanline(&mut self, scanline: u32, lod: LevelOfDetail) {
+        let _ = self.event_sender.send(ProviderEvent::Converted(scanline, lod));
+    }
+
+    fn finished(&mut self) {
+        self.inner.finished();
+        let _ = self.event_sender.send(ProviderEvent::Finished);
+    }
+
+    fn cancelled(&mut self) {
+        let _ = self.event_sender.send(ProviderEvent::Cancelled);
+    }
+}
+
+/// Returns `png` cut off just before the zlib checksum at the end of its only `IDAT` chunk, so
+/// that all of the image data is present but the image isn't finished.
+fn without_checksum(png: &[u8]) -> &[u8] {
+    // Drop the `IEND` chunk, the `IDAT` CRC, and the Adler-32 checksum.
+    &png[0..(png.len() - 12 - 4 - 4)]
+}
+
+/// Returns `png` without its `IEND` chunk.
+fn without_iend(png: &[u8]) -> &[u8] {
+    &png[0..(png.len() - 12)]
+}
+
+/// Adds all of `data` to `loader`, attaching `data_provider` when it is asked for.
+fn add_all_data<P>(loader: &mut ImageLoader, data: &[u8], data_provider: P) -> LoadProgress
+                   where P: DataProvider + Send + 'static {
+    let (mut data_provider, mut data) = (Some(data_provider), Cursor::new(data));
+    loop {
+        match loader.add_data(&mut data).unwrap() {
+            LoadProgress::NeedDataProviderAndMoreData => {
+                loader.set_data_provider(Box::new(data_provider.take().unwrap()))
+            }
+            load_progress => return load_progress,
+        }
+    }
+}
+
+#[test]
+fn rgba_conversion_happens_before_the_image_ends() {
+    // Every scanline of an image that isn't RGBA should be converted once the image data has all
+    // arrived, even though the image hasn't ended.
+    for &color_type in &[0, 2, 3] {
+        let image = TestImage::new(40, 30, color_type, false, 8);
+        let png = image.encode();
+        let (data_provider, _, events) = EventDataProvider::new(image.width, image.height);
+        let mut loader = ImageLoader::new();
+        assert!(add_all_data(&mut loader, without_checksum(&png), data_provider) ==
+                LoadProgress::NeedMoreData);
+
+        let mut converted = vec![];
+        while converted.len() < image.height as usize {
+            match events.recv_timeout(Duration::from_secs(10)).unwrap() {
+                ProviderEvent::Converted(scanline, LevelOfDetail::None) => {
+                    converted.push(scanline)
+                }
+                ProviderEvent::Predicted(..) => {}
+                event => panic!("Unexpected event {:?} before the image ended!", event),
+            }
+        }
+        assert!(converted == (0..image.height).collect::<Vec<_>>());
+    }
+}
+
+/// Returns the total number of scanlines in all the levels of detail of an image.
+fn scanline_count(image: &TestImage) -> u32 {
+    if !image.interlaced {
+        return image.height
+    }
+    (0..7).map(|pass| {
+        let lod = LevelOfDetail::Adam7(pass);
+        if InterlacingInfo::new(0, 32, lod).scanline_width(image.width, 32) == 0 {
+            0
+        } else {
+            InterlacingInfo::height_of_lod(image.height, lod)
+        }
+    }).sum()
+}
+
+#[test]
+fn inline_image_loaders_decode_within_add_data() {
+    for &color_type in &[2, 6] {
+        for &interlaced in &[false, true] {
+            let image = TestImage::new(45, 33, color_type, interlaced, 9);
+            let png = image.encode();
+            let (data_provider, pixels, events) = EventDataProvider::new(45, 33);
+            let mut loader = ImageLoader::new_inline();
+            let image_data = without_checksum(&png);
+            assert!(add_all_data(&mut loader, image_data, data_provider) ==
+                    LoadProgress::NeedMoreData);
+
+            // Every scanline was predicted before `add_data()` returned.
+            let predicted = events.try_iter().filter(|event| {
+                match *event {
+                    ProviderEvent::Predicted(..) => true,
+                    _ => false,
+                }
+            }).count();
+            assert_eq!(predicted as u32, scanline_count(&image));
+
+            let mut rest = Cursor::new(&png[image_data.len()..]);
+            assert!(loader.add_data(&mut rest).unwrap() == LoadProgress::Finished);
+            assert_eq!(events.try_recv(), Ok(ProviderEvent::Finished));
+            loader.wait_until_finished().unwrap();
+            assert!(pixels.try_recv().unwrap() == image.expected_rgba())
+        }
+    }
+}
+
+/// A `TestDataProvider` that records that it has finished in a flag shared through an `Rc`, which
+/// makes it impossible to send to another thread.
+struct LocalDataProvider {
+    inner: TestDataProvider,
+    finished: Rc<Cell<bool>>,
+}
+
+impl DataProvider for LocalDataProvider {
+    fn fetch_scanlines_for_prediction<'a>(&'a mut self,
+                                          reference_scanline: Option<u32>,
+                                          current_scanline: u32,
+                                          lod: LevelOfDetail,
+                                          indexed: bool)
+                                          -> ScanlinesForPrediction<'a> {
+        self.inner
+            .fetch_scanlines_for_prediction(reference_scanline, current_scanline, lod, indexed)
+    }
+
+    fn prediction_complete_for_scanline(&mut self, scanline: u32, lod: LevelOfDetail) {
+        self.inner.prediction_complete_for_scanline(scanline, lod)
+    }
+
+    fn fetch_scanlines_for_rgba_conversion<'a>(&'a mut self,
+                                               scanline: u32,
+                                               lod: LevelOfDetail,
+                                               indexed: bool)
+                                               -> ScanlinesForRgbaConversion<'a> {
+        self.inner.fetch_scanlines_for_rgba_conversion(scanline, lod, indexed)
+    }
+
+    fn rgba_conversion_complete_for_scanline(&mut self, scanline: u32, lod: LevelOfDetail) {
+        self.inner.rgba_conversion_complete_for_scanline(scanline, lod)
+    }
+
+    fn finished(&mut self) {
+        self.inner.finished();
+        self.finished.set(true)
+    }
+}
+
+#[test]
+fn local_image_loaders_take_data_providers_that_are_not_send() {
+    let image = TestImage::new(45, 33, 3, true, 10);
+    let png = image.encode();
+    let mut loader = ImageLoader::new_local();
+    let mut data = Cursor::new(&png[..]);
+    assert!(loader.add_data(&mut data).unwrap() == LoadProgress::NeedDataProviderAndMoreData);
+
+    let (inner, pixels) = TestDataProvider::new(image.width, image.height);
+    let finished = Rc::new(Cell::new(false));
+    loader.set_data_provider(Box::new(LocalDataProvider {
+        inner: inner,
+        finished: finished.clone(),
+    }));
+    assert!(loader.add_data(&mut data).unwrap() == LoadProgress::Finished);
+    loader.wait_until_finished().unwrap();
+    assert!(finished.get());
+    assert!(pixels.try_recv().unwrap() == image.expected_rgba());
+}
+
+/// Decodes `png` with the given image loader into a `TestDataProvider`, returning the pixels.
+fn decode_with_loader(loader: &mut ImageLoader, png: &[u8]) -> Result<Vec<u8>, PngError> {
+    let (mut receiver, mut png) = (None, Cursor::new(png));
+    loop {
+        match try!(loader.add_data(&mut png)) {
+            LoadProgress::NeedDataProviderAndMoreData => {
+                let dimensions = loader.metadata().as_ref().unwrap().dimensions;
+                let (data_provider, pixel_receiver) = TestDataProvider::new(dimensions.width,
+                                                                            dimensions.height);
+                loader.set_data_provider(Box::new(data_provider));
+                receiver = Some(pixel_receiver)
+            }
+            LoadProgress::Finished => break,
+            LoadProgress::NeedMoreData => {
+                return Err(PngError::Io(io::Error::new(ErrorKind::UnexpectedEof,
+                                                       "PNG image stream ended prematurely")))
+            }
+            LoadProgress::WaitingForPrediction | LoadProgress::Yielded => {
+                panic!("Blocking decoding neither waits for prediction nor yields!")
+            }
+        }
+    }
+    try!(loader.wait_until_finished());
+    Ok(receiver.expect("No metadata?!").recv().expect("Data provider dropped unfinished!"))
+}
+
+#[test]
+fn worker_threads_decode_the_same_pixels_as_a_single_thread() {
+    // Wide enough to be split among all of the workers, with a tile boundary that doesn't fall on
+    // a SIMD vector boundary until it is rounded down to one.
+    for &color_type in &[0, 2, 3, 4, 6] {
+        for &interlaced in &[false, true] {
+            let image = TestImage::new(1030, 19, color_type, interlaced, 6);
+            let png = image.encode();
+            let single_threaded = decode_with_loader(&mut ImageLoader::new(), &png).unwrap();
+            assert!(single_threaded == image.expected_rgba());
+            for &worker_count in &[2, 3, 4] {
+                let pixels = decode_with_loader(&mut ImageLoader::with_worker_count(worker_count),
+                                                &png).unwrap();
+                assert!(pixels == single_threaded,
+                        "{} workers decoded color type {} (interlaced: {}) differently",
+                        worker_count,
+                        color_type,
+                        interlaced)
+            }
+        }
+    }
+}
+
+/// A reader that returns at most `max_read_size` bytes from each call to `read()`, as a socket
+/// might.
+struct TrickleReader {
+    inner: Cursor<Vec<u8>>,
+    max_read_size: usize,
+}
+
+impl Read for TrickleReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let length = cmp::min(buffer.len(), self.max_read_size);
+        self.inner.read(&mut buffer[0..length])
+    }
+}
+
+impl Seek for TrickleReader {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(position)
+    }
+}
+
+#[test]
+fn batches_of_scanlines_of_narrow_and_wide_images() {
+    // Narrow images have many scanlines to a batch, and wide ones just one. The last batch of the
+    // tall ones isn't full, and the short reads split batches between calls to `add_data()`.
+    for &(width, height) in &[(3, 1500), (1, 700), (2000, 5)] {
+        for &color_type in &[0, 3, 6] {
+            for &interlaced in &[false, true] {
+                let image = TestImage::new(width, height, color_type, interlaced, 11);
+                let expected = image.expected_rgba();
+                assert!(decode_with_loader(&mut ImageLoader::new(), &image.encode()).unwrap() ==
+                        expected);
+
+                let mut reader = TrickleReader {
+                    inner: Cursor::new(image.encode()),
+                    max_read_size: 777,
+                };
+                let decoded = Image::load(&mut reader).unwrap();
+                assert!(packed_pixels(&decoded) == expected,
+                        "{}x{} image of color type {} (interlaced: {}) decoded incorrectly",
+                        width,
+                        height,
+                        color_type,
+                        interlaced)
+            }
+        }
+    }
+}
+
+#[test]
+fn image_loaders_sharing_a_pool_decode_concurrently() {
+    let pool = DecoderPool::new(2);
+    let threads: Vec<_> = (0..4).map(|thread_index| {
+        let pool = pool.clone();
+        thread::spawn(move || {
+            for &color_type in &[2, 3, 6] {
+                for &interlaced in &[false, true] {
+                    let image = TestImage::new(67, 45, color_type, interlaced, thread_index);
+                    let pixels = decode_with_loader(&mut ImageLoader::with_pool(&pool),
+                                                    &image.encode()).unwrap();
+                    assert!(pixels == image.expected_rgba())
+                }
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap()
+    }
+
+    // More loaders than threads, decoded in lockstep on one thread, so that each pool thread
+    // switches between several images partway through each of them.
+    let images: Vec<_> = (0..5).map(|seed| TestImage::new(50, 60, 2, false, seed)).collect();
+    let pngs: Vec<_> = images.iter().map(|image| image.encode()).collect();
+    let mut loaders: Vec<_> = (0..5).map(|_| ImageLoader::with_pool(&pool)).collect();
+    let mut receivers = vec![];
+    for (loader, png) in loaders.iter_mut().zip(pngs.iter()) {
+        let (data_provider, receiver) = TestDataProvider::new(50, 60);
+        assert!(add_all_data(loader, &png[0..(png.len() / 2)], data_provider) ==
+                LoadProgress::NeedMoreData);
+        receivers.push(receiver)
+    }
+    for ((loader, png), (image, receiver)) in loaders.iter_mut()
+                                                   .zip(pngs.iter())
+                                                   .zip(images.iter().zip(receivers.iter())) {
+        let mut rest = Cursor::new(&png[(png.len() / 2)..]);
+        assert!(loader.add_data(&mut rest).unwrap() == LoadProgress::Finished);
+        loader.wait_until_finished().unwrap();
+        assert!(receiver.recv().unwrap() == image.expected_rgba())
+    }
+}
+
+#[test]
+fn the_c_api_rejects_invalid_arguments() {
+    unsafe {
+        let mut image_loader = ptr::null_mut();
+        assert_eq!(capi::parng_image_loader_create_with_worker_count(&mut image_loader, 0),
+                   PARNG_ERROR_INVALID_ARGUMENT);
+        assert!(image_loader.is_null());
+        assert_eq!(capi::parng_image_loader_create_with_worker_count(&mut image_loader, 2),
+                   PARNG_SUCCESS);
+        capi::parng_image_loader_destroy(image_loader);
+
+        assert_eq!(capi::parng_image_loader_create_inline(&mut image_loader), PARNG_SUCCESS);
+        capi::parng_image_loader_destroy(image_loader);
+
+        let mut pool = ptr::null_mut();
+        assert_eq!(capi::parng_decoder_pool_create(&mut pool, 0), PARNG_ERROR_INVALID_ARGUMENT);
+        assert!(pool.is_null());
+        let mut image_loader = ptr::null_mut();
+        assert_eq!(capi::parng_image_loader_create_with_pool(&mut image_loader, pool),
+                   PARNG_ERROR_INVALID_ARGUMENT);
+        assert!(image_loader.is_null());
+        assert_eq!(capi::parng_decoder_pool_create(&mut pool, 1), PARNG_SUCCESS);
+        assert_eq!(capi::parng_image_loader_create_with_pool(&mut image_loader, pool),
+                   PARNG_SUCCESS);
+        capi::parng_image_loader_destroy(image_loader);
+        capi::parng_decoder_pool_destroy(pool);
+
+        let mut c_image: capi::parng_image = mem::zeroed();
+        assert_eq!(capi::parng_image_load_from_memory(&mut c_image, ptr::null(), 100),
+                   PARNG_ERROR_INVALID_ARGUMENT);
+        let mut image_loader = ptr::null_mut();
+        capi::parng_image_loader_create(&mut image_loader);
+        let mut load_progress = 0;
+        assert_eq!(capi::parng_image_loader_add_data_from_memory(image_loader,
+                                                                 ptr::null(),
+                                                                 100,
+                                                                 &mut load_progress),
+                   PARNG_ERROR_INVALID_ARGUMENT);
+        capi::parng_image_loader_destroy(image_loader);
+
+        assert_eq!(capi::parng_image_load_from_path(&mut c_image, ptr::null()),
+                   PARNG_ERROR_INVALID_ARGUMENT);
+
+        let png = TestImage::new(16, 16, 6, false, 12).encode();
+        let mut pixels = vec![0; 16 * 16 * 4];
+        let (mut width, mut height) = (0, 0);
+        assert_eq!(capi::parng_image_load_from_memory_into_buffer(png.as_ptr(),
+                                                                  png.len(),
+                                                                  pixels.as_mut_ptr(),
+                                                                  pixels.len(),
+                                                                  16 * 4,
+                                                                  ptr::null_mut(),
+                                                                  &mut height),
+                   PARNG_ERROR_INVALID_ARGUMENT);
+        assert_eq!(capi::parng_image_load_from_memory_into_buffer(png.as_ptr(),
+                                                                  png.len(),
+                                                                  ptr::null_mut(),
+                                                                  pixels.len(),
+                                                                  16 * 4,
+                                                                  &mut width,
+                                                                  &mut height),
+                   PARNG_ERROR_INVALID_BUFFER);
+        assert_eq!(capi::parng_image_load_from_memory_into_buffer(png.as_ptr(),
+                                                                  png.len(),
+                                                                  pixels.as_mut_ptr(),
+                                                                  pixels.len(),
+                                                                  16 * 4,
+                                                                  &mut width,
+                                                                  &mut height),
+                   PARNG_SUCCESS);
+        assert_eq!((width, height), (16, 16));
+
+        assert_eq!(capi::parng_image_load_from_memory_with_stride(&mut c_image,
+                                                                  ptr::null(),
+                                                                  100,
+                                                                  16 * 4),
+                   PARNG_ERROR_INVALID_ARGUMENT);
+
+        let mut region = capi::parng_region {
+            x: 4,
+            y: 4,
+            width: 100,
+            height: 100,
+        };
+        assert_eq!(capi::parng_image_load_region_from_memory(&mut c_image,
+                                                             png.as_ptr(),
+                                                             png.len(),
+                                                             ptr::null()),
+                   PARNG_ERROR_INVALID_ARGUMENT);
+        assert_eq!(capi::parng_image_load_region_from_memory(&mut c_image,
+                                                             ptr::null(),
+                                                             100,
+                                                             &region),
+                   PARNG_ERROR_INVALID_ARGUMENT);
+        let mut image_loader = ptr::null_mut();
+        capi::parng_image_loader_create(&mut image_loader);
+        assert_eq!(capi::parng_image_loader_set_region_of_interest(image_loader, &mut region),
+                   PARNG_ERROR_INVALID_ARGUMENT);
+        assert_eq!(capi::parng_image_loader_add_data_from_memory(image_loader,
+                                                                 png.as_ptr(),
+                                                                 png.len(),
+                                                                 &mut load_progress),
+                   PARNG_SUCCESS);
+        assert_eq!(capi::parng_image_loader_set_region_of_interest(image_loader,
+                                                                   ptr::null_mut()),
+                   PARNG_ERROR_INVALID_ARGUMENT);
+        assert_eq!(capi::parng_image_loader_set_region_of_interest(image_loader, &mut region),
+                   PARNG_SUCCESS);
+        assert_eq!((region.x, region.y, region.width, region.height), (4, 4, 12, 12));
+        capi::parng_image_loader_destroy(image_loader);
+        let mut interlacing_info = mem::zeroed();
+        assert_eq!(capi::parng_interlacing_info_init_in_region(&mut interlacing_info,
+                                                               0,
+                                                               32,
+                                                               capi::PARNG_LEVEL_OF_DETAIL_NONE,
+                                                               ptr::null()),
+                   0);
+
+        assert_eq!(capi::parng_image_load_scaled_from_memory(&mut c_image,
+                                                             png.as_ptr(),
+                                                             png.len(),
+                                                             3),
+                   PARNG_ERROR_INVALID_ARGUMENT);
+        assert_eq!(capi::parng_image_load_scaled_from_memory(&mut c_image,
+                                                             ptr::null(),
+                                                             100,
+                                                             capi::PARNG_SCALE_HALF),
+                   PARNG_ERROR_INVALID_ARGUMENT);
+
+        assert_eq!(capi::parng_image_load_preview_from_memory(&mut c_image,
+                                                              png.as_ptr(),
+                                                              png.len(),
+                                                              7),
+                   PARNG_ERROR_INVALID_ARGUMENT);
+        assert_eq!(capi::parng_image_load_preview_from_memory(&mut c_image,
+                                                              ptr::null(),
+                                                              100,
+                                                              capi::PARNG_LEVEL_OF_DETAIL_ADAM7_0),
+                   PARNG_ERROR_INVALID_ARGUMENT);
+        let mut image_loader = ptr::null_mut();
+        capi::parng_image_loader_create(&mut image_loader);
+        assert_eq!(capi::parng_image_loader_set_last_level_of_detail(
+                       image_loader,
+                       capi::PARNG_LEVEL_OF_DETAIL_ADAM7_0),
+                   PARNG_ERROR_INVALID_ARGUMENT);
+        assert_eq!(capi::parng_image_loader_add_data_from_memory(image_loader,
+                                                                 png.as_ptr(),
+                                                                 png.len(),
+                                                                 &mut load_progress),
+                   PARNG_SUCCESS);
+        assert_eq!(capi::parng_image_loader_set_last_level_of_detail(image_loader, -2),
+                   PARNG_ERROR_INVALID_ARGUMENT);
+        assert_eq!(capi::parng_image_loader_set_last_level_of_detail(
+                       image_loader,
+                       capi::PARNG_LEVEL_OF_DETAIL_ADAM7_0),
+                   PARNG_SUCCESS);
+        capi::parng_image_loader_destroy(image_loader)
+    }
+}
+
+// Tests of the built-in inflater.
+
+/// Writes bits least significant first, as DEFLATE does, for constructing streams by hand.
+struct BitWriter {
+    data: Vec<u8>,
+    bit_count: u32,
+}
+
+impl BitWriter {
+    /// Starts a `zlib` stream with the usual header.
+    fn new() -> BitWriter {
+        BitWriter {
+            data: vec![0x78, 0x01],
+            bit_count: 0,
+        }
+    }
+
+    fn write_bits(&mut self, value: u32, count: u32) {
+        for bit in 0..count {
+            if self.bit_count % 8 == 0 {
+                self.data.push(0)
+            }
+            *self.data.last_mut().unwrap() |= (((value >> bit) & 1) as u8) << (self.bit_count % 8);
+            self.bit_count += 1
+        }
+    }
+
+    /// Writes a Huffman code, which is stored most significant bit first.
+    fn write_code(&mut self, code: u32, length: u32) {
+        for bit in (0..length).rev() {
+            self.write_bits(code >> bit, 1)
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.data.extend_from_slice(&[0, 0, 0, 1]);
+        self.data
+    }
+}
+
+/// Returns data resembling filtered PNG scanlines, with long runs to produce long and overlapping
+/// matches, big enough that the inflater's window has to slide.
+fn compressible_data() -> Vec<u8> {
+    let mut data = TestImage::new(301, 150, 6, false, 7).filtered_data();
+    for length in 1..300 {
+        let value = data[length * 3];
+        data.extend((0..length).map(|_| value))
+    }
+    data
+}
+
+#[cfg(feature = "zlib")]
+fn compress(data: &[u8], compression: Compression) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(vec![], compression);
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Returns the type of the first block of a `zlib` stream: 0 for stored, 1 for fixed Huffman, and
+/// 2 for dynamic Huffman.
+#[cfg(feature = "zlib")]
+fn first_block_type(stream: &[u8]) -> u8 {
+    (stream[2] >> 1) & 3
+}
+
+/// Decompresses `stream` with the built-in inflater, supplying it `input_size` bytes at a time, as
+/// it might arrive over a network, and asking for `output_size` bytes at a time, as the image
+/// loader asks for a batch of scanlines at a time.
+fn inflate_in_pieces(stream: &[u8], input_size: usize, output_size: usize)
+                     -> Result<Vec<u8>, PngError> {
+    let mut inflater = Inflater::new();
+    let (mut available, mut buffer, mut output) = (0, vec![0; output_size], vec![]);
+    loop {
+        let (total_in, total_out) = (inflater.total_in() as usize, inflater.total_out() as usize);
+        try!(inflater.decompress(&stream[total_in..available], &mut buffer));
+        let produced = inflater.total_out() as usize - total_out;
+        output.extend_from_slice(&buffer[0..produced]);
+        if produced < output_size {
+            if available == stream.len() {
+                return Ok(output)
+            }
+            available = cmp::min(available + input_size, stream.len())
+        }
+    }
+}
+
+/// Decompresses all of `stream` with the built-in inflater.
+fn inflate_whole(stream: &[u8]) -> Result<Vec<u8>, PngError> {
+    inflate_in_pieces(stream, stream.len(), 65536)
+}
+
+fn assert_entropy_decoding_error<T>(result: Result<T, PngError>) {
+    match result {
+        Err(PngError::EntropyDecodingError) => {}
+        Err(error) => panic!("Expected an entropy decoding error, not {:?}!", error),
+        Ok(_) => panic!("Expected an entropy decoding error!"),
+    }
+}
+
+#[test]
+fn inflate_stored_blocks() {
+    let data = compressible_data();
+    assert!(inflate_whole(&zlib_stored(&data)).unwrap() == data);
+    assert!(inflate_whole(&zlib_stored(&[])).unwrap().is_empty());
+}
+
+#[cfg(feature = "zlib")]
+#[test]
+fn inflate_round_trips_against_zlib() {
+    let data = compressible_data();
+    let short_data = &data[0..100];
+    let streams = [
+        (compress(&data, Compression::None), 0),
+        (compress(short_data, Compression::Default), 1),
+        (compress(&data, Compression::Fast), 2),
+        (compress(&data, Compression::Best), 2),
+    ];
+    for &(ref stream, block_type) in &streams {
+        assert_eq!(first_block_type(stream), block_type);
+        let expected = if block_type == 1 { short_data } else { &data[..] };
+        assert!(inflate_whole(stream).unwrap() == expected);
+    }
+}
+
+#[cfg(feature = "zlib")]
+#[test]
+fn inflate_input_split_at_arbitrary_boundaries() {
+    let data = compressible_data();
+    for stream in &[compress(&data, Compression::Default), zlib_stored(&data)] {
+        for &input_size in &[1, 2, 3, 5, 64, 1000, 4093, stream.len()] {
+            for &output_size in &[1, 7, 301 * 4 + 1, 8 * (301 * 4 + 1), 200000] {
+                if input_size < 5 && output_size < 100 {
+                    // Slow, and covered well enough by the other combinations.
+                    continue
+                }
+                let output = inflate_in_pieces(stream, input_size, output_size).unwrap();
+                assert!(output == data,
+                        "Input in pieces of {} and output in pieces of {} decoded incorrectly",
+                        input_size,
+                        output_size)
+            }
+        }
+    }
+}
+
+#[test]
+fn inflate_rejects_bad_headers() {
+    let stream = zlib_stored(b"scanlines");
+    // The compression method, the header check bits, and a preset dictionary.
+    for &header in &[[0x79, 0x01], [0x78, 0x02], [0x78, 0x20 | 0x1b]] {
+        let mut corrupt = stream.clone();
+        corrupt[0..2].clone_from_slice(&header);
+        assert_entropy_decoding_error(inflate_whole(&corrupt));
+        assert_entropy_decoding_error(inflate_in_pieces(&corrupt, 1, 1))
+    }
+
+    // A reserved block type.
+    let mut writer = BitWriter::new();
+    writer.write_bits(1 | (3 << 1), 3);
+    assert_entropy_decoding_error(inflate_whole(&writer.finish()));
+}
+
+#[test]
+fn inflate_rejects_bad_code_lengths() {
+    // Too many codes of length 1 in the code length code.
+    let mut writer = BitWriter::new();
+    writer.write_bits(1 | (2 << 1), 3);
+    writer.write_bits(0, 5);
+    writer.write_bits(0, 5);
+    writer.write_bits(15, 4);
+    for _ in 0..19 {
+        writer.write_bits(1, 3)
+    }
+    assert_entropy_decoding_error(inflate_whole(&writer.finish()));
+
+    // A run of zero code lengths that overruns the number of codes. Symbols 18 and 0 of the code
+    // length code get the one-bit codes 1 and 0 respectively.
+    let mut writer = BitWriter::new();
+    writer.write_bits(1 | (2 << 1), 3);
+    writer.write_bits(0, 5);
+    writer.write_bits(0, 5);
+    writer.write_bits(0, 4);
+    for &length in &[0, 0, 1, 1] {
+        writer.write_bits(length, 3)
+    }
+    for _ in 0..2 {
+        writer.write_code(1, 1);
+        writer.write_bits(127, 7)
+    }
+    assert_entropy_decoding_error(inflate_whole(&writer.finish()));
+
+    // A repeat of the previous code length with no previous code length. Symbols 16 and 0 of the
+    // code length code get the one-bit codes 1 and 0 respectively.
+    let mut writer = BitWriter::new();
+    writer.write_bits(1 | (2 << 1), 3);
+    writer.write_bits(0, 5);
+    writer.write_bits(0, 5);
+    writer.write_bits(0, 4);
+    for &length in &[1, 0, 0, 1] {
+        writer.write_bits(length, 3)
+    }
+    writer.write_code(1, 1);
+    writer.write_bits(0, 2);
+    assert_entropy_decoding_error(inflate_whole(&writer.finish()));
+}
+
+#[test]
+fn inflate_rejects_distances_past_the_start_of_the_window() {
+    // A fixed Huffman block that begins with a match of length 3 at distance 1 (symbol 257, code
+    // 0000001, and distance code 0). Then the same match after a single literal, which is fine,
+    // then one at distance 2, which reaches back past the start.
+    let mut writer = BitWriter::new();
+    writer.write_bits(1 | (1 << 1), 3);
+    writer.write_code(1, 7);
+    writer.write_code(0, 5);
+    assert_entropy_decoding_error(inflate_whole(&writer.finish()));
+
+    let mut writer = BitWriter::new();
+    writer.write_bits(1 | (1 << 1), 3);
+    writer.write_code(0x30 + b'x' as u32, 8);
+    writer.write_code(1, 7);
+    writer.write_code(0, 5);
+    writer.write_code(1, 7);
+    writer.write_code(0, 5);
+    writer.write_code(0, 7);
+    let mut stream = writer.finish();
+    let length = stream.len();
+    stream[(length - 4)..].clone_from_slice(&[0x0d, 0x27, 0x03, 0x49]);
+    assert!(inflate_whole(&stream).unwrap() == b"xxxxxxx");
+
+    let mut writer = BitWriter::new();
+    writer.write_bits(1 | (1 << 1), 3);
+    writer.write_code(0x30 + b'x' as u32, 8);
+    writer.write_code(1, 7);
+    writer.write_code(1, 5);
+    assert_entropy_decoding_error(inflate_whole(&writer.finish()));
+}
+
+#[test]
+fn inflate_rejects_checksum_mismatches() {
+    let data = compressible_data();
+    let mut stream = zlib_stored(&data);
+    let length = stream.len();
+    stream[length - 1] ^= 1;
+    assert_entropy_decoding_error(inflate_whole(&stream));
+    assert_entropy_decoding_error(inflate_in_pieces(&stream, 1000, 4096));
+}
+
+#[test]
+fn inflate_never_panics_on_corrupt_data() {
+    let data = &compressible_data()[0..5000];
+    let mut streams = vec![zlib_stored(data)];
+    #[cfg(feature = "zlib")]
+    streams.push(compress(data, Compression::Default));
+    let mut rng = Rng(12345);
+    for stream in &streams {
+        for _ in 0..2000 {
+            let mut corrupt = stream.clone();
+            for _ in 0..(1 + rng.below(4)) {
+                let index = rng.below(corrupt.len() as u32) as usize;
+                corrupt[index] ^= 1 << rng.below(8)
+            }
+            match inflate_whole(&corrupt) {
+                Ok(_) | Err(PngError::EntropyDecodingError) => {}
+                Err(error) => panic!("Unexpected error {:?}!", error),
+            }
+        }
+    }
+}
+
+/// Encodes `image` with its image data split into two segments, the second of which starts in an
+/// `IDAT` chunk of its own that an `iDOT` chunk points to.
+fn encode_with_idot(image: &TestImage) -> Vec<u8> {
+    let (stream, split_points) = zlib_stored_in_segments(&image.filtered_data(), 2);
+    let split_point = split_points[0];
+    let header = image.encode_with_zlib_stream(&[]);
+    let mut png = header[0..(header.len() - 12)].to_vec();
+
+    // The offset of the second `IDAT` chunk from the start of the `iDOT` chunk, past the `iDOT`
+    // chunk itself and the first `IDAT` chunk.
+    let mut idot = vec![];
+    let (first_half_height, second_half_height) = (image.height / 2, (image.height + 1) / 2);
+    for &field in &[2, 0, first_half_height, 0x28, first_half_height, second_half_height] {
+        push_u32(&mut idot, field)
+    }
+    push_u32(&mut idot, 12 + 28 + 12 + split_point as u32);
+    push_chunk(&mut png, b"iDOT", &idot);
+    push_chunk(&mut png, b"IDAT", &stream[..split_point]);
+    push_chunk(&mut png, b"IDAT", &stream[split_point..]);
+    push_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+#[test]
+fn image_data_split_as_an_idot_chunk_describes_is_decompressed_in_parallel() {
+    for &color_type in &[2, 6] {
+        for &interlaced in &[false, true] {
+            let image = TestImage::new(200, 31, color_type, interlaced, 8);
+            let png = encode_with_idot(&image);
+            for &worker_count in &[1, 2, 3] {
+                let mut loader = ImageLoader::with_worker_count(worker_count);
+                let pixels = decode_with_loader(&mut loader, &png).unwrap();
+                assert!(pixels == image.expected_rgba(),
+                        "{} workers decoded color type {} (interlaced: {}) incorrectly",
+                        worker_count,
+                        color_type,
+                        interlaced)
+            }
+        }
+    }
+}
+
+/// Decompresses `stream` with a `ParallelInflater`, splitting it at `split_points`, and returns
+/// the segments that it returns.
+fn inflate_in_parallel(stream: &[u8], split_points: &[usize]) -> Vec<Vec<u8>> {
+    let mut parallel_inflater = ParallelInflater::new();
+    let mut start = 0;
+    for &split_point in split_points {
+        parallel_inflater.add_segment(stream[start..split_point].to_vec(), false);
+        start = split_point
+    }
+    parallel_inflater.add_segment(stream[start..].to_vec(), true);
+    let mut segments = vec![];
+    while let Some(segment) = parallel_inflater.next_segment() {
+        segments.push(segment.unwrap())
+    }
+    segments
+}
+
+#[test]
+fn parallel_inflaters_fall_back_to_decompressing_serially() {
+    // Real full flushes: each segment is returned separately.
+    let mut data = compressible_data()[0..20000].to_vec();
+    let (stream, split_points) = zlib_stored_in_segments(&data, 4);
+    let segments = inflate_in_parallel(&stream, &split_points);
+    assert_eq!(segments.len(), 4);
+    assert!(segments.concat() == data);
+
+    // Stored data that looks like the end of a full flush splits a stored block in two, and the
+    // first half doesn't end on a block boundary.
+    data[10000..10004].clone_from_slice(&[0x00, 0x00, 0xff, 0xff]);
+    let stream = zlib_stored(&data);
+    assert!(inflate_in_parallel(&stream, &[2 + 5 + 10004]).concat() == data);
+
+    // A real full flush followed by a split that isn't one, just past the header of the next
+    // stored block: the first segment is returned as is, and the rest of the stream serially.
+    let (stream, split_points) = zlib_stored_in_segments(&data, 2);
+    let segments = inflate_in_parallel(&stream, &[split_points[0], split_points[0] + 5 + 4]);
+    assert_eq!(segments.len(), 2);
+    assert_eq!(segments[0].len(), 10000);
+    assert!(segments.concat() == data);
+
+    // Corrupt data is still reported once the rest of the stream turns out to be corrupt too.
+    let mut stream = zlib_stored(&data);
+    let length = stream.len();
+    stream[length - 1] ^= 1;
+    let mut parallel_inflater = ParallelInflater::new();
+    parallel_inflater.add_segment(stream[..10000].to_vec(), false);
+    parallel_inflater.add_segment(stream[10000..].to_vec(), true);
+    match parallel_inflater.next_segment() {
+        Some(Err(PngError::EntropyDecodingError)) => {}
+        _ => panic!("Corrupt data wasn't reported!"),
+    }
+}
+
+#[test]
+fn images_decode_in_place_from_slices_that_grow_as_data_arrives() {
+    for &(color_type, interlaced) in &[(0, false), (2, true), (3, false), (6, true)] {
+        let image = TestImage::new(37, 23, color_type, interlaced, 10);
+        let png = image.encode();
+        let decoded = Image::load_from_slice(&png).unwrap();
+        assert!(packed_pixels(&decoded) == image.expected_rgba());
+        match Image::load_from_slice(&png[0..(png.len() / 2)]) {
+            Err(PngError::Io(_)) => {}
+            _ => panic!("A truncated slice should be an I/O error!"),
+        }
+
+        let mut loader = ImageLoader::new();
+        let (data_provider, pixel_receiver) = TestDataProvider::new(image.width, image.height);
+        let (mut data_provider, mut length) = (Some(data_provider), 0);
+        loop {
+            match loader.add_data_from_slice(&png[0..length]).unwrap() {
+                LoadProgress::NeedDataProviderAndMoreData => {
+                    loader.set_data_provider(Box::new(data_provider.take().unwrap()))
+                }
+                LoadProgress::NeedMoreData => {
+                    assert!(length < png.len(), "Needed more data than the whole image!");
+                    length = cmp::min(length + 97, png.len())
+                }
+                LoadProgress::Finished => break,
+                LoadProgress::WaitingForPrediction | LoadProgress::Yielded => {
+                    panic!("Blocking decoding neither waits for prediction nor yields!")
+                }
+            }
+        }
+        loader.wait_until_finished().unwrap();
+        assert!(pixel_receiver.recv().unwrap() == image.expected_rgba())
+    }
+}
+
+/// Decodes `png`, an encoding of `image`, by adding it all at once with `add_data_from_slice()`.
+fn decode_slice_with_loader(loader: &mut ImageLoader, image: &TestImage, png: &[u8]) -> Vec<u8> {
+    let (data_provider, pixel_receiver) = TestDataProvider::new(image.width, image.height);
+    let mut data_provider = Some(data_provider);
+    loop {
+        match loader.add_data_from_slice(png).unwrap() {
+            LoadProgress::NeedDataProviderAndMoreData => {
+                loader.set_data_provider(Box::new(data_provider.take().unwrap()))
+            }
+            LoadProgress::Finished => break,
+            LoadProgress::NeedMoreData => panic!("Needed more data than the whole image!"),
+            LoadProgress::WaitingForPrediction | LoadProgress::Yielded => {
+                panic!("Blocking decoding neither waits for prediction nor yields!")
+            }
+        }
+    }
+    loader.wait_until_finished().unwrap();
+    pixel_receiver.recv().unwrap()
+}
+
+#[test]
+fn image_data_split_with_full_flushes_is_decompressed_in_parallel_from_slices() {
+    for &color_type in &[2, 6] {
+        for &interlaced in &[false, true] {
+            let image = TestImage::new(300, 40, color_type, interlaced, 7);
+            let (stream, split_points) = zlib_stored_in_segments(&image.filtered_data(), 4);
+            assert!(inflate::find_full_flush_points(&stream) == split_points);
+            let png = image.encode_with_zlib_stream(&stream);
+            for &worker_count in &[1, 2, 3, 4] {
+                let mut loader = ImageLoader::with_worker_count(worker_count);
+                let pixels = decode_slice_with_loader(&mut loader, &image, &png);
+                assert!(pixels == image.expected_rgba(),
+                        "{} workers decoded color type {} (interlaced: {}) incorrectly",
+                        worker_count,
+                        color_type,
+                        interlaced)
+            }
+        }
+    }
+
+    // The split that an `iDOT` chunk describes is used too.
+    let image = TestImage::new(200, 31, 6, true, 8);
+    let png = encode_with_idot(&image);
+    let pixels = decode_slice_with_loader(&mut ImageLoader::with_worker_count(2), &image, &png);
+    assert!(pixels == image.expected_rgba())
+}
+
+#[test]
+fn split_points_divide_the_stream_as_evenly_as_possible() {
+    assert!(inflate::choose_split_points(1000, vec![100, 240, 260, 510, 990], 4) ==
+            vec![240, 510]);
+    assert!(inflate::choose_split_points(1000, vec![0, 2, 998], 2).is_empty());
+    assert!(inflate::choose_split_points(1000, vec![500, 500], 3) == vec![500]);
+}
+
+#[cfg(feature = "zlib")]
+#[test]
+fn image_data_with_sync_flushes_decodes_correctly_with_worker_threads() {
+    // A sync flush looks just like a full flush, but what follows it may refer back past it.
+    let image = TestImage::new(300, 40, 2, false, 9);
+    let data = image.filtered_data();
+    let mut encoder = ZlibEncoder::new(vec![], Compression::Default);
+    for half in data.chunks(data.len() / 2 + 1) {
+        encoder.write_all(half).unwrap();
+        encoder.flush().unwrap()
+    }
+    let stream = encoder.finish().unwrap();
+    assert!(!inflate::find_full_flush_points(&stream).is_empty());
+    let png = image.encode_with_zlib_stream(&stream);
+    let pixels = decode_slice_with_loader(&mut ImageLoader::with_worker_count(2), &image, &png);
+    assert!(pixels == image.expected_rgba())
+}
+
+#[test]
+fn images_load_from_memory_mapped_files() {
+    let image = TestImage::new(45, 17, 2, true, 11);
+    let path = env::temp_dir().join(format!("parng-test-{}.png", process::id()));
+    File::create(&path).unwrap().write_all(&image.encode()).unwrap();
+    let decoded = Image::load_from_path(&path);
+    fs::remove_file(&path).unwrap();
+    assert!(packed_pixels(&decoded.unwrap()) == image.expected_rgba());
+
+    match Image::load_from_path(&path) {
+        Err(PngError::Io(_)) => {}
+        _ => panic!("A missing file should be an I/O error!"),
+    }
+}
+
+#[test]
+fn buffers_that_are_too_small_are_rejected_before_being_written_to() {
+    let image = TestImage::new(21, 13, 6, false, 13);
+    let png = image.encode();
+    let (scanline_length, stride) = (21 * 4, 21 * 4 + 12);
+    let required_length = stride * 12 + scanline_length;
+
+    let mut pixels = vec![0xaa; required_length + 16];
+    for &(length, stride) in &[(required_length, scanline_length - 1),
+                               (required_length - 1, stride),
+                               (0, stride)] {
+        match simple::load_into_buffer(&mut Cursor::new(&png[..]), &mut pixels[0..length], stride) {
+            Err(PngError::InvalidBuffer(_)) => {}
+            _ => panic!("A {}-byte buffer with a stride of {} should be rejected!", length, stride),
+        }
+        assert!(pixels.iter().all(|&byte| byte == 0xaa))
+    }
+
+    // A buffer that is just large enough is filled in, and nothing past its end is touched.
+    let dimensions = simple::load_from_slice_into_buffer(&png,
+                                                         &mut pixels[0..required_length],
+                                                         stride).unwrap();
+    assert_eq!((dimensions.width, dimensions.height), (21, 13));
+    assert!(pixels[required_length..].iter().all(|&byte| byte == 0xaa));
+    let decoded = Image {
+        width: 21,
+        height: 13,
+        stride: stride,
+        pixels: pixels,
+    };
+    assert!(packed_pixels(&decoded) == image.expected_rgba())
+}
+
+/// A reader that panics once it has read `panic_at` bytes.
+struct PanickingReader {
+    inner: Cursor<Vec<u8>>,
+    panic_at: u64,
+}
+
+impl Read for PanickingReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.inner.position() >= self.panic_at {
+            panic!("Reader panicked!")
+        }
+        let length = cmp::min(buffer.len() as u64, self.panic_at - self.inner.position());
+        self.inner.read(&mut buffer[0..(length as usize)])
+    }
+}
+
+impl Seek for PanickingReader {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(position)
+    }
+}
+
+#[test]
+fn loading_into_a_buffer_unwinds_when_the_reader_panics() {
+    let image = TestImage::new(64, 64, 6, false, 14);
+    let png = image.encode();
+    let panic_at = png.len() as u64 / 2;
+    let mut pixels = vec![0; 64 * 64 * 4];
+    let mut reader = PanickingReader {
+        inner: Cursor::new(png),
+        panic_at: panic_at,
+    };
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        simple::load_into_buffer(&mut reader, &mut pixels, 64 * 4)
+    }));
+    assert!(result.is_err());
+
+    // Nothing writes to the buffer once the panic has reached us.
+    for byte in &mut pixels {
+        *byte = 0xaa
+    }
+    thread::sleep(Duration::from_millis(50));
+    assert!(pixels.iter().all(|&byte| byte == 0xaa))
+}
+
+#[test]
+fn images_load_with_strides_chosen_by_the_caller() {
+    for &interlaced in &[false, true] {
+        // An odd width, so that tightly packed scanlines aren't aligned for SIMD.
+        let image = TestImage::new(37, 11, 2, interlaced, 14);
+        let png = image.encode();
+        for &stride in &[37 * 4, 37 * 4 + 4, 37 * 4 + 64] {
+            let decoded = Image::load_with_stride(&mut Cursor::new(&png[..]), stride).unwrap();
+            assert_eq!(decoded.stride, stride);
+            assert!(packed_pixels(&decoded) == image.expected_rgba());
+            let decoded = Image::load_from_slice_with_stride(&png, stride).unwrap();
+            assert_eq!(decoded.stride, stride);
+            assert!(packed_pixels(&decoded) == image.expected_rgba())
+        }
+        match Image::load_from_slice_with_stride(&png, 37 * 4 - 1) {
+            Err(PngError::InvalidBuffer(_)) => {}
+            _ => panic!("A stride narrower than a scanline should be rejected!"),
+        }
+    }
+}
+
+const EVERY_LEVEL_OF_DETAIL: [LevelOfDetail; 8] = [
+    LevelOfDetail::None,
+    LevelOfDetail::Adam7(0),
+    LevelOfDetail::Adam7(1),
+    LevelOfDetail::Adam7(2),
+    LevelOfDetail::Adam7(3),
+    LevelOfDetail::Adam7(4),
+    LevelOfDetail::Adam7(5),
+    LevelOfDetail::Adam7(6),
+];
+
+#[test]
+fn interlacing_info_in_a_region_locates_the_pixels_inside_it() {
+    let (width, height) = (23, 19);
+    for &region in &[Region { x: 5, y: 3, width: 9, height: 10 },
+                     Region { x: 0, y: 0, width: 1, height: 1 },
+                     Region { x: 7, y: 6, width: 16, height: 13 }] {
+        for &lod in &EVERY_LEVEL_OF_DETAIL {
+            for y in 0..InterlacingInfo::height_of_lod(height, lod) {
+                // Find the columns of the pixels of the scanline that lie inside the region by
+                // walking along it.
+                let info = InterlacingInfo::new(y, 32, lod);
+                let mut columns = vec![];
+                let mut x = info.offset as u32 / 4;
+                while x < width {
+                    if x >= region.x && x < region.x + region.width {
+                        columns.push(x)
+                    }
+                    x += info.stride as u32 / 4
+                }
+                if info.y < region.y || info.y >= region.y + region.height {
+                    columns.clear()
+                }
+
+                match InterlacingInfo::in_region(y, 32, lod, &region) {
+                    None => assert!(columns.is_empty()),
+                    Some(region_info) => {
+                        assert_eq!(region_info.y, info.y - region.y);
+                        assert_eq!(region_info.stride, info.stride);
+                        assert_eq!(region_info.offset as u32, (columns[0] - region.x) * 4);
+                        assert_eq!(region_info.scanline_width(region.width, 32),
+                                   columns.len() as u32)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the given region of a tightly packed RGBA image `width` pixels wide.
+fn crop(pixels: &[u8], width: u32, region: Region) -> Vec<u8> {
+    let mut cropped = vec![];
+    for y in region.y..(region.y + region.height) {
+        let start = ((y * width + region.x) * 4) as usize;
+        cropped.extend_from_slice(&pixels[start..(start + region.width as usize * 4)])
+    }
+    cropped
+}
+
+#[test]
+fn regions_decode_the_same_pixels_as_the_whole_image() {
+    for &color_type in &[0, 3, 6] {
+        for &interlaced in &[false, true] {
+            let image = TestImage::new(41, 29, color_type, interlaced, 15);
+            let png = image.encode();
+            let full = packed_pixels(&Image::load_from_slice(&png).unwrap());
+            for &region in &[Region { x: 3, y: 5, width: 17, height: 11 },
+                             Region { x: 0, y: 0, width: 41, height: 1 },
+                             Region { x: 40, y: 28, width: 1, height: 1 },
+                             Region { x: 30, y: 20, width: 100, height: 100 }] {
+                let clipped = Region {
+                    x: region.x,
+                    y: region.y,
+                    width: cmp::min(region.width, 41 - region.x),
+                    height: cmp::min(region.height, 29 - region.y),
+                };
+                let decoded = Image::load_region(&mut Cursor::new(&png[..]), region).unwrap();
+                assert_eq!((decoded.width, decoded.height), (clipped.width, clipped.height));
+                assert!(packed_pixels(&decoded) == crop(&full, 41, clipped));
+                let decoded = Image::load_region_from_slice(&png, region).unwrap();
+                assert!(packed_pixels(&decoded) == crop(&full, 41, clipped))
+            }
+        }
+    }
+}
+
+#[test]
+fn decoding_a_region_stops_at_the_bottom_of_it() {
+    // With a single stored block in a single `IDAT` chunk, the scanlines down to the bottom of the
+    // region end a known distance into the file.
+    let mut image = TestImage::new(40, 30, 2, false, 16);
+    image.idat_size = 100000;
+    let png = image.encode();
+    let image_data_start = without_iend(&image.encode_with_zlib_stream(&[])).len() + 8;
+    let region = Region { x: 10, y: 4, width: 20, height: 6 };
+    let end = image_data_start + 7 + 10 * (1 + 40 * 3);
+    let full = packed_pixels(&Image::load_from_slice(&png).unwrap());
+    let decoded = Image::load_region_from_slice(&png[0..end], region).unwrap();
+    assert!(packed_pixels(&decoded) == crop(&full, 40, region))
+}
+
+#[test]
+fn the_region_of_interest_can_only_be_set_between_the_metadata_and_the_image_data() {
+    let image = TestImage::new(20, 10, 6, false, 17);
+    let png = image.encode();
+    let region = Region { x: 5, y: 5, width: 20, height: 20 };
+    let mut loader = ImageLoader::new();
+    assert!(loader.try_set_region_of_interest(region).is_none());
+    match loader.add_data_from_slice(&png).unwrap() {
+        LoadProgress::NeedDataProviderAndMoreData => {}
+        _ => panic!("Expected to need a data provider after the metadata!"),
+    }
+    assert!(loader.try_set_region_of_interest(region) ==
+            Some(Region { x: 5, y: 5, width: 15, height: 5 }));
+
+    let mut loader = ImageLoader::new();
+    assert!(decode_slice_with_loader(&mut loader, &image, &png) == image.expected_rgba());
+    assert!(loader.try_set_region_of_interest(region).is_none())
+}
+
+/// Downscales a tightly packed RGBA image by `factor` by averaging each block of pixels, rounding
+/// to the nearest value.
+fn box_filter(pixels: &[u8], width: u32, height: u32, factor: u32) -> Vec<u8> {
+    let mut scaled = vec![];
+    for block_y in 0..((height + factor - 1) / factor) {
+        for block_x in 0..((width + factor - 1) / factor) {
+            let (mut sums, mut count) = ([0; 4], 0);
+            for y in (block_y * factor)..cmp::min(block_y * factor + factor, height) {
+                for x in (block_x * factor)..cmp::min(block_x * factor + factor, width) {
+                    let start = ((y * width + x) * 4) as usize;
+                    for channel in 0..4 {
+                        sums[channel] += pixels[start + channel] as u32
+                    }
+                    count += 1
+                }
+            }
+            for sum in &sums {
+                scaled.push(((sum + count / 2) / count) as u8)
+            }
+        }
+    }
+    scaled
+}
+
+/// Downscales a tightly packed RGBA image by `x_factor` and `y_factor` by taking the pixel at the
+/// top left of each block.
+fn sample(pixels: &[u8], width: u32, height: u32, x_factor: u32, y_factor: u32) -> Vec<u8> {
+    let mut sampled = vec![];
+    for y in 0..((height + y_factor - 1) / y_factor) {
+        for x in 0..((width + x_factor - 1) / x_factor) {
+            let start = ((y * y_factor * width + x * x_factor) * 4) as usize;
+            sampled.extend_from_slice(&pixels[start..(start + 4)])
+        }
+    }
+    sampled
+}
+
+#[test]
+fn scaled_images_are_box_filtered_or_sampled_from_the_whole_image() {
+    for &color_type in &[2, 3, 4] {
+        for &interlaced in &[false, true] {
+            let image = TestImage::new(43, 21, color_type, interlaced, 17);
+            let png = image.encode();
+            let full = packed_pixels(&Image::load_from_slice(&png).unwrap());
+            for &scale in &[Scale::Half, Scale::Quarter, Scale::Eighth] {
+                let factor = scale.factor();
+                let expected = if interlaced {
+                    sample(&full, 43, 21, factor, factor)
+                } else {
+                    box_filter(&full, 43, 21, factor)
+                };
+                let dimensions = scale.dimensions(Dimensions { width: 43, height: 21 });
+                assert_eq!((dimensions.width, dimensions.height),
+                           ((43 + factor - 1) / factor, (21 + factor - 1) / factor));
+
+                let decoded = Image::load_scaled(&mut Cursor::new(&png[..]), scale).unwrap();
+                assert_eq!((decoded.width, decoded.height), (dimensions.width, dimensions.height));
+                assert!(packed_pixels(&decoded) == expected,
+                        "Color type {} (interlaced: {}) scaled by {:?} incorrectly",
+                        color_type,
+                        interlaced,
+                        scale);
+                let decoded = Image::load_scaled_from_slice(&png, scale).unwrap();
+                assert!(packed_pixels(&decoded) == expected)
+            }
+        }
+    }
+}
+
+/// Returns the horizontal and vertical distances between the pixels that the Adam7 passes up to
+/// and including pass `pass` supply.
+fn preview_factors(pass: u8) -> (u32, u32) {
+    [(8, 8), (4, 8), (4, 4), (2, 4), (2, 2), (1, 2), (1, 1)][pass as usize]
+}
+
+/// Returns the length of the filtered data of the Adam7 passes up to and including `last_pass` of
+/// an image with the given dimensions and bytes per pixel. Every pass must be nonempty.
+fn length_of_passes(width: u32, height: u32, bytes_per_pixel: u32, last_pass: u8) -> usize {
+    let mut length = 0;
+    for pass in 0..(last_pass + 1) {
+        let lod = LevelOfDetail::Adam7(pass);
+        let pass_width = InterlacingInfo::new(0, 8, lod).scanline_width(width, 8);
+        let scanline_length = 1 + pass_width * bytes_per_pixel;
+        length += (InterlacingInfo::height_of_lod(height, lod) * scanline_length) as usize
+    }
+    length
+}
+
+#[test]
+fn previews_hold_the_pixels_of_the_first_passes() {
+    for &color_type in &[0, 3, 6] {
+        for &interlaced in &[false, true] {
+            let image = TestImage::new(35, 26, color_type, interlaced, 18);
+            let png = image.encode();
+            let full = packed_pixels(&Image::load_from_slice(&png).unwrap());
+            for pass in 0..7 {
+                let lod = LevelOfDetail::Adam7(pass);
+                let expected = if interlaced {
+                    let (x_factor, y_factor) = preview_factors(pass);
+                    sample(&full, 35, 26, x_factor, y_factor)
+                } else {
+                    full.clone()
+                };
+                let decoded = Image::load_preview(&mut Cursor::new(&png[..]), lod).unwrap();
+                assert!(packed_pixels(&decoded) == expected,
+                        "Color type {} (interlaced: {}) previewed incorrectly at pass {}",
+                        color_type,
+                        interlaced,
+                        pass);
+                let decoded = Image::load_preview_from_slice(&png, lod).unwrap();
+                assert!(packed_pixels(&decoded) == expected)
+            }
+            let decoded = Image::load_preview_from_slice(&png, LevelOfDetail::None).unwrap();
+            assert!(packed_pixels(&decoded) == full)
+        }
+    }
+}
+
+#[test]
+fn decoding_stops_at_the_last_level_of_detail() {
+    // With a single stored block in a single `IDAT` chunk, the passes up to the last level of
+    // detail end a known distance into the file, and nothing past that is needed.
+    let mut image = TestImage::new(40, 30, 2, true, 19);
+    image.idat_size = 100000;
+    let png = image.encode();
+    let image_data_start = without_iend(&image.encode_with_zlib_stream(&[])).len() + 8;
+    for last_pass in 0..7 {
+        let end = image_data_start + 7 + length_of_passes(40, 30, 3, last_pass);
+        let mut loader = ImageLoader::new();
+        let (data_provider, _, event_receiver) = EventDataProvider::new(40, 30);
+        let mut data_provider = Some(data_provider);
+        loop {
+            match loader.add_data_from_slice(&png[0..end]).unwrap() {
+                LoadProgress::NeedDataProviderAndMoreData => {
+                    loader.set_data_provider(Box::new(data_provider.take().unwrap()));
+                    loader.set_last_level_of_detail(LevelOfDetail::Adam7(last_pass))
+                }
+                LoadProgress::Finished => break,
+                _ => panic!("Decoding should finish at pass {}!", last_pass),
+            }
+        }
+        loader.wait_until_finished().unwrap();
+        drop(loader);
+
+        let mut predicted = 0;
+        let mut finished = false;
+        for event in event_receiver.iter() {
+            match event {
+                ProviderEvent::Predicted(_, LevelOfDetail::Adam7(pass)) => {
+                    assert!(pass <= last_pass);
+                    predicted += 1
+                }
+                ProviderEvent::Finished => finished = true,
+                _ => {}
+            }
+        }
+        let expected_count = (0..(last_pass + 1)).map(|pass| {
+            InterlacingInfo::height_of_lod(30, LevelOfDetail::Adam7(pass))
+        }).sum::<u32>();
+        assert_eq!(predicted, expected_count);
+        assert!(finished)
+    }
+}
+
+#[test]
+fn the_last_level_of_detail_can_only_be_set_between_the_metadata_and_the_image_data() {
+    let image = TestImage::new(20, 10, 6, true, 18);
+    let png = image.encode();
+    let mut loader = ImageLoader::new();
+    assert!(!loader.try_set_last_level_of_detail(LevelOfDetail::Adam7(0)));
+    match loader.add_data_from_slice(&png).unwrap() {
+        LoadProgress::NeedDataProviderAndMoreData => {}
+        _ => panic!("Expected to need a data provider after the metadata!"),
+    }
+    assert!(loader.try_set_last_level_of_detail(LevelOfDetail::Adam7(0)));
+
+    let mut loader = ImageLoader::new();
+    assert!(decode_slice_with_loader(&mut loader, &image, &png) == image.expected_rgba());
+    assert!(!loader.try_set_last_level_of_detail(LevelOfDetail::Adam7(0)))
+}
+
+/// Fills in each block of a tightly packed RGBA image `x_factor` by `y_factor` pixels in size with
+/// the pixel at its top left.
+fn fill_blocks(pixels: &[u8], width: u32, height: u32, x_factor: u32, y_factor: u32) -> Vec<u8> {
+    let mut filled = vec![];
+    for y in 0..height {
+        for x in 0..width {
+            let start = (((y - y % y_factor) * width + x - x % x_factor) * 4) as usize;
+            filled.extend_from_slice(&pixels[start..(start + 4)])
+        }
+    }
+    filled
+}
+
+#[test]
+fn block_fill_data_providers_send_a_coarse_image_after_each_pass() {
+    for &color_type in &[2, 3, 6] {
+        for &interlaced in &[false, true] {
+            let image = TestImage::new(29, 22, color_type, interlaced, 20);
+            let png = image.encode();
+            let full = packed_pixels(&Image::load_from_slice(&png).unwrap());
+
+            let mut loader = ImageLoader::new();
+            assert!(loader.add_data_from_slice(&png).unwrap() ==
+                    LoadProgress::NeedDataProviderAndMoreData);
+            let (data_provider, image_receiver) =
+                BlockFillDataProvider::new(loader.metadata().as_ref().unwrap());
+            loader.set_data_provider(Box::new(data_provider));
+            assert!(loader.add_data_from_slice(&png).unwrap() == LoadProgress::Finished);
+            loader.wait_until_finished().unwrap();
+            drop(loader);
+
+            let images: Vec<_> = image_receiver.iter().map(|image| packed_pixels(&image)).collect();
+            if !interlaced {
+                assert!(images == vec![full]);
+                continue
+            }
+            assert_eq!(images.len(), 7);
+            for (pass, pixels) in images.iter().enumerate() {
+                let (x_factor, y_factor) = preview_factors(pass as u8);
+                assert!(*pixels == fill_blocks(&full, 29, 22, x_factor, y_factor),
+                        "Color type {} filled in incorrectly after pass {}",
+                        color_type,
+                        pass)
+            }
+        }
+    }
+}
+
+#[test]
+fn shared_data_providers_record_the_rows_that_change() {
+    for &color_type in &[0, 3, 6] {
+        for &interlaced in &[false, true] {
+            let image = TestImage::new(26, 19, color_type, interlaced, 21);
+            let png = image.encode();
+            let full = packed_pixels(&Image::load_from_slice(&png).unwrap());
+
+            let mut loader = ImageLoader::new();
+            assert!(loader.add_data_from_slice(&png).unwrap() ==
+                    LoadProgress::NeedDataProviderAndMoreData);
+            let (data_provider, shared_image) =
+                SharedDataProvider::new(loader.metadata().as_ref().unwrap());
+            loader.set_data_provider(Box::new(data_provider));
+            {
+                let shared_image = shared_image.lock().unwrap();
+                assert!(!shared_image.finished);
+                assert!(shared_image.image.pixels.iter().all(|&byte| byte == 0))
+            }
+            assert!(loader.add_data_from_slice(&png).unwrap() == LoadProgress::Finished);
+            loader.wait_until_finished().unwrap();
+
+            // The data provider is told that the image is finished on the predictor thread, which
+            // may not have gotten around to it yet.
+            let mut attempts = 0;
+            while !shared_image.lock().unwrap().finished {
+                attempts += 1;
+                assert!(attempts < 10000, "The shared image was never finished!");
+                thread::sleep(Duration::from_millis(1))
+            }
+
+            // Each level of detail is decoded from top to bottom, so its rows are merged into a
+            // single range.
+            let lods: Vec<LevelOfDetail> = if interlaced {
+                EVERY_LEVEL_OF_DETAIL[1..].to_vec()
+            } else {
+                vec![LevelOfDetail::None]
+            };
+            let expected_dirty_rows: Vec<DirtyRows> = lods.into_iter().map(|lod| {
+                let scanline_count = InterlacingInfo::height_of_lod(19, lod);
+                DirtyRows {
+                    lod: lod,
+                    start: InterlacingInfo::new(0, 32, lod).y,
+                    end: InterlacingInfo::new(scanline_count - 1, 32, lod).y + 1,
+                }
+            }).collect();
+
+            let mut shared_image = shared_image.lock().unwrap();
+            assert!(!shared_image.cancelled);
+            assert_eq!(shared_image.take_dirty_rows(), expected_dirty_rows);
+            assert!(shared_image.take_dirty_rows().is_empty());
+            assert!(packed_pixels(&shared_image.image) == full)
+        }
+    }
+}
+
+#[test]
+fn row_data_providers_complete_each_row_once() {
+    for &color_type in &[0, 3, 4, 6] {
+        for &(interlaced, last_pass) in &[(false, None), (true, None), (true, Some(3))] {
+            let image = TestImage::new(31, 18, color_type, interlaced, 22);
+            let png = image.encode();
+            let full = packed_pixels(&Image::load_from_slice(&png).unwrap());
+
+            let mut loader = ImageLoader::new();
+            assert!(loader.add_data_from_slice(&png).unwrap() ==
+                    LoadProgress::NeedDataProviderAndMoreData);
+            let (row_sender, row_receiver) = mpsc::channel();
+            // Rows that are never decoded into keep this value.
+            let rows = vec![vec![0xaa; 31 * 4]; 18];
+            let data_provider = RowDataProvider::new(loader.metadata().as_ref().unwrap(),
+                                                     (rows, row_sender),
+                                                     |rows, y| &mut rows.0[y as usize][..],
+                                                     |rows, y| {
+                let row = rows.0[y as usize].clone();
+                rows.1.send((y, row)).unwrap()
+            });
+            loader.set_data_provider(Box::new(data_provider));
+            if let Some(last_pass) = last_pass {
+                loader.set_last_level_of_detail(LevelOfDetail::Adam7(last_pass))
+            }
+            assert!(loader.add_data_from_slice(&png).unwrap() == LoadProgress::Finished);
+            loader.wait_until_finished().unwrap();
+            drop(loader);
+
+            let completed_rows: Vec<(u32, Vec<u8>)> = row_receiver.iter().collect();
+            let mut ys: Vec<u32> = completed_rows.iter().map(|&(y, _)| y).collect();
+            if !interlaced {
+                assert!(ys == (0..18).collect::<Vec<_>>(), "Rows completed out of order!")
+            }
+            ys.sort();
+            assert!(ys == (0..18).collect::<Vec<_>>(), "Each row should be completed once!");
+            for &(y, ref row) in &completed_rows {
+                let full_row = &full[(y as usize * 31 * 4)..((y as usize + 1) * 31 * 4)];
+                if last_pass.is_none() {
+                    assert!(&row[..] == full_row, "Row {} decoded incorrectly", y);
+                    continue
+                }
+
+                // Only the pixels of the passes up to the last one are decoded.
+                let (x_factor, y_factor) = preview_factors(3);
+                for (x, (pixel, full_pixel)) in row.chunks(4).zip(full_row.chunks(4)).enumerate() {
+                    if x as u32 % x_factor == 0 && y % y_factor == 0 {
+                        assert!(pixel == full_pixel)
+                    } else {
+                        assert!(pixel == [0xaa; 4])
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn rows_are_decoded_one_at_a_time_from_readers_that_return_short_reads() {
+    // Including transparency, since reading the `tRNS` chunk needs more data after a short read
+    // too.
+    for &(color_type, interlaced) in &[(0, false), (2, false), (3, true), (6, true)] {
+        let image = TestImage::new(33, 15, color_type, interlaced, 23);
+        let image = match color_type {
+            0 | 2 => image.with_magic_color(4, 2),
+            3 => image.with_palette_alpha(5),
+            _ => image,
+        };
+        let png = image.encode();
+        let full = packed_pixels(&Image::load_from_slice(&png).unwrap());
+        for &max_read_size in &[1, 7, 1000000] {
+            let reader = TrickleReader {
+                inner: Cursor::new(png.clone()),
+                max_read_size: max_read_size,
+            };
+            let rows = Rows::new(reader).unwrap();
+            assert_eq!(rows.metadata().dimensions.width, 33);
+            let rows: Vec<Vec<u8>> = rows.map(|row| row.unwrap()).collect();
+            assert_eq!(rows.len(), 15);
+            assert!(rows.concat() == full,
+                    "Color type {} (interlaced: {}) decoded incorrectly {} bytes at a time",
+                    color_type,
+                    interlaced,
+                    max_read_size)
+        }
+
+        // Running out of data is an error, which ends the rows.
+        let truncated = png[0..(png.len() - 200)].to_vec();
+        let results: Vec<_> = Rows::new(Cursor::new(truncated)).unwrap().collect();
+        match results.last() {
+            Some(&Err(PngError::Io(_))) => {}
+            _ => panic!("A truncated image should end with an I/O error!"),
+        }
+        assert!(results[0..(results.len() - 1)].iter().all(|result| result.is_ok()))
+    }
+}
+
+/// A `TrickleReader` that isn't ready every other time it's read from, as an asynchronous socket
+/// might not be.
+#[cfg(feature = "async")]
+struct PendingReader {
+    inner: TrickleReader,
+    ready: bool,
+}
+
+#[cfg(feature = "async")]
+impl Read for PendingReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.ready = !self.ready;
+        if self.ready {
+            return self.inner.read(buffer)
+        }
+        // Ask to be polled again right away, as an event loop would once the socket is readable.
+        task::current().notify();
+        Err(io::Error::new(ErrorKind::WouldBlock, "Not ready yet"))
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncRead for PendingReader {}
+
+#[cfg(feature = "async")]
+#[test]
+fn image_streams_decode_from_asynchronous_readers_on_other_threads() {
+    for &(color_type, interlaced) in &[(2, false), (3, true), (6, true)] {
+        for &max_read_size in &[1, 97, 1000000] {
+            let image = TestImage::new(70, 45, color_type, interlaced, 29);
+            let png = image.encode();
+            let reader = PendingReader {
+                inner: TrickleReader {
+                    inner: Cursor::new(png.clone()),
+                    max_read_size: max_read_size,
+                },
+                ready: false,
+            };
+
+            // The stream, image loader included, must be able to move to another thread.
+            let stream = ImageStream::new(reader, ImageLoader::new());
+            let decoder = thread::spawn(move || {
+                let mut stream = task::spawn(stream);
+                let (mut events, mut receiver) = (vec![], None);
+                while let Some(event) = stream.wait_stream() {
+                    let event = event.unwrap();
+                    if event == LoadEvent::NeedDataProvider {
+                        let dimensions = stream.get_ref().metadata().as_ref().unwrap().dimensions;
+                        let (data_provider, pixel_receiver) =
+                            TestDataProvider::new(dimensions.width, dimensions.height);
+                        stream.get_mut().set_data_provider(Box::new(data_provider));
+                        receiver = Some(pixel_receiver)
+                    }
+                    events.push(event)
+                }
+                (events, receiver.unwrap().recv().unwrap())
+            });
+            let (events, pixels) = decoder.join().unwrap();
+
+            assert_eq!(events.iter().filter(|&&event| event == LoadEvent::NeedDataProvider).count(),
+                       1);
+            assert_eq!(events.last(), Some(&LoadEvent::Finished));
+            let bytes_read: Vec<u64> = events.iter().filter_map(|event| {
+                match *event {
+                    LoadEvent::DataAdded(bytes_read) => Some(bytes_read),
+                    _ => None,
+                }
+            }).collect();
+            assert!(bytes_read.windows(2).all(|pair| pair[0] < pair[1]));
+            assert!(bytes_read.iter().all(|&bytes_read| bytes_read <= png.len() as u64));
+            assert!(pixels == image.expected_rgba())
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn image_streams_fail_when_the_reader_ends_early() {
+    let png = TestImage::new(30, 30, 6, false, 31).encode();
+    let truncated = png[0..(png.len() / 2)].to_vec();
+    let mut stream = task::spawn(ImageStream::new(&truncated[..], ImageLoader::new()));
+    loop {
+        match stream.wait_stream() {
+            Some(Ok(LoadEvent::NeedDataProvider)) => {
+                let (data_provider, _) = TestDataProvider::new(30, 30);
+                stream.get_mut().set_data_provider(Box::new(data_provider))
+            }
+            Some(Ok(LoadEvent::DataAdded(_))) => {}
+            Some(Err(PngError::Io(ref error))) if error.kind() == ErrorKind::UnexpectedEof => break,
+            result => panic!("Expected the stream to end with an error, not {:?}!", result),
+        }
+    }
+}
+
+/// Returns the events that a data provider reported once the image loader that was using it has
+/// been dropped.
+fn remaining_events(loader: ImageLoader, events: Receiver<ProviderEvent>) -> Vec<ProviderEvent> {
+    drop(loader);
+    events.iter().collect()
+}
+
+#[test]
+fn cancelling_decoding_from_another_thread_stops_it() {
+    let image = TestImage::new(64, 200, 6, true, 37);
+    let png = image.encode();
+    let (data_provider, _, events) = EventDataProvider::new(image.width, image.height);
+    let mut loader = ImageLoader::new();
+    assert!(add_all_data(&mut loader, &png[0..(png.len() / 2)], data_provider) ==
+            LoadProgress::NeedMoreData);
+
+    let cancel_handle = loader.cancel_handle();
+    let other_cancel_handle = cancel_handle.clone();
+    thread::spawn(move || other_cancel_handle.cancel()).join().unwrap();
+    assert!(cancel_handle.is_cancelled());
+
+    // Both adding data and waiting fail, however many times they're called.
+    for _ in 0..2 {
+        match loader.add_data_from_slice(&png) {
+            Err(PngError::Cancelled) => {}
+            _ => panic!("Adding data to a cancelled image loader should fail!"),
+        }
+        match loader.wait_until_finished() {
+            Err(PngError::Cancelled) => {}
+            _ => panic!("Waiting for a cancelled image loader should fail!"),
+        }
+    }
+
+    // The data provider is told that decoding was cancelled, instead of that it finished.
+    let events = remaining_events(loader, events);
+    assert_eq!(events.last(), Some(&ProviderEvent::Cancelled));
+    assert!(!events.contains(&ProviderEvent::Finished));
+}
+
+/// A reader that cancels decoding once `cancel_position` bytes have been read from it.
+struct CancellingReader {
+    inner: Cursor<Vec<u8>>,
+    cancel_handle: CancelHandle,
+    cancel_position: u64,
+}
+
+impl Read for CancellingReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.inner.position() >= self.cancel_position {
+            self.cancel_handle.cancel()
+        }
+        let length = cmp::min(buffer.len(), 4096);
+        self.inner.read(&mut buffer[0..length])
+    }
+}
+
+impl Seek for CancellingReader {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(position)
+    }
+}
+
+#[test]
+fn cancelling_decoding_stops_a_call_to_add_data_partway_through() {
+    let image = TestImage::new(256, 512, 6, false, 41);
+    let png = image.encode();
+    let mut loader = ImageLoader::new();
+    let mut reader = CancellingReader {
+        inner: Cursor::new(png.clone()),
+        cancel_handle: loader.cancel_handle(),
+        cancel_position: png.len() as u64 / 10,
+    };
+    let mut events = None;
+    loop {
+        match loader.add_data(&mut reader) {
+            Ok(LoadProgress::NeedDataProviderAndMoreData) => {
+                let (data_provider, _, event_receiver) = EventDataProvider::new(image.width,
+                                                                                image.height);
+                loader.set_data_provider(Box::new(data_provider));
+                events = Some(event_receiver)
+            }
+            Err(PngError::Cancelled) => break,
+            _ => panic!("Decoding should have been cancelled!"),
+        }
+    }
+    assert!(reader.inner.position() < png.len() as u64);
+
+    // Fewer than all the scanlines were predicted before the data provider was told.
+    let events = remaining_events(loader, events.unwrap());
+    assert_eq!(events.last(), Some(&ProviderEvent::Cancelled));
+    let predicted = events.iter().filter(|event| {
+        match **event {
+            ProviderEvent::Predicted(..) => true,
+            _ => false,
+        }
+    }).count();
+    assert!(predicted < image.height as usize);
+}
+
+#[test]
+fn cancelling_decoding_once_the_image_has_finished_does_nothing() {
+    let image = TestImage::new(40, 40, 2, false, 43);
+    let (data_provider, pixels, events) = EventDataProvider::new(image.width, image.height);
+    let mut loader = ImageLoader::new();
+    assert!(add_all_data(&mut loader, &image.encode(), data_provider) == LoadProgress::Finished);
+    loader.wait_until_finished().unwrap();
+
+    loader.cancel_handle().cancel();
+    loader.wait_until_finished().unwrap();
+    assert!(pixels.recv().unwrap() == image.expected_rgba());
+    let events = remaining_events(loader, events);
+    assert!(events.contains(&ProviderEvent::Finished));
+    assert!(!events.contains(&ProviderEvent::Cancelled));
+}
+
+/// Decodes `png` a slice at a time, with the given budget for each call, returning the pixels and
+/// the number of times that the image loader yielded.
+fn decode_with_budget(loader: &mut ImageLoader, png: &[u8], budget: Budget, from_slice: bool)
+                      -> (Vec<u8>, usize) {
+    let (mut receiver, mut yields, mut cursor) = (None, 0, Cursor::new(png));
+    loop {
+        let load_progress = if from_slice {
+            loader.add_data_from_slice_with_budget(png, budget)
+        } else {
+            loader.add_data_with_budget(&mut cursor, budget)
+        };
+        match load_progress.unwrap() {
+            LoadProgress::NeedDataProviderAndMoreData => {
+                let dimensions = loader.metadata().as_ref().unwrap().dimensions;
+                let (data_provider, pixel_receiver) = TestDataProvider::new(dimensions.width,
+                                                                            dimensions.height);
+                loader.set_data_provider(Box::new(data_provider));
+                receiver = Some(pixel_receiver)
+            }
+            LoadProgress::Yielded => yields += 1,
+            LoadProgress::WaitingForPrediction => thread::sleep(Duration::from_millis(1)),
+            LoadProgress::Finished => break,
+            LoadProgress::NeedMoreData => panic!("The whole image was there!"),
+        }
+    }
+    while !loader.try_wait_until_finished().unwrap() {
+        thread::sleep(Duration::from_millis(1))
+    }
+    (receiver.unwrap().recv().unwrap(), yields)
+}
+
+#[test]
+fn decoding_with_a_budget_yields_when_it_runs_out() {
+    for &interlaced in &[false, true] {
+        let image = TestImage::new(64, 300, 2, interlaced, 47);
+        let (png, expected) = (image.encode(), image.expected_rgba());
+        for &from_slice in &[false, true] {
+            let (pixels, yields) =
+                decode_with_budget(&mut ImageLoader::new(), &png, Budget::Scanlines(1), from_slice);
+            assert!(pixels == expected);
+            assert!(yields > 1);
+
+            // The image data is stored uncompressed, so it takes many 1000-byte budgets.
+            let (pixels, yields) =
+                decode_with_budget(&mut ImageLoader::new(), &png, Budget::Bytes(1000), from_slice);
+            assert!(pixels == expected);
+            assert!(yields >= png.len() / 1000 / 2);
+
+            // A zero time budget still decodes a batch of scanlines each time.
+            let budget = Budget::Time(Duration::from_secs(0));
+            let (pixels, yields) =
+                decode_with_budget(&mut ImageLoader::new(), &png, budget, from_slice);
+            assert!(pixels == expected);
+            assert!(yields > 1);
+
+            // A budget bigger than the image is never used up.
+            let budget = Budget::Bytes(png.len() as u64 * 2);
+            let (pixels, yields) =
+                decode_with_budget(&mut ImageLoader::new(), &png, budget, from_slice);
+            assert!(pixels == expected);
+            assert_eq!(yields, 0);
+        }
+    }
+}
+
+fn assert_send<T: Send>() {}
+
+#[test]
+fn image_loaders_are_send() {
+    assert_send::<ImageLoader>();
+    #[cfg(feature = "async")]
+    assert_send::<ImageStream<&'static [u8]>>();
+}
+
+#[test]
+fn progress_is_reported_as_scanlines_are_decompressed_and_predicted() {
+    for &interlaced in &[false, true] {
+        let image = TestImage::new(50, 120, 6, interlaced, 53);
+        let png = image.encode();
+        let mut loader = ImageLoader::new();
+        assert!(loader.progress().scanlines_decompressed.is_empty());
+
+        let reports = Arc::new(Mutex::new(vec![]));
+        let reports_to_add_to = reports.clone();
+        loader.set_progress_callback(Box::new(move |progress| {
+            reports_to_add_to.lock().unwrap().push(progress.clone())
+        }));
+        assert!(decode_with_loader(&mut loader, &png).unwrap() == image.expected_rgba());
+
+        // Nothing ever goes backwards, and prediction never gets ahead of decompression.
+        let reports = reports.lock().unwrap();
+        assert!(!reports.is_empty());
+        for pair in reports.windows(2) {
+            assert!(pair[0].level_of_detail <= pair[1].level_of_detail);
+            assert!(pair[0].bytes_read <= pair[1].bytes_read);
+            assert!(pair[0].fraction_complete <= pair[1].fraction_complete);
+        }
+        for progress in reports.iter().chain(Some(&loader.progress())) {
+            assert_eq!(progress.scanlines_decompressed.len(), if interlaced { 7 } else { 1 });
+            for (&predicted, &decompressed) in progress.scanlines_predicted
+                                                       .iter()
+                                                       .zip(&progress.scanlines_decompressed) {
+                assert!(predicted <= decompressed)
+            }
+            assert!(progress.fraction_complete >= 0.0 && progress.fraction_complete <= 1.0);
+        }
+
+        // Once the image has finished, every scanline has been accounted for.
+        let progress = loader.progress();
+        let heights: Vec<u32> = if interlaced {
+            (0..7).map(|pass| InterlacingInfo::height_of_lod(120, LevelOfDetail::Adam7(pass)))
+                  .collect()
+        } else {
+            vec![120]
+        };
+        assert!(progress.scanlines_decompressed == heights);
+        assert!(progress.scanlines_predicted == heights);
+        assert_eq!(progress.fraction_complete, 1.0);
+        assert!(progress.bytes_read <= png.len() as u64);
+    }
+}
+
+#[test]
+fn reset_image_loaders_decode_one_image_after_another() {
+    let images = [
+        TestImage::new(90, 20, 6, false, 59),
+        TestImage::new(13, 70, 3, true, 61),
+        TestImage::new(300, 9, 0, true, 67),
+        TestImage::new(1, 1, 2, false, 71),
+    ];
+    for &worker_count in &[1, 3] {
+        let mut loader = ImageLoader::with_worker_count(worker_count);
+        for image in images.iter().chain(images.iter().rev()) {
+            assert!(decode_with_loader(&mut loader, &image.encode()).unwrap() ==
+                    image.expected_rgba());
+            loader.reset();
+            assert!(loader.metadata().is_none());
+            assert_eq!(loader.progress().bytes_read, 0);
+
+            // Data added from slices begins at the start of the next image.
+            assert!(decode_slice_with_loader(&mut loader, image, &image.encode()) ==
+                    image.expected_rgba());
+            loader.reset();
+        }
+    }
+}
+
+#[test]
+fn resetting_an_image_loader_partway_through_cancels_the_image() {
+    let (first, second) = (TestImage::new(64, 100, 6, false, 73),
+                           TestImage::new(30, 40, 2, true, 79));
+    let png = first.encode();
+    let (data_provider, _, events) = EventDataProvider::new(first.width, first.height);
+    let mut loader = ImageLoader::new();
+    let old_cancel_handle = loader.cancel_handle();
+    assert!(add_all_data(&mut loader, &png[0..(png.len() / 2)], data_provider) ==
+            LoadProgress::NeedMoreData);
+    loader.reset();
+    let events: Vec<ProviderEvent> = events.iter().collect();
+    assert_eq!(events.last(), Some(&ProviderEvent::Cancelled));
+    assert!(!events.contains(&ProviderEvent::Finished));
+
+    // Neither the cancellation nor the old cancel handle affect the next image.
+    old_cancel_handle.cancel();
+    assert!(decode_with_loader(&mut loader, &second.encode()).unwrap() == second.expected_rgba());
+
+    // Nor does resetting once decoding has failed.
+    loader.reset();
+    loader.cancel_handle().cancel();
+    match decode_with_loader(&mut loader, &first.encode()) {
+        Err(PngError::Cancelled) => {}
+        _ => panic!("Decoding should have been cancelled!"),
+    }
+    loader.reset();
+    assert!(decode_with_loader(&mut loader, &first.encode()).unwrap() == first.expected_rgba());
+}
+
+/// Encodes `image` with the given chunks in front of its image data and after it.
+fn encode_with_chunks(image: &TestImage,
+                      before: &[(&[u8; 4], Vec<u8>)],
+                      after: &[(&[u8; 4], Vec<u8>)])
+                      -> Vec<u8> {
+    let header = image.encode_with_zlib_stream(&[]);
+    let mut png = without_iend(&header).to_vec();
+    for &(chunk_type, ref data) in before {
+        push_chunk(&mut png, chunk_type, data)
+    }
+    push_chunk(&mut png, b"IDAT", &zlib_stored(&image.filtered_data()));
+    for &(chunk_type, ref data) in after {
+        push_chunk(&mut png, chunk_type, data)
+    }
+    push_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+/// Joins the fields of a text chunk, or of the start of one, with null bytes.
+fn text_fields(fields: &[&[u8]]) -> Vec<u8> {
+    fields.join(&0)
+}
+
+/// Returns the contents of a chunk made of `fields`, as `text_fields()` joins them, followed by a
+/// null byte, a compression method of 0 and `compressed_data` compressed, as in `zTXt` and `iCCP`
+/// chunks.
+fn compressed_text_fields(fields: &[&[u8]], compressed_data: &[u8]) -> Vec<u8> {
+    let mut data = text_fields(fields);
+    data.extend_from_slice(&[0, 0]);
+    data.extend_from_slice(&zlib_stored(compressed_data));
+    data
+}
+
+/// Returns the contents of an `iTXt` chunk, compressing the text if `compressed` is true.
+fn itxt(keyword: &[u8], compressed: bool, language_tag: &[u8], translated_keyword: &str, text: &str)
+        -> Vec<u8> {
+    let mut data = text_fields(&[keyword, &[compressed as u8, 0]]);
+    data.extend_from_slice(&text_fields(&[language_tag, translated_keyword.as_bytes(), b""]));
+    if compressed {
+        data.extend_from_slice(&zlib_stored(text.as_bytes()))
+    } else {
+        data.extend_from_slice(text.as_bytes())
+    }
+    data
+}
+
+fn text(keyword: &str, text: &str, language_tag: &str, translated_keyword: &str) -> TextChunk {
+    TextChunk {
+        keyword: keyword.to_owned(),
+        text: text.to_owned(),
+        language_tag: language_tag.to_owned(),
+        translated_keyword: translated_keyword.to_owned(),
+    }
+}
+
+fn probe(png: &[u8]) -> ImageInfo {
+    ImageInfo::probe(&mut Cursor::new(png)).unwrap()
+}
+
+#[test]
+fn probing_reports_the_chunks_in_front_of_the_image_data() {
+    let image = TestImage::new(20, 10, 3, true, 83).with_palette_alpha(5);
+    let profile: Vec<u8> = (0..200).map(|value| value as u8).collect();
+    let mut chromaticities = vec![];
+    for value in 1..9 {
+        push_u32(&mut chromaticities, value * 1000)
+    }
+    let png = encode_with_chunks(&image, &[
+        (b"gAMA", vec![0, 0, 0xb1, 0x8f]),
+        (b"cHRM", chromaticities),
+        (b"sRGB", vec![1]),
+        (b"iCCP", compressed_text_fields(&[b"Display"], &profile)),
+        (b"tEXt", text_fields(&[b"Title", b"Caf\xe9"])),
+        (b"zTXt", compressed_text_fields(&[b"Comment"], b"Squeezed")),
+        (b"iTXt", itxt(b"Author", true, b"fr", "Auteur", "Zoë")),
+        (b"iTXt", itxt(b"Note", false, b"", "", "Plain")),
+        (b"bKGD", vec![0]),
+    ], &[
+        (b"tEXt", text_fields(&[b"Late", b"Too late to be probed"])),
+    ]);
+
+    let info = probe(&png);
+    assert_eq!(info.metadata.dimensions, Dimensions { width: 20, height: 10 });
+    assert_eq!(info.metadata.interlace_method, InterlaceMethod::Adam7);
+    assert_eq!(info.palette_size, image.palette.len() as u32 / 3);
+    assert!(info.has_transparency);
+    assert_eq!(info.color_space, ColorSpace {
+        gamma: Some(45455),
+        chromaticities: Some([1000, 2000, 3000, 4000, 5000, 6000, 7000, 8000]),
+        srgb_rendering_intent: Some(1),
+        icc_profile: Some(IccProfile {
+            name: "Display".to_owned(),
+            profile: profile,
+        }),
+    });
+    assert_eq!(info.text, vec![
+        text("Title", "Café", "", ""),
+        text("Comment", "Squeezed", "", ""),
+        text("Author", "Zoë", "fr", "Auteur"),
+        text("Note", "Plain", "", ""),
+    ]);
+    assert_eq!(info.animation, None);
+
+    // An image with nothing but the critical chunks has nothing else to report.
+    let info = probe(&TestImage::new(5, 6, 2, false, 89).encode());
+    assert_eq!(info.metadata.interlace_method, InterlaceMethod::Disabled);
+    assert_eq!(info.palette_size, 0);
+    assert!(!info.has_transparency);
+    assert_eq!(info.color_space, ColorSpace::default());
+    assert!(info.text.is_empty());
+}
+
+#[test]
+fn probing_finds_out_whether_the_static_image_is_the_first_frame_of_an_animation() {
+    let image = TestImage::new(8, 8, 6, false, 97);
+    let mut actl = vec![];
+    push_u32(&mut actl, 3);
+    push_u32(&mut actl, 2);
+    let fctl = vec![0; 26];
+    let animation = |static_image_is_first_frame| {
+        Some(Animation {
+            frame_count: 3,
+            play_count: 2,
+            static_image_is_first_frame: static_image_is_first_frame,
+        })
+    };
+
+    // A frame control chunk in front of the image data makes the static image the first frame,
+    // whether it comes before or after the animation control chunk.
+    let png = encode_with_chunks(&image, &[(b"acTL", actl.clone()), (b"fcTL", fctl.clone())], &[]);
+    assert_eq!(probe(&png).animation, animation(true));
+    let png = encode_with_chunks(&image, &[(b"fcTL", fctl.clone()), (b"acTL", actl.clone())], &[]);
+    assert_eq!(probe(&png).animation, animation(true));
+
+    // If the first frame control chunk comes after the image data, it's for a later frame.
+    let png = encode_with_chunks(&image, &[(b"acTL", actl.clone())], &[(b"fcTL", fctl.clone())]);
+    assert_eq!(probe(&png).animation, animation(false));
+
+    // Without an animation control chunk in front of the image data, the image isn't animated.
+    let png = encode_with_chunks(&image, &[(b"fcTL", fctl.clone())], &[(b"acTL", actl)]);
+    assert_eq!(probe(&png).animation, None);
+}
+
+#[test]
+fn probing_ignores_malformed_ancillary_chunks() {
+    let image = TestImage::new(12, 7, 2, false, 101);
+    let mut truncated_stream = zlib_stored(b"Cut off");
+    truncated_stream.pop();
+    let mut iccp_with_unknown_compression_method = compressed_text_fields(&[b"ICC"], b"Profile");
+    iccp_with_unknown_compression_method[4] = 1;
+    let mut itxt_with_unknown_compression_method = itxt(b"Key", true, b"", "", "Text");
+    itxt_with_unknown_compression_method[5] = 1;
+    let mut itxt_with_truncated_text = text_fields(&[b"Key", &[1, 0], b"", b""]);
+    itxt_with_truncated_text.extend_from_slice(&truncated_stream);
+    let mut ztxt_with_truncated_text = text_fields(&[b"Key", &[0]]);
+    ztxt_with_truncated_text.extend_from_slice(&truncated_stream);
+
+    let png = encode_with_chunks(&image, &[
+        (b"gAMA", vec![0, 1, 2]),
+        (b"cHRM", vec![0; 31]),
+        (b"sRGB", vec![0, 0]),
+        (b"iCCP", b"No null byte".to_vec()),
+        (b"iCCP", iccp_with_unknown_compression_method),
+        (b"tEXt", b"No null byte".to_vec()),
+        (b"tEXt", text_fields(&[b"Fine", b"Still read"])),
+        (b"zTXt", b"Key".to_vec()),
+        (b"zTXt", ztxt_with_truncated_text),
+        (b"iTXt", b"Key\0".to_vec()),
+        (b"iTXt", text_fields(&[b"Key", &[0, 0], b"No translated keyword"])),
+        (b"iTXt", itxt_with_unknown_compression_method),
+        (b"iTXt", itxt_with_truncated_text),
+        (b"acTL", vec![0; 7]),
+    ], &[]);
+    let info = probe(&png);
+    assert_eq!(info.color_space, ColorSpace::default());
+    assert_eq!(info.text, vec![text("Fine", "Still read", "", "")]);
+    assert_eq!(info.animation, None);
+
+    // Running out of data before the image data is an error, though.
+    match ImageInfo::probe(&mut Cursor::new(&png[0..60])) {
+        Err(PngError::InvalidMetadata(_)) => {}
+        _ => panic!("Probing a truncated image should fail!"),
+    }
+}
+
+#[test]
+fn probing_ignores_compressed_chunks_that_decompress_to_more_than_4mb() {
+    let image = TestImage::new(3, 3, 0, false, 103);
+    let (limit, over_limit) = (vec![b'a'; 4 * 1024 * 1024], vec![b'b'; 4 * 1024 * 1024 + 1]);
+    let png = encode_with_chunks(&image, &[
+        (b"iCCP", compressed_text_fields(&[b"Huge"], &over_limit)),
+        (b"zTXt", compressed_text_fields(&[b"Huge"], &over_limit)),
+        (b"iTXt", itxt(b"Huge", true, b"", "", &String::from_utf8(over_limit.clone()).unwrap())),
+        (b"zTXt", compressed_text_fields(&[b"Big"], &limit)),
+    ], &[]);
+    let info = probe(&png);
+    assert_eq!(info.color_space.icc_profile, None);
+    assert_eq!(info.text.len(), 1);
+    assert_eq!(info.text[0].keyword, "Big");
+    assert!(info.text[0].text.as_bytes() == &limit[..]);
+
+    assert!(inflate::inflate_at_most(&zlib_stored(&limit), limit.len()).unwrap() == limit);
+    assert_entropy_decoding_error(inflate::inflate_at_most(&zlib_stored(&limit),
+                                                           limit.len() - 1));
+}
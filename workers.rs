@@ -0,0 +1,428 @@
+// parng/workers.rs
+//
+// Copyright (c) 2016 Mozilla Foundation
+
+//! A pool of threads that cooperate to predict batches of scanlines.
+//!
+//! Each scanline is divided into column tiles, one per worker, and worker N always predicts tile N
+//! of every scanline. The predictor thread itself is worker 0, so it does its share of the work
+//! instead of waiting for the others. Each worker keeps its own copy of the tile it predicted for
+//! the previous scanline to use as the reference, so the None and Up predictors need nothing from
+//! other workers. The Left, Average, and Paeth predictors additionally need the pixel to the left
+//! of the tile (and, for Paeth, the pixel above that). Worker N receives those pixels from worker
+//! N - 1 as soon as that worker has finished the scanline, so runs of dependent scanlines are
+//! pipelined across the workers as a wavefront.
+//!
+//! Tiles begin on SIMD vector boundaries, and truecolor tiles are predicted with the same
+//! accelerated routines as whole scanlines are.
+//!
+//! The workers predict into their own buffers, because the data provider can only be accessed
+//! from the predictor thread. That thread copies the predicted tiles into the data provider's
+//! scanlines afterward.
+
+use imageloader::{self, InterlacingInfo, LevelOfDetail};
+use prediction::{self, Predictor, ScanlineToPredict};
+use std::cmp;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+/// Tiles narrower than this many pixels aren't worth handing to a separate worker.
+const MIN_TILE_WIDTH: u32 = 64;
+
+/// Tiles begin at multiples of this many pixels, the number of RGBA pixels in a SIMD vector.
+const TILE_ALIGNMENT: u32 = 4;
+
+pub struct WorkerPool {
+    /// The state of worker 0, which runs on the predictor thread.
+    local_worker: TileWorker,
+    senders: Vec<Sender<WorkerMsg>>,
+    threads: Vec<JoinHandle<()>>,
+    receiver: Receiver<WorkerResult>,
+    next_scanline_index: u64,
+    spare_output_buffers: Vec<Vec<u8>>,
+}
+
+impl WorkerPool {
+    /// Creates a pool of `worker_count` workers, spawning a thread for each except the first.
+    pub fn new(worker_count: u32) -> WorkerPool {
+        let (result_sender, result_receiver) = mpsc::channel();
+        let mut workers = Vec::with_capacity(worker_count as usize);
+        let mut edge_receiver = None;
+        for worker_index in 0..worker_count {
+            let (next_edge_sender, next_edge_receiver) = if worker_index + 1 < worker_count {
+                let (edge_sender, edge_receiver) = mpsc::channel();
+                (Some(edge_sender), Some(edge_receiver))
+            } else {
+                (None, None)
+            };
+            workers.push(TileWorker {
+                index: worker_index,
+                count: worker_count,
+                edges: edge_receiver.map(EdgeReceiver::new),
+                edge_sender: next_edge_sender,
+                prev: vec![],
+                aligned_src: vec![],
+                aligned_prev: vec![],
+                aligned_dest: vec![],
+            });
+            edge_receiver = next_edge_receiver;
+        }
+
+        let mut workers = workers.into_iter();
+        let local_worker = workers.next().expect("A worker pool needs at least one worker!");
+        let (mut senders, mut threads) = (vec![], vec![]);
+        for worker in workers {
+            let (sender, receiver) = mpsc::channel();
+            let result_sender = result_sender.clone();
+            threads.push(thread::spawn(move || run_worker(worker, receiver, result_sender)));
+            senders.push(sender)
+        }
+        WorkerPool {
+            local_worker: local_worker,
+            senders: senders,
+            threads: threads,
+            receiver: result_receiver,
+            next_scanline_index: 0,
+            spare_output_buffers: vec![],
+        }
+    }
+
+    /// Predicts the given scanlines, which must be in the order they appear in the image data.
+    ///
+    /// Returns the block of image data and the scanlines so that they can be recycled, along with
+    /// the predicted pixels, which are to be copied out with `PredictedTiles::copy_next_scanline()`
+    /// in the same order.
+    pub fn predict(&mut self,
+                   width: u32,
+                   color_depth: u8,
+                   indexed: bool,
+                   data: Vec<u8>,
+                   scanlines: Vec<ScanlineToPredict>)
+                   -> (Vec<u8>, Vec<ScanlineToPredict>, PredictedTiles) {
+        let batch = Arc::new(Batch {
+            width: width,
+            color_depth: color_depth,
+            indexed: indexed,
+            first_scanline_index: self.next_scanline_index,
+            data: data,
+            scanlines: scanlines,
+        });
+        self.next_scanline_index += batch.scanlines.len() as u64;
+
+        for sender in &self.senders {
+            let output = self.spare_output_buffers.pop().unwrap_or(vec![]);
+            sender.send(WorkerMsg {
+                batch: batch.clone(),
+                output: output,
+            }).unwrap()
+        }
+
+        let mut outputs = vec![vec![]; self.senders.len() + 1];
+        let mut local_output = self.spare_output_buffers.pop().unwrap_or(vec![]);
+        self.local_worker.predict_batch(&*batch, &mut local_output);
+        outputs[0] = local_output;
+        for _ in 0..self.senders.len() {
+            let result = self.receiver.recv().expect("Worker thread hung up!");
+            outputs[result.worker_index as usize] = result.output;
+        }
+
+        let batch = match Arc::try_unwrap(batch) {
+            Ok(batch) => batch,
+            Err(_) => panic!("Worker thread still holds a batch it finished predicting!"),
+        };
+        let cursors = vec![0; outputs.len()];
+        (batch.data, batch.scanlines, PredictedTiles {
+            width: width,
+            pixel_size: pixel_size(indexed),
+            outputs: outputs,
+            cursors: cursors,
+        })
+    }
+
+    /// Returns the buffers of a batch of predicted tiles to the pool for reuse.
+    pub fn recycle(&mut self, tiles: PredictedTiles) {
+        self.spare_output_buffers.extend(tiles.outputs)
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // Hanging up tells the worker threads to exit.
+        self.senders.clear();
+        for thread in self.threads.drain(..) {
+            drop(thread.join())
+        }
+    }
+}
+
+/// The pixels that the workers predicted for a batch of scanlines.
+pub struct PredictedTiles {
+    width: u32,
+    pixel_size: usize,
+    outputs: Vec<Vec<u8>>,
+    cursors: Vec<usize>,
+}
+
+impl PredictedTiles {
+    /// Copies the pixels of the next scanline in the batch into `dest`, `stride` bytes apart.
+    pub fn copy_next_scanline(&mut self,
+                              dest: &mut [u8],
+                              stride: u8,
+                              scanline_y: u32,
+                              lod: LevelOfDetail) {
+        let scanline_width = InterlacingInfo::new(scanline_y, 32, lod).scanline_width(self.width,
+                                                                                       32);
+        let (pixel_size, stride) = (self.pixel_size, stride as usize);
+        let worker_count = self.outputs.len() as u32;
+        for (tile_index, (output, cursor)) in self.outputs
+                                                  .iter()
+                                                  .zip(self.cursors.iter_mut())
+                                                  .enumerate() {
+            let (start, end) = tile_bounds(scanline_width, tile_index as u32, worker_count);
+            if start == end {
+                continue
+            }
+            let tile_length = (end - start) as usize * pixel_size;
+            let tile = &output[*cursor..(*cursor + tile_length)];
+            *cursor += tile_length;
+            for (dest, src) in dest[(start as usize * stride)..].chunks_mut(stride)
+                                                                 .zip(tile.chunks(pixel_size)) {
+                dest[0..pixel_size].clone_from_slice(src)
+            }
+        }
+    }
+}
+
+struct Batch {
+    width: u32,
+    color_depth: u8,
+    indexed: bool,
+    first_scanline_index: u64,
+    data: Vec<u8>,
+    scanlines: Vec<ScanlineToPredict>,
+}
+
+struct WorkerMsg {
+    batch: Arc<Batch>,
+    output: Vec<u8>,
+}
+
+struct WorkerResult {
+    worker_index: u32,
+    output: Vec<u8>,
+}
+
+/// The last pixel of a worker's tile in a scanline, sent to the worker responsible for the next
+/// tile.
+#[derive(Copy, Clone)]
+struct EdgePixel {
+    scanline_index: u64,
+    pixel: [u8; 4],
+}
+
+struct EdgeReceiver {
+    receiver: Receiver<EdgePixel>,
+    current: Option<EdgePixel>,
+    previous: Option<EdgePixel>,
+}
+
+impl EdgeReceiver {
+    fn new(receiver: Receiver<EdgePixel>) -> EdgeReceiver {
+        EdgeReceiver {
+            receiver: receiver,
+            current: None,
+            previous: None,
+        }
+    }
+
+    /// Waits for the previous worker to finish the given scanline, and returns the pixels to the
+    /// left of and to the upper left of this worker's tile.
+    fn wait(&mut self, scanline_index: u64) -> ([u8; 4], [u8; 4]) {
+        while self.current.map_or(true, |edge| edge.scanline_index < scanline_index) {
+            self.previous = self.current;
+            self.current = Some(self.receiver.recv().expect("Previous worker thread hung up!"))
+        }
+        let left = self.current.unwrap();
+        debug_assert!(left.scanline_index == scanline_index);
+        let upper_left = match self.previous {
+            Some(edge) if edge.scanline_index + 1 == scanline_index => edge.pixel,
+            _ => [0; 4],
+        };
+        (left.pixel, upper_left)
+    }
+}
+
+/// The state of one worker, which predicts the same tile of every scanline.
+struct TileWorker {
+    index: u32,
+    count: u32,
+    edges: Option<EdgeReceiver>,
+    edge_sender: Option<Sender<EdgePixel>>,
+    /// The pixels this worker predicted for the previous scanline.
+    prev: Vec<u8>,
+    /// Space to set up properly aligned tiles for the accelerated routines in.
+    aligned_src: Vec<u8>,
+    aligned_prev: Vec<u8>,
+    aligned_dest: Vec<u8>,
+}
+
+fn run_worker(mut worker: TileWorker,
+              receiver: Receiver<WorkerMsg>,
+              result_sender: Sender<WorkerResult>) {
+    while let Ok(WorkerMsg { batch, mut output }) = receiver.recv() {
+        worker.predict_batch(&*batch, &mut output);
+
+        // Let go of the batch before replying so that the predictor thread can take back
+        // ownership of the image data.
+        drop(batch);
+        result_sender.send(WorkerResult {
+            worker_index: worker.index,
+            output: output,
+        }).unwrap()
+    }
+}
+
+impl TileWorker {
+    fn predict_batch(&mut self, batch: &Batch, output: &mut Vec<u8>) {
+        output.clear();
+        for (index, scanline) in batch.scanlines.iter().enumerate() {
+            let scanline_index = batch.first_scanline_index + index as u64;
+            self.predict_scanline(batch, scanline, scanline_index, output)
+        }
+    }
+
+    fn predict_scanline(&mut self,
+                        batch: &Batch,
+                        scanline: &ScanlineToPredict,
+                        scanline_index: u64,
+                        output: &mut Vec<u8>) {
+        let scanline_width =
+            InterlacingInfo::new(scanline.y, 32, scanline.lod).scanline_width(batch.width, 32);
+        let (start, end) = tile_bounds(scanline_width, self.index, self.count);
+        if start == end {
+            return
+        }
+
+        let pixel_size = pixel_size(batch.indexed);
+        let bytes_per_pixel = (batch.color_depth / 8) as usize;
+        let tile_length = (end - start) as usize * pixel_size;
+        if scanline.y == 0 {
+            self.prev.clear();
+            self.prev.resize(tile_length, 0);
+        }
+
+        let (left, upper_left) = match (scanline.predictor, &mut self.edges) {
+            (Predictor::Left, &mut Some(ref mut edges)) |
+            (Predictor::Average, &mut Some(ref mut edges)) |
+            (Predictor::Paeth, &mut Some(ref mut edges)) => {
+                let (left, upper_left) = edges.wait(scanline_index);
+                if scanline.y == 0 {
+                    (left, [0; 4])
+                } else {
+                    (left, upper_left)
+                }
+            }
+            _ => ([0; 4], [0; 4]),
+        };
+
+        let output_start = output.len();
+        output.resize(output_start + tile_length, 0);
+        let src_start = scanline.offset + start as usize * bytes_per_pixel;
+        let src_end = scanline.offset + end as usize * bytes_per_pixel;
+        if batch.indexed {
+            // The accelerated routines always write 32-bit pixels, so they can't be used for
+            // indexed images.
+            scanline.predictor.predict_tile(&mut output[output_start..],
+                                            &batch.data[src_start..src_end],
+                                            &self.prev[..],
+                                            batch.color_depth,
+                                            pixel_size as u8,
+                                            true,
+                                            left,
+                                            upper_left);
+        } else {
+            self.accelerated_predict_tile(scanline.predictor,
+                                          &mut output[output_start..],
+                                          &batch.data[src_start..src_end],
+                                          batch.color_depth,
+                                          left,
+                                          upper_left)
+        }
+
+        let tile = &output[output_start..];
+        self.prev.clear();
+        self.prev.extend_from_slice(tile);
+
+        if let Some(ref edge_sender) = self.edge_sender {
+            let mut pixel = [0; 4];
+            pixel[0..pixel_size].clone_from_slice(&tile[(tile_length - pixel_size)..]);
+            edge_sender.send(EdgePixel {
+                scanline_index: scanline_index,
+                pixel: pixel,
+            }).unwrap()
+        }
+    }
+
+    /// Predicts a tile of truecolor pixels into `dest` with the accelerated routines, by way of
+    /// properly aligned copies of the tile and its reference pixels.
+    fn accelerated_predict_tile(&mut self,
+                                predictor: Predictor,
+                                dest: &mut [u8],
+                                src: &[u8],
+                                color_depth: u8,
+                                left: [u8; 4],
+                                upper_left: [u8; 4]) {
+        // The accelerated routines may read up to 32 bytes past the end of the source pixels.
+        let aligned_src_length = imageloader::align(src.len());
+        let aligned_src = prediction::aligned_scratch_scanline(aligned_src_length + 32,
+                                                               &mut self.aligned_src);
+        aligned_src[0..src.len()].clone_from_slice(src);
+        for sample in 0..((color_depth / 8) as usize) {
+            let adjustment = predictor.tile_start_adjustment(left[sample],
+                                                             self.prev[sample],
+                                                             upper_left[sample]);
+            aligned_src[sample] = aligned_src[sample].wrapping_add(adjustment)
+        }
+
+        let aligned_length = imageloader::align(dest.len());
+        let aligned_prev = prediction::aligned_scratch_scanline(aligned_length,
+                                                                &mut self.aligned_prev);
+        aligned_prev[0..dest.len()].clone_from_slice(&self.prev[..]);
+        let aligned_dest = prediction::aligned_scratch_scanline(aligned_length,
+                                                                &mut self.aligned_dest);
+        predictor.accelerated_predict(aligned_dest,
+                                      &aligned_src[0..aligned_src_length],
+                                      aligned_prev,
+                                      (dest.len() / 4) as u32,
+                                      color_depth,
+                                      4);
+        dest.clone_from_slice(&aligned_dest[0..dest.len()])
+    }
+}
+
+/// Returns the range of pixels in a scanline `width` pixels wide that the given worker is
+/// responsible for. The range is empty if the scanline is too narrow to be worth splitting that
+/// many ways. Every range but the last is a whole number of SIMD vectors wide.
+fn tile_bounds(width: u32, worker_index: u32, worker_count: u32) -> (u32, u32) {
+    let tile_count = cmp::max(cmp::min(worker_count, width / MIN_TILE_WIDTH), 1);
+    if worker_index >= tile_count {
+        return (width, width)
+    }
+    let boundary = |tile_index: u32| {
+        if tile_index == tile_count {
+            width
+        } else {
+            (width as u64 * tile_index as u64 / tile_count as u64) as u32 & !(TILE_ALIGNMENT - 1)
+        }
+    };
+    (boundary(worker_index), boundary(worker_index + 1))
+}
+
+fn pixel_size(indexed: bool) -> usize {
+    if indexed {
+        1
+    } else {
+        4
+    }
+}